@@ -0,0 +1,109 @@
+//! Config files are layered under the environment: each setting is loaded
+//! into the environment variable named after its dotted path, where command
+//! line options pick it up along with anything set there directly.
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+
+/// A setting by its dotted path in the config file, with its value if set
+pub type Setting = (&'static str, Option<String>);
+
+/// Directory searched for config files after the XDG config dirs
+const SYSTEM_DIR: &str = "/etc/bark";
+
+#[derive(Debug, Error)]
+pub enum LoadError {
+    #[error("reading {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("{0}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+}
+
+pub fn setting<T: ToString>(path: &'static str, value: Option<T>) -> Setting {
+    (path, value.map(|value| value.to_string()))
+}
+
+/// Environment variable a setting is passed on through, eg.
+/// `source.delay_ms` sets `BARK_SOURCE_DELAY_MS`
+pub fn env_name(path: &str) -> String {
+    format!("BARK_{}", path.replace('.', "_").to_uppercase())
+}
+
+/// Pass settings on through the environment. Variables that are already
+/// set are left alone, so the environment takes precedence over the file
+pub fn load_into_env(settings: Vec<Setting>) {
+    for (path, value) in settings {
+        let name = env_name(path);
+
+        if let Some(value) = value {
+            if env::var_os(&name).is_none() {
+                env::set_var(name, value);
+            }
+        }
+    }
+}
+
+/// Read and parse a config file, returning the raw table alongside so that
+/// callers can look for settings they don't know
+pub fn load<T: DeserializeOwned>(path: &Path) -> Result<(T, toml::Table), LoadError> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| LoadError::Read(path.to_owned(), e))?;
+
+    // catches syntax errors, with the line they're on
+    let table = toml::from_str::<toml::Table>(&contents)
+        .map_err(|e| LoadError::Parse(path.to_owned(), e))?;
+
+    // catches values of the wrong type
+    let config = toml::from_str::<T>(&contents)
+        .map_err(|e| LoadError::Parse(path.to_owned(), e))?;
+
+    Ok((config, table))
+}
+
+/// Dotted paths of settings in `table` that aren't among `known`, which
+/// would otherwise be silently ignored
+pub fn unknown_settings(table: &toml::Table, known: &[&str]) -> Vec<String> {
+    let mut unknown = Vec::new();
+    collect_unknown(table, "", known, &mut unknown);
+    unknown
+}
+
+fn collect_unknown(table: &toml::Table, prefix: &str, known: &[&str], unknown: &mut Vec<String>) {
+    for (key, value) in table {
+        let path = format!("{prefix}{key}");
+
+        match value {
+            toml::Value::Table(table) if !known.contains(&path.as_str()) => {
+                collect_unknown(table, &format!("{path}."), known, unknown);
+            }
+            _ if known.contains(&path.as_str()) => {}
+            _ => unknown.push(path),
+        }
+    }
+}
+
+/// Locate a config file by name: the file named by `BARK_CONFIG` if set,
+/// otherwise in the current directory, the XDG config dirs, then /etc/bark
+pub fn find(file_name: &str) -> Option<PathBuf> {
+    if let Some(path) = env::var_os("BARK_CONFIG") {
+        return Some(path.into());
+    }
+
+    // try current directory first
+    let local = Path::new(file_name);
+    if local.exists() {
+        return Some(local.to_owned());
+    }
+
+    // then xdg config dirs
+    let dirs = xdg::BaseDirectories::new().unwrap();
+    if let Some(path) = dirs.find_config_file(file_name) {
+        return Some(path);
+    }
+
+    let system = Path::new(SYSTEM_DIR).join(file_name);
+    system.exists().then_some(system)
+}
@@ -0,0 +1,10 @@
+//! Scaffolding shared by the executables in the bark workspace: logging,
+//! config file loading, the metrics server, threads and their scheduling,
+//! and signal handling.
+
+pub mod config;
+pub mod logging;
+pub mod metrics;
+pub mod sched;
+pub mod signal;
+pub mod thread;
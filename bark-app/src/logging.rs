@@ -0,0 +1,19 @@
+use log::LevelFilter;
+
+/// Log to stderr, at debug level in debug builds and info otherwise unless
+/// overridden with `RUST_LOG`
+pub fn init() {
+    env_logger::builder()
+        .format_timestamp_millis()
+        .filter_level(default_level())
+        .parse_default_env()
+        .init();
+}
+
+fn default_level() -> LevelFilter {
+    if cfg!(debug_assertions) {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    }
+}
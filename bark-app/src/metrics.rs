@@ -0,0 +1,40 @@
+use std::net::SocketAddr;
+
+#[cfg(feature = "http")]
+use axum::Router;
+use structopt::StructOpt;
+#[cfg(feature = "http")]
+use thiserror::Error;
+
+#[derive(StructOpt)]
+pub struct MetricsOpt {
+    #[structopt(
+        long = "metrics-listen",
+        env = "BARK_METRICS_LISTEN",
+        default_value = "0.0.0.0:1530",
+    )]
+    listen: SocketAddr,
+}
+
+impl MetricsOpt {
+    pub fn listen(&self) -> SocketAddr {
+        self.listen
+    }
+}
+
+#[cfg(feature = "http")]
+#[derive(Debug, Error)]
+#[error("starting metrics server: {0}")]
+pub struct StartError(#[from] std::io::Error);
+
+/// Serve `app` on the metrics listen address in the background
+#[cfg(feature = "http")]
+pub async fn serve(opt: &MetricsOpt, app: Router) -> Result<(), StartError> {
+    let listener = tokio::net::TcpListener::bind(&opt.listen).await?;
+
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap()
+    });
+
+    Ok(())
+}
@@ -0,0 +1,377 @@
+//! Scheduling priority for audio threads. What can be set depends on the
+//! platform and on what the process is allowed to do: on Linux, SCHED_FIFO
+//! and SCHED_RR need CAP_SYS_NICE or an RLIMIT_RTPRIO allowance, and nice
+//! values below zero need CAP_SYS_NICE or an RLIMIT_NICE allowance, while
+//! macOS has a time constraint policy instead. Each audio thread tries the
+//! configured policy at the most the system allows, then falls back to the
+//! next best, and logs what it got rather than failing.
+
+use std::fmt::{self, Display};
+use std::io::{self, ErrorKind};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+
+use structopt::StructOpt;
+use thiserror::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// The best the platform has: fifo on Linux, time-constraint on macOS
+    Auto,
+    Fifo,
+    RoundRobin,
+    Nice,
+    TimeConstraint,
+    None,
+}
+
+#[derive(Debug, Error)]
+#[error("unknown scheduling policy {0:?}, expected auto, fifo, rr, nice, time-constraint or none")]
+pub struct ParsePolicyError(String);
+
+impl FromStr for Policy {
+    type Err = ParsePolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Policy::Auto),
+            "fifo" => Ok(Policy::Fifo),
+            "rr" => Ok(Policy::RoundRobin),
+            "nice" => Ok(Policy::Nice),
+            "time-constraint" => Ok(Policy::TimeConstraint),
+            "none" => Ok(Policy::None),
+            _ => Err(ParsePolicyError(s.to_owned())),
+        }
+    }
+}
+
+impl Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Policy::Auto => write!(f, "auto"),
+            Policy::Fifo => write!(f, "fifo"),
+            Policy::RoundRobin => write!(f, "rr"),
+            Policy::Nice => write!(f, "nice"),
+            Policy::TimeConstraint => write!(f, "time-constraint"),
+            Policy::None => write!(f, "none"),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct SchedOpt {
+    /// Scheduling policy for audio threads: auto, fifo, rr, nice,
+    /// time-constraint (macOS only) or none. Falls back to the next best
+    /// policy if the system doesn't allow it
+    #[structopt(long, env = "BARK_SCHED_POLICY", default_value = "auto")]
+    pub sched_policy: Policy,
+
+    /// Realtime priority for fifo and rr, lowered to the most the system
+    /// allows
+    #[structopt(long, env = "BARK_SCHED_PRIORITY", default_value = "99")]
+    pub sched_priority: i32,
+
+    /// Nice value for the nice policy, which fifo and rr fall back to,
+    /// raised to the least the system allows
+    #[structopt(long, env = "BARK_SCHED_NICE", default_value = "-10", allow_hyphen_values = true)]
+    pub sched_nice: i32,
+}
+
+impl Default for SchedOpt {
+    fn default() -> Self {
+        SchedOpt {
+            sched_policy: Policy::Auto,
+            sched_priority: 99,
+            sched_nice: -10,
+        }
+    }
+}
+
+static CONFIG: OnceLock<SchedOpt> = OnceLock::new();
+
+/// Set how audio threads are scheduled, before any are started. Threads
+/// use the defaults if this is never called
+pub fn configure(opt: SchedOpt) {
+    let _ = CONFIG.set(opt);
+}
+
+/// How a thread ended up scheduled
+#[derive(PartialEq)]
+enum Scheduled {
+    Realtime(Policy, i32),
+    Nice(i32),
+    TimeConstraint,
+    Default,
+}
+
+impl Display for Scheduled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scheduled::Realtime(Policy::RoundRobin, priority) => write!(f, "SCHED_RR priority {priority}"),
+            Scheduled::Realtime(_, priority) => write!(f, "SCHED_FIFO priority {priority}"),
+            Scheduled::Nice(nice) => write!(f, "nice value {nice}"),
+            Scheduled::TimeConstraint => write!(f, "time constraint policy"),
+            Scheduled::Default => write!(f, "default priority"),
+        }
+    }
+}
+
+/// Raise the calling thread's priority for audio work, as configured
+pub fn raise_current_thread() {
+    let opt = CONFIG.get_or_init(SchedOpt::default);
+
+    let mut failures = Vec::new();
+    let mut scheduled = Scheduled::Default;
+
+    for policy in fallbacks(opt.sched_policy) {
+        match apply(*policy, opt) {
+            Ok(result) => {
+                scheduled = result;
+                break;
+            }
+            Err(err) => {
+                failures.push((*policy, err));
+            }
+        }
+    }
+
+    report(scheduled, &failures);
+}
+
+/// Policies to try in turn for `policy`
+fn fallbacks(policy: Policy) -> &'static [Policy] {
+    if cfg!(any(target_os = "linux", target_os = "android")) {
+        match policy {
+            Policy::Auto | Policy::Fifo => &[Policy::Fifo, Policy::Nice],
+            Policy::RoundRobin => &[Policy::RoundRobin, Policy::Nice],
+            Policy::Nice => &[Policy::Nice],
+            Policy::TimeConstraint => &[Policy::TimeConstraint, Policy::Fifo, Policy::Nice],
+            Policy::None => &[],
+        }
+    } else if cfg!(target_os = "macos") {
+        match policy {
+            Policy::Auto | Policy::TimeConstraint => &[Policy::TimeConstraint],
+            Policy::Fifo => &[Policy::Fifo, Policy::TimeConstraint],
+            Policy::RoundRobin => &[Policy::RoundRobin, Policy::TimeConstraint],
+            Policy::Nice => &[Policy::Nice],
+            Policy::None => &[],
+        }
+    } else {
+        match policy {
+            Policy::Auto | Policy::Fifo => &[Policy::Fifo],
+            Policy::RoundRobin => &[Policy::RoundRobin],
+            Policy::Nice => &[Policy::Nice],
+            Policy::TimeConstraint => &[Policy::TimeConstraint],
+            Policy::None => &[],
+        }
+    }
+}
+
+fn apply(policy: Policy, opt: &SchedOpt) -> io::Result<Scheduled> {
+    match policy {
+        Policy::Fifo => set_realtime(libc::SCHED_FIFO, opt.sched_priority)
+            .map(|priority| Scheduled::Realtime(Policy::Fifo, priority)),
+        Policy::RoundRobin => set_realtime(libc::SCHED_RR, opt.sched_priority)
+            .map(|priority| Scheduled::Realtime(Policy::RoundRobin, priority)),
+        Policy::Nice => set_nice(opt.sched_nice)
+            .map(Scheduled::Nice),
+        Policy::TimeConstraint => set_time_constraint()
+            .map(|()| Scheduled::TimeConstraint),
+        Policy::Auto | Policy::None => Ok(Scheduled::Default),
+    }
+}
+
+/// Set a realtime policy, returning the priority it was set at
+fn set_realtime(policy: libc::c_int, priority: i32) -> io::Result<i32> {
+    let (min, max) = unsafe {
+        (libc::sched_get_priority_min(policy), libc::sched_get_priority_max(policy))
+    };
+
+    if min < 0 || max < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let priority = priority.clamp(min, max);
+
+    match set_realtime_at(policy, priority) {
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+            // unprivileged processes may still use priorities up to
+            // RLIMIT_RTPRIO, such as under systemd's LimitRTPRIO
+            match rtprio_limit().filter(|limit| *limit >= min && *limit < priority) {
+                Some(limit) => set_realtime_at(policy, limit).map(|()| limit),
+                None => Err(err),
+            }
+        }
+        result => result.map(|()| priority),
+    }
+}
+
+fn set_realtime_at(policy: libc::c_int, priority: i32) -> io::Result<()> {
+    // sched_param has more fields on some platforms, such as musl
+    let mut param: libc::sched_param = unsafe { std::mem::zeroed() };
+    param.sched_priority = priority;
+
+    // pthread_setschedparam rather than sched_setscheduler, which musl
+    // doesn't implement. it returns the error rather than setting errno
+    let rc = unsafe {
+        libc::pthread_setschedparam(libc::pthread_self(), policy, &param)
+    };
+
+    match rc {
+        0 => Ok(()),
+        err => Err(io::Error::from_raw_os_error(err)),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn rtprio_limit() -> Option<i32> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    let rc = unsafe { libc::getrlimit(libc::RLIMIT_RTPRIO, &mut limit) };
+    soft_limit(rc, &limit)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn rtprio_limit() -> Option<i32> {
+    None
+}
+
+/// Set the thread's nice value, returning the value it was set to
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_nice(nice: i32) -> io::Result<i32> {
+    let nice = nice.clamp(-20, 19);
+
+    match set_nice_at(nice) {
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+            // unprivileged processes may still lower their nice value as
+            // far as 20 - RLIMIT_NICE
+            let floor = nice_limit().map(|limit| 20 - limit);
+
+            match floor.filter(|floor| *floor > nice && *floor < 0) {
+                Some(floor) => set_nice_at(floor).map(|()| floor),
+                None => Err(err),
+            }
+        }
+        result => result.map(|()| nice),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_nice_at(nice: i32) -> io::Result<()> {
+    // on linux, PRIO_PROCESS with a who of 0 applies to the calling thread
+    let rc = unsafe {
+        libc::setpriority(libc::PRIO_PROCESS, 0, nice)
+    };
+
+    if rc < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Elsewhere nice values apply to the whole process, not just the thread
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_nice(_: i32) -> io::Result<i32> {
+    Err(io::Error::new(ErrorKind::Unsupported, "nice values are per process on this platform"))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn nice_limit() -> Option<i32> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    let rc = unsafe { libc::getrlimit(libc::RLIMIT_NICE, &mut limit) };
+    soft_limit(rc, &limit)
+}
+
+/// Soft limit from a `getrlimit` call, if there is one and it fits in an
+/// i32. The resource argument's type differs between libcs, so callers
+/// make the call themselves
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn soft_limit(rc: libc::c_int, limit: &libc::rlimit) -> Option<i32> {
+    if rc < 0 || limit.rlim_cur == libc::RLIM_INFINITY {
+        return None;
+    }
+
+    i32::try_from(limit.rlim_cur).ok()
+}
+
+/// Audio threads wake every packet, a millisecond or so, and do a fraction
+/// of a millisecond's work
+#[cfg(target_os = "macos")]
+const TIME_CONSTRAINT_PERIOD_NS: u64 = 1_000_000;
+#[cfg(target_os = "macos")]
+const TIME_CONSTRAINT_COMPUTATION_NS: u64 = 250_000;
+
+#[cfg(target_os = "macos")]
+fn set_time_constraint() -> io::Result<()> {
+    let mut timebase = libc::mach_timebase_info { numer: 0, denom: 0 };
+    unsafe { libc::mach_timebase_info(&mut timebase) };
+
+    if timebase.numer == 0 {
+        return Err(io::Error::other("mach_timebase_info failed"));
+    }
+
+    let to_abs = |ns: u64| (ns * u64::from(timebase.denom) / u64::from(timebase.numer)) as u32;
+
+    let mut policy = libc::thread_time_constraint_policy {
+        period: to_abs(TIME_CONSTRAINT_PERIOD_NS),
+        computation: to_abs(TIME_CONSTRAINT_COMPUTATION_NS),
+        constraint: to_abs(TIME_CONSTRAINT_PERIOD_NS),
+        preemptible: 1,
+    };
+
+    let rc = unsafe {
+        libc::thread_policy_set(
+            libc::pthread_mach_thread_np(libc::pthread_self()),
+            libc::THREAD_TIME_CONSTRAINT_POLICY,
+            &mut policy as *mut _ as libc::thread_policy_t,
+            libc::THREAD_TIME_CONSTRAINT_POLICY_COUNT,
+        )
+    };
+
+    if rc != libc::KERN_SUCCESS {
+        return Err(io::Error::other(format!("thread_policy_set returned {rc}")));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "macos"))]
+fn set_time_constraint() -> io::Result<()> {
+    Err(io::Error::new(ErrorKind::Unsupported, "time constraint policy is only on macOS"))
+}
+
+/// Log how the first audio thread was scheduled, and any other thread
+/// that ended up differently
+fn report(scheduled: Scheduled, failures: &[(Policy, io::Error)]) {
+    static FIRST: Mutex<Option<Scheduled>> = Mutex::new(None);
+
+    let mut first = FIRST.lock().unwrap();
+
+    if first.as_ref() == Some(&scheduled) {
+        return;
+    }
+
+    for (policy, err) in failures {
+        log::warn!("couldn't schedule audio thread with {policy} policy: {err}");
+    }
+
+    let denied = failures.iter().any(|(_, err)| err.kind() == ErrorKind::PermissionDenied);
+
+    if denied && cfg!(any(target_os = "linux", target_os = "android")) {
+        let path = std::env::current_exe()
+            .map(|path| path.display().to_string());
+
+        let path = path.as_ref()
+            .map(|path| path.as_str())
+            .unwrap_or("path/to/bark");
+
+        log::warn!("fix by running: setcap cap_sys_nice=ep {path}");
+    }
+
+    match (&scheduled, failures.is_empty()) {
+        (Scheduled::Default, false) => log::warn!("audio threads running at {scheduled}"),
+        _ => log::info!("audio threads running with {scheduled}"),
+    }
+
+    first.get_or_insert(scheduled);
+}
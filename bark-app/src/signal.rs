@@ -0,0 +1,48 @@
+use std::future::Future;
+use std::pin::pin;
+
+use futures::future::{self, Either};
+use nix::sys::signal::{SigSet, Signal};
+use tokio::sync::oneshot;
+
+use crate::thread;
+
+/// Run `future` until it finishes, or until the process is asked to stop
+/// with SIGINT or SIGTERM, returning `None` in that case. The future is
+/// dropped rather than the process killed, so that it can clean up, eg.
+/// withdrawing mDNS announcements.
+///
+/// Call this before starting any threads: the signals are blocked so that
+/// only a dedicated thread receives them, and threads inherit the blocked
+/// set from the thread that starts them.
+pub async fn until_shutdown<T>(future: impl Future<Output = T>) -> Option<T> {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+
+    if let Err(e) = signals.thread_block() {
+        log::warn!("failed to block signals, shutdown will not be clean: {e}");
+        return Some(future.await);
+    }
+
+    let (tx, rx) = oneshot::channel();
+
+    std::thread::spawn(move || {
+        thread::set_name("bark/signal");
+
+        match signals.wait() {
+            Ok(signal) => {
+                log::info!("received {signal}, shutting down");
+                let _ = tx.send(());
+            }
+            Err(e) => log::warn!("failed waiting for signals, shutdown will not be clean: {e}"),
+        }
+    });
+
+    match future::select(pin!(future), rx).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right((Ok(()), _)) => None,
+        // signal thread gave up, carry on without it
+        Either::Right((Err(_), future)) => Some(future.await),
+    }
+}
@@ -0,0 +1,39 @@
+use std::ffi::CString;
+
+use futures::future::{Future, FutureExt};
+use tokio::sync::oneshot;
+
+use crate::sched;
+
+pub fn set_name(name: &str) {
+    let cstr = CString::new(name)
+        .expect("not a cstring in set_thread_name");
+
+    unsafe {
+        libc::pthread_setname_np(libc::pthread_self(), cstr.as_ptr());
+    }
+}
+
+/// Raise the calling thread's priority for audio work, as configured with
+/// `sched::configure`
+pub fn set_realtime_priority() {
+    sched::raise_current_thread();
+}
+
+pub fn start<Ret: Send + 'static>(name: &'static str, func: impl FnOnce() -> Ret + Send + 'static)
+    -> impl Future<Output = Ret>
+{
+    let (tx, rx) = oneshot::channel();
+
+    std::thread::spawn(move || {
+        set_name(name);
+        let _ = tx.send(func());
+    });
+
+    rx.map(move |result| {
+        match result {
+            Ok(result) => result,
+            Err(_) => { panic!("thread panicked: {name}"); }
+        }
+    })
+}
@@ -1,8 +1,19 @@
 use bytemuck::{Pod, Zeroable};
 
+use crate::receive::resample;
+
+pub mod channels;
+pub mod declick;
+pub mod dsp;
+pub mod fade;
+pub mod format;
+pub mod limiter;
+pub mod loudness;
+pub mod protection;
+
 pub trait Format: Send + Sync + 'static {
     type Frame: Pod + Zeroable + Copy + Clone + Send;
-    type Sample: Pod + Zeroable + Copy + Clone + Send + soxr::format::Sample;
+    type Sample: Pod + Zeroable + Copy + Clone + Send + resample::Sample;
     const KIND: FormatKind;
 
     fn frames(frames: &[Self::Frame]) -> Frames;
@@ -104,3 +115,40 @@ pub fn f32_to_s16(input: f32) -> i16 {
     let output = (input * -scale).clamp(i16::MIN as f32, i16::MAX as f32);
     output as i16
 }
+
+pub fn s32_to_f32(input: i32) -> f32 {
+    let scale = i32::MIN as f64;
+    (input as f64 / -scale) as f32
+}
+
+pub fn f32_to_s32(input: f32) -> i32 {
+    let scale = i32::MIN as f64;
+    let output = (input as f64 * -scale).clamp(i32::MIN as f64, i32::MAX as f64);
+    output as i32
+}
+
+pub fn s16_to_s32(input: i16) -> i32 {
+    i32::from(input) << 16
+}
+
+pub fn s32_to_s16(input: i32) -> i16 {
+    (input >> 16) as i16
+}
+
+/// Scale audio by a linear gain factor
+pub fn apply_gain(frames: FramesMut, gain: f32) {
+    match frames {
+        FramesMut::S16(frames) => {
+            for frame in frames {
+                frame.0 = f32_to_s16(s16_to_f32(frame.0) * gain);
+                frame.1 = f32_to_s16(s16_to_f32(frame.1) * gain);
+            }
+        }
+        FramesMut::F32(frames) => {
+            for frame in frames {
+                frame.0 *= gain;
+                frame.1 *= gain;
+            }
+        }
+    }
+}
@@ -0,0 +1,47 @@
+//! Mapping stereo audio onto the receiver's speakers.
+
+use super::FrameF32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelMap {
+    /// Left and right as received
+    Stereo,
+    /// Both channels mixed down and played on each output
+    Mono,
+    /// Left and right exchanged
+    Swap,
+    /// Left channel played on each output
+    Left,
+    /// Right channel played on each output
+    Right,
+}
+
+impl ChannelMap {
+    pub fn apply(self, frames: &mut [FrameF32]) {
+        match self {
+            ChannelMap::Stereo => {}
+            ChannelMap::Mono => {
+                for frame in frames {
+                    // halved so that full scale in-phase content can't clip
+                    let mono = (frame.0 + frame.1) * 0.5;
+                    *frame = FrameF32(mono, mono);
+                }
+            }
+            ChannelMap::Swap => {
+                for frame in frames {
+                    *frame = FrameF32(frame.1, frame.0);
+                }
+            }
+            ChannelMap::Left => {
+                for frame in frames {
+                    frame.1 = frame.0;
+                }
+            }
+            ChannelMap::Right => {
+                for frame in frames {
+                    frame.0 = frame.1;
+                }
+            }
+        }
+    }
+}
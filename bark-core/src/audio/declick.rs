@@ -0,0 +1,71 @@
+//! Smooths over discontinuities in the audio sent to the output, such as
+//! when the stream switches, the queue resets, or silence is inserted for
+//! a lost packet. Rather than jumping straight to the new audio, which
+//! pops, output crossfades from the last frame played into it.
+
+use std::time::Duration;
+
+use bark_protocol::time::SampleDuration;
+
+use super::FrameF32;
+
+const FADE: Duration = Duration::from_millis(5);
+
+pub struct Declick {
+    fade: usize,
+    // last frame output
+    last: FrameF32,
+    // frame held at the start of the current fade, and how far into it
+    from: FrameF32,
+    position: Option<usize>,
+    silent: bool,
+}
+
+impl Declick {
+    /// Start from `last`, the last frame the output played. The first
+    /// audio processed crossfades from it
+    pub fn new(last: FrameF32) -> Self {
+        let fade = SampleDuration::from_std_duration_lossy(FADE).to_frame_count() as usize;
+
+        Declick {
+            fade,
+            last,
+            from: last,
+            position: Some(0),
+            silent: false,
+        }
+    }
+
+    pub fn last(&self) -> FrameF32 {
+        self.last
+    }
+
+    /// The next audio processed does not follow on from the last, crossfade
+    /// into it
+    pub fn discontinuity(&mut self) {
+        self.from = self.last;
+        self.position = Some(0);
+    }
+
+    /// Process frames about to be output. `silent` frames are silence
+    /// inserted in place of audio, faded into and out of
+    pub fn process(&mut self, frames: &mut [FrameF32], silent: bool) {
+        if silent != self.silent {
+            self.silent = silent;
+            self.discontinuity();
+        }
+
+        for frame in frames {
+            if let Some(position) = self.position {
+                let t = position as f32 / self.fade as f32;
+
+                frame.0 = self.from.0 * (1.0 - t) + frame.0 * t;
+                frame.1 = self.from.1 * (1.0 - t) + frame.1 * t;
+
+                self.position = Some(position + 1).filter(|position| *position < self.fade);
+            }
+
+            self.last = *frame;
+        }
+    }
+}
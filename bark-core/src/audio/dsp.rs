@@ -0,0 +1,132 @@
+//! The receiver's output chain: speaker protection high-pass and the peak
+//! limiter, run as the last stages before audio reaches the output.
+//!
+//! The chain can be reconfigured while audio is playing. Filters keep
+//! their state and ramp to their new coefficients, and the limiter ramps to
+//! its new threshold, so adjustments are heard without a gap or click. All
+//! stages always run, passing audio through unchanged when disabled, so
+//! the chain's latency never changes. It can also be bypassed, crossfading
+//! to the unprocessed audio for comparing settings by ear.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use bark_protocol::time::SampleDuration;
+
+use super::limiter::{self, Limiter};
+use super::protection::{Biquad, Coeffs, Profile};
+use super::FrameF32;
+
+// time taken to move between settings, or in and out of bypass
+const RAMP: Duration = Duration::from_millis(50);
+
+// threshold while the limiter is disabled, well clear of anything that
+// would not be clipped by the output anyway
+const UNLIMITED: f32 = 16.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Settings {
+    /// Soft limit peaks to just below full scale
+    pub limiter: bool,
+    /// Speaker protection profile
+    pub protection: Option<Profile>,
+}
+
+impl Settings {
+    fn threshold(&self) -> f32 {
+        let limiter = if self.limiter { limiter::THRESHOLD } else { UNLIMITED };
+        let protection = self.protection.map(Profile::threshold).unwrap_or(UNLIMITED);
+        limiter.min(protection)
+    }
+}
+
+pub struct Chain {
+    settings: Settings,
+    bypass: bool,
+    ramp: usize,
+    // fourth order high-pass, as two cascaded biquads
+    highpass: [Biquad; 2],
+    limiter: Limiter,
+    // input delayed to line up with the limiter's output, for bypass
+    dry: VecDeque<FrameF32>,
+    // how much of the dry audio is heard, ramping between 0.0 and 1.0
+    wet_dry: f32,
+}
+
+impl Chain {
+    pub fn new(settings: Settings) -> Self {
+        let ramp = SampleDuration::from_std_duration_lossy(RAMP).to_frame_count() as usize;
+        let limiter = Limiter::with_threshold(settings.threshold());
+        let latency = limiter.latency().to_frame_count() as usize;
+
+        Chain {
+            settings,
+            bypass: false,
+            ramp,
+            highpass: [0, 1].map(|index| Biquad::new(Coeffs::protection(settings.protection, index))),
+            limiter,
+            dry: std::iter::repeat_n(FrameF32(0.0, 0.0), latency).collect(),
+            wet_dry: 0.0,
+        }
+    }
+
+    pub fn settings(&self) -> Settings {
+        self.settings
+    }
+
+    /// Move to new settings, ramping over a short time
+    pub fn configure(&mut self, settings: Settings) {
+        if settings == self.settings {
+            return;
+        }
+
+        for (index, filter) in self.highpass.iter_mut().enumerate() {
+            filter.retune(Coeffs::protection(settings.protection, index), self.ramp);
+        }
+
+        self.limiter.set_threshold(settings.threshold(), self.ramp);
+        self.settings = settings;
+    }
+
+    pub fn bypass(&self) -> bool {
+        self.bypass
+    }
+
+    /// Crossfade to the unprocessed audio, or back
+    pub fn set_bypass(&mut self, bypass: bool) {
+        self.bypass = bypass;
+    }
+
+    /// Delay introduced into the audio
+    pub fn latency(&self) -> SampleDuration {
+        self.limiter.latency()
+    }
+
+    pub fn process(&mut self, frames: &mut [FrameF32]) {
+        self.dry.extend(frames.iter().copied());
+
+        for filter in &mut self.highpass {
+            filter.process(frames);
+        }
+
+        // limit after filtering, the high-pass can add a little overshoot
+        self.limiter.process(frames);
+
+        let target = if self.bypass { 1.0 } else { 0.0 };
+        let step = 1.0 / self.ramp.max(1) as f32;
+
+        for frame in frames {
+            let dry = self.dry.pop_front().unwrap_or(FrameF32(0.0, 0.0));
+
+            self.wet_dry = if self.wet_dry < target {
+                (self.wet_dry + step).min(target)
+            } else {
+                (self.wet_dry - step).max(target)
+            };
+
+            let wet = 1.0 - self.wet_dry;
+            frame.0 = frame.0 * wet + dry.0 * self.wet_dry;
+            frame.1 = frame.1 * wet + dry.1 * self.wet_dry;
+        }
+    }
+}
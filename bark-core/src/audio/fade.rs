@@ -0,0 +1,81 @@
+//! Gradual fades: in, for bringing a stream back in after it was interrupted
+//! by another, such as music resuming after an announcement, and out, for
+//! winding down a stream whose source has ended.
+
+use std::f32::consts::PI;
+use std::time::Duration;
+
+use bark_protocol::time::SampleDuration;
+
+use super::FrameF32;
+
+pub struct FadeIn {
+    length: usize,
+    position: usize,
+}
+
+impl FadeIn {
+    pub fn new(duration: Duration) -> Self {
+        FadeIn {
+            length: SampleDuration::from_std_duration_lossy(duration).to_frame_count() as usize,
+            position: 0,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.position >= self.length
+    }
+
+    pub fn process(&mut self, frames: &mut [FrameF32]) {
+        for frame in frames {
+            if self.is_done() {
+                return;
+            }
+
+            // raised cosine, starting and finishing gently
+            let t = self.position as f32 / self.length as f32;
+            let gain = 0.5 - 0.5 * (PI * t).cos();
+
+            frame.0 *= gain;
+            frame.1 *= gain;
+
+            self.position += 1;
+        }
+    }
+}
+
+pub struct FadeOut {
+    length: usize,
+    position: usize,
+}
+
+impl FadeOut {
+    pub fn new(duration: Duration) -> Self {
+        FadeOut {
+            length: SampleDuration::from_std_duration_lossy(duration).to_frame_count() as usize,
+            position: 0,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.position >= self.length
+    }
+
+    /// Frames past the end of the fade are silenced
+    pub fn process(&mut self, frames: &mut [FrameF32]) {
+        for frame in frames {
+            let gain = if self.is_done() {
+                0.0
+            } else {
+                // raised cosine, the mirror image of FadeIn
+                let t = self.position as f32 / self.length as f32;
+                0.5 + 0.5 * (PI * t).cos()
+            };
+
+            frame.0 *= gain;
+            frame.1 *= gain;
+
+            self.position = (self.position + 1).min(self.length);
+        }
+    }
+}
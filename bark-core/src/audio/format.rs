@@ -0,0 +1,70 @@
+//! Conversion from the f32 receive pipeline to the output device format.
+
+use bark_protocol::CHANNELS;
+
+use super::{Format, FrameF32, FrameS16, FramesMut};
+
+/// Quantizes f32 audio to 16 bit with TPDF dither, so that low level audio
+/// is not distorted by truncation. Optionally shapes the dither noise out of
+/// the most audible frequencies with first order error feedback.
+pub struct Dither {
+    rng: u32,
+    noise_shaping: bool,
+    // quantization error from the previous sample of each channel
+    error: [f32; CHANNELS.0 as usize],
+}
+
+impl Dither {
+    pub fn new(noise_shaping: bool) -> Self {
+        Dither {
+            rng: 0x9e3779b9,
+            noise_shaping,
+            error: Default::default(),
+        }
+    }
+
+    pub fn quantize(&mut self, input: &[FrameF32], output: &mut [FrameS16]) {
+        for (input, output) in input.iter().zip(output) {
+            output.0 = self.quantize_sample(0, input.0);
+            output.1 = self.quantize_sample(1, input.1);
+        }
+    }
+
+    fn quantize_sample(&mut self, channel: usize, sample: f32) -> i16 {
+        let scale = -(i16::MIN as f32);
+
+        let mut value = sample * scale;
+
+        if self.noise_shaping {
+            value -= self.error[channel];
+        }
+
+        // sum of two uniform distributions gives triangular noise of
+        // +/- 1 LSB, which decorrelates quantization error from the signal
+        let dither = self.uniform() + self.uniform();
+
+        let quantized = (value + dither).round().clamp(i16::MIN as f32, i16::MAX as f32);
+
+        if self.noise_shaping {
+            self.error[channel] = quantized - value;
+        }
+
+        quantized as i16
+    }
+
+    // uniform in -0.5..0.5, from xorshift32
+    fn uniform(&mut self) -> f32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        (self.rng as f32 / u32::MAX as f32) - 0.5
+    }
+}
+
+/// Convert f32 audio into the output format, dithering if the output is 16 bit
+pub fn convert<F: Format>(dither: &mut Dither, input: &[FrameF32], output: &mut [F::Frame]) {
+    match F::frames_mut(output) {
+        FramesMut::S16(output) => dither.quantize(input, output),
+        FramesMut::F32(output) => output.copy_from_slice(input),
+    }
+}
@@ -0,0 +1,150 @@
+//! Lookahead peak limiter, keeping audio that has been boosted by earlier
+//! gain stages from clipping when converted to the output format.
+//!
+//! Audio is delayed by the lookahead time, so that gain reduction can be
+//! ramped in before a peak arrives rather than applied abruptly as it does.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use bark_protocol::time::SampleDuration;
+use bark_protocol::SAMPLE_RATE;
+
+use super::FrameF32;
+
+// -1 dBFS, leaving a little headroom for dither and resampler overshoot
+pub const THRESHOLD: f32 = 0.891;
+
+const LOOKAHEAD: Duration = Duration::from_micros(1500);
+const RELEASE: Duration = Duration::from_millis(100);
+
+pub struct Limiter {
+    threshold: f32,
+    // per frame ratio the threshold moves by while ramping to a new one,
+    // and frames to go
+    threshold_step: f32,
+    threshold_ramp: usize,
+    threshold_target: f32,
+    lookahead: usize,
+    release: f32,
+    // frames waiting to be output, always lookahead - 1 long between calls
+    delay: VecDeque<FrameF32>,
+    // candidates for minimum required gain over the lookahead window, as
+    // (frame index, gain) with gains increasing from front to back
+    minimum: VecDeque<(u64, f32)>,
+    envelope: f32,
+    // recent envelope values, averaged to smooth the applied gain
+    smooth: VecDeque<f32>,
+    smooth_sum: f64,
+    index: u64,
+}
+
+impl Limiter {
+    pub fn new() -> Self {
+        Self::with_threshold(THRESHOLD)
+    }
+
+    /// Limiter holding peaks below `threshold`, as a linear amplitude
+    pub fn with_threshold(threshold: f32) -> Self {
+        let lookahead = SampleDuration::from_std_duration_lossy(LOOKAHEAD)
+            .to_frame_count() as usize;
+
+        let release_frames = RELEASE.as_secs_f32() * SAMPLE_RATE.0 as f32;
+
+        Limiter {
+            threshold,
+            threshold_step: 1.0,
+            threshold_ramp: 0,
+            threshold_target: threshold,
+            lookahead,
+            release: 1.0 - (-1.0 / release_frames).exp(),
+            delay: std::iter::repeat_n(FrameF32(0.0, 0.0), lookahead - 1).collect(),
+            minimum: VecDeque::new(),
+            envelope: 1.0,
+            smooth: std::iter::repeat_n(1.0, lookahead).collect(),
+            smooth_sum: lookahead as f64,
+            index: 0,
+        }
+    }
+
+    /// Delay introduced into the audio by the limiter
+    pub fn latency(&self) -> SampleDuration {
+        SampleDuration::from_frame_count(self.delay.len())
+    }
+
+    /// Move to a new threshold over `frames`, ramping in dB so that the
+    /// change sounds even throughout
+    pub fn set_threshold(&mut self, threshold: f32, frames: usize) {
+        let frames = frames.max(1);
+        self.threshold_target = threshold;
+        self.threshold_step = (threshold / self.threshold).powf(1.0 / frames as f32);
+        self.threshold_ramp = frames;
+    }
+
+    pub fn process(&mut self, frames: &mut [FrameF32]) {
+        for frame in frames {
+            let gain = self.gain(*frame);
+
+            self.delay.push_back(*frame);
+            let delayed = self.delay.pop_front().unwrap_or(FrameF32(0.0, 0.0));
+
+            *frame = FrameF32(delayed.0 * gain, delayed.1 * gain);
+        }
+    }
+
+    /// Take in the next frame, returning the gain to apply to the frame
+    /// leaving the delay line
+    fn gain(&mut self, frame: FrameF32) -> f32 {
+        let index = self.index;
+        self.index += 1;
+
+        if self.threshold_ramp > 0 {
+            self.threshold_ramp -= 1;
+            self.threshold = if self.threshold_ramp == 0 {
+                self.threshold_target
+            } else {
+                self.threshold * self.threshold_step
+            };
+        }
+
+        let peak = frame.0.abs().max(frame.1.abs());
+        let required = if peak > self.threshold { self.threshold / peak } else { 1.0 };
+
+        // minimum required gain over the lookahead window
+        while self.minimum.back().is_some_and(|&(_, gain)| gain >= required) {
+            self.minimum.pop_back();
+        }
+
+        self.minimum.push_back((index, required));
+
+        while self.minimum.front().is_some_and(|&(i, _)| i + (self.lookahead as u64) <= index) {
+            self.minimum.pop_front();
+        }
+
+        let held = self.minimum.front().map(|&(_, gain)| gain).unwrap_or(1.0);
+
+        // reduce immediately, recover slowly
+        if held < self.envelope {
+            self.envelope = held;
+        } else {
+            self.envelope += (held - self.envelope) * self.release;
+        }
+
+        // averaging over the lookahead window ramps the gain down in time
+        // for the peak, and never above what any frame in the window needs
+        self.smooth.push_back(self.envelope);
+        self.smooth_sum += f64::from(self.envelope);
+
+        if let Some(old) = self.smooth.pop_front() {
+            self.smooth_sum -= f64::from(old);
+        }
+
+        (self.smooth_sum / self.lookahead as f64) as f32
+    }
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,178 @@
+//! Loudness normalization, so that sources mastered at different levels
+//! play at a similar volume.
+//!
+//! Loudness is measured as EBU R128 momentary loudness: K-weighted mean
+//! square over a sliding 400ms window. A gain rider then slowly moves the
+//! gain towards whatever brings that to the target.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use bark_protocol::SAMPLE_RATE;
+
+use super::{apply_gain, s16_to_f32, FramesMut};
+
+const WINDOW: Duration = Duration::from_millis(400);
+
+// quieter than this is treated as silence, which shouldn't be boosted
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+
+const MAX_BOOST_DB: f64 = 12.0;
+const MAX_CUT_DB: f64 = 24.0;
+
+// cut quickly when something loud starts, boost slowly as it gets quieter
+const ATTACK: Duration = Duration::from_secs(1);
+const RELEASE: Duration = Duration::from_secs(5);
+
+pub struct Normalizer {
+    target_lufs: f64,
+    filters: [KWeighting; 2],
+    // K-weighted sum of squares and frame count of each recent block
+    blocks: VecDeque<(f64, usize)>,
+    window_sum: f64,
+    window_frames: usize,
+    gain_db: f64,
+}
+
+impl Normalizer {
+    pub fn new(target_lufs: f64) -> Self {
+        Normalizer {
+            target_lufs,
+            filters: [KWeighting::new(), KWeighting::new()],
+            blocks: VecDeque::new(),
+            window_sum: 0.0,
+            window_frames: 0,
+            gain_db: 0.0,
+        }
+    }
+
+    /// Current gain in decibels
+    pub fn gain_db(&self) -> f64 {
+        self.gain_db
+    }
+
+    /// Measure a block of audio and apply the normalization gain to it
+    pub fn process(&mut self, mut frames: FramesMut) {
+        let (sum, count) = match &mut frames {
+            FramesMut::S16(frames) => self.measure(frames.iter().map(|f| (s16_to_f32(f.0), s16_to_f32(f.1)))),
+            FramesMut::F32(frames) => self.measure(frames.iter().map(|f| (f.0, f.1))),
+        };
+
+        self.push_block(sum, count);
+        self.ride(count);
+
+        let gain = 10f64.powf(self.gain_db / 20.0) as f32;
+        apply_gain(frames, gain);
+    }
+
+    fn measure(&mut self, frames: impl Iterator<Item = (f32, f32)>) -> (f64, usize) {
+        let mut sum = 0.0;
+        let mut count = 0;
+
+        for (left, right) in frames {
+            let left = self.filters[0].process(f64::from(left));
+            let right = self.filters[1].process(f64::from(right));
+            sum += left * left + right * right;
+            count += 1;
+        }
+
+        (sum, count)
+    }
+
+    fn push_block(&mut self, sum: f64, frames: usize) {
+        self.blocks.push_back((sum, frames));
+        self.window_sum += sum;
+        self.window_frames += frames;
+
+        let window = window_frames();
+
+        while let Some(&(sum, frames)) = self.blocks.front() {
+            if self.window_frames - frames < window {
+                break;
+            }
+
+            self.blocks.pop_front();
+            self.window_sum -= sum;
+            self.window_frames -= frames;
+        }
+    }
+
+    /// Momentary loudness in LUFS, once a full window has been measured
+    fn loudness(&self) -> Option<f64> {
+        if self.window_frames < window_frames() {
+            return None;
+        }
+
+        let mean_square = self.window_sum.max(0.0) / self.window_frames as f64;
+        Some(-0.691 + 10.0 * mean_square.max(f64::MIN_POSITIVE).log10())
+    }
+
+    fn ride(&mut self, frames: usize) {
+        let Some(loudness) = self.loudness() else {
+            return;
+        };
+
+        // hold the gain through silence
+        if loudness < ABSOLUTE_GATE_LUFS {
+            return;
+        }
+
+        let target_db = (self.target_lufs - loudness).clamp(-MAX_CUT_DB, MAX_BOOST_DB);
+
+        let time_constant = if target_db < self.gain_db { ATTACK } else { RELEASE };
+        let elapsed = frames as f64 / f64::from(SAMPLE_RATE.0);
+        let coeff = 1.0 - (-elapsed / time_constant.as_secs_f64()).exp();
+
+        self.gain_db += (target_db - self.gain_db) * coeff;
+    }
+}
+
+fn window_frames() -> usize {
+    (WINDOW.as_secs_f64() * f64::from(SAMPLE_RATE.0)) as usize
+}
+
+/// ITU-R BS.1770 K-weighting filter at 48khz: a high shelf modelling the
+/// acoustic effect of the head, followed by a high pass
+struct KWeighting {
+    shelf: Biquad,
+    highpass: Biquad,
+}
+
+impl KWeighting {
+    fn new() -> Self {
+        KWeighting {
+            shelf: Biquad::new(
+                [1.53512485958697, -2.69169618940638, 1.19839281085285],
+                [-1.69065929318241, 0.73248077421585],
+            ),
+            highpass: Biquad::new(
+                [1.0, -2.0, 1.0],
+                [-1.99004745483398, 0.99007225036621],
+            ),
+        }
+    }
+
+    fn process(&mut self, sample: f64) -> f64 {
+        self.highpass.process(self.shelf.process(sample))
+    }
+}
+
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Biquad { b, a, z: [0.0; 2] }
+    }
+
+    // transposed direct form II
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
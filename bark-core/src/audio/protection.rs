@@ -0,0 +1,161 @@
+//! Speaker protection, the last stage before output. Keeps small speakers
+//! from being driven with bass they can't reproduce, which at high volume
+//! can push drivers past their excursion limits, and holds peaks below a
+//! level the amplifier can deliver cleanly.
+//!
+//! Profiles are applied by the output chain in [`super::dsp`].
+
+use std::f32::consts::PI;
+
+use bark_protocol::SAMPLE_RATE;
+
+use super::FrameF32;
+
+// q of each second order section of a fourth order butterworth filter
+const BUTTERWORTH_Q: [f32; 2] = [0.5412, 1.3066];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    /// Small full range drivers, eg. 3" speakers on a Pi amp HAT. 80 Hz
+    /// high-pass and a -3 dBFS limit
+    SmallFullrange,
+    /// Bookshelf speakers with some bass extension. 45 Hz high-pass and a
+    /// -1 dBFS limit
+    Bookshelf,
+}
+
+impl Profile {
+    fn highpass_hz(self) -> f32 {
+        match self {
+            Profile::SmallFullrange => 80.0,
+            Profile::Bookshelf => 45.0,
+        }
+    }
+
+    /// Peak level to hold output below, as a linear amplitude
+    pub fn threshold(self) -> f32 {
+        10f32.powf(self.threshold_db() / 20.0)
+    }
+
+    fn threshold_db(self) -> f32 {
+        match self {
+            Profile::SmallFullrange => -3.0,
+            Profile::Bookshelf => -1.0,
+        }
+    }
+}
+
+/// Second order IIR filter section, in transposed direct form II.
+/// Retuning ramps the coefficients to their new values while keeping the
+/// filter's state, so it can be adjusted during playback without clicking
+pub(crate) struct Biquad {
+    coeffs: Coeffs,
+    // per frame change in coefficients while ramping, and frames to go
+    step: Coeffs,
+    ramp: usize,
+    target: Coeffs,
+    // per channel state
+    state: [[f32; 2]; 2],
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Coeffs {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl Coeffs {
+    /// Passes audio through unchanged
+    pub const IDENTITY: Coeffs = Coeffs { b0: 1.0, b1: 0.0, b2: 0.0, a1: 0.0, a2: 0.0 };
+
+    const ZERO: Coeffs = Coeffs { b0: 0.0, b1: 0.0, b2: 0.0, a1: 0.0, a2: 0.0 };
+
+    /// High-pass from the RBJ audio EQ cookbook
+    pub fn highpass(hz: f32, q: f32) -> Self {
+        let w0 = 2.0 * PI * hz / SAMPLE_RATE.0 as f32;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos = w0.cos();
+        let a0 = 1.0 + alpha;
+
+        Coeffs {
+            b0: (1.0 + cos) / 2.0 / a0,
+            b1: -(1.0 + cos) / a0,
+            b2: (1.0 + cos) / 2.0 / a0,
+            a1: -2.0 * cos / a0,
+            a2: (1.0 - alpha) / a0,
+        }
+    }
+
+    /// Section `index` of the fourth order high-pass for `profile`, or
+    /// identity without one
+    pub fn protection(profile: Option<Profile>, index: usize) -> Self {
+        match profile {
+            Some(profile) => Coeffs::highpass(profile.highpass_hz(), BUTTERWORTH_Q[index]),
+            None => Coeffs::IDENTITY,
+        }
+    }
+
+    fn map(self, other: Coeffs, f: impl Fn(f32, f32) -> f32) -> Coeffs {
+        Coeffs {
+            b0: f(self.b0, other.b0),
+            b1: f(self.b1, other.b1),
+            b2: f(self.b2, other.b2),
+            a1: f(self.a1, other.a1),
+            a2: f(self.a2, other.a2),
+        }
+    }
+}
+
+impl Biquad {
+    pub fn new(coeffs: Coeffs) -> Self {
+        Biquad {
+            coeffs,
+            step: Coeffs::ZERO,
+            ramp: 0,
+            target: coeffs,
+            state: [[0.0; 2]; 2],
+        }
+    }
+
+    /// Move to new coefficients over `frames`. Every point on a straight
+    /// line between two stable sections is also stable, so the filter
+    /// stays well behaved throughout the ramp
+    pub fn retune(&mut self, target: Coeffs, frames: usize) {
+        let frames = frames.max(1);
+        self.target = target;
+        self.step = target.map(self.coeffs, |to, from| (to - from) / frames as f32);
+        self.ramp = frames;
+    }
+
+    pub fn process(&mut self, frames: &mut [FrameF32]) {
+        for frame in frames {
+            if self.ramp > 0 {
+                self.ramp -= 1;
+                self.coeffs = if self.ramp == 0 {
+                    self.target
+                } else {
+                    self.coeffs.map(self.step, |c, step| c + step)
+                };
+            }
+
+            frame.0 = self.sample(0, frame.0);
+            frame.1 = self.sample(1, frame.1);
+        }
+    }
+
+    fn sample(&mut self, channel: usize, x: f32) -> f32 {
+        let Coeffs { b0, b1, b2, a1, a2 } = self.coeffs;
+        let [s1, s2] = self.state[channel];
+        let y = b0 * x + s1;
+
+        self.state[channel] = [
+            b1 * x - a1 * y + s2,
+            b2 * x - a2 * y,
+        ];
+
+        y
+    }
+}
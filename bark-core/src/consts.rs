@@ -1,4 +1,2 @@
-use bark_protocol::FRAMES_PER_PACKET;
-
 pub const MAX_QUEUED_DECODE_SEGMENTS: usize = 1024;
-pub const DECODE_BUFFER_FRAMES: usize = FRAMES_PER_PACKET * 2;
+pub const LATENCY_HISTORY: usize = 64;
@@ -1,6 +1,4 @@
-#[cfg(feature = "opus")]
 pub mod opus;
-
 pub mod pcm;
 
 use core::fmt::Display;
@@ -11,6 +9,7 @@ use bark_protocol::packet::Audio;
 use bark_protocol::types::{AudioPacketHeader, AudioPacketFormat};
 
 use crate::audio::FramesMut;
+use crate::registry;
 
 #[derive(Debug, Error)]
 pub enum NewDecoderError {
@@ -19,75 +18,71 @@ pub enum NewDecoderError {
     #[cfg(feature = "opus")]
     #[error("opus codec error: {0}")]
     Opus(#[from] ::opus::Error),
+    /// Errors from codecs provided by embedders
+    #[error("codec error: {0}")]
+    Codec(Box<dyn std::error::Error + Send + Sync>),
 }
 
 #[derive(Debug, Error)]
 pub enum DecodeError {
     #[error("wrong byte length: {length}, expected: {expected}")]
     WrongLength { length: usize, expected: usize },
-    #[error("wrong frame count: {frames}, expected: {expected}")]
+    #[error("too many frames: {frames}, expected at most: {expected}")]
     WrongFrameCount { frames: usize, expected: usize },
     #[cfg(feature = "opus")]
     #[error("opus codec error: {0}")]
     Opus(#[from] ::opus::Error),
+    /// Errors from codecs provided by embedders
+    #[error("codec error: {0}")]
+    Codec(Box<dyn std::error::Error + Send + Sync>),
 }
 
 pub struct Decoder {
-    decode: DecodeFormat,
+    decode: Box<dyn Decode>,
 }
 
 impl Decoder {
     pub fn new(header: &AudioPacketHeader) -> Result<Self, NewDecoderError> {
-        let decode = match header.format {
-            AudioPacketFormat::S16LE => DecodeFormat::S16LE(pcm::S16LEDecoder),
-            AudioPacketFormat::F32LE => DecodeFormat::F32LE(pcm::F32LEDecoder),
-            #[cfg(feature = "opus")]
-            AudioPacketFormat::OPUS => DecodeFormat::Opus(opus::OpusDecoder::new()?),
-            format => { return Err(NewDecoderError::UnknownFormat(format)) }
-        };
-
+        let decode = registry::new_decoder(header)?;
         Ok(Decoder { decode })
     }
 
     pub fn describe(&self) -> impl Display + '_ {
-        &self.decode as &dyn Display
+        &self.decode
     }
 
-    pub fn decode(&mut self, packet: Option<&Audio>, out: FramesMut) -> Result<(), DecodeError> {
+    pub fn decode(&mut self, packet: Option<&Audio>, out: FramesMut) -> Result<usize, DecodeError> {
         let bytes = packet.map(|packet| packet.buffer_bytes());
         self.decode.decode_packet(bytes, out)
     }
-}
 
-trait Decode: Display {
-    fn decode_packet(&mut self, bytes: Option<&[u8]>, out: FramesMut) -> Result<(), DecodeError>;
-}
+    /// Whether lost packets are concealed, rather than played as silence
+    pub fn conceals_loss(&self) -> bool {
+        self.decode.conceals_loss()
+    }
 
-enum DecodeFormat {
-    S16LE(pcm::S16LEDecoder),
-    F32LE(pcm::F32LEDecoder),
-    #[cfg(feature = "opus")]
-    Opus(opus::OpusDecoder),
+    /// Decode a lost packet, using any forward error correction data carried
+    /// in the packet that follows it.
+    pub fn decode_fec(&mut self, next: &Audio, out: FramesMut) -> Result<usize, DecodeError> {
+        self.decode.decode_fec(next.buffer_bytes(), out)
+    }
 }
 
-impl Decode for DecodeFormat {
-    fn decode_packet(&mut self, bytes: Option<&[u8]>, out: FramesMut) -> Result<(), DecodeError> {
-        match self {
-            DecodeFormat::S16LE(dec) => dec.decode_packet(bytes, out),
-            DecodeFormat::F32LE(dec) => dec.decode_packet(bytes, out),
-            #[cfg(feature = "opus")]
-            DecodeFormat::Opus(dec) => dec.decode_packet(bytes, out),
-        }
+pub trait Decode: Display + Send {
+    /// Decodes a packet into `out`, returning the number of frames decoded.
+    /// Packets may carry fewer frames than `out` has room for, such as the
+    /// final packet of a finite stream.
+    fn decode_packet(&mut self, bytes: Option<&[u8]>, out: FramesMut) -> Result<usize, DecodeError>;
+
+    /// Whether decoding a lost packet produces concealment audio rather
+    /// than silence
+    fn conceals_loss(&self) -> bool {
+        false
     }
-}
 
-impl Display for DecodeFormat {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        match self {
-            DecodeFormat::S16LE(dec) => dec.fmt(f),
-            DecodeFormat::F32LE(dec) => dec.fmt(f),
-            #[cfg(feature = "opus")]
-            DecodeFormat::Opus(dec) => dec.fmt(f),
-        }
+    /// Codecs without forward error correction fall back to regular loss
+    /// concealment by default
+    fn decode_fec(&mut self, _next: &[u8], out: FramesMut) -> Result<usize, DecodeError> {
+        self.decode_packet(None, out)
     }
 }
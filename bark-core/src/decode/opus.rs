@@ -1,26 +1,63 @@
+//! Opus decoding, through a pluggable backend. libopus is built in with the
+//! `opus` feature. Targets where linking C is a pain, such as embedded
+//! receivers, can instead provide a decoder of their own, such as a pure
+//! Rust one, as an `OpusBackend` and `register` it.
+
 use core::fmt::{self, Display};
 
-use bark_protocol::SAMPLE_RATE;
+use bark_protocol::types::{AudioPacketFormat, AudioPacketHeader};
 
 use crate::audio::{self, FramesMut, F32, S16};
+use crate::registry;
+
+use super::{Decode, DecodeError, NewDecoderError};
+
+/// An Opus decoder, decoding stereo at 48 kHz
+pub trait OpusBackend: Send {
+    /// Decode `bytes` into interleaved samples, returning the number of
+    /// frames decoded. An empty packet asks for loss concealment. With
+    /// `fec` set, decode the forward error correction data `bytes` carries
+    /// for the packet before it instead
+    fn decode_float(&mut self, bytes: &[u8], out: &mut [f32], fec: bool) -> Result<usize, DecodeError>;
+
+    /// As `decode_float`, into 16 bit samples. Backends without a 16 bit
+    /// decoder of their own decode to float and convert
+    fn decode_s16(&mut self, bytes: &[u8], out: &mut [i16], fec: bool) -> Result<usize, DecodeError> {
+        let mut float = vec![0.0; out.len()];
+        let frames = self.decode_float(bytes, &mut float, fec)?;
+
+        for (out, sample) in out.iter_mut().zip(&float) {
+            *out = audio::f32_to_s16(*sample);
+        }
 
-use super::{Decode, DecodeError};
+        Ok(frames)
+    }
+}
 
 pub struct OpusDecoder {
-    opus: opus::Decoder,
+    opus: Box<dyn OpusBackend>,
 }
 
 impl OpusDecoder {
+    #[cfg(feature = "opus")]
     pub fn new() -> Result<Self, opus::Error> {
-        let opus = opus::Decoder::new(
-            SAMPLE_RATE.0,
-            opus::Channels::Stereo,
-        )?;
+        Ok(Self::with_backend(LibOpus::new()?))
+    }
 
-        Ok(OpusDecoder { opus })
+    pub fn with_backend(backend: impl OpusBackend + 'static) -> Self {
+        OpusDecoder { opus: Box::new(backend) }
     }
 }
 
+/// Decode Opus streams with backends made by `new`, in place of libopus
+pub fn register<B: OpusBackend + 'static>(
+    new: impl Fn() -> Result<B, NewDecoderError> + Send + Sync + 'static,
+) {
+    registry::register_decoder(AudioPacketFormat::OPUS, move |_: &AudioPacketHeader| {
+        Ok(Box::new(OpusDecoder::with_backend(new()?)) as Box<dyn Decode>)
+    });
+}
+
 impl Display for OpusDecoder {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "opus")
@@ -28,28 +65,65 @@ impl Display for OpusDecoder {
 }
 
 impl Decode for OpusDecoder {
-    fn decode_packet(&mut self, bytes: Option<&[u8]>, out: FramesMut) -> Result<(), DecodeError> {
+    fn decode_packet(&mut self, bytes: Option<&[u8]>, out: FramesMut) -> Result<usize, DecodeError> {
+        match bytes {
+            Some(bytes) => self.decode_impl(bytes, out, false),
+            // empty packet with fec set is packet loss concealment:
+            None => self.decode_impl(&[], out, true),
+        }
+    }
+
+    fn conceals_loss(&self) -> bool {
+        true
+    }
+
+    fn decode_fec(&mut self, next: &[u8], out: FramesMut) -> Result<usize, DecodeError> {
+        self.decode_impl(next, out, true)
+    }
+}
+
+impl OpusDecoder {
+    fn decode_impl(&mut self, bytes: &[u8], out: FramesMut, fec: bool) -> Result<usize, DecodeError> {
         let expected = out.len();
 
         let frames = match out {
-            FramesMut::F32(out) => {
-                match bytes {
-                    Some(bytes) => self.opus.decode_float(bytes, audio::as_interleaved_mut::<F32>(out), false)?,
-                    None => self.opus.decode_float(&[], audio::as_interleaved_mut::<F32>(out), true)?,
-                }
-            }
-            FramesMut::S16(out) => {
-                match bytes {
-                    Some(bytes) => self.opus.decode(bytes, audio::as_interleaved_mut::<S16>(out), false)?,
-                    None => self.opus.decode(&[], audio::as_interleaved_mut::<S16>(out), true)?,
-                }
-            }
+            FramesMut::F32(out) => self.opus.decode_float(bytes, audio::as_interleaved_mut::<F32>(out), fec)?,
+            FramesMut::S16(out) => self.opus.decode_s16(bytes, audio::as_interleaved_mut::<S16>(out), fec)?,
         };
 
-        if expected != frames {
+        if frames > expected {
             return Err(DecodeError::WrongFrameCount { frames, expected });
         }
 
-        Ok(())
+        Ok(frames)
+    }
+}
+
+/// libopus, through the opus crate
+#[cfg(feature = "opus")]
+struct LibOpus {
+    opus: opus::Decoder,
+}
+
+#[cfg(feature = "opus")]
+impl LibOpus {
+    fn new() -> Result<Self, opus::Error> {
+        let opus = opus::Decoder::new(
+            bark_protocol::SAMPLE_RATE.0,
+            opus::Channels::Stereo,
+        )?;
+
+        Ok(LibOpus { opus })
+    }
+}
+
+#[cfg(feature = "opus")]
+impl OpusBackend for LibOpus {
+    fn decode_float(&mut self, bytes: &[u8], out: &mut [f32], fec: bool) -> Result<usize, DecodeError> {
+        Ok(self.opus.decode_float(bytes, out, fec)?)
+    }
+
+    fn decode_s16(&mut self, bytes: &[u8], out: &mut [i16], fec: bool) -> Result<usize, DecodeError> {
+        Ok(self.opus.decode(bytes, out, fec)?)
     }
 }
@@ -1,8 +1,9 @@
 use core::fmt::{self, Display};
 
+use bark_protocol::CHANNELS;
 use bytemuck::Zeroable;
 
-use crate::audio::{self, f32_to_s16, s16_to_f32, Format, FramesMut, F32, S16};
+use crate::audio::{self, f32_to_s16, s16_to_f32, s32_to_f32, s32_to_s16, Format, FramesMut, F32, S16};
 use super::{Decode, DecodeError};
 
 pub struct S16LEDecoder;
@@ -14,7 +15,7 @@ impl Display for S16LEDecoder {
 }
 
 impl Decode for S16LEDecoder {
-    fn decode_packet(&mut self, bytes: Option<&[u8]>, out: FramesMut) -> Result<(), DecodeError> {
+    fn decode_packet(&mut self, bytes: Option<&[u8]>, out: FramesMut) -> Result<usize, DecodeError> {
         decode_packed(bytes, out, decode_s16le_to_i16, decode_s16le_to_f32)
     }
 }
@@ -27,6 +28,30 @@ fn decode_s16le_to_f32(bytes: [u8; 2]) -> f32 {
     s16_to_f32(i16::from_le_bytes(bytes))
 }
 
+/// Not a bark packet format, for audio from elsewhere such as RTP's L16
+/// payload, which is big endian
+pub struct S16BEDecoder;
+
+impl Display for S16BEDecoder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "signed16 (big endian)")
+    }
+}
+
+impl Decode for S16BEDecoder {
+    fn decode_packet(&mut self, bytes: Option<&[u8]>, out: FramesMut) -> Result<usize, DecodeError> {
+        decode_packed(bytes, out, decode_s16be_to_i16, decode_s16be_to_f32)
+    }
+}
+
+fn decode_s16be_to_i16(bytes: [u8; 2]) -> i16 {
+    i16::from_be_bytes(bytes)
+}
+
+fn decode_s16be_to_f32(bytes: [u8; 2]) -> f32 {
+    s16_to_f32(i16::from_be_bytes(bytes))
+}
+
 pub struct F32LEDecoder;
 
 impl Display for F32LEDecoder {
@@ -36,7 +61,7 @@ impl Display for F32LEDecoder {
 }
 
 impl Decode for F32LEDecoder {
-    fn decode_packet(&mut self, bytes: Option<&[u8]>, out: FramesMut) -> Result<(), DecodeError> {
+    fn decode_packet(&mut self, bytes: Option<&[u8]>, out: FramesMut) -> Result<usize, DecodeError> {
         decode_packed(bytes, out, decode_f32le_to_i16, decode_f32le_to_f32)
     }
 }
@@ -50,12 +75,61 @@ fn decode_f32le_to_f32(bytes: [u8; 4]) -> f32 {
     f32::from_le_bytes(bytes)
 }
 
+pub struct S24LEDecoder;
+
+impl Display for S24LEDecoder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "signed24 packed (little endian)")
+    }
+}
+
+impl Decode for S24LEDecoder {
+    fn decode_packet(&mut self, bytes: Option<&[u8]>, out: FramesMut) -> Result<usize, DecodeError> {
+        decode_packed(bytes, out, decode_s24le_to_i16, decode_s24le_to_f32)
+    }
+}
+
+fn decode_s24le_to_i16(bytes: [u8; 3]) -> i16 {
+    s32_to_s16(s24le_to_s32(bytes))
+}
+
+fn decode_s24le_to_f32(bytes: [u8; 3]) -> f32 {
+    s32_to_f32(s24le_to_s32(bytes))
+}
+
+// place a 24 bit sample in the most significant bytes of a 32 bit sample
+fn s24le_to_s32([a, b, c]: [u8; 3]) -> i32 {
+    i32::from_le_bytes([0, a, b, c])
+}
+
+pub struct S32LEDecoder;
+
+impl Display for S32LEDecoder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "signed32 (little endian)")
+    }
+}
+
+impl Decode for S32LEDecoder {
+    fn decode_packet(&mut self, bytes: Option<&[u8]>, out: FramesMut) -> Result<usize, DecodeError> {
+        decode_packed(bytes, out, decode_s32le_to_i16, decode_s32le_to_f32)
+    }
+}
+
+fn decode_s32le_to_i16(bytes: [u8; 4]) -> i16 {
+    s32_to_s16(i32::from_le_bytes(bytes))
+}
+
+fn decode_s32le_to_f32(bytes: [u8; 4]) -> f32 {
+    s32_to_f32(i32::from_le_bytes(bytes))
+}
+
 fn decode_packed<const N: usize>(
     bytes: Option<&[u8]>,
     out: FramesMut,
     decode_s16: impl Fn([u8; N]) -> i16,
     decode_f32: impl Fn([u8; N]) -> f32,
-) -> Result<(), DecodeError> {
+) -> Result<usize, DecodeError> {
     match out {
         FramesMut::S16(out) => decode_packed_impl::<S16, N>(bytes, out, decode_s16),
         FramesMut::F32(out) => decode_packed_impl::<F32, N>(bytes, out, decode_f32),
@@ -66,17 +140,19 @@ fn decode_packed_impl<F: Format, const N: usize>(
     bytes: Option<&[u8]>,
     out: &mut [F::Frame],
     decode: impl Fn([u8; N]) -> F::Sample,
-) -> Result<(), DecodeError> {
+) -> Result<usize, DecodeError> {
+    let frames = out.len();
+    let frame_bytes = N * usize::from(CHANNELS);
     let out_samples = audio::as_interleaved_mut::<F>(out);
 
     let Some(bytes) = bytes else {
         // PCM codecs have no packet loss correction
         // just zero fill and return
         out_samples.fill(F::Sample::zeroed());
-        return Ok(());
+        return Ok(frames);
     };
 
-    check_length(bytes, out_samples.len() * N)?;
+    check_length(bytes, frame_bytes, out_samples.len() * N)?;
 
     for (input, output) in bytes.chunks_exact(N).zip(out_samples) {
         // when array_chunks stabilises we can use that instead
@@ -86,13 +162,16 @@ fn decode_packed_impl<F: Format, const N: usize>(
         *output = decode(input);
     }
 
-    Ok(())
+    Ok(bytes.len() / frame_bytes)
 }
 
-fn check_length(bytes: &[u8], expected: usize) -> Result<(), DecodeError> {
+// packets may be shorter than the buffer (eg. the final packet in a stream)
+// but must always contain a whole number of frames
+fn check_length(bytes: &[u8], frame_bytes: usize, expected: usize) -> Result<(), DecodeError> {
     let length = bytes.len();
+    let whole_frames = bytes.chunks_exact(frame_bytes).remainder().is_empty();
 
-    if length == expected {
+    if length <= expected && whole_frames {
         Ok(())
     } else {
         Err(DecodeError::WrongLength { length, expected })
@@ -12,9 +12,11 @@ use crate::audio::Frames;
 
 #[derive(Debug, Error)]
 pub enum NewEncoderError {
+    #[error("no encoder registered for format: {0:?}")]
+    UnknownFormat(AudioPacketFormat),
     #[cfg(feature = "opus")]
     #[error("opus codec error: {0}")]
-    Opus(#[from] ::opus::Error),
+    Opus(#[from] self::opus::OpusError),
 }
 
 #[derive(Debug, Error)]
@@ -23,7 +25,7 @@ pub enum EncodeError {
     OutputBufferTooSmall { need: usize },
     #[cfg(feature = "opus")]
     #[error("opus codec error: {0}")]
-    Opus(#[from] ::opus::Error),
+    Opus(#[from] self::opus::OpusError),
 }
 
 pub trait Encode: Display + Send {
@@ -1,27 +1,146 @@
+use core::ffi::{c_int, CStr};
 use core::fmt::{self, Display};
+use core::ptr::NonNull;
 
+use audiopus_sys as ffi;
+use bark_protocol::time::SampleDuration;
 use bark_protocol::{types::AudioPacketFormat, SAMPLE_RATE};
 
 use crate::audio::{self, Frames, F32, S16};
 use super::{Encode, EncodeError, NewEncoderError};
 
+pub struct OpusEncoderOpt {
+    /// Target bitrate in bits per second, None for maximum bitrate
+    pub bitrate: Option<i32>,
+    /// Embed forward error correction data for the previous packet in each
+    /// packet, allowing receivers to recover from single packet loss
+    pub inband_fec: bool,
+    /// Encoder complexity from 0 to 10, trading CPU time for quality. None
+    /// for the libopus default
+    pub complexity: Option<u8>,
+    /// Discontinuous transmission: packets encoding silence carry almost
+    /// no data, saving bandwidth while nothing is playing
+    pub dtx: bool,
+}
+
+impl Default for OpusEncoderOpt {
+    fn default() -> Self {
+        OpusEncoderOpt {
+            bitrate: None,
+            inband_fec: true,
+            complexity: None,
+            dtx: false,
+        }
+    }
+}
+
+/// Error code returned by libopus
+#[derive(Debug)]
+pub struct OpusError {
+    function: &'static str,
+    code: c_int,
+}
+
+impl Display for OpusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // opus_strerror returns a static string for any code
+        let message = unsafe { CStr::from_ptr(ffi::opus_strerror(self.code)) };
+        write!(f, "{}: {}", self.function, message.to_string_lossy())
+    }
+}
+
+impl std::error::Error for OpusError {}
+
+/// libopus constants are generated with whichever integer type fits, so
+/// are converted to the c_int its functions take
+fn int(value: impl TryInto<c_int>) -> c_int {
+    value.try_into().unwrap_or(c_int::MAX)
+}
+
+fn check(function: &'static str, code: c_int) -> Result<c_int, OpusError> {
+    if code < 0 {
+        Err(OpusError { function, code })
+    } else {
+        Ok(code)
+    }
+}
+
+/// Opus encoder, driving libopus directly as the opus crate doesn't expose
+/// complexity or DTX
 pub struct OpusEncoder {
-    opus: opus::Encoder,
+    opus: NonNull<ffi::OpusEncoder>,
 }
 
+// the encoder state is only ever used through &mut self
+unsafe impl Send for OpusEncoder {}
+
 impl OpusEncoder {
-    pub fn new() -> Result<Self, NewEncoderError> {
-        let mut opus = opus::Encoder::new(
-            SAMPLE_RATE.0,
-            opus::Channels::Stereo,
-            opus::Application::Audio,
-        )?;
+    pub fn new(opt: &OpusEncoderOpt) -> Result<Self, NewEncoderError> {
+        let mut error = 0;
+
+        let opus = unsafe {
+            ffi::opus_encoder_create(
+                int(SAMPLE_RATE.0),
+                2,
+                int(ffi::OPUS_APPLICATION_AUDIO),
+                &mut error,
+            )
+        };
+
+        check("opus_encoder_create", error)?;
+
+        let opus = NonNull::new(opus)
+            .ok_or(OpusError { function: "opus_encoder_create", code: int(ffi::OPUS_ALLOC_FAIL) })?;
+
+        let mut encoder = OpusEncoder { opus };
+
+        encoder.set(ffi::OPUS_SET_INBAND_FEC_REQUEST, c_int::from(opt.inband_fec))?;
 
-        opus.set_inband_fec(true)?;
-        opus.set_packet_loss_perc(50)?;
-        opus.set_bitrate(opus::Bitrate::Max)?;
+        if opt.inband_fec {
+            encoder.set(ffi::OPUS_SET_PACKET_LOSS_PERC_REQUEST, 50)?;
+        }
+
+        encoder.set(ffi::OPUS_SET_BITRATE_REQUEST, match opt.bitrate {
+            Some(bits) => bits,
+            None => int(ffi::OPUS_BITRATE_MAX),
+        })?;
+
+        if let Some(complexity) = opt.complexity {
+            encoder.set(ffi::OPUS_SET_COMPLEXITY_REQUEST, c_int::from(complexity))?;
+        }
+
+        encoder.set(ffi::OPUS_SET_DTX_REQUEST, c_int::from(opt.dtx))?;
+
+        Ok(encoder)
+    }
 
-        Ok(OpusEncoder { opus })
+    fn set(&mut self, request: impl TryInto<c_int>, value: c_int) -> Result<(), OpusError> {
+        let code = unsafe { ffi::opus_encoder_ctl(self.opus.as_ptr(), int(request), value) };
+        check("opus_encoder_ctl", code)?;
+        Ok(())
+    }
+
+    /// Audio the encoder holds back to analyse before encoding, which adds
+    /// to the latency of the stream
+    pub fn lookahead(&mut self) -> Result<SampleDuration, EncodeError> {
+        let mut frames: ffi::opus_int32 = 0;
+
+        let code = unsafe {
+            ffi::opus_encoder_ctl(
+                self.opus.as_ptr(),
+                int(ffi::OPUS_GET_LOOKAHEAD_REQUEST),
+                &mut frames as *mut ffi::opus_int32,
+            )
+        };
+
+        check("opus_encoder_ctl", code)?;
+        Ok(SampleDuration::from_frame_count(usize::try_from(frames).unwrap_or(0)))
+    }
+}
+
+impl Drop for OpusEncoder {
+    fn drop(&mut self) {
+        unsafe { ffi::opus_encoder_destroy(self.opus.as_ptr()) }
     }
 }
 
@@ -37,11 +156,26 @@ impl Encode for OpusEncoder {
     }
 
     fn encode_packet(&mut self, frames: Frames, out: &mut [u8]) -> Result<usize, EncodeError> {
+        let frame_count = int(frames.len());
+        let max_bytes = int(out.len());
+
         let n = match frames {
-            Frames::S16(frames) => self.opus.encode(audio::as_interleaved::<S16>(frames), out)?,
-            Frames::F32(frames) => self.opus.encode_float(audio::as_interleaved::<F32>(frames), out)?,
+            Frames::S16(frames) => {
+                let pcm = audio::as_interleaved::<S16>(frames);
+                let n = unsafe {
+                    ffi::opus_encode(self.opus.as_ptr(), pcm.as_ptr(), frame_count, out.as_mut_ptr(), max_bytes)
+                };
+                check("opus_encode", n)?
+            }
+            Frames::F32(frames) => {
+                let pcm = audio::as_interleaved::<F32>(frames);
+                let n = unsafe {
+                    ffi::opus_encode_float(self.opus.as_ptr(), pcm.as_ptr(), frame_count, out.as_mut_ptr(), max_bytes)
+                };
+                check("opus_encode_float", n)?
+            }
         };
 
-        Ok(n)
+        Ok(usize::try_from(n).unwrap_or(0))
     }
 }
@@ -2,7 +2,7 @@ use core::fmt::{self, Display};
 
 use bark_protocol::types::AudioPacketFormat;
 
-use crate::audio::{self, f32_to_s16, s16_to_f32, Format, Frames, F32, S16};
+use crate::audio::{self, f32_to_s16, f32_to_s32, s16_to_f32, s16_to_s32, Format, Frames, F32, S16};
 
 use super::{Encode, EncodeError};
 
@@ -58,6 +58,64 @@ fn encode_f32_to_f32le(sample: f32) -> [u8; 4] {
     f32::to_le_bytes(sample)
 }
 
+pub struct S24LEEncoder;
+
+impl Display for S24LEEncoder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "signed24 packed (little endian)")
+    }
+}
+
+impl Encode for S24LEEncoder {
+    fn header_format(&self) -> AudioPacketFormat {
+        AudioPacketFormat::S24LE
+    }
+
+    fn encode_packet(&mut self, frames: Frames, out: &mut [u8]) -> Result<usize, EncodeError> {
+        encode_packed(frames, out, encode_i16_to_s24le, encode_f32_to_s24le)
+    }
+}
+
+fn encode_i16_to_s24le(sample: i16) -> [u8; 3] {
+    s32_to_s24le(s16_to_s32(sample))
+}
+
+fn encode_f32_to_s24le(sample: f32) -> [u8; 3] {
+    s32_to_s24le(f32_to_s32(sample))
+}
+
+// take the most significant 3 bytes of a 32 bit sample
+fn s32_to_s24le(sample: i32) -> [u8; 3] {
+    let [_, a, b, c] = i32::to_le_bytes(sample);
+    [a, b, c]
+}
+
+pub struct S32LEEncoder;
+
+impl Display for S32LEEncoder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "signed32 (little endian)")
+    }
+}
+
+impl Encode for S32LEEncoder {
+    fn header_format(&self) -> AudioPacketFormat {
+        AudioPacketFormat::S32LE
+    }
+
+    fn encode_packet(&mut self, frames: Frames, out: &mut [u8]) -> Result<usize, EncodeError> {
+        encode_packed(frames, out, encode_i16_to_s32le, encode_f32_to_s32le)
+    }
+}
+
+fn encode_i16_to_s32le(sample: i16) -> [u8; 4] {
+    i32::to_le_bytes(s16_to_s32(sample))
+}
+
+fn encode_f32_to_s32le(sample: f32) -> [u8; 4] {
+    i32::to_le_bytes(f32_to_s32(sample))
+}
+
 fn encode_packed<const N: usize>(
     frames: Frames,
     out: &mut [u8],
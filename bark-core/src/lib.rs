@@ -3,3 +3,4 @@ pub mod consts;
 pub mod decode;
 pub mod encode;
 pub mod receive;
+pub mod registry;
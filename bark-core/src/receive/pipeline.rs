@@ -1,8 +1,8 @@
-use bark_protocol::FRAMES_PER_PACKET;
 use bytemuck::Zeroable;
 
 use bark_protocol::packet::Audio;
 use bark_protocol::types::AudioPacketHeader;
+use bark_protocol::SampleRate;
 
 use crate::audio::Format;
 use crate::decode::Decoder;
@@ -14,6 +14,12 @@ pub struct Pipeline<F: Format> {
     decoder: Option<Decoder>,
     resampler: Resampler<F>,
     rate_adjust: RateAdjust,
+    /// Input rate the resampler is currently running at
+    rate: SampleRate,
+    /// Sized for one packet of this stream
+    decode_buffer: Vec<F::Frame>,
+    /// Number of frames in decode_buffer from the last packet processed
+    decoded: usize,
 }
 
 impl<F: Format> Pipeline<F> {
@@ -33,25 +39,71 @@ impl<F: Format> Pipeline<F> {
             decoder,
             resampler: Resampler::new(),
             rate_adjust: RateAdjust::new(),
+            rate: bark_protocol::SAMPLE_RATE,
+            decode_buffer: vec![F::Frame::zeroed(); header.frames_per_packet()],
+            decoded: 0,
         }
     }
 
+    pub fn frames_per_packet(&self) -> usize {
+        self.decode_buffer.len()
+    }
+
+    /// Decoded audio from the last packet processed, before resampling
+    pub fn decoded(&self) -> &[F::Frame] {
+        &self.decode_buffer[0..self.decoded]
+    }
+
+    /// Whether lost packets are concealed by the decoder, rather than
+    /// played as silence
+    pub fn conceals_loss(&self) -> bool {
+        self.decoder.as_ref().is_some_and(|decoder| decoder.conceals_loss())
+    }
+
     pub fn slew(&self) -> bool {
         self.rate_adjust.slew()
     }
 
+    pub fn drift_ppm(&self) -> f64 {
+        self.rate_adjust.drift_ppm()
+    }
+
+    pub fn set_drift_ppm(&mut self, ppm: f64) {
+        self.rate_adjust.set_drift_ppm(ppm);
+    }
+
+    pub fn sample_rate(&self) -> SampleRate {
+        self.rate
+    }
+
     pub fn set_timing(&mut self, timing: Timing) {
         let rate = self.rate_adjust.sample_rate(timing);
         let _ = self.resampler.set_input_rate(rate.0);
+        self.rate = rate;
     }
 
-    pub fn process(&mut self, packet: Option<&Audio>, out: &mut [F::Frame]) -> usize {
+    /// When `packet` is lost, `next` is the packet following it if it has
+    /// already arrived. Codecs with forward error correction use it to
+    /// recover the lost audio.
+    pub fn process(&mut self, packet: Option<&Audio>, next: Option<&Audio>, out: &mut [F::Frame]) -> usize {
         // decode packet
-        let mut decode_buffer = [F::Frame::zeroed(); FRAMES_PER_PACKET];
+        let decode_buffer = &mut self.decode_buffer;
+        decode_buffer.fill(F::Frame::zeroed());
+
+        // packets may be short, so only take as many frames as were decoded.
+        // on error or missing decoder we play a full packet of silence
+        let mut frames = decode_buffer.len();
 
         if let Some(decoder) = self.decoder.as_mut() {
-            match decoder.decode(packet, F::frames_mut(&mut decode_buffer)) {
-                Ok(()) => {}
+            let out = F::frames_mut(decode_buffer);
+
+            let result = match (packet, next) {
+                (None, Some(next)) => decoder.decode_fec(next, out),
+                (packet, _) => decoder.decode(packet, out),
+            };
+
+            match result {
+                Ok(n) => { frames = n; }
                 Err(e) => {
                     log::warn!("error in decoder, skipping packet: {e}");
                     decode_buffer.fill(F::Frame::zeroed());
@@ -59,8 +111,11 @@ impl<F: Format> Pipeline<F> {
             }
         }
 
+        let decode_buffer = &decode_buffer[0..frames];
+        self.decoded = frames;
+
         // resample decoded audio
-        let resample = self.resampler.process(&decode_buffer, out)
+        let resample = self.resampler.process(decode_buffer, out)
             .expect("resample error!");
 
         assert_eq!(resample.input_read.0, decode_buffer.len());
@@ -16,6 +16,11 @@ pub struct PacketQueue {
     /// allow for some buffering. The amount of packets buffered depends on
     /// the difference between dts and pts in the initial packet.
     start: DelayStart,
+    /// Delay added by the receiver on top of the stream's own delay
+    extra_delay: SampleDuration,
+    /// Synchronised start of the stream, if it is yet to come. Packets
+    /// before it are never played
+    start_pts: Option<Timestamp>,
 }
 
 #[derive(Debug)]
@@ -31,17 +36,41 @@ impl AudioPts {
     }
 }
 
+/// What became of a packet passed to `PacketQueue::insert_packet`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Insert {
+    Queued,
+    /// Packet with this seq already received, eg. sent redundantly
+    Duplicate,
+    /// Packet arrived after its slot was already played
+    Late,
+    /// Packet was too far ahead of the queue, which was reset to start from it
+    Reset,
+    /// Packet is before the synchronised start of the stream
+    BeforeStart,
+}
+
 enum NoSlot {
     InPast,
     TooFarInFuture,
 }
 
 impl PacketQueue {
-    pub fn new(initial: &AudioPacketHeader) -> Self {
+    /// Queue for a stream beginning with `initial`. If `start_pts` is given,
+    /// the caller waits until then to begin popping packets, so there is
+    /// no need to delay for buffering
+    pub fn new(initial: &AudioPacketHeader, extra_delay: SampleDuration, start_pts: Option<Timestamp>) -> Self {
+        let start = match start_pts {
+            Some(_) => DelayStart::Live,
+            None => DelayStart::init(initial, extra_delay),
+        };
+
         PacketQueue {
             queue: Deque::new(),
             head_seq: initial.seq,
-            start: DelayStart::init(initial),
+            start,
+            extra_delay,
+            start_pts,
         }
     }
 
@@ -58,30 +87,46 @@ impl PacketQueue {
         None
     }
 
-    pub fn insert_packet(&mut self, packet: AudioPts) {
+    pub fn insert_packet(&mut self, packet: AudioPts) -> Insert {
         let packet_seq = packet.header().seq;
+
+        if self.start_pts.is_some_and(|start| packet.pts < start) {
+            // move the head of the queue past this packet, so the first
+            // packet popped is the one the stream starts on
+            while self.head_seq <= packet_seq {
+                self.queue.pop_front();
+                self.head_seq += 1;
+            }
+
+            return Insert::BeforeStart;
+        }
         let head_seq = self.head_seq;
         let tail_seq = self.head_seq + self.queue.capacity() as u64;
 
         match self.queue_slot_mut(packet_seq) {
             Ok(slot@&mut None) => {
                 *slot = Some(packet);
+                Insert::Queued
             }
             Ok(Some(_)) => {
-                log::warn!("received duplicate packet, retaining first received: packet_seq={packet_seq}");
+                // retain first received
+                Insert::Duplicate
             }
             Err(NoSlot::InPast) => {
                 log::warn!("received packet in past, dropping: head_seq={head_seq}, packet_seq={packet_seq}");
+                Insert::Late
             }
             Err(NoSlot::TooFarInFuture) => {
                 log::warn!("received packet too far in future, resetting queue: tail_seq={tail_seq}, packet_seq={packet_seq}");
 
                 // reset queue:
                 self.head_seq = packet_seq;
-                self.start = DelayStart::init(packet.header());
+                self.start = DelayStart::init(packet.header(), self.extra_delay);
+                self.start_pts = None;
                 self.queue.clear();
                 self.queue.push_back(Some(packet)).expect("always room in queue after clear");
 
+                Insert::Reset
             }
         }
     }
@@ -109,6 +154,11 @@ impl PacketQueue {
         }
     }
 
+    /// Peek at the packet at the front of the queue, if it has been received
+    pub fn front(&self) -> Option<&AudioPts> {
+        self.queue.front().and_then(|entry| entry.as_ref())
+    }
+
     pub fn len(&self) -> usize {
         self.queue.len()
     }
@@ -120,15 +170,15 @@ enum DelayStart {
 }
 
 impl DelayStart {
-    pub fn init(header: &AudioPacketHeader) -> Self {
+    pub fn init(header: &AudioPacketHeader, extra_delay: SampleDuration) -> Self {
         // calculate the stream delay by taking the difference between
         // pts and dts in the initial packet:
         let initial_pts = Timestamp::from_micros_lossy(header.pts);
         let initial_dts = Timestamp::from_micros_lossy(header.dts);
-        let delay = initial_pts.saturating_duration_since(initial_dts);
+        let delay = initial_pts.saturating_duration_since(initial_dts).add(extra_delay);
 
         // calculate number of packets this delay represents:
-        let packet_delay = delay.to_frame_count() / SampleDuration::ONE_PACKET.to_frame_count();
+        let packet_delay = delay.to_frame_count() / header.packet_duration().to_frame_count();
 
         // quick n dirty round up:
         let packet_delay = packet_delay + 1;
@@ -1,12 +1,62 @@
+//! Resampling, to follow the stream's clock as it drifts from the output's,
+//! and to convert to other output rates. The backend is chosen at build
+//! time: soxr with the `soxr` feature, otherwise a windowed sinc resampler
+//! in pure Rust, for builds that can't link C libraries.
+
 use std::marker::PhantomData;
 
-use soxr::Soxr;
-use soxr::format::Stereo;
+use thiserror::Error;
+
+use bark_protocol::time::SampleDuration;
+
+use crate::audio::{Format, FrameCount, FrameF32, F32};
+
+pub mod sinc;
+#[cfg(feature = "soxr")]
+pub mod soxr;
 
-use crate::audio::{Format, FrameCount};
+#[cfg(feature = "soxr")]
+pub type DefaultBackend<F> = self::soxr::SoxrBackend<F>;
+#[cfg(not(feature = "soxr"))]
+pub type DefaultBackend<F> = sinc::SincBackend<F>;
 
-pub struct Resampler<F: Format> {
-    soxr: Soxr<Stereo<F::Sample>>,
+/// Name of the backend `Resampler` uses by default
+pub const BACKEND: &str = <DefaultBackend<F32> as Backend<F32>>::NAME;
+
+/// Sample types every backend built in can resample
+#[cfg(feature = "soxr")]
+pub trait Sample: ::soxr::format::Sample {}
+#[cfg(feature = "soxr")]
+impl<T: ::soxr::format::Sample> Sample for T {}
+
+/// Sample types every backend built in can resample
+#[cfg(not(feature = "soxr"))]
+pub trait Sample {}
+#[cfg(not(feature = "soxr"))]
+impl<T> Sample for T {}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("can't resample from {input} Hz to {output} Hz")]
+    Rate { input: u32, output: u32 },
+    #[cfg(feature = "soxr")]
+    #[error("soxr: {0}")]
+    Soxr(::soxr::Error),
+}
+
+/// A resampling implementation. Backends take all the input they are
+/// given, buffering whatever they can't write out yet
+pub trait Backend<F: Format>: Sized {
+    const NAME: &'static str;
+
+    fn new(input_rate: u32, output_rate: u32) -> Result<Self, Error>;
+    fn set_rates(&mut self, input_rate: u32, output_rate: u32) -> Result<(), Error>;
+    fn process(&mut self, input: &[F::Frame], output: &mut [F::Frame]) -> Result<ProcessResult, Error>;
+}
+
+pub struct Resampler<F: Format, B: Backend<F> = DefaultBackend<F>> {
+    backend: B,
+    output_rate: u32,
     _phantom: PhantomData<F>,
 }
 
@@ -17,27 +67,73 @@ pub struct ProcessResult {
 
 impl<F: Format> Resampler<F> {
     pub fn new() -> Self {
-        let rate = bark_protocol::SAMPLE_RATE.0 as f64;
-        let soxr = Soxr::variable_rate(rate, rate).unwrap();
-        Resampler { soxr, _phantom: PhantomData }
+        Self::with_output_rate(bark_protocol::SAMPLE_RATE.0)
+    }
+
+    /// Resample the stream to another output rate, for outputs which
+    /// can't take audio at the stream rate
+    pub fn with_output_rate(output_rate: u32) -> Self {
+        Self::with_backend(output_rate)
+    }
+}
+
+impl<F: Format, B: Backend<F>> Resampler<F, B> {
+    /// Resample with a particular backend, rather than the one chosen at
+    /// build time
+    pub fn with_backend(output_rate: u32) -> Self {
+        let backend = B::new(bark_protocol::SAMPLE_RATE.0, output_rate).unwrap();
+        Resampler { backend, output_rate, _phantom: PhantomData }
     }
 
-    pub fn set_input_rate(&mut self, rate: u32) -> Result<(), soxr::Error> {
-        let input = rate as f64;
-        let output = bark_protocol::SAMPLE_RATE.0 as f64;
-        self.soxr.set_rates(input, output, 0)
+    pub fn set_input_rate(&mut self, rate: u32) -> Result<(), Error> {
+        self.backend.set_rates(rate, self.output_rate)
     }
 
     pub fn process(&mut self, input: &[F::Frame], output: &mut [F::Frame])
-        -> Result<ProcessResult, soxr::Error>
+        -> Result<ProcessResult, Error>
     {
-        let input = bytemuck::must_cast_slice(input);
-        let output = bytemuck::must_cast_slice_mut(output);
-        let result = self.soxr.process(input, output)?;
-
-        Ok(ProcessResult {
-            input_read: FrameCount(result.input_frames),
-            output_written: FrameCount(result.output_frames),
-        })
+        self.backend.process(input, output)
     }
 }
+
+/// Measure the delay the resampler adds, by passing an impulse through it at
+/// the stream rate and finding where it comes out. `None` if it never does
+pub fn measure_delay() -> Option<SampleDuration> {
+    const CHUNK: usize = 256;
+    // far longer than any resampler filter
+    const LIMIT: usize = 48000;
+
+    let mut resampler = Resampler::<F32>::new();
+
+    let mut input = vec![FrameF32(0.0, 0.0); CHUNK];
+    let mut output = vec![FrameF32(0.0, 0.0); CHUNK];
+    input[0] = FrameF32(1.0, 1.0);
+
+    let mut position = 0;
+    let mut peak = (0, 0.0f32);
+
+    for _ in 0..(LIMIT / CHUNK) {
+        let mut remaining = &input[..];
+
+        while !remaining.is_empty() {
+            let result = resampler.process(remaining, &mut output).ok()?;
+            remaining = &remaining[result.input_read.0..];
+
+            for (i, frame) in output[..result.output_written.0].iter().enumerate() {
+                if frame.0.abs() > peak.1 {
+                    peak = (position + i, frame.0.abs());
+                }
+            }
+
+            position += result.output_written.0;
+
+            if result.input_read.0 == 0 && result.output_written.0 == 0 {
+                break;
+            }
+        }
+
+        input[0] = FrameF32(0.0, 0.0);
+    }
+
+    (peak.1 > 0.0).then(|| SampleDuration::from_frame_count(peak.0))
+}
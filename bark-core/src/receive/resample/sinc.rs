@@ -0,0 +1,229 @@
+//! Windowed sinc resampling in pure Rust. Each output frame is the input
+//! around it convolved with a Kaiser windowed sinc filter, whose
+//! coefficients are tabulated at fixed phases between input frames and
+//! interpolated between them. The rate may change at any time, which is
+//! all following the stream's clock needs.
+
+use std::f64::consts::PI;
+use std::marker::PhantomData;
+
+use crate::audio::{self, Format, FrameCount, FrameF32, FrameS16, Frames, FramesMut};
+use crate::receive::resample::{Backend, Error, ProcessResult};
+
+/// Input frames either side of each output frame the filter takes in.
+/// This is also the delay the resampler adds, 0.67ms at 48 kHz
+const HALF_TAPS: usize = 32;
+const TAPS: usize = HALF_TAPS * 2;
+
+/// Phases between input frames the filter is tabulated at
+const PHASES: usize = 256;
+
+/// Shape of the Kaiser window, for about 85 dB of stopband attenuation
+const KAISER_BETA: f64 = 8.6;
+
+/// Cutoff of the filter relative to the lower of the two Nyquist
+/// frequencies, leaving room for its transition band beneath
+const CUTOFF: f64 = 0.91;
+
+pub struct SincBackend<F: Format> {
+    /// `TAPS` coefficients for each of `PHASES + 1` phases, the last
+    /// standing in for the first phase of the next input frame
+    filter: Vec<f32>,
+    cutoff: f64,
+    /// Input frames per output frame
+    step: f64,
+    /// Input still needed, starting `HALF_TAPS - 1` frames before the
+    /// next output frame
+    history: Vec<FrameF32>,
+    /// Where in `history` the next output frame falls
+    position: f64,
+    _phantom: PhantomData<F>,
+}
+
+impl<F: Format> Backend<F> for SincBackend<F> {
+    const NAME: &'static str = "sinc";
+
+    fn new(input_rate: u32, output_rate: u32) -> Result<Self, Error> {
+        let (step, cutoff) = rates(input_rate, output_rate)?;
+
+        // start out on silence, so that output begins straight away and
+        // the delay is the same from the first frame
+        Ok(SincBackend {
+            filter: filter(cutoff),
+            cutoff,
+            step,
+            history: vec![FrameF32(0.0, 0.0); TAPS - 1],
+            position: (HALF_TAPS - 1) as f64,
+            _phantom: PhantomData,
+        })
+    }
+
+    fn set_rates(&mut self, input_rate: u32, output_rate: u32) -> Result<(), Error> {
+        let (step, cutoff) = rates(input_rate, output_rate)?;
+        self.step = step;
+
+        // the stream's clock drifts by no more than a few hundred ppm, so
+        // only retabulate the filter for a real change in rate
+        if (cutoff / self.cutoff - 1.0).abs() > 0.01 {
+            self.filter = filter(cutoff);
+            self.cutoff = cutoff;
+        }
+
+        Ok(())
+    }
+
+    fn process(&mut self, input: &[F::Frame], output: &mut [F::Frame])
+        -> Result<ProcessResult, Error>
+    {
+        match F::frames(input) {
+            Frames::S16(input) => {
+                self.history.extend(input.iter().map(|frame| {
+                    FrameF32(audio::s16_to_f32(frame.0), audio::s16_to_f32(frame.1))
+                }));
+            }
+            Frames::F32(input) => {
+                self.history.extend_from_slice(input);
+            }
+        }
+
+        let written = match F::frames_mut(output) {
+            FramesMut::S16(output) => {
+                self.resample(output.len(), |n, frame| {
+                    output[n] = FrameS16(audio::f32_to_s16(frame.0), audio::f32_to_s16(frame.1));
+                })
+            }
+            FramesMut::F32(output) => {
+                self.resample(output.len(), |n, frame| {
+                    output[n] = frame;
+                })
+            }
+        };
+
+        Ok(ProcessResult {
+            input_read: FrameCount(input.len()),
+            output_written: FrameCount(written),
+        })
+    }
+}
+
+impl<F: Format> SincBackend<F> {
+    /// Write up to `len` output frames, as far as the input goes, then let
+    /// go of input no longer needed
+    fn resample(&mut self, len: usize, mut write: impl FnMut(usize, FrameF32)) -> usize {
+        let mut written = 0;
+
+        while written < len {
+            let index = self.position as usize;
+
+            if index + HALF_TAPS >= self.history.len() {
+                break;
+            }
+
+            let frac = self.position - index as f64;
+            let input = &self.history[index + 1 - HALF_TAPS..][..TAPS];
+            write(written, self.convolve(input, frac));
+
+            written += 1;
+            self.position += self.step;
+        }
+
+        let consumed = (self.position as usize + 1)
+            .saturating_sub(HALF_TAPS)
+            .min(self.history.len());
+
+        self.history.drain(..consumed);
+        self.position -= consumed as f64;
+
+        written
+    }
+
+    /// The output frame `frac` of the way between the middle two frames of
+    /// `input`
+    fn convolve(&self, input: &[FrameF32], frac: f64) -> FrameF32 {
+        let phase = frac * PHASES as f64;
+        let index = (phase as usize).min(PHASES - 1);
+        let weight = (phase - index as f64) as f32;
+
+        let a = &self.filter[index * TAPS..][..TAPS];
+        let b = &self.filter[(index + 1) * TAPS..][..TAPS];
+
+        let mut out = FrameF32(0.0, 0.0);
+
+        for ((frame, a), b) in input.iter().zip(a).zip(b) {
+            let coeff = a + (b - a) * weight;
+            out.0 += frame.0 * coeff;
+            out.1 += frame.1 * coeff;
+        }
+
+        out
+    }
+}
+
+/// Input frames per output frame, and the filter cutoff relative to the
+/// input's Nyquist frequency
+fn rates(input_rate: u32, output_rate: u32) -> Result<(f64, f64), Error> {
+    if input_rate == 0 || output_rate == 0 {
+        return Err(Error::Rate { input: input_rate, output: output_rate });
+    }
+
+    let step = f64::from(input_rate) / f64::from(output_rate);
+    let cutoff = CUTOFF * (1.0 / step).min(1.0);
+    Ok((step, cutoff))
+}
+
+fn filter(cutoff: f64) -> Vec<f32> {
+    let mut filter = Vec::with_capacity((PHASES + 1) * TAPS);
+
+    for phase in 0..=PHASES {
+        let frac = phase as f64 / PHASES as f64;
+
+        let coeffs = (0..TAPS)
+            .map(|tap| {
+                let distance = (tap + 1) as f64 - HALF_TAPS as f64 - frac;
+                cutoff * sinc(cutoff * distance) * kaiser(distance / HALF_TAPS as f64)
+            })
+            .collect::<Vec<_>>();
+
+        // normalise each phase to unity gain, so that the level doesn't
+        // ripple as the phase moves
+        let gain = coeffs.iter().sum::<f64>();
+        filter.extend(coeffs.iter().map(|coeff| (coeff / gain) as f32));
+    }
+
+    filter
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Kaiser window over -1..1
+fn kaiser(x: f64) -> f64 {
+    if x.abs() > 1.0 {
+        return 0.0;
+    }
+
+    bessel_i0(KAISER_BETA * (1.0 - x * x).sqrt()) / bessel_i0(KAISER_BETA)
+}
+
+/// Zeroth order modified Bessel function of the first kind, by its power
+/// series, which converges quickly for the arguments a Kaiser window takes
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+
+    for k in 1..64 {
+        term *= (x / (2.0 * k as f64)).powi(2);
+        sum += term;
+
+        if term < sum * 1e-12 {
+            break;
+        }
+    }
+
+    sum
+}
@@ -0,0 +1,42 @@
+use std::marker::PhantomData;
+
+use soxr::Soxr;
+use soxr::format::Stereo;
+
+use crate::audio::{Format, FrameCount};
+use crate::receive::resample::{Backend, Error, ProcessResult};
+
+/// Resampling through libsoxr, in its variable rate mode
+pub struct SoxrBackend<F: Format> {
+    soxr: Soxr<Stereo<F::Sample>>,
+    _phantom: PhantomData<F>,
+}
+
+impl<F: Format> Backend<F> for SoxrBackend<F> {
+    const NAME: &'static str = "soxr";
+
+    fn new(input_rate: u32, output_rate: u32) -> Result<Self, Error> {
+        let soxr = Soxr::variable_rate(input_rate as f64, output_rate as f64)
+            .map_err(Error::Soxr)?;
+
+        Ok(SoxrBackend { soxr, _phantom: PhantomData })
+    }
+
+    fn set_rates(&mut self, input_rate: u32, output_rate: u32) -> Result<(), Error> {
+        self.soxr.set_rates(input_rate as f64, output_rate as f64, 0)
+            .map_err(Error::Soxr)
+    }
+
+    fn process(&mut self, input: &[F::Frame], output: &mut [F::Frame])
+        -> Result<ProcessResult, Error>
+    {
+        let input = bytemuck::must_cast_slice(input);
+        let output = bytemuck::must_cast_slice_mut(output);
+        let result = self.soxr.process(input, output).map_err(Error::Soxr)?;
+
+        Ok(ProcessResult {
+            input_read: FrameCount(result.input_frames),
+            output_written: FrameCount(result.output_frames),
+        })
+    }
+}
@@ -1,10 +1,52 @@
 use core::time::Duration;
 
+use heapless::{Deque, Vec};
+
 use bark_protocol::time::{Timestamp, SampleDuration};
 use bark_protocol::SampleRate;
 
+use crate::consts::LATENCY_HISTORY;
+
+// offset beyond which the stream is reported as slewing, and within which
+// it is reported as in sync again. only affects reported status, the servo
+// below runs continuously
+const START_SLEW_THRESHOLD: Duration = Duration::from_micros(500);
+const STOP_SLEW_THRESHOLD: Duration = Duration::from_micros(100);
+
+// smooths jitter in offset measurements, eg. from output delay granularity
+const FILTER_TIME: Duration = Duration::from_millis(100);
+
+// proportional gain per second of offset, and integral gain, chosen for a
+// critically damped loop settling within a couple of seconds
+const KP: f64 = 2.0;
+const KI: f64 = KP * KP / 4.0;
+
+// we shouldn't ever get too far ahead of the stream, and sound card clocks
+// shouldn't drift anywhere near this far from the system clock
+const MAX_ADJUST: f64 = 0.01;
+const MAX_DRIFT: f64 = 0.005;
+
+// latency samples above this percentile of recent history are outliers
+const LATENCY_OUTLIER_PERCENTILE: usize = 95;
+// don't reject anything until there's enough history to judge by
+const LATENCY_MIN_HISTORY: usize = 16;
+
+// only learn drift once close to sync, otherwise correcting a large
+// initial offset winds up the integral term and overshoots
+const DRIFT_THRESHOLD: Duration = Duration::from_millis(1);
+
+/// Clock servo matching the rate we consume a stream at to the rate the
+/// output device plays it, keeping playback in sync with the stream. A PI
+/// controller on the audio offset: the proportional term corrects offset,
+/// while the integral term learns the steady drift between clocks, so that
+/// drift is tracked smoothly rather than by repeatedly slewing.
 pub struct RateAdjust {
     slew: bool,
+    last_play: Option<Timestamp>,
+    // low pass filtered audio offset, in seconds
+    offset: f64,
+    // learned drift correction, as a fraction of the sample rate
+    drift: f64,
 }
 
 #[derive(Copy, Clone)]
@@ -16,7 +58,10 @@ pub struct Timing {
 impl RateAdjust {
     pub fn new() -> Self {
         RateAdjust {
-            slew: false
+            slew: false,
+            last_play: None,
+            offset: 0.0,
+            drift: 0.0,
         }
     }
 
@@ -24,41 +69,111 @@ impl RateAdjust {
         self.slew
     }
 
-    pub fn sample_rate(&mut self, timing: Timing) -> SampleRate {
-        self.adjusted_rate(timing).unwrap_or(bark_protocol::SAMPLE_RATE)
+    /// Drift learned between the output device and stream clocks, in parts
+    /// per million
+    pub fn drift_ppm(&self) -> f64 {
+        self.drift * 1_000_000.0
     }
 
-    fn adjusted_rate(&mut self, timing: Timing) -> Option<SampleRate> {
-        // parameters, maybe these could be cli args?
-        let start_slew_threshold = Duration::from_micros(500);
-        let stop_slew_threshold = Duration::from_micros(100);
+    /// Start from drift learned earlier, such as in a previous session
+    /// between the same clocks, rather than learning it from scratch
+    pub fn set_drift_ppm(&mut self, ppm: f64) {
+        self.drift = (ppm / 1_000_000.0).clamp(-MAX_DRIFT, MAX_DRIFT);
+    }
 
-        // turn them into native units
-        let start_slew_threshold = SampleDuration::from_std_duration_lossy(start_slew_threshold);
-        let stop_slew_threshold = SampleDuration::from_std_duration_lossy(stop_slew_threshold);
+    pub fn sample_rate(&mut self, timing: Timing) -> SampleRate {
+        let delta = timing.real.delta(timing.play);
 
-        let offset = timing.real.delta(timing.play);
+        let start_slew_threshold = SampleDuration::from_std_duration_lossy(START_SLEW_THRESHOLD);
+        let stop_slew_threshold = SampleDuration::from_std_duration_lossy(STOP_SLEW_THRESHOLD);
 
-        if offset.abs() < stop_slew_threshold {
+        if delta.abs() < stop_slew_threshold {
             self.slew = false;
-            return None;
+        } else if delta.abs() >= start_slew_threshold {
+            self.slew = true;
         }
 
-        if offset.abs() < start_slew_threshold && !self.slew {
-            return None;
+        // time elapsed in the stream since the last measurement
+        let elapsed = match self.last_play {
+            Some(last) => timing.play.saturating_duration_since(last),
+            None => SampleDuration::zero(),
+        };
+        self.last_play = Some(timing.play);
+
+        let elapsed = elapsed.to_std_duration_lossy().as_secs_f64();
+        let offset = delta.to_seconds();
+
+        if elapsed > 0.0 {
+            let alpha = 1.0 - (-elapsed / FILTER_TIME.as_secs_f64()).exp();
+            self.offset += (offset - self.offset) * alpha;
+        } else {
+            self.offset = offset;
         }
 
-        let base_sample_rate = i64::from(bark_protocol::SAMPLE_RATE);
+        let proportional = KP * self.offset;
 
-        let rate_adjust = offset.as_frames().pow(3) / 48;
-        let rate = base_sample_rate + rate_adjust;
+        if self.offset.abs() < DRIFT_THRESHOLD.as_secs_f64() {
+            self.drift = (self.drift + KI * self.offset * elapsed)
+                .clamp(-MAX_DRIFT, MAX_DRIFT);
+        }
+
+        let adjust = (proportional + self.drift).clamp(-MAX_ADJUST, MAX_ADJUST);
+
+        // a positive offset means we are playing late, so consume the
+        // stream faster to catch up
+        let base_sample_rate = f64::from(bark_protocol::SAMPLE_RATE.0);
+        let rate = (base_sample_rate * (1.0 + adjust)).round();
 
-        // clamp any potential rate adjustment to 1%, we shouldn't ever get too far
-        // ahead of the stream
-        let rate = std::cmp::max(base_sample_rate * 99 / 100, rate);
-        let rate = std::cmp::min(base_sample_rate * 101 / 100, rate);
+        SampleRate(rate as u32)
+    }
+}
+
+/// Rejects network latency samples that are outliers among recent history,
+/// such as a single packet held up by a wifi retransmit
+pub struct LatencyFilter {
+    history: Deque<Duration, LATENCY_HISTORY>,
+}
+
+impl LatencyFilter {
+    pub fn new() -> Self {
+        LatencyFilter {
+            history: Deque::new(),
+        }
+    }
+
+    /// Record a latency sample, returning it unless it is an outlier
+    pub fn filter(&mut self, latency: Duration) -> Option<Duration> {
+        let accept = match self.percentile(LATENCY_OUTLIER_PERCENTILE) {
+            Some(limit) => latency <= limit,
+            None => true,
+        };
+
+        // outliers still go into history, so that a real increase in
+        // latency is accepted once it becomes the norm
+        if self.history.is_full() {
+            self.history.pop_front();
+        }
+
+        let _ = self.history.push_back(latency);
+
+        accept.then_some(latency)
+    }
+
+    fn percentile(&self, percentile: usize) -> Option<Duration> {
+        if self.history.len() < LATENCY_MIN_HISTORY {
+            return None;
+        }
+
+        let mut sorted = self.history.iter().copied().collect::<Vec<_, LATENCY_HISTORY>>();
+        sorted.sort_unstable();
+
+        let index = (sorted.len() * percentile / 100).min(sorted.len() - 1);
+        Some(sorted[index])
+    }
+}
 
-        self.slew = true;
-        Some(SampleRate(u32::try_from(rate).unwrap()))
+impl Default for LatencyFilter {
+    fn default() -> Self {
+        Self::new()
     }
 }
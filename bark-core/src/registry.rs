@@ -0,0 +1,134 @@
+//! Encoder and decoder factories, keyed by AudioPacketFormat.
+//!
+//! Built-in codecs are registered on first use. Embedders may register
+//! additional codecs, or replace built-in ones, before starting a stream.
+//! Additional codecs take a format from `AudioPacketFormat::THIRD_PARTY`.
+
+use std::sync::{Arc, OnceLock, RwLock};
+
+use bark_protocol::types::{AudioFormatSet, AudioPacketFormat, AudioPacketHeader};
+
+use crate::decode::{self, Decode, NewDecoderError};
+use crate::encode::{self, Encode, NewEncoderError};
+
+pub type EncoderFactory = Arc<dyn Fn() -> Result<Box<dyn Encode>, NewEncoderError> + Send + Sync>;
+pub type DecoderFactory = Arc<dyn Fn(&AudioPacketHeader) -> Result<Box<dyn Decode>, NewDecoderError> + Send + Sync>;
+
+struct Registry<T> {
+    entries: RwLock<Vec<(AudioPacketFormat, T)>>,
+}
+
+impl<T: Clone> Registry<T> {
+    fn new() -> Self {
+        Registry { entries: RwLock::new(Vec::new()) }
+    }
+
+    fn register(&self, format: AudioPacketFormat, factory: T) {
+        let mut entries = self.entries.write().unwrap();
+        entries.retain(|(fmt, _)| *fmt != format);
+        entries.push((format, factory));
+    }
+
+    fn formats(&self) -> AudioFormatSet {
+        let entries = self.entries.read().unwrap();
+        entries.iter().map(|(fmt, _)| *fmt).collect()
+    }
+
+    fn get(&self, format: AudioPacketFormat) -> Option<T> {
+        let entries = self.entries.read().unwrap();
+        entries.iter()
+            .find(|(fmt, _)| *fmt == format)
+            .map(|(_, factory)| factory.clone())
+    }
+}
+
+fn encoders() -> &'static Registry<EncoderFactory> {
+    static ENCODERS: OnceLock<Registry<EncoderFactory>> = OnceLock::new();
+
+    ENCODERS.get_or_init(|| {
+        let registry = Registry::<EncoderFactory>::new();
+
+        registry.register(AudioPacketFormat::S16LE,
+            Arc::new(|| Ok(Box::new(encode::pcm::S16LEEncoder) as Box<dyn Encode>)));
+
+        registry.register(AudioPacketFormat::F32LE,
+            Arc::new(|| Ok(Box::new(encode::pcm::F32LEEncoder) as Box<dyn Encode>)));
+
+        registry.register(AudioPacketFormat::S24LE,
+            Arc::new(|| Ok(Box::new(encode::pcm::S24LEEncoder) as Box<dyn Encode>)));
+
+        registry.register(AudioPacketFormat::S32LE,
+            Arc::new(|| Ok(Box::new(encode::pcm::S32LEEncoder) as Box<dyn Encode>)));
+
+        #[cfg(feature = "opus")]
+        registry.register(AudioPacketFormat::OPUS,
+            Arc::new(|| {
+                let opt = encode::opus::OpusEncoderOpt::default();
+                Ok(Box::new(encode::opus::OpusEncoder::new(&opt)?) as Box<dyn Encode>)
+            }));
+
+        registry
+    })
+}
+
+fn decoders() -> &'static Registry<DecoderFactory> {
+    static DECODERS: OnceLock<Registry<DecoderFactory>> = OnceLock::new();
+
+    DECODERS.get_or_init(|| {
+        let registry = Registry::<DecoderFactory>::new();
+
+        registry.register(AudioPacketFormat::S16LE,
+            Arc::new(|_: &AudioPacketHeader| Ok(Box::new(decode::pcm::S16LEDecoder) as Box<dyn Decode>)));
+
+        registry.register(AudioPacketFormat::F32LE,
+            Arc::new(|_: &AudioPacketHeader| Ok(Box::new(decode::pcm::F32LEDecoder) as Box<dyn Decode>)));
+
+        registry.register(AudioPacketFormat::S24LE,
+            Arc::new(|_: &AudioPacketHeader| Ok(Box::new(decode::pcm::S24LEDecoder) as Box<dyn Decode>)));
+
+        registry.register(AudioPacketFormat::S32LE,
+            Arc::new(|_: &AudioPacketHeader| Ok(Box::new(decode::pcm::S32LEDecoder) as Box<dyn Decode>)));
+
+        #[cfg(feature = "opus")]
+        registry.register(AudioPacketFormat::OPUS,
+            Arc::new(|_: &AudioPacketHeader| Ok(Box::new(decode::opus::OpusDecoder::new()?) as Box<dyn Decode>)));
+
+        registry
+    })
+}
+
+/// Register an encoder factory for `format`, replacing any existing factory
+pub fn register_encoder(
+    format: AudioPacketFormat,
+    factory: impl Fn() -> Result<Box<dyn Encode>, NewEncoderError> + Send + Sync + 'static,
+) {
+    encoders().register(format, Arc::new(factory));
+}
+
+/// Register a decoder factory for `format`, replacing any existing factory
+pub fn register_decoder(
+    format: AudioPacketFormat,
+    factory: impl Fn(&AudioPacketHeader) -> Result<Box<dyn Decode>, NewDecoderError> + Send + Sync + 'static,
+) {
+    decoders().register(format, Arc::new(factory));
+}
+
+/// Formats with a decoder registered, which receivers announce in their
+/// stats replies so that sources can tell what they can play
+pub fn decoder_formats() -> AudioFormatSet {
+    decoders().formats()
+}
+
+pub fn new_encoder(format: AudioPacketFormat) -> Result<Box<dyn Encode>, NewEncoderError> {
+    let factory = encoders().get(format)
+        .ok_or(NewEncoderError::UnknownFormat(format))?;
+
+    factory()
+}
+
+pub fn new_decoder(header: &AudioPacketHeader) -> Result<Box<dyn Decode>, NewDecoderError> {
+    let factory = decoders().get(header.format)
+        .ok_or(NewDecoderError::UnknownFormat(header.format))?;
+
+    factory(header)
+}
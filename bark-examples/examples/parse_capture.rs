@@ -0,0 +1,79 @@
+//! Lists the bark packets in a packet capture, parsing each datagram with
+//! bark-protocol:
+//!
+//!     tcpdump -i eth0 -w bark.pcap udp port 1530
+//!     cargo run -p bark-examples --example parse_capture -- bark.pcap
+
+use std::error::Error;
+use std::path::PathBuf;
+
+use bark_protocol::buffer::PacketBuffer;
+use bark_protocol::packet::{Packet, PacketKind};
+use bark_protocol::types::PROTOCOL_VERSION;
+
+use bark_examples::pcap::Capture;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let path = PathBuf::from(std::env::args().nth(1).ok_or("usage: parse_capture <capture.pcap>")?);
+    let mut capture = Capture::open(&path)?;
+
+    while let Some(datagram) = capture.next_datagram()? {
+        let from = datagram.source;
+
+        let Some(packet) = Packet::from_buffer(PacketBuffer::from_raw(datagram.payload)) else {
+            println!("{from}: too short for a bark packet");
+            continue;
+        };
+
+        let magic = packet.header().magic;
+        let version = packet.header().version();
+
+        match packet.parse() {
+            Some(PacketKind::Audio(audio)) => {
+                let header = audio.header();
+                println!("{from}: audio sid={} seq={} format={} frames={} pts={} dts={}",
+                    header.sid.0,
+                    header.seq,
+                    header.format.name().unwrap_or("unknown"),
+                    header.frames_per_packet(),
+                    header.pts.0,
+                    header.dts.0);
+            }
+            Some(PacketKind::StreamEnd(end)) => {
+                println!("{from}: stream end sid={}", end.sid().0);
+            }
+            Some(PacketKind::Metadata(metadata)) => {
+                println!("{from}: now playing sid={} title={:?} artist={:?} album={:?}",
+                    metadata.sid().0,
+                    metadata.title(),
+                    metadata.artist(),
+                    metadata.album());
+            }
+            Some(PacketKind::ReceiverReport(report)) => {
+                println!("{from}: receiver report sid={} highest_seq={} received={} lost={} jitter={:?}",
+                    report.sid().0,
+                    report.highest_seq(),
+                    report.received(),
+                    report.lost(),
+                    report.jitter());
+            }
+            Some(PacketKind::StatsRequest(_)) => {
+                println!("{from}: stats request");
+            }
+            Some(PacketKind::StatsReply(reply)) => {
+                println!("{from}: stats reply sid={} flags={:?}", reply.data().sid.0, reply.flags());
+            }
+            Some(_) => {
+                println!("{from}: {magic:?}");
+            }
+            None if version > PROTOCOL_VERSION => {
+                println!("{from}: packet from a newer protocol version {version} ({magic:?})");
+            }
+            None => {
+                println!("{from}: not a valid bark packet ({magic:?})");
+            }
+        }
+    }
+
+    Ok(())
+}
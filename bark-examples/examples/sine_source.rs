@@ -0,0 +1,78 @@
+//! Sends a 440 Hz tone as a bark stream, encoding with bark-core and
+//! building packets with bark-protocol, over a plain std UDP socket:
+//!
+//!     cargo run -p bark-examples --example sine_source -- 224.100.100.100:1530
+//!
+//! Receivers on the group play it like any other source.
+
+use std::error::Error;
+use std::net::{SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+use bark_core::audio::{Format, FrameF32, F32};
+use bark_core::registry;
+use bark_protocol::packet::Audio;
+use bark_protocol::types::{AudioPacketFormat, AudioPacketHeader, SessionId, TimestampMicros};
+use bark_protocol::FRAMES_PER_PACKET;
+
+use bark_examples::{now, Sine, DEFAULT_GROUP};
+
+/// How far ahead of playing it audio is sent, for receivers to buffer
+const DELAY: Duration = Duration::from_millis(20);
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let group: SocketAddrV4 = std::env::args().nth(1)
+        .as_deref()
+        .unwrap_or(DEFAULT_GROUP)
+        .parse()?;
+
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+
+    let mut encoder = registry::new_encoder(AudioPacketFormat::F32LE)?;
+    let mut sine = Sine::new(440.0, 0.2);
+
+    let start = now();
+
+    let mut header = AudioPacketHeader {
+        // newer sessions win at equal priority, so start time makes a
+        // good session id
+        sid: SessionId(start.0 as i64),
+        seq: 1,
+        pts: TimestampMicros(0),
+        dts: TimestampMicros(0),
+        format: encoder.header_format(),
+        priority: 0,
+        packet_frames: FRAMES_PER_PACKET as u16,
+        delay_ms: DELAY.as_millis() as u16,
+        min_buffer_ms: 0,
+        start_pts: TimestampMicros(0),
+    };
+
+    let packet_micros = header.packet_duration().to_micros_lossy();
+    let started = Instant::now();
+
+    let mut frames = vec![FrameF32(0.0, 0.0); FRAMES_PER_PACKET];
+    let mut encoded = [0; Audio::MAX_BUFFER_LENGTH];
+
+    println!("sending to {group}: sid={}", header.sid.0);
+
+    loop {
+        // pace packets by the audio they carry
+        let offset = (header.seq - 1) * packet_micros;
+        let due = started + Duration::from_micros(offset);
+        std::thread::sleep(due.saturating_duration_since(Instant::now()));
+
+        sine.fill(&mut frames);
+        let len = encoder.encode_packet(F32::frames(&frames), &mut encoded)?;
+
+        header.pts = TimestampMicros(start.0 + offset + DELAY.as_micros() as u64);
+        header.dts = now();
+
+        let audio = Audio::new(&header, &encoded[0..len])
+            .expect("allocate Audio packet");
+
+        socket.send_to(audio.as_packet().as_buffer().as_bytes(), group)?;
+
+        header.seq += 1;
+    }
+}
@@ -0,0 +1,140 @@
+//! Receives a bark stream into a WAV file, decoding with bark-core's
+//! receive pipeline and handing its output to a sink of our own in place
+//! of an audio device:
+//!
+//!     cargo run -p bark-examples --example wav_receiver -- out.wav 224.100.100.100:1530
+//!
+//! Records ten seconds of the first stream heard. A file has no clock of
+//! its own to keep in sync with, so unlike `bark receive` this plays the
+//! stream at its nominal rate and lets the stream's timing pass it by.
+
+use std::error::Error;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::path::PathBuf;
+
+use bark_core::audio::{FrameF32, F32};
+use bark_core::receive::pipeline::Pipeline;
+use bark_protocol::buffer::PacketBuffer;
+use bark_protocol::packet::{Audio, Packet, PacketKind, MAX_PACKET_SIZE};
+use bark_protocol::types::SessionId;
+use bark_protocol::SAMPLE_RATE;
+
+use bark_examples::wav::WavWriter;
+use bark_examples::DEFAULT_GROUP;
+
+const RECORD_SECS: usize = 10;
+
+/// Gaps longer than this are skipped over rather than concealed
+const MAX_CONCEAL_PACKETS: u64 = 100;
+
+/// Where decoded audio goes. `bark receive` writes to ALSA, here it's a file
+trait Sink {
+    fn write(&mut self, frames: &[FrameF32]) -> Result<(), Box<dyn Error>>;
+}
+
+impl Sink for WavWriter {
+    fn write(&mut self, frames: &[FrameF32]) -> Result<(), Box<dyn Error>> {
+        Ok(WavWriter::write(self, frames)?)
+    }
+}
+
+/// One stream being decoded into a sink
+struct Stream {
+    sid: SessionId,
+    next_seq: u64,
+    pipeline: Pipeline<F32>,
+    buffer: Vec<FrameF32>,
+}
+
+impl Stream {
+    fn new(audio: &Audio) -> Self {
+        let header = audio.header();
+        let pipeline = Pipeline::<F32>::new(header);
+
+        // room for the resampler to run a little fast
+        let buffer = vec![FrameF32(0.0, 0.0); pipeline.frames_per_packet() * 2];
+
+        Stream { sid: header.sid, next_seq: header.seq, pipeline, buffer }
+    }
+
+    /// Decode a packet into the sink, concealing any packets lost before
+    /// it. Returns frames written
+    fn receive(&mut self, audio: &Audio, sink: &mut impl Sink) -> Result<usize, Box<dyn Error>> {
+        let seq = audio.header().seq;
+
+        // late or duplicate, it's been played already
+        if seq < self.next_seq {
+            return Ok(0);
+        }
+
+        if seq - self.next_seq > MAX_CONCEAL_PACKETS {
+            self.next_seq = seq;
+        }
+
+        let mut written = 0;
+
+        for _ in self.next_seq..seq {
+            written += self.play(None, Some(audio), sink)?;
+        }
+
+        written += self.play(Some(audio), None, sink)?;
+        self.next_seq = seq + 1;
+
+        Ok(written)
+    }
+
+    fn play(&mut self, packet: Option<&Audio>, next: Option<&Audio>, sink: &mut impl Sink) -> Result<usize, Box<dyn Error>> {
+        let frames = self.pipeline.process(packet, next, &mut self.buffer);
+        sink.write(&self.buffer[0..frames])?;
+        Ok(frames)
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = std::env::args().skip(1);
+
+    let path = PathBuf::from(args.next().ok_or("usage: wav_receiver <out.wav> [group:port]")?);
+    let group: SocketAddrV4 = args.next()
+        .as_deref()
+        .unwrap_or(DEFAULT_GROUP)
+        .parse()?;
+
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, group.port()))?;
+    socket.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)?;
+
+    let mut wav = WavWriter::create(&path)?;
+    let mut stream: Option<Stream> = None;
+    let mut recorded = 0;
+
+    println!("recording {RECORD_SECS}s from {group} into {}", path.display());
+
+    while recorded < RECORD_SECS * SAMPLE_RATE.0 as usize {
+        let mut buffer = vec![0; MAX_PACKET_SIZE];
+        let (len, _) = socket.recv_from(&mut buffer)?;
+        buffer.truncate(len);
+
+        let Some(packet) = Packet::from_buffer(PacketBuffer::from_raw(buffer)) else {
+            continue;
+        };
+
+        let Some(PacketKind::Audio(audio)) = packet.parse() else {
+            continue;
+        };
+
+        let stream = stream.get_or_insert_with(|| {
+            println!("receiving sid={}", audio.header().sid.0);
+            Stream::new(&audio)
+        });
+
+        // stick with the first stream, bark receive would switch to a
+        // newer or higher priority one
+        if audio.header().sid != stream.sid {
+            continue;
+        }
+
+        recorded += stream.receive(&audio, &mut wav)?;
+    }
+
+    wav.finish()?;
+    Ok(())
+}
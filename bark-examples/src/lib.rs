@@ -0,0 +1,54 @@
+//! Examples of using bark-core and bark-protocol from outside the `bark`
+//! binary, in `examples/`. Building them checks that the library surface
+//! is enough for a third party to send, receive and inspect bark streams.
+//!
+//! This crate holds the plumbing they share that isn't bark's concern: a
+//! tone to send, a WAV file to receive into, and a pcap file to read.
+
+pub mod pcap;
+pub mod wav;
+
+use std::f32::consts::TAU;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bark_core::audio::FrameF32;
+use bark_protocol::types::TimestampMicros;
+use bark_protocol::SAMPLE_RATE;
+
+/// Multicast group bark uses unless told otherwise
+pub const DEFAULT_GROUP: &str = "224.100.100.100:1530";
+
+/// The realtime clock, which bark timestamps audio by. Nodes keep their
+/// clocks in sync with NTP or PTP
+pub fn now() -> TimestampMicros {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock before 1970");
+
+    TimestampMicros(since_epoch.as_micros() as u64)
+}
+
+/// Sine wave generator, the same tone in both channels
+pub struct Sine {
+    phase: f32,
+    step: f32,
+    amplitude: f32,
+}
+
+impl Sine {
+    pub fn new(frequency: f32, amplitude: f32) -> Self {
+        Sine {
+            phase: 0.0,
+            step: TAU * frequency / SAMPLE_RATE.0 as f32,
+            amplitude,
+        }
+    }
+
+    pub fn fill(&mut self, frames: &mut [FrameF32]) {
+        for frame in frames {
+            let sample = self.phase.sin() * self.amplitude;
+            *frame = FrameF32(sample, sample);
+            self.phase = (self.phase + self.step) % TAU;
+        }
+    }
+}
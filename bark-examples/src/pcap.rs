@@ -0,0 +1,154 @@
+//! Reading UDP datagrams out of classic pcap captures, such as those taken
+//! with `tcpdump -w bark.pcap udp port 1530`. Ethernet, Linux cooked and
+//! raw IP link types are understood, carrying IPv4.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::Path;
+
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+const IPPROTO_UDP: u8 = 17;
+
+pub struct Datagram {
+    pub source: SocketAddrV4,
+    pub dest: SocketAddrV4,
+    pub payload: Vec<u8>,
+}
+
+pub struct Capture {
+    file: BufReader<File>,
+    big_endian: bool,
+    link_type: u32,
+}
+
+impl Capture {
+    pub fn open(path: &Path) -> Result<Self, io::Error> {
+        let mut file = BufReader::new(File::open(path)?);
+
+        let mut header = [0; 24];
+        file.read_exact(&mut header)?;
+
+        // microsecond and nanosecond captures differ only in timestamps,
+        // which we don't use
+        let big_endian = match header[0..4] {
+            [0xd4, 0xc3, 0xb2, 0xa1] | [0x4d, 0x3c, 0xb2, 0xa1] => false,
+            [0xa1, 0xb2, 0xc3, 0xd4] | [0xa1, 0xb2, 0x3c, 0x4d] => true,
+            _ => return Err(invalid("not a pcap file, pcapng captures must be converted first")),
+        };
+
+        let mut capture = Capture { file, big_endian, link_type: 0 };
+        capture.link_type = capture.u32_at(&header, 20);
+
+        if ![LINKTYPE_ETHERNET, LINKTYPE_RAW, LINKTYPE_LINUX_SLL].contains(&capture.link_type) {
+            return Err(invalid("unsupported link type"));
+        }
+
+        Ok(capture)
+    }
+
+    /// Next UDP datagram in the capture, skipping anything else
+    pub fn next_datagram(&mut self) -> Result<Option<Datagram>, io::Error> {
+        loop {
+            let mut record = [0; 16];
+
+            match self.file.read_exact(&mut record) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+
+            let len = self.u32_at(&record, 8) as usize;
+            let mut frame = vec![0; len];
+            self.file.read_exact(&mut frame)?;
+
+            if let Some(datagram) = self.datagram(&frame) {
+                return Ok(Some(datagram));
+            }
+        }
+    }
+
+    fn datagram(&self, frame: &[u8]) -> Option<Datagram> {
+        let ip = match self.link_type {
+            LINKTYPE_ETHERNET => {
+                let mut ethertype = u16_be(frame, 12)?;
+                let mut offset = 14;
+
+                if ethertype == ETHERTYPE_VLAN {
+                    ethertype = u16_be(frame, 16)?;
+                    offset = 18;
+                }
+
+                if ethertype != ETHERTYPE_IPV4 {
+                    return None;
+                }
+
+                frame.get(offset..)?
+            }
+            LINKTYPE_LINUX_SLL => {
+                if u16_be(frame, 14)? != ETHERTYPE_IPV4 {
+                    return None;
+                }
+
+                frame.get(16..)?
+            }
+            _ => frame,
+        };
+
+        udp(ip)
+    }
+
+    fn u32_at(&self, bytes: &[u8], offset: usize) -> u32 {
+        let bytes = [bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]];
+
+        match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        }
+    }
+}
+
+fn udp(ip: &[u8]) -> Option<Datagram> {
+    let version = ip.first()? >> 4;
+    let header_len = usize::from(ip.first()? & 0x0f) * 4;
+
+    if version != 4 || ip.get(9) != Some(&IPPROTO_UDP) {
+        return None;
+    }
+
+    // only whole datagrams, bark packets fit in one
+    let fragment = u16_be(ip, 6)?;
+    if fragment & 0x3fff != 0 {
+        return None;
+    }
+
+    let addrs = ip.get(12..20)?;
+    let source_ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+    let dest_ip = Ipv4Addr::new(addrs[4], addrs[5], addrs[6], addrs[7]);
+
+    let udp = ip.get(header_len..)?;
+    let source_port = u16_be(udp, 0)?;
+    let dest_port = u16_be(udp, 2)?;
+    let len = usize::from(u16_be(udp, 4)?);
+
+    Some(Datagram {
+        source: SocketAddrV4::new(source_ip, source_port),
+        dest: SocketAddrV4::new(dest_ip, dest_port),
+        payload: udp.get(8..len)?.to_vec(),
+    })
+}
+
+fn u16_be(bytes: &[u8], offset: usize) -> Option<u16> {
+    let bytes = bytes.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
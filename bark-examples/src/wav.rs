@@ -0,0 +1,68 @@
+//! Writing stereo float WAV files at the stream sample rate
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use bark_core::audio::FrameF32;
+use bark_protocol::{CHANNELS, SAMPLE_RATE};
+
+const HEADER_LENGTH: u32 = 44;
+const BYTES_PER_FRAME: u32 = 8;
+
+pub struct WavWriter {
+    file: BufWriter<File>,
+    frames: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path) -> Result<Self, io::Error> {
+        let mut writer = WavWriter {
+            file: BufWriter::new(File::create(path)?),
+            frames: 0,
+        };
+
+        // lengths are filled in once we know them, in finish
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    pub fn write(&mut self, frames: &[FrameF32]) -> Result<(), io::Error> {
+        for frame in frames {
+            self.file.write_all(&frame.0.to_le_bytes())?;
+            self.file.write_all(&frame.1.to_le_bytes())?;
+        }
+
+        self.frames += frames.len() as u32;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), io::Error> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.file.flush()
+    }
+
+    fn write_header(&mut self) -> Result<(), io::Error> {
+        let data_len = self.frames * BYTES_PER_FRAME;
+        let byte_rate = SAMPLE_RATE.0 * BYTES_PER_FRAME;
+
+        let file = &mut self.file;
+        file.write_all(b"RIFF")?;
+        file.write_all(&(HEADER_LENGTH - 8 + data_len).to_le_bytes())?;
+        file.write_all(b"WAVE")?;
+
+        file.write_all(b"fmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        // WAVE_FORMAT_IEEE_FLOAT
+        file.write_all(&3u16.to_le_bytes())?;
+        file.write_all(&CHANNELS.0.to_le_bytes())?;
+        file.write_all(&SAMPLE_RATE.0.to_le_bytes())?;
+        file.write_all(&byte_rate.to_le_bytes())?;
+        file.write_all(&(BYTES_PER_FRAME as u16).to_le_bytes())?;
+        file.write_all(&32u16.to_le_bytes())?;
+
+        file.write_all(b"data")?;
+        file.write_all(&data_len.to_le_bytes())
+    }
+}
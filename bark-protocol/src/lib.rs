@@ -2,6 +2,7 @@
 
 use derive_more::Into;
 
+pub mod auth;
 pub mod buffer;
 pub mod packet;
 pub mod time;
@@ -12,6 +13,16 @@ pub const CHANNELS: ChannelCount = ChannelCount(2);
 // pub const FRAMES_PER_PACKET: usize = 120; // 2.5ms at 48khz, compatible with opus
 pub const FRAMES_PER_PACKET: usize = 48;
 pub const SAMPLES_PER_PACKET: usize = CHANNELS.0 as usize * FRAMES_PER_PACKET;
+// upper bound on per-stream packet size, 20ms at 48khz. every packet
+// buffer is sized for this, so memory constrained builds can opt into a
+// 1.25ms bound instead, at the cost of not receiving streams with packets
+// larger than that, which includes every opus stream. receivers advertise
+// their bound so sources can fit their packets to it
+#[cfg(not(feature = "small-packets"))]
+pub const MAX_FRAMES_PER_PACKET: usize = 960;
+#[cfg(feature = "small-packets")]
+pub const MAX_FRAMES_PER_PACKET: usize = 60;
+pub const MAX_SAMPLES_PER_PACKET: usize = CHANNELS.0 as usize * MAX_FRAMES_PER_PACKET;
 
 #[derive(Copy, Clone, Debug, Into)]
 #[into(u64, u128, i64, f64)]
@@ -1,12 +1,16 @@
-use core::mem::size_of;
+use core::mem::{offset_of, size_of};
+use core::net::{Ipv4Addr, SocketAddrV4};
+use core::time::Duration;
 
 use bytemuck::Zeroable;
 
-use crate::SAMPLES_PER_PACKET;
+use crate::MAX_SAMPLES_PER_PACKET;
+use crate::auth::{self, MAC_LENGTH};
 use crate::buffer::{AllocError, PacketBuffer};
 use crate::types::stats::node::NodeStats;
 use crate::types::stats::receiver::ReceiverStats;
-use crate::types::{self, Magic, SessionId, StatsReplyFlags, AudioPacketHeader};
+use crate::types::stats::source::SourceStats;
+use crate::types::{self, Magic, SessionId, TimestampMicros, StatsReplyFlags, AudioPacketHeader, DumpReplyFlags, OutputReplyFlags, ReplayReplyFlags, VolumeFlags, ZoneFlags, DspFlags, SyncReplyFlags};
 
 pub const MAX_PACKET_SIZE: usize =
     size_of::<types::PacketHeader>() +
@@ -23,6 +27,7 @@ impl Packet {
 
         let mut packet = Packet(PacketBuffer::allocate(packet_len)?);
         packet.header_mut().magic = magic;
+        packet.header_mut().set_version(types::PROTOCOL_VERSION);
         Ok(packet)
     }
 
@@ -40,16 +45,52 @@ impl Packet {
     }
 
     pub fn parse(self) -> Option<PacketKind> {
+        // a layout we don't know
+        if self.header().version() > types::PROTOCOL_VERSION {
+            return None;
+        }
+
         match self.header().magic {
             Magic::AUDIO => Audio::parse(self).map(PacketKind::Audio),
             Magic::STATS_REQ => StatsRequest::parse(self).map(PacketKind::StatsRequest),
             Magic::STATS_REPLY => StatsReply::parse(self).map(PacketKind::StatsReply),
             Magic::PING => Some(PacketKind::Ping(Ping(self))),
             Magic::PONG => Some(PacketKind::Pong(Pong(self))),
+            Magic::DUMP_REQ => DumpRequest::parse(self).map(PacketKind::DumpRequest),
+            Magic::DUMP_REPLY => Some(PacketKind::DumpReply(DumpReply(self))),
+            Magic::OUTPUT_REQ => OutputRequest::parse(self).map(PacketKind::OutputRequest),
+            Magic::OUTPUT_REPLY => Some(PacketKind::OutputReply(OutputReply(self))),
+            Magic::REPLAY_REQ => ReplayRequest::parse(self).map(PacketKind::ReplayRequest),
+            Magic::REPLAY_REPLY => Some(PacketKind::ReplayReply(ReplayReply(self))),
+            Magic::VOLUME => VolumeRequest::parse(self).map(PacketKind::VolumeRequest),
+            Magic::ZONE => ZoneRequest::parse(self).map(PacketKind::ZoneRequest),
+            Magic::TAKEOVER => Takeover::parse(self).map(PacketKind::Takeover),
+            Magic::IDENTIFY => Identify::parse(self).map(PacketKind::Identify),
+            Magic::SIGNED => Signed::parse(self).map(PacketKind::Signed),
+            Magic::DSP => DspRequest::parse(self).map(PacketKind::DspRequest),
+            Magic::STREAM_END => StreamEnd::parse(self).map(PacketKind::StreamEnd),
+            Magic::METADATA => Metadata::parse(self).map(PacketKind::Metadata),
+            Magic::RECEIVER_REPORT => ReceiverReport::parse(self).map(PacketKind::ReceiverReport),
+            Magic::SYNC_REQ => SyncRequest::parse(self).map(PacketKind::SyncRequest),
+            Magic::SYNC_REPLY => SyncReply::parse(self).map(PacketKind::SyncReply),
             _ => None,
         }
     }
 
+    /// Whether this packet changes a node's state, or has a source play
+    /// to every receiver, and so must be signed when nodes require it
+    pub fn is_control(&self) -> bool {
+        matches!(self.header().magic,
+            Magic::DUMP_REQ |
+            Magic::OUTPUT_REQ |
+            Magic::REPLAY_REQ |
+            Magic::VOLUME |
+            Magic::ZONE |
+            Magic::TAKEOVER |
+            Magic::IDENTIFY |
+            Magic::DSP)
+    }
+
     pub fn header(&self) -> &types::PacketHeader {
         let header_size = size_of::<types::PacketHeader>();
         let header_bytes = &self.0.as_bytes()[0..header_size];
@@ -85,6 +126,23 @@ pub enum PacketKind {
     StatsReply(StatsReply),
     Ping(Ping),
     Pong(Pong),
+    DumpRequest(DumpRequest),
+    DumpReply(DumpReply),
+    OutputRequest(OutputRequest),
+    OutputReply(OutputReply),
+    ReplayRequest(ReplayRequest),
+    ReplayReply(ReplayReply),
+    VolumeRequest(VolumeRequest),
+    ZoneRequest(ZoneRequest),
+    Takeover(Takeover),
+    Identify(Identify),
+    Signed(Signed),
+    DspRequest(DspRequest),
+    StreamEnd(StreamEnd),
+    Metadata(Metadata),
+    ReceiverReport(ReceiverReport),
+    SyncRequest(SyncRequest),
+    SyncReply(SyncReply),
 }
 
 #[derive(Debug)]
@@ -95,7 +153,7 @@ impl Audio {
         size_of::<types::AudioPacketHeader>();
 
     pub const MAX_BUFFER_LENGTH: usize =
-        size_of::<[f32; SAMPLES_PER_PACKET]>();
+        size_of::<[f32; MAX_SAMPLES_PER_PACKET]>();
 
     pub fn new(header: &AudioPacketHeader, data: &[u8]) -> Result<Audio, AllocError> {
         let length = Self::HEADER_LENGTH + data.len();
@@ -106,17 +164,38 @@ impl Audio {
     }
 
     pub fn parse(packet: Packet) -> Option<Self> {
-        if packet.len() <= Self::HEADER_LENGTH {
+        if packet.header().flags() != 0 {
             return None;
         }
 
-        if packet.header().flags != 0 {
+        if packet.header().version() == 0 {
+            return Self::parse_v0(packet);
+        }
+
+        if packet.len() <= Self::HEADER_LENGTH {
             return None;
         }
 
         Some(Audio(packet))
     }
 
+    /// Audio from a node predating versions, whose header ends before the
+    /// start time. Copied into the current layout, with no start time
+    fn parse_v0(packet: Packet) -> Option<Self> {
+        let header_length = offset_of!(AudioPacketHeader, start_pts);
+
+        if packet.len() <= header_length {
+            return None;
+        }
+
+        let (header_bytes, data) = packet.as_bytes().split_at(header_length);
+
+        let mut header = AudioPacketHeader::zeroed();
+        bytemuck::bytes_of_mut(&mut header)[0..header_length].copy_from_slice(header_bytes);
+
+        Audio::new(&header, data).ok()
+    }
+
     pub fn as_packet(&self) -> &Packet {
         &self.0
     }
@@ -159,7 +238,7 @@ impl StatsRequest {
             return None;
         }
 
-        if packet.header().flags != 0 {
+        if packet.header().flags() != 0 {
             return None;
         }
 
@@ -179,7 +258,7 @@ impl StatsReply {
 
     fn new(flags: StatsReplyFlags, data: types::StatsReplyPacket) -> Result<Self, AllocError> {
         let mut packet = Packet::allocate(Magic::STATS_REPLY, Self::LENGTH)?;
-        packet.header_mut().flags = bytemuck::cast(flags);
+        packet.header_mut().set_flags(bytemuck::cast(flags));
 
         let mut reply = StatsReply(packet);
         *reply.data_mut() = data;
@@ -187,28 +266,50 @@ impl StatsReply {
         Ok(reply)
     }
 
-    pub fn source(sid: SessionId, node: NodeStats) -> Result<Self, AllocError> {
+    pub fn source(sid: SessionId, source: SourceStats, node: NodeStats) -> Result<Self, AllocError> {
         let receiver = ReceiverStats::zeroed();
 
         Self::new(
             StatsReplyFlags::IS_STREAM,
-            types::StatsReplyPacket { sid, receiver, node },
+            types::StatsReplyPacket { sid, receiver, source, node },
         )
     }
 
     pub fn receiver(sid: SessionId, receiver: ReceiverStats, node: NodeStats) -> Result<Self, AllocError> {
+        let source = SourceStats::zeroed();
+
         Self::new(
             StatsReplyFlags::IS_RECEIVER,
-            types::StatsReplyPacket { sid, receiver, node },
+            types::StatsReplyPacket { sid, receiver, source, node },
         )
     }
 
+    /// Length of stats replies from nodes predating protocol versions,
+    /// before source stats and the later receiver and node stats
+    const V0_LENGTH: usize = size_of::<SessionId>() + ReceiverStats::V0_LENGTH + NodeStats::V0_LENGTH;
+
     pub fn parse(packet: Packet) -> Option<Self> {
-        if packet.len() != Self::LENGTH {
-            return None;
+        match packet.len() {
+            Self::LENGTH => Some(StatsReply(packet)),
+            Self::V0_LENGTH => Self::parse_v0(packet),
+            _ => None,
         }
+    }
+
+    /// Stats reply in the layout of nodes predating protocol versions,
+    /// copied into the current layout
+    fn parse_v0(packet: Packet) -> Option<Self> {
+        let (sid, rest) = packet.as_bytes().split_at(size_of::<SessionId>());
+        let (receiver, node) = rest.split_at(ReceiverStats::V0_LENGTH);
 
-        Some(StatsReply(packet))
+        let data = types::StatsReplyPacket {
+            sid: bytemuck::pod_read_unaligned(sid),
+            receiver: ReceiverStats::from_v0(receiver.try_into().ok()?),
+            source: SourceStats::zeroed(),
+            node: NodeStats::from_v0(node.try_into().ok()?),
+        };
+
+        Self::new(bytemuck::cast(packet.header().flags()), data).ok()
     }
 
     pub fn as_packet(&self) -> &Packet {
@@ -216,7 +317,7 @@ impl StatsReply {
     }
 
     pub fn flags(&self) -> types::StatsReplyFlags {
-        bytemuck::cast(self.0.header().flags)
+        bytemuck::cast(self.0.header().flags())
     }
 
     pub fn data(&self) -> &types::StatsReplyPacket {
@@ -232,11 +333,18 @@ impl StatsReply {
 pub struct Ping(Packet);
 
 impl Ping {
-    pub fn new() -> Result<Self, AllocError> {
-        let packet = Packet::allocate(Magic::PING, 0)?;
+    /// Ping carrying `seq`, which replies echo back
+    pub fn new(seq: u64) -> Result<Self, AllocError> {
+        let mut packet = Packet::allocate(Magic::PING, size_of::<u64>())?;
+        packet.as_bytes_mut().copy_from_slice(&seq.to_le_bytes());
         Ok(Ping(packet))
     }
 
+    /// None from older nodes, which send pings without one
+    pub fn seq(&self) -> Option<u64> {
+        echo_seq(&self.0)
+    }
+
     pub fn as_packet(&self) -> &Packet {
         &self.0
     }
@@ -246,12 +354,934 @@ impl Ping {
 pub struct Pong(Packet);
 
 impl Pong {
-    pub fn new() -> Result<Self, AllocError> {
-        let packet = Packet::allocate(Magic::PONG, 0)?;
+    /// Reply to `ping`, echoing back its seq if it has one
+    pub fn reply(ping: &Ping) -> Result<Self, AllocError> {
+        let seq = ping.as_packet().as_bytes();
+        let mut packet = Packet::allocate(Magic::PONG, seq.len())?;
+        packet.as_bytes_mut().copy_from_slice(seq);
         Ok(Pong(packet))
     }
 
+    /// Seq of the ping replied to, None from older nodes
+    pub fn seq(&self) -> Option<u64> {
+        echo_seq(&self.0)
+    }
+
+    pub fn as_packet(&self) -> &Packet {
+        &self.0
+    }
+}
+
+fn echo_seq(packet: &Packet) -> Option<u64> {
+    let seq = packet.as_bytes().try_into().ok()?;
+    Some(u64::from_le_bytes(seq))
+}
+
+#[derive(Debug)]
+pub struct DumpRequest(Packet);
+
+impl DumpRequest {
+    const LENGTH: usize = size_of::<types::DumpRequestPacket>();
+
+    pub fn new(duration: Duration) -> Result<Self, AllocError> {
+        let mut packet = DumpRequest(Packet::allocate(Magic::DUMP_REQ, Self::LENGTH)?);
+        let duration_ms = u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+        *packet.data_mut() = types::DumpRequestPacket { duration_ms };
+        Ok(packet)
+    }
+
+    pub fn parse(packet: Packet) -> Option<Self> {
+        if packet.len() != Self::LENGTH {
+            return None;
+        }
+
+        Some(DumpRequest(packet))
+    }
+
+    pub fn as_packet(&self) -> &Packet {
+        &self.0
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.data().duration_ms)
+    }
+
+    fn data(&self) -> &types::DumpRequestPacket {
+        bytemuck::from_bytes(self.0.as_bytes())
+    }
+
+    fn data_mut(&mut self) -> &mut types::DumpRequestPacket {
+        bytemuck::from_bytes_mut(self.0.as_bytes_mut())
+    }
+}
+
+#[derive(Debug)]
+pub struct DumpReply(Packet);
+
+impl DumpReply {
+    fn new(flags: DumpReplyFlags, message: &str) -> Result<Self, AllocError> {
+        let mut packet = Packet::allocate(Magic::DUMP_REPLY, message.len())?;
+        packet.header_mut().set_flags(bytemuck::cast(flags));
+        packet.as_bytes_mut().copy_from_slice(message.as_bytes());
+        Ok(DumpReply(packet))
+    }
+
+    /// Reply with the location the dump is being written to
+    pub fn location(path: &str) -> Result<Self, AllocError> {
+        Self::new(DumpReplyFlags::empty(), path)
+    }
+
+    pub fn error(message: &str) -> Result<Self, AllocError> {
+        Self::new(DumpReplyFlags::ERROR, message)
+    }
+
+    pub fn as_packet(&self) -> &Packet {
+        &self.0
+    }
+
+    pub fn flags(&self) -> DumpReplyFlags {
+        bytemuck::cast(self.0.header().flags())
+    }
+
+    pub fn message(&self) -> &str {
+        core::str::from_utf8(self.0.as_bytes()).unwrap_or_default()
+    }
+}
+
+#[derive(Debug)]
+pub struct OutputRequest(Packet);
+
+impl OutputRequest {
+    /// Request a receiver switch to the named output device, or its default
+    /// device if `None`
+    pub fn new(device: Option<&str>) -> Result<Self, AllocError> {
+        let device = device.unwrap_or_default();
+        let mut packet = Packet::allocate(Magic::OUTPUT_REQ, device.len())?;
+        packet.as_bytes_mut().copy_from_slice(device.as_bytes());
+        Ok(OutputRequest(packet))
+    }
+
+    pub fn parse(packet: Packet) -> Option<Self> {
+        core::str::from_utf8(packet.as_bytes()).ok()?;
+        Some(OutputRequest(packet))
+    }
+
+    pub fn as_packet(&self) -> &Packet {
+        &self.0
+    }
+
+    pub fn device(&self) -> Option<&str> {
+        let device = core::str::from_utf8(self.0.as_bytes()).unwrap_or_default();
+        if device.is_empty() { None } else { Some(device) }
+    }
+}
+
+#[derive(Debug)]
+pub struct OutputReply(Packet);
+
+impl OutputReply {
+    fn new(flags: OutputReplyFlags, message: &str) -> Result<Self, AllocError> {
+        let mut packet = Packet::allocate(Magic::OUTPUT_REPLY, message.len())?;
+        packet.header_mut().set_flags(bytemuck::cast(flags));
+        packet.as_bytes_mut().copy_from_slice(message.as_bytes());
+        Ok(OutputReply(packet))
+    }
+
+    /// Reply with the name of the device now in use
+    pub fn device(device: &str) -> Result<Self, AllocError> {
+        Self::new(OutputReplyFlags::empty(), device)
+    }
+
+    pub fn error(message: &str) -> Result<Self, AllocError> {
+        Self::new(OutputReplyFlags::ERROR, message)
+    }
+
+    pub fn as_packet(&self) -> &Packet {
+        &self.0
+    }
+
+    pub fn flags(&self) -> OutputReplyFlags {
+        bytemuck::cast(self.0.header().flags())
+    }
+
+    pub fn message(&self) -> &str {
+        core::str::from_utf8(self.0.as_bytes()).unwrap_or_default()
+    }
+}
+
+#[derive(Debug)]
+pub struct ReplayRequest(Packet);
+
+impl ReplayRequest {
+    const LENGTH: usize = size_of::<types::ReplayRequestPacket>();
+    const TARGET_LENGTH: usize = size_of::<types::ReplayTarget>();
+
+    pub fn new(duration: Duration, targets: &[SocketAddrV4]) -> Result<Self, AllocError> {
+        let length = Self::LENGTH + targets.len() * Self::TARGET_LENGTH;
+        let mut packet = ReplayRequest(Packet::allocate(Magic::REPLAY_REQ, length)?);
+
+        let duration_ms = u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+        *packet.data_mut() = types::ReplayRequestPacket { duration_ms };
+
+        let target_bytes = &mut packet.0.as_bytes_mut()[Self::LENGTH..];
+        let target_data = bytemuck::cast_slice_mut::<u8, types::ReplayTarget>(target_bytes);
+
+        for (data, target) in target_data.iter_mut().zip(targets) {
+            *data = types::ReplayTarget {
+                addr: target.ip().octets(),
+                port: target.port().to_be_bytes(),
+            };
+        }
+
+        Ok(packet)
+    }
+
+    pub fn parse(packet: Packet) -> Option<Self> {
+        let target_bytes = packet.as_bytes().get(Self::LENGTH..)?;
+
+        if !target_bytes.chunks_exact(Self::TARGET_LENGTH).remainder().is_empty() {
+            return None;
+        }
+
+        Some(ReplayRequest(packet))
+    }
+
+    pub fn as_packet(&self) -> &Packet {
+        &self.0
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.data().duration_ms)
+    }
+
+    /// Receivers to send the replay to, empty for all receivers
+    pub fn targets(&self) -> impl Iterator<Item = SocketAddrV4> + '_ {
+        let target_bytes = &self.0.as_bytes()[Self::LENGTH..];
+
+        bytemuck::cast_slice::<u8, types::ReplayTarget>(target_bytes)
+            .iter()
+            .map(|target| SocketAddrV4::new(
+                Ipv4Addr::from(target.addr),
+                u16::from_be_bytes(target.port),
+            ))
+    }
+
+    fn data(&self) -> &types::ReplayRequestPacket {
+        bytemuck::from_bytes(&self.0.as_bytes()[0..Self::LENGTH])
+    }
+
+    fn data_mut(&mut self) -> &mut types::ReplayRequestPacket {
+        bytemuck::from_bytes_mut(&mut self.0.as_bytes_mut()[0..Self::LENGTH])
+    }
+}
+
+#[derive(Debug)]
+pub struct ReplayReply(Packet);
+
+impl ReplayReply {
+    fn new(flags: ReplayReplyFlags, message: &str) -> Result<Self, AllocError> {
+        let mut packet = Packet::allocate(Magic::REPLAY_REPLY, message.len())?;
+        packet.header_mut().set_flags(bytemuck::cast(flags));
+        packet.as_bytes_mut().copy_from_slice(message.as_bytes());
+        Ok(ReplayReply(packet))
+    }
+
+    /// Reply describing the replay that has started
+    pub fn started(message: &str) -> Result<Self, AllocError> {
+        Self::new(ReplayReplyFlags::empty(), message)
+    }
+
+    pub fn error(message: &str) -> Result<Self, AllocError> {
+        Self::new(ReplayReplyFlags::ERROR, message)
+    }
+
+    pub fn as_packet(&self) -> &Packet {
+        &self.0
+    }
+
+    pub fn flags(&self) -> ReplayReplyFlags {
+        bytemuck::cast(self.0.header().flags())
+    }
+
+    pub fn message(&self) -> &str {
+        core::str::from_utf8(self.0.as_bytes()).unwrap_or_default()
+    }
+}
+
+#[derive(Debug)]
+pub struct VolumeRequest(Packet);
+
+impl VolumeRequest {
+    const LENGTH: usize = size_of::<types::VolumePacket>();
+
+    /// Request a receiver change its volume and/or mute state, leaving
+    /// whichever is `None` unchanged
+    pub fn new(volume: Option<f32>, mute: Option<bool>) -> Result<Self, AllocError> {
+        let mut packet = VolumeRequest(Packet::allocate(Magic::VOLUME, Self::LENGTH)?);
+
+        let mut flags = VolumeFlags::empty();
+
+        if let Some(volume) = volume {
+            flags.insert(VolumeFlags::SET_VOLUME);
+            packet.data_mut().volume = volume;
+        }
+
+        match mute {
+            Some(true) => flags.insert(VolumeFlags::MUTE),
+            Some(false) => flags.insert(VolumeFlags::UNMUTE),
+            None => {}
+        }
+
+        packet.0.header_mut().set_flags(bytemuck::cast(flags));
+        Ok(packet)
+    }
+
+    /// Request a receiver change its maximum volume and/or lock it against
+    /// further changes. Receivers ignore this once locked.
+    pub fn limit(max_volume: Option<f32>, lock: bool) -> Result<Self, AllocError> {
+        let mut packet = VolumeRequest(Packet::allocate(Magic::VOLUME, Self::LENGTH)?);
+
+        let mut flags = VolumeFlags::empty();
+
+        if let Some(max_volume) = max_volume {
+            flags.insert(VolumeFlags::SET_MAX_VOLUME);
+            packet.data_mut().max_volume = max_volume;
+        }
+
+        flags.set(VolumeFlags::LOCK, lock);
+
+        packet.0.header_mut().set_flags(bytemuck::cast(flags));
+        Ok(packet)
+    }
+
+    pub fn parse(packet: Packet) -> Option<Self> {
+        if packet.len() != Self::LENGTH {
+            return None;
+        }
+
+        Some(VolumeRequest(packet))
+    }
+
+    pub fn as_packet(&self) -> &Packet {
+        &self.0
+    }
+
+    pub fn flags(&self) -> VolumeFlags {
+        bytemuck::cast(self.0.header().flags())
+    }
+
+    pub fn volume(&self) -> Option<f32> {
+        if self.flags().contains(VolumeFlags::SET_VOLUME) {
+            Some(self.data().volume)
+        } else {
+            None
+        }
+    }
+
+    pub fn max_volume(&self) -> Option<f32> {
+        if self.flags().contains(VolumeFlags::SET_MAX_VOLUME) {
+            Some(self.data().max_volume)
+        } else {
+            None
+        }
+    }
+
+    pub fn lock(&self) -> bool {
+        self.flags().contains(VolumeFlags::LOCK)
+    }
+
+    pub fn mute(&self) -> Option<bool> {
+        let flags = self.flags();
+
+        if flags.contains(VolumeFlags::MUTE) {
+            Some(true)
+        } else if flags.contains(VolumeFlags::UNMUTE) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    fn data(&self) -> &types::VolumePacket {
+        bytemuck::from_bytes(self.0.as_bytes())
+    }
+
+    fn data_mut(&mut self) -> &mut types::VolumePacket {
+        bytemuck::from_bytes_mut(self.0.as_bytes_mut())
+    }
+}
+
+#[derive(Debug)]
+pub struct DspRequest(Packet);
+
+impl DspRequest {
+    const LENGTH: usize = size_of::<types::DspPacket>();
+
+    /// Request a receiver change its output processing, leaving whatever
+    /// is `None` unchanged. `protection` is a profile code as described on
+    /// `DspPacket`
+    pub fn new(limiter: Option<bool>, protection: Option<u32>, bypass: Option<bool>) -> Result<Self, AllocError> {
+        let mut packet = DspRequest(Packet::allocate(Magic::DSP, Self::LENGTH)?);
+
+        let mut flags = DspFlags::empty();
+
+        match limiter {
+            Some(true) => flags.insert(DspFlags::LIMITER_ON),
+            Some(false) => flags.insert(DspFlags::LIMITER_OFF),
+            None => {}
+        }
+
+        if let Some(protection) = protection {
+            flags.insert(DspFlags::SET_PROTECTION);
+            packet.data_mut().protection = protection;
+        }
+
+        match bypass {
+            Some(true) => flags.insert(DspFlags::BYPASS),
+            Some(false) => flags.insert(DspFlags::UNBYPASS),
+            None => {}
+        }
+
+        packet.0.header_mut().set_flags(bytemuck::cast(flags));
+        Ok(packet)
+    }
+
+    pub fn parse(packet: Packet) -> Option<Self> {
+        if packet.len() != Self::LENGTH {
+            return None;
+        }
+
+        Some(DspRequest(packet))
+    }
+
     pub fn as_packet(&self) -> &Packet {
         &self.0
     }
+
+    pub fn flags(&self) -> DspFlags {
+        bytemuck::cast(self.0.header().flags())
+    }
+
+    pub fn limiter(&self) -> Option<bool> {
+        let flags = self.flags();
+
+        if flags.contains(DspFlags::LIMITER_ON) {
+            Some(true)
+        } else if flags.contains(DspFlags::LIMITER_OFF) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    pub fn protection(&self) -> Option<u32> {
+        if self.flags().contains(DspFlags::SET_PROTECTION) {
+            Some(self.data().protection)
+        } else {
+            None
+        }
+    }
+
+    pub fn bypass(&self) -> Option<bool> {
+        let flags = self.flags();
+
+        if flags.contains(DspFlags::BYPASS) {
+            Some(true)
+        } else if flags.contains(DspFlags::UNBYPASS) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    fn data(&self) -> &types::DspPacket {
+        bytemuck::from_bytes(self.0.as_bytes())
+    }
+
+    fn data_mut(&mut self) -> &mut types::DspPacket {
+        bytemuck::from_bytes_mut(self.0.as_bytes_mut())
+    }
+}
+
+#[derive(Debug)]
+pub struct ZoneRequest(Packet);
+
+impl ZoneRequest {
+    const LENGTH: usize = size_of::<types::ZonePacket>();
+
+    /// Zone names longer than `ZONE_NAME_LENGTH` bytes are truncated
+    pub fn new(flags: ZoneFlags, zone: Option<&str>) -> Result<Self, AllocError> {
+        let zone = types::to_fixed_str(zone.unwrap_or_default());
+
+        let mut packet = ZoneRequest(Packet::allocate(Magic::ZONE, Self::LENGTH)?);
+        packet.0.header_mut().set_flags(bytemuck::cast(flags));
+        *packet.data_mut() = types::ZonePacket { zone };
+        Ok(packet)
+    }
+
+    pub fn parse(packet: Packet) -> Option<Self> {
+        if packet.len() != Self::LENGTH {
+            return None;
+        }
+
+        Some(ZoneRequest(packet))
+    }
+
+    pub fn as_packet(&self) -> &Packet {
+        &self.0
+    }
+
+    pub fn flags(&self) -> ZoneFlags {
+        bytemuck::cast(self.0.header().flags())
+    }
+
+    /// Zone name, or `None` for all zones
+    pub fn zone(&self) -> Option<&str> {
+        types::from_fixed_str(&self.data().zone)
+    }
+
+    fn data(&self) -> &types::ZonePacket {
+        bytemuck::from_bytes(self.0.as_bytes())
+    }
+
+    fn data_mut(&mut self) -> &mut types::ZonePacket {
+        bytemuck::from_bytes_mut(self.0.as_bytes_mut())
+    }
+}
+
+#[derive(Debug)]
+pub struct Takeover(Packet);
+
+impl Takeover {
+    const LENGTH: usize = size_of::<types::TakeoverPacket>();
+
+    pub fn new(sid: SessionId) -> Result<Self, AllocError> {
+        let mut packet = Takeover(Packet::allocate(Magic::TAKEOVER, Self::LENGTH)?);
+        *packet.data_mut() = types::TakeoverPacket { sid };
+        Ok(packet)
+    }
+
+    pub fn parse(packet: Packet) -> Option<Self> {
+        if packet.len() != Self::LENGTH {
+            return None;
+        }
+
+        Some(Takeover(packet))
+    }
+
+    pub fn as_packet(&self) -> &Packet {
+        &self.0
+    }
+
+    pub fn sid(&self) -> SessionId {
+        self.data().sid
+    }
+
+    fn data(&self) -> &types::TakeoverPacket {
+        bytemuck::from_bytes(self.0.as_bytes())
+    }
+
+    fn data_mut(&mut self) -> &mut types::TakeoverPacket {
+        bytemuck::from_bytes_mut(self.0.as_bytes_mut())
+    }
+}
+
+/// Sent by a source as it stops, so that receivers can wind down its session
+/// straight away rather than waiting for it to time out
+#[derive(Debug)]
+pub struct StreamEnd(Packet);
+
+impl StreamEnd {
+    const LENGTH: usize = size_of::<types::StreamEndPacket>();
+
+    pub fn new(sid: SessionId) -> Result<Self, AllocError> {
+        let mut packet = StreamEnd(Packet::allocate(Magic::STREAM_END, Self::LENGTH)?);
+        *packet.data_mut() = types::StreamEndPacket { sid };
+        Ok(packet)
+    }
+
+    pub fn parse(packet: Packet) -> Option<Self> {
+        if packet.len() != Self::LENGTH {
+            return None;
+        }
+
+        Some(StreamEnd(packet))
+    }
+
+    pub fn as_packet(&self) -> &Packet {
+        &self.0
+    }
+
+    pub fn sid(&self) -> SessionId {
+        self.data().sid
+    }
+
+    fn data(&self) -> &types::StreamEndPacket {
+        bytemuck::from_bytes(self.0.as_bytes())
+    }
+
+    fn data_mut(&mut self) -> &mut types::StreamEndPacket {
+        bytemuck::from_bytes_mut(self.0.as_bytes_mut())
+    }
+}
+
+/// Sent by receivers to the source of each stream they play every so often,
+/// reporting how well its packets are arriving, much like an RTCP receiver
+/// report
+#[derive(Debug)]
+pub struct ReceiverReport(Packet);
+
+impl ReceiverReport {
+    const LENGTH: usize = size_of::<types::ReceiverReportPacket>();
+
+    pub fn new(sid: SessionId, highest_seq: u64, received: u64, lost: u64, jitter: Duration) -> Result<Self, AllocError> {
+        let mut packet = ReceiverReport(Packet::allocate(Magic::RECEIVER_REPORT, Self::LENGTH)?);
+
+        *packet.data_mut() = types::ReceiverReportPacket {
+            sid,
+            highest_seq,
+            received,
+            lost,
+            jitter_us: u64::try_from(jitter.as_micros()).unwrap_or(u64::MAX),
+        };
+
+        Ok(packet)
+    }
+
+    pub fn parse(packet: Packet) -> Option<Self> {
+        if packet.len() != Self::LENGTH {
+            return None;
+        }
+
+        Some(ReceiverReport(packet))
+    }
+
+    pub fn as_packet(&self) -> &Packet {
+        &self.0
+    }
+
+    pub fn sid(&self) -> SessionId {
+        self.data().sid
+    }
+
+    pub fn highest_seq(&self) -> u64 {
+        self.data().highest_seq
+    }
+
+    /// Packets received, not counting duplicates
+    pub fn received(&self) -> u64 {
+        self.data().received
+    }
+
+    /// Packets never received, up to the highest seq
+    pub fn lost(&self) -> u64 {
+        self.data().lost
+    }
+
+    /// Interarrival jitter, as in RTP
+    pub fn jitter(&self) -> Duration {
+        Duration::from_micros(self.data().jitter_us)
+    }
+
+    fn data(&self) -> &types::ReceiverReportPacket {
+        bytemuck::from_bytes(self.0.as_bytes())
+    }
+
+    fn data_mut(&mut self) -> &mut types::ReceiverReportPacket {
+        bytemuck::from_bytes_mut(self.0.as_bytes_mut())
+    }
+}
+
+/// Asks receivers to report when they play a packet, so that `bark
+/// sync-check` can compare how closely they play together
+#[derive(Debug)]
+pub struct SyncRequest(Packet);
+
+impl SyncRequest {
+    const LENGTH: usize = size_of::<types::SyncRequestPacket>();
+
+    pub fn new(sid: SessionId, seq: u64, sent: TimestampMicros) -> Result<Self, AllocError> {
+        let mut packet = SyncRequest(Packet::allocate(Magic::SYNC_REQ, Self::LENGTH)?);
+        *packet.data_mut() = types::SyncRequestPacket { sid, seq, sent };
+        Ok(packet)
+    }
+
+    pub fn parse(packet: Packet) -> Option<Self> {
+        if packet.len() != Self::LENGTH {
+            return None;
+        }
+
+        Some(SyncRequest(packet))
+    }
+
+    pub fn as_packet(&self) -> &Packet {
+        &self.0
+    }
+
+    pub fn sid(&self) -> SessionId {
+        self.data().sid
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.data().seq
+    }
+
+    /// Requester's time when the request was sent
+    pub fn sent(&self) -> TimestampMicros {
+        self.data().sent
+    }
+
+    fn data(&self) -> &types::SyncRequestPacket {
+        bytemuck::from_bytes(self.0.as_bytes())
+    }
+
+    fn data_mut(&mut self) -> &mut types::SyncRequestPacket {
+        bytemuck::from_bytes_mut(self.0.as_bytes_mut())
+    }
+}
+
+#[derive(Debug)]
+pub struct SyncReply(Packet);
+
+impl SyncReply {
+    const LENGTH: usize = size_of::<types::SyncReplyPacket>();
+
+    fn new(flags: SyncReplyFlags, data: types::SyncReplyPacket) -> Result<Self, AllocError> {
+        let mut packet = SyncReply(Packet::allocate(Magic::SYNC_REPLY, Self::LENGTH)?);
+        packet.0.header_mut().set_flags(bytemuck::cast(flags));
+        *packet.data_mut() = data;
+        Ok(packet)
+    }
+
+    /// Reply to `request`, which arrived at `received`, with the time its
+    /// packet was heard
+    pub fn heard(request: &SyncRequest, received: TimestampMicros, played: TimestampMicros, replied: TimestampMicros) -> Result<Self, AllocError> {
+        Self::new(SyncReplyFlags::empty(), types::SyncReplyPacket {
+            sid: request.sid(),
+            seq: request.seq(),
+            sent: request.sent(),
+            received,
+            replied,
+            played,
+        })
+    }
+
+    /// Reply to `request` saying its packet was never played
+    pub fn missed(request: &SyncRequest, received: TimestampMicros, replied: TimestampMicros) -> Result<Self, AllocError> {
+        Self::new(SyncReplyFlags::MISSED, types::SyncReplyPacket {
+            sid: request.sid(),
+            seq: request.seq(),
+            sent: request.sent(),
+            received,
+            replied,
+            played: TimestampMicros(0),
+        })
+    }
+
+    pub fn parse(packet: Packet) -> Option<Self> {
+        if packet.len() != Self::LENGTH {
+            return None;
+        }
+
+        Some(SyncReply(packet))
+    }
+
+    pub fn as_packet(&self) -> &Packet {
+        &self.0
+    }
+
+    pub fn flags(&self) -> SyncReplyFlags {
+        bytemuck::cast(self.0.header().flags())
+    }
+
+    pub fn sid(&self) -> SessionId {
+        self.data().sid
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.data().seq
+    }
+
+    /// Requester's time when the request was sent
+    pub fn sent(&self) -> TimestampMicros {
+        self.data().sent
+    }
+
+    /// Receiver's time when the request arrived
+    pub fn received(&self) -> TimestampMicros {
+        self.data().received
+    }
+
+    /// Receiver's time when the reply was sent
+    pub fn replied(&self) -> TimestampMicros {
+        self.data().replied
+    }
+
+    /// Receiver's time when the packet was heard, None if it wasn't played
+    pub fn played(&self) -> Option<TimestampMicros> {
+        if self.flags().contains(SyncReplyFlags::MISSED) {
+            None
+        } else {
+            Some(self.data().played)
+        }
+    }
+
+    fn data(&self) -> &types::SyncReplyPacket {
+        bytemuck::from_bytes(self.0.as_bytes())
+    }
+
+    fn data_mut(&mut self) -> &mut types::SyncReplyPacket {
+        bytemuck::from_bytes_mut(self.0.as_bytes_mut())
+    }
+}
+
+/// What a source is playing, such as the current song of the player feeding
+/// it, sent periodically so that receivers and `bark stats` can show it
+#[derive(Debug)]
+pub struct Metadata(Packet);
+
+impl Metadata {
+    const LENGTH: usize = size_of::<types::MetadataPacket>();
+
+    /// Fields longer than `METADATA_FIELD_LENGTH` bytes are truncated
+    pub fn new(sid: SessionId, title: Option<&str>, artist: Option<&str>, album: Option<&str>) -> Result<Self, AllocError> {
+        let mut packet = Metadata(Packet::allocate(Magic::METADATA, Self::LENGTH)?);
+
+        *packet.data_mut() = types::MetadataPacket {
+            sid,
+            title: types::to_fixed_str(title.unwrap_or_default()),
+            artist: types::to_fixed_str(artist.unwrap_or_default()),
+            album: types::to_fixed_str(album.unwrap_or_default()),
+        };
+
+        Ok(packet)
+    }
+
+    pub fn parse(packet: Packet) -> Option<Self> {
+        if packet.len() != Self::LENGTH {
+            return None;
+        }
+
+        Some(Metadata(packet))
+    }
+
+    pub fn as_packet(&self) -> &Packet {
+        &self.0
+    }
+
+    pub fn sid(&self) -> SessionId {
+        self.data().sid
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        types::from_fixed_str(&self.data().title)
+    }
+
+    pub fn artist(&self) -> Option<&str> {
+        types::from_fixed_str(&self.data().artist)
+    }
+
+    pub fn album(&self) -> Option<&str> {
+        types::from_fixed_str(&self.data().album)
+    }
+
+    fn data(&self) -> &types::MetadataPacket {
+        bytemuck::from_bytes(self.0.as_bytes())
+    }
+
+    fn data_mut(&mut self) -> &mut types::MetadataPacket {
+        bytemuck::from_bytes_mut(self.0.as_bytes_mut())
+    }
+}
+
+#[derive(Debug)]
+pub struct Identify(Packet);
+
+impl Identify {
+    pub fn new() -> Result<Self, AllocError> {
+        Ok(Identify(Packet::allocate(Magic::IDENTIFY, 0)?))
+    }
+
+    pub fn parse(packet: Packet) -> Option<Self> {
+        if packet.len() != 0 {
+            return None;
+        }
+
+        Some(Identify(packet))
+    }
+
+    pub fn as_packet(&self) -> &Packet {
+        &self.0
+    }
+}
+
+/// Wraps another packet with a timestamp and a MAC over both, so that
+/// receivers can tell it came from someone holding the shared secret
+#[derive(Debug)]
+pub struct Signed(Packet);
+
+impl Signed {
+    const HEADER_LENGTH: usize = size_of::<types::SignedPacket>();
+
+    pub fn new(packet: &Packet, key: &[u8], timestamp: TimestampMicros) -> Result<Self, AllocError> {
+        let inner = packet.as_buffer().as_bytes();
+
+        let mut signed = Signed(Packet::allocate(Magic::SIGNED, Self::HEADER_LENGTH + inner.len())?);
+        signed.0.as_bytes_mut()[Self::HEADER_LENGTH..].copy_from_slice(inner);
+
+        *signed.header_mut() = types::SignedPacket {
+            timestamp,
+            mac: auth::hmac(key, &[bytemuck::bytes_of(&timestamp), inner]),
+        };
+
+        Ok(signed)
+    }
+
+    pub fn parse(packet: Packet) -> Option<Self> {
+        if packet.len() < Self::HEADER_LENGTH + size_of::<types::PacketHeader>() {
+            return None;
+        }
+
+        Some(Signed(packet))
+    }
+
+    pub fn as_packet(&self) -> &Packet {
+        &self.0
+    }
+
+    pub fn timestamp(&self) -> TimestampMicros {
+        self.header().timestamp
+    }
+
+    pub fn mac(&self) -> &[u8; MAC_LENGTH] {
+        &self.header().mac
+    }
+
+    /// Check the MAC against the shared secret
+    pub fn verify(&self, key: &[u8]) -> bool {
+        let expected = auth::hmac(key, &[bytemuck::bytes_of(&self.timestamp()), self.inner_bytes()]);
+        auth::verify(&expected, self.mac())
+    }
+
+    /// The wrapped packet. Only meaningful once verified
+    pub fn into_inner(self) -> Result<Option<Packet>, AllocError> {
+        let inner = self.inner_bytes();
+
+        let mut buffer = PacketBuffer::allocate(inner.len())?;
+        buffer.as_bytes_mut().copy_from_slice(inner);
+
+        Ok(Packet::from_buffer(buffer))
+    }
+
+    fn inner_bytes(&self) -> &[u8] {
+        &self.0.as_bytes()[Self::HEADER_LENGTH..]
+    }
+
+    fn header(&self) -> &types::SignedPacket {
+        bytemuck::from_bytes(&self.0.as_bytes()[..Self::HEADER_LENGTH])
+    }
+
+    fn header_mut(&mut self) -> &mut types::SignedPacket {
+        bytemuck::from_bytes_mut(&mut self.0.as_bytes_mut()[..Self::HEADER_LENGTH])
+    }
 }
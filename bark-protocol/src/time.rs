@@ -106,6 +106,10 @@ impl TimestampDelta {
         TimestampDelta(0)
     }
 
+    pub fn from_micros_lossy(micros: i64) -> TimestampDelta {
+        TimestampDelta(micros * i64::from(SAMPLE_RATE.0) / 1_000_000)
+    }
+
     pub fn abs(&self) -> SampleDuration {
         SampleDuration(u64::try_from(self.0.abs()).unwrap())
     }
@@ -1,10 +1,12 @@
+use core::ops::RangeInclusive;
 use core::time::Duration;
 
 use bytemuck::{Pod, Zeroable};
 
 pub mod stats;
 
-use crate::SAMPLES_PER_PACKET;
+use crate::time::{SampleDuration, Timestamp};
+use crate::{FRAMES_PER_PACKET, MAX_SAMPLES_PER_PACKET};
 
 #[derive(Debug, Clone, Copy, Zeroable, Pod, PartialEq, Eq)]
 #[repr(transparent)]
@@ -20,15 +22,69 @@ impl Magic {
     pub const STATS_REPLY: Magic = Magic::tag(0x03);
     pub const PING: Magic        = Magic::tag(0x04);
     pub const PONG: Magic        = Magic::tag(0x05);
+    pub const DUMP_REQ: Magic    = Magic::tag(0x06);
+    pub const DUMP_REPLY: Magic  = Magic::tag(0x07);
+    pub const OUTPUT_REQ: Magic  = Magic::tag(0x08);
+    pub const OUTPUT_REPLY: Magic = Magic::tag(0x09);
+    pub const REPLAY_REQ: Magic  = Magic::tag(0x0a);
+    pub const REPLAY_REPLY: Magic = Magic::tag(0x0b);
+    pub const VOLUME: Magic      = Magic::tag(0x0c);
+    pub const ZONE: Magic        = Magic::tag(0x0d);
+    pub const TAKEOVER: Magic    = Magic::tag(0x0e);
+    pub const IDENTIFY: Magic    = Magic::tag(0x0f);
+    pub const SIGNED: Magic      = Magic::tag(0x10);
+    pub const DSP: Magic         = Magic::tag(0x11);
+    pub const STREAM_END: Magic  = Magic::tag(0x12);
+    pub const METADATA: Magic    = Magic::tag(0x13);
+    pub const RECEIVER_REPORT: Magic = Magic::tag(0x14);
+    pub const SYNC_REQ: Magic    = Magic::tag(0x15);
+    pub const SYNC_REPLY: Magic  = Magic::tag(0x16);
 }
 
+/// Version of the packet layouts this build sends. Nodes drop packets of
+/// versions newer than their own, and parse older versions in the layout
+/// they were sent in:
+///
+/// 0. nodes predating versions, which leave the version bits zero. their
+///    audio headers are 40 bytes and stats replies 104 bytes
+/// 1. audio headers carry the session's start time, and stats replies
+///    carry source stats and more about receivers and nodes
+///
+/// Stats replies are told apart by length, as nodes built between the two
+/// sent the larger layout as version 0
+pub const PROTOCOL_VERSION: u8 = 1;
+
 #[derive(Debug, Clone, Copy, Zeroable, Pod)]
 #[repr(C)]
 pub struct PacketHeader {
     // magic and flags. there is a distinct magic value for each packet type,
     // and flags has a packet-dependent meaning.
     pub magic: Magic,
-    pub flags: u32,
+    // low 24 bits are flags, top 8 bits the protocol version. nodes
+    // predating the version left them zero, as version 0
+    flags: u32,
+}
+
+impl PacketHeader {
+    const VERSION_SHIFT: u32 = 24;
+    const FLAGS_MASK: u32 = (1 << Self::VERSION_SHIFT) - 1;
+
+    pub fn version(&self) -> u8 {
+        (self.flags >> Self::VERSION_SHIFT) as u8
+    }
+
+    pub fn set_version(&mut self, version: u8) {
+        self.flags = (u32::from(version) << Self::VERSION_SHIFT) | self.flags();
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flags & Self::FLAGS_MASK
+    }
+
+    pub fn set_flags(&mut self, flags: u32) {
+        debug_assert!(flags & !Self::FLAGS_MASK == 0, "packet flags overlap version");
+        self.flags = (self.flags & !Self::FLAGS_MASK) | (flags & Self::FLAGS_MASK);
+    }
 }
 
 /// our network Packet struct
@@ -57,10 +113,59 @@ pub struct AudioPacketHeader {
     pub format: AudioPacketFormat,
     pub priority: i8,
 
-    pub padding: [u8; 6],
+    // number of frames carried in each packet of this stream. zero means
+    // FRAMES_PER_PACKET, as sent by sources predating this field
+    pub packet_frames: u16,
+
+    // delay the source adds between capturing audio and presenting it, in
+    // milliseconds. zero means unreported
+    pub delay_ms: u16,
+
+    // minimum audio the source recommends receivers buffer, in milliseconds,
+    // to absorb jitter in its own pacing. zero means no recommendation
+    pub min_buffer_ms: u16,
+
+    // time every receiver begins playing the session together, announced
+    // far enough ahead of the first packets' pts that receivers can prefill
+    // their buffers before it. zero means start as soon as buffered. since
+    // protocol version 1, zero in audio from older nodes
+    pub start_pts: TimestampMicros,
 }
 
-#[derive(Debug, Clone, Copy, Zeroable, Pod, PartialEq, Eq)]
+impl AudioPacketHeader {
+    pub fn frames_per_packet(&self) -> usize {
+        match self.packet_frames {
+            0 => FRAMES_PER_PACKET,
+            frames => usize::from(frames),
+        }
+    }
+
+    pub fn packet_duration(&self) -> SampleDuration {
+        SampleDuration::from_frame_count(self.frames_per_packet())
+    }
+
+    /// Delay between capture and presentation, if the source reported it
+    pub fn delay(&self) -> Option<SampleDuration> {
+        match self.delay_ms {
+            0 => None,
+            ms => Some(SampleDuration::from_std_duration_lossy(Duration::from_millis(u64::from(ms)))),
+        }
+    }
+
+    pub fn min_buffer(&self) -> SampleDuration {
+        SampleDuration::from_std_duration_lossy(Duration::from_millis(u64::from(self.min_buffer_ms)))
+    }
+
+    /// Time all receivers start playing the session together, if announced
+    pub fn start(&self) -> Option<Timestamp> {
+        match self.start_pts {
+            TimestampMicros(0) => None,
+            pts => Some(Timestamp::from_micros_lossy(pts)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Zeroable, Pod, PartialEq, Eq, Hash)]
 #[repr(transparent)]
 pub struct AudioPacketFormat(u8);
 
@@ -68,15 +173,88 @@ impl AudioPacketFormat {
     pub const F32LE: Self = Self(1);
     pub const S16LE: Self = Self(2);
     pub const OPUS: Self = Self(3);
+    pub const S24LE: Self = Self(4);
+    pub const S32LE: Self = Self(5);
+
+    /// Format numbers bark never assigns, left for codecs registered by
+    /// other crates. They all fit in an `AudioFormatSet`, so receivers
+    /// can advertise them like built-in formats
+    pub const THIRD_PARTY: RangeInclusive<u8> = 32..=63;
+
+    /// Format with the given number, for codecs registered by other crates.
+    /// These should be numbered in `THIRD_PARTY` so as not to clash with
+    /// formats bark adds later
+    pub const fn new(format: u8) -> Self {
+        Self(format)
+    }
+
+    /// Name of the format as given to `--format`, if known
+    pub fn name(&self) -> Option<&'static str> {
+        match *self {
+            Self::F32LE => Some("f32le"),
+            Self::S16LE => Some("s16le"),
+            Self::OPUS => Some("opus"),
+            Self::S24LE => Some("s24le"),
+            Self::S32LE => Some("s32le"),
+            _ => None,
+        }
+    }
+}
+
+/// A set of audio formats, such as those a receiver can decode. Only
+/// formats numbered below 64 fit
+#[derive(Debug, Clone, Copy, Zeroable, Pod, PartialEq, Eq, Default)]
+#[repr(transparent)]
+pub struct AudioFormatSet(u64);
+
+impl AudioFormatSet {
+    pub fn empty() -> Self {
+        AudioFormatSet(0)
+    }
+
+    pub fn insert(&mut self, format: AudioPacketFormat) {
+        self.0 |= Self::bit(format);
+    }
+
+    pub fn contains(&self, format: AudioPacketFormat) -> bool {
+        self.0 & Self::bit(format) != 0
+    }
+
+    /// Formats in both sets
+    pub fn intersection(&self, other: AudioFormatSet) -> AudioFormatSet {
+        AudioFormatSet(self.0 & other.0)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = AudioPacketFormat> {
+        let set = *self;
+        (0..64u8).map(AudioPacketFormat).filter(move |format| set.contains(*format))
+    }
+
+    fn bit(format: AudioPacketFormat) -> u64 {
+        1u64.checked_shl(u32::from(format.0)).unwrap_or(0)
+    }
 }
 
-pub type AudioPacketBuffer = [f32; SAMPLES_PER_PACKET];
+impl FromIterator<AudioPacketFormat> for AudioFormatSet {
+    fn from_iter<I: IntoIterator<Item = AudioPacketFormat>>(iter: I) -> Self {
+        let mut set = AudioFormatSet::empty();
+
+        for format in iter {
+            set.insert(format);
+        }
+
+        set
+    }
+}
+
+pub type AudioPacketBuffer = [f32; MAX_SAMPLES_PER_PACKET];
 
 #[derive(Debug, Clone, Copy, Zeroable, Pod)]
 #[repr(C)]
 pub struct StatsReplyPacket {
     pub sid: SessionId,
     pub receiver: stats::receiver::ReceiverStats,
+    pub source: stats::source::SourceStats,
     pub node: stats::node::NodeStats,
 }
 
@@ -89,6 +267,232 @@ bitflags::bitflags! {
     }
 }
 
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct DumpRequestPacket {
+    // how long to record audio for
+    pub duration_ms: u64,
+}
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, Zeroable, Pod)]
+    #[repr(transparent)]
+    pub struct DumpReplyFlags: u32 {
+        // reply message is an error rather than the dump location
+        const ERROR = 0x01;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct ReplayRequestPacket {
+    // how much recent audio to replay
+    pub duration_ms: u64,
+}
+
+// receiver to send a replay to. a replay request is followed by zero or
+// more of these, replays with no targets are sent to all receivers
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct ReplayTarget {
+    pub addr: [u8; 4],
+    // big endian
+    pub port: [u8; 2],
+}
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, Zeroable, Pod)]
+    #[repr(transparent)]
+    pub struct ReplayReplyFlags: u32 {
+        // reply message is an error rather than a description of the replay
+        const ERROR = 0x01;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct VolumePacket {
+    // linear volume between 0.0 and 1.0, only valid with SET_VOLUME
+    pub volume: f32,
+    // linear maximum volume, only valid with SET_MAX_VOLUME
+    pub max_volume: f32,
+}
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, Zeroable, Pod)]
+    #[repr(transparent)]
+    pub struct VolumeFlags: u32 {
+        const SET_VOLUME = 0x01;
+        const MUTE       = 0x02;
+        const UNMUTE     = 0x04;
+        const SET_MAX_VOLUME = 0x08;
+        const LOCK       = 0x10;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct DspPacket {
+    // speaker protection profile, only valid with SET_PROTECTION. 0 for
+    // none, 1 for small-fullrange, 2 for bookshelf
+    pub protection: u32,
+}
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, Zeroable, Pod)]
+    #[repr(transparent)]
+    pub struct DspFlags: u32 {
+        const LIMITER_ON  = 0x01;
+        const LIMITER_OFF = 0x02;
+        const SET_PROTECTION = 0x04;
+        const BYPASS      = 0x08;
+        const UNBYPASS    = 0x10;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct TakeoverPacket {
+    // session receivers should switch to, regardless of priority
+    pub sid: SessionId,
+}
+
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct StreamEndPacket {
+    // session the source has stopped sending
+    pub sid: SessionId,
+}
+
+pub const METADATA_FIELD_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct MetadataPacket {
+    // session the metadata describes
+    pub sid: SessionId,
+    // what is playing, each nul padded and empty if unknown
+    pub title: [u8; METADATA_FIELD_LENGTH],
+    pub artist: [u8; METADATA_FIELD_LENGTH],
+    pub album: [u8; METADATA_FIELD_LENGTH],
+}
+
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct ReceiverReportPacket {
+    // session the report is on
+    pub sid: SessionId,
+    // highest seq received in the session
+    pub highest_seq: u64,
+    // packets received, not counting duplicates
+    pub received: u64,
+    // packets never received, up to highest_seq
+    pub lost: u64,
+    // interarrival jitter, in microseconds
+    pub jitter_us: u64,
+}
+
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct SyncRequestPacket {
+    // session and packet whose playback time to report
+    pub sid: SessionId,
+    pub seq: u64,
+    // requester's time when the request was sent, echoed in the reply
+    pub sent: TimestampMicros,
+}
+
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct SyncReplyPacket {
+    pub sid: SessionId,
+    pub seq: u64,
+    // echoed from the request
+    pub sent: TimestampMicros,
+    // receiver's time when the request arrived and when the reply was
+    // sent, from which the requester can estimate the receiver's clock
+    // offset from its own
+    pub received: TimestampMicros,
+    pub replied: TimestampMicros,
+    // receiver's time when the first frame of the packet was heard, zero
+    // with MISSED
+    pub played: TimestampMicros,
+}
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, Zeroable, Pod)]
+    #[repr(transparent)]
+    pub struct SyncReplyFlags: u32 {
+        // the packet was not played, because it was lost or is too old
+        const MISSED = 0x01;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct SignedPacket {
+    // time the packet was signed, to reject replays
+    pub timestamp: TimestampMicros,
+    // HMAC-SHA256 over timestamp followed by the whole wrapped packet,
+    // header included
+    pub mac: [u8; crate::auth::MAC_LENGTH],
+}
+
+pub const ZONE_NAME_LENGTH: usize = 32;
+
+/// Copy a string into a nul padded fixed size field, truncating it at a char
+/// boundary if it does not fit
+pub(crate) fn to_fixed_str<const N: usize>(s: &str) -> [u8; N] {
+    let mut len = core::cmp::min(s.len(), N);
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+
+    let mut fixed = [0; N];
+    fixed[0..len].copy_from_slice(&s.as_bytes()[0..len]);
+    fixed
+}
+
+/// Read a nul padded fixed size string field, `None` if empty
+pub(crate) fn from_fixed_str(fixed: &[u8]) -> Option<&str> {
+    let len = fixed.iter().position(|b| *b == 0).unwrap_or(fixed.len());
+
+    core::str::from_utf8(&fixed[0..len]).ok()
+        .filter(|s| !s.is_empty())
+}
+
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct ZonePacket {
+    // zone name, nul padded. empty means all zones for MUTE, UNMUTE, and
+    // SOLO, and no zone for ASSIGN
+    pub zone: [u8; ZONE_NAME_LENGTH],
+}
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, Zeroable, Pod)]
+    #[repr(transparent)]
+    pub struct ZoneFlags: u32 {
+        // sent to a single receiver, moves it into the zone
+        const ASSIGN = 0x01;
+        // mute receivers in the zone
+        const MUTE   = 0x02;
+        // unmute receivers in the zone
+        const UNMUTE = 0x04;
+        // unmute receivers in the zone, mute all others
+        const SOLO   = 0x08;
+    }
+}
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, Zeroable, Pod)]
+    #[repr(transparent)]
+    pub struct OutputReplyFlags: u32 {
+        // reply message is an error rather than the new output device
+        const ERROR = 0x01;
+    }
+}
+
 #[derive(Debug, Clone, Copy, Zeroable, Pod, PartialEq, PartialOrd)]
 #[repr(transparent)]
 pub struct TimestampMicros(pub u64);
@@ -1,2 +1,3 @@
 pub mod node;
 pub mod receiver;
+pub mod source;
@@ -1,3 +1,5 @@
+use core::mem::offset_of;
+
 use bytemuck::{Zeroable, Pod};
 
 #[derive(Debug, Clone, Copy, Zeroable, Pod)]
@@ -5,4 +7,21 @@ use bytemuck::{Zeroable, Pod};
 pub struct NodeStats {
     pub username: [u8; 32],
     pub hostname: [u8; 32],
+    // persistent uuid of the receiver, zero from sources and older receivers
+    pub id: [u8; 16],
+    // name given to the receiver, nul padded
+    pub name: [u8; 32],
+}
+
+impl NodeStats {
+    /// Length of node stats from nodes predating protocol versions, which
+    /// end after the hostname
+    pub(crate) const V0_LENGTH: usize = offset_of!(NodeStats, id);
+
+    /// Node stats in the layout of nodes predating protocol versions
+    pub(crate) fn from_v0(bytes: &[u8; Self::V0_LENGTH]) -> Self {
+        let mut stats = NodeStats::zeroed();
+        bytemuck::bytes_of_mut(&mut stats)[0..Self::V0_LENGTH].copy_from_slice(bytes);
+        stats
+    }
 }
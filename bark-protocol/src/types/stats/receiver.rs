@@ -1,18 +1,43 @@
+use core::mem::offset_of;
+
 use bitflags::bitflags;
 use bytemuck::{Zeroable, Pod};
 
 use crate::time::{SampleDuration, TimestampDelta};
+use crate::types::{self, AudioFormatSet, ZONE_NAME_LENGTH};
 
 #[derive(Debug, Clone, Copy, Zeroable, Pod)]
 #[repr(C)]
 pub struct ReceiverStats {
     flags: ReceiverStatsFlags,
     stream_status: u8,
-    _pad: [u8; 6],
+    // maximum volume as a percentage, 0 if not reported
+    max_volume: u8,
+    // flags that no longer fit in `flags`, zero from older receivers
+    more_flags: ReceiverStatsMoreFlags,
+    volume: f32,
 
     audio_latency: f64,
     output_latency: f64,
     network_latency: f64,
+
+    // name of the output device in use, nul padded
+    output_device: [u8; 32],
+
+    // zone the receiver belongs to, nul padded
+    zone: [u8; ZONE_NAME_LENGTH],
+
+    // drift of the output device clock relative to the stream
+    clock_drift: f64,
+
+    // how far into the session the audio now playing is, in seconds
+    stream_position: f64,
+
+    // formats the receiver can decode
+    decoders: AudioFormatSet,
+
+    // largest packet the receiver accepts, in frames
+    max_packet_frames: u64,
 }
 
 #[derive(Clone, Copy)]
@@ -48,18 +73,49 @@ bitflags! {
     #[derive(Debug, Clone, Copy, Zeroable, Pod)]
     #[repr(transparent)]
     pub struct ReceiverStatsFlags: u8 {
+        const HAS_VOLUME          = 0x01;
+        const MUTED               = 0x02;
         const HAS_AUDIO_LATENCY   = 0x04;
+        const ZONE_MUTED          = 0x08;
         const HAS_NETWORK_LATENCY = 0x10;
-        const HAS_PREDICT_OFFSET  = 0x20;
+        const HAS_CLOCK_DRIFT     = 0x20;
         const HAS_OUTPUT_LATENCY  = 0x40;
+        const VOLUME_LOCKED       = 0x80;
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, Zeroable, Pod)]
+    #[repr(transparent)]
+    pub struct ReceiverStatsMoreFlags: u8 {
+        const HAS_STREAM_POSITION = 0x01;
+        const HAS_DECODERS        = 0x02;
+        const HAS_MAX_PACKET      = 0x04;
     }
 }
 
 impl ReceiverStats {
+    /// Length of receiver stats from nodes predating protocol versions,
+    /// which end after the network latency
+    pub(crate) const V0_LENGTH: usize = offset_of!(ReceiverStats, output_device);
+
     pub fn new() -> Self {
         ReceiverStats::zeroed()
     }
 
+    /// Receiver stats in the layout of nodes predating protocol versions
+    pub(crate) fn from_v0(bytes: &[u8; Self::V0_LENGTH]) -> Self {
+        let mut stats = ReceiverStats::zeroed();
+        bytemuck::bytes_of_mut(&mut stats)[0..Self::V0_LENGTH].copy_from_slice(bytes);
+
+        // 0x20 was an offset prediction with no field, not the clock drift
+        stats.flags &= ReceiverStatsFlags::HAS_AUDIO_LATENCY
+            | ReceiverStatsFlags::HAS_NETWORK_LATENCY
+            | ReceiverStatsFlags::HAS_OUTPUT_LATENCY;
+
+        stats
+    }
+
     pub fn stream(&self) -> Option<StreamStatus> {
         StreamStatus::from_u8(self.stream_status)
     }
@@ -71,6 +127,7 @@ impl ReceiverStats {
     pub fn clear(&mut self) {
         self.set_stream(StreamStatus::Seek);
         self.flags = ReceiverStatsFlags::empty();
+        self.more_flags = ReceiverStatsMoreFlags::empty();
     }
 
     fn field(&self, flag: ReceiverStatsFlags, value: f64) -> Option<f64> {
@@ -86,7 +143,8 @@ impl ReceiverStats {
         self.field(ReceiverStatsFlags::HAS_AUDIO_LATENCY, self.audio_latency)
     }
 
-    /// Length of output audio buffer (including hardware latency) in seconds
+    /// Latency of the output in seconds: its audio buffer, including
+    /// hardware latency, plus any configured latency of the sink after it
     pub fn output_latency(&self) -> Option<f64> {
         self.field(ReceiverStatsFlags::HAS_OUTPUT_LATENCY, self.output_latency)
     }
@@ -96,6 +154,113 @@ impl ReceiverStats {
         self.field(ReceiverStatsFlags::HAS_NETWORK_LATENCY, self.network_latency)
     }
 
+    /// Drift of the output device clock relative to the stream, in parts
+    /// per million. Positive when the device runs slow
+    pub fn clock_drift(&self) -> Option<f64> {
+        self.field(ReceiverStatsFlags::HAS_CLOCK_DRIFT, self.clock_drift)
+    }
+
+    /// How far into the session the audio now playing is, in seconds,
+    /// counted from the session's announced start if it has one
+    pub fn stream_position(&self) -> Option<f64> {
+        if self.more_flags.contains(ReceiverStatsMoreFlags::HAS_STREAM_POSITION) {
+            Some(self.stream_position)
+        } else {
+            None
+        }
+    }
+
+    /// Formats the receiver can decode, if reported
+    pub fn decoders(&self) -> Option<AudioFormatSet> {
+        if self.more_flags.contains(ReceiverStatsMoreFlags::HAS_DECODERS) {
+            Some(self.decoders)
+        } else {
+            None
+        }
+    }
+
+    pub fn set_decoders(&mut self, decoders: AudioFormatSet) {
+        self.decoders = decoders;
+        self.more_flags.insert(ReceiverStatsMoreFlags::HAS_DECODERS);
+    }
+
+    /// Largest packet the receiver accepts, in frames, if reported. It
+    /// plays silence for streams with larger packets
+    pub fn max_packet_frames(&self) -> Option<usize> {
+        if self.more_flags.contains(ReceiverStatsMoreFlags::HAS_MAX_PACKET) {
+            Some(usize::try_from(self.max_packet_frames).unwrap_or(usize::MAX))
+        } else {
+            None
+        }
+    }
+
+    pub fn set_max_packet_frames(&mut self, frames: usize) {
+        self.max_packet_frames = frames as u64;
+        self.more_flags.insert(ReceiverStatsMoreFlags::HAS_MAX_PACKET);
+    }
+
+    /// Linear output volume between 0.0 and 1.0
+    pub fn volume(&self) -> Option<f32> {
+        if self.flags.contains(ReceiverStatsFlags::HAS_VOLUME) {
+            Some(self.volume)
+        } else {
+            None
+        }
+    }
+
+    pub fn muted(&self) -> bool {
+        self.flags.contains(ReceiverStatsFlags::MUTED)
+    }
+
+    pub fn set_volume(&mut self, volume: f32, muted: bool) {
+        self.volume = volume;
+        self.flags.insert(ReceiverStatsFlags::HAS_VOLUME);
+        self.flags.set(ReceiverStatsFlags::MUTED, muted);
+    }
+
+    /// Linear maximum volume the receiver will play at, if reported
+    pub fn max_volume(&self) -> Option<f32> {
+        match self.max_volume {
+            0 => None,
+            percent => Some(f32::from(percent) / 100.0),
+        }
+    }
+
+    /// Whether the maximum volume is locked against control requests
+    pub fn volume_locked(&self) -> bool {
+        self.flags.contains(ReceiverStatsFlags::VOLUME_LOCKED)
+    }
+
+    pub fn set_max_volume(&mut self, max_volume: f32, locked: bool) {
+        // reported to the nearest percent, never 0 so it can't be mistaken
+        // for a receiver that doesn't report it
+        self.max_volume = (max_volume * 100.0).round().clamp(1.0, 100.0) as u8;
+        self.flags.set(ReceiverStatsFlags::VOLUME_LOCKED, locked);
+    }
+
+    /// Name of the output device in use
+    pub fn output_device(&self) -> Option<&str> {
+        types::from_fixed_str(&self.output_device)
+    }
+
+    /// Set output device name, truncating if it does not fit
+    pub fn set_output_device(&mut self, device: &str) {
+        self.output_device = types::to_fixed_str(device);
+    }
+
+    pub fn zone(&self) -> Option<&str> {
+        types::from_fixed_str(&self.zone)
+    }
+
+    pub fn zone_muted(&self) -> bool {
+        self.flags.contains(ReceiverStatsFlags::ZONE_MUTED)
+    }
+
+    pub fn set_zone(&mut self, zone: Option<&str>, muted: bool) {
+        self.zone = types::to_fixed_str(zone.unwrap_or_default());
+        self.flags.set(ReceiverStatsFlags::ZONE_MUTED, muted);
+    }
+
     pub fn set_audio_latency(&mut self, delta: TimestampDelta) {
         self.audio_latency = delta.to_seconds();
         self.flags.insert(ReceiverStatsFlags::HAS_AUDIO_LATENCY);
@@ -106,6 +271,16 @@ impl ReceiverStats {
         self.flags.insert(ReceiverStatsFlags::HAS_OUTPUT_LATENCY);
     }
 
+    pub fn set_clock_drift(&mut self, ppm: f64) {
+        self.clock_drift = ppm;
+        self.flags.insert(ReceiverStatsFlags::HAS_CLOCK_DRIFT);
+    }
+
+    pub fn set_stream_position(&mut self, position: SampleDuration) {
+        self.stream_position = position.to_std_duration_lossy().as_micros() as f64 / 1_000_000.0;
+        self.more_flags.insert(ReceiverStatsMoreFlags::HAS_STREAM_POSITION);
+    }
+
     pub fn set_network_latency(&mut self, latency: core::time::Duration) {
         self.network_latency = latency.as_micros() as f64 / 1_000_000.0;
         self.flags.insert(ReceiverStatsFlags::HAS_NETWORK_LATENCY);
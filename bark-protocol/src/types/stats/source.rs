@@ -0,0 +1,126 @@
+use bitflags::bitflags;
+use bytemuck::{Zeroable, Pod};
+
+use crate::types::AudioPacketFormat;
+
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct SourceStats {
+    flags: SourceStatsFlags,
+    format: AudioPacketFormat,
+    packet_frames: u16,
+    // receivers playing the stream, as last counted
+    receivers: u32,
+
+    // average encoded audio bitrate, in bits per second
+    bitrate: f64,
+    // packets sent per second, including redundant copies
+    packet_rate: f64,
+    // how long the source has been running, in seconds
+    uptime: f64,
+
+    // fraction of packets lost across receivers, from their reports
+    loss: f64,
+    // worst interarrival jitter receivers report, in seconds
+    jitter: f64,
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, Zeroable, Pod)]
+    #[repr(transparent)]
+    pub struct SourceStatsFlags: u8 {
+        const HAS_FORMAT    = 0x01;
+        const HAS_BITRATE   = 0x02;
+        const HAS_RECEPTION = 0x04;
+    }
+}
+
+impl Default for SourceStats {
+    fn default() -> Self {
+        SourceStats::new()
+    }
+}
+
+impl SourceStats {
+    pub fn new() -> Self {
+        SourceStats::zeroed()
+    }
+
+    /// Format audio is encoded in, once the source has started encoding
+    pub fn format(&self) -> Option<AudioPacketFormat> {
+        if self.flags.contains(SourceStatsFlags::HAS_FORMAT) {
+            Some(self.format)
+        } else {
+            None
+        }
+    }
+
+    /// Frames of audio carried in each packet
+    pub fn packet_frames(&self) -> Option<u16> {
+        self.format().map(|_| self.packet_frames)
+    }
+
+    pub fn set_format(&mut self, format: AudioPacketFormat, packet_frames: u16) {
+        self.format = format;
+        self.packet_frames = packet_frames;
+        self.flags.insert(SourceStatsFlags::HAS_FORMAT);
+    }
+
+    /// Average encoded audio bitrate in bits per second, once any audio
+    /// has been sent
+    pub fn bitrate(&self) -> Option<f64> {
+        if self.flags.contains(SourceStatsFlags::HAS_BITRATE) {
+            Some(self.bitrate)
+        } else {
+            None
+        }
+    }
+
+    pub fn set_bitrate(&mut self, bitrate: f64) {
+        self.bitrate = bitrate;
+        self.flags.insert(SourceStatsFlags::HAS_BITRATE);
+    }
+
+    /// Packets sent per second, including redundant copies
+    pub fn packet_rate(&self) -> f64 {
+        self.packet_rate
+    }
+
+    pub fn set_packet_rate(&mut self, packet_rate: f64) {
+        self.packet_rate = packet_rate;
+    }
+
+    /// How long the source has been running, in seconds
+    pub fn uptime(&self) -> f64 {
+        self.uptime
+    }
+
+    pub fn set_uptime(&mut self, uptime: core::time::Duration) {
+        self.uptime = uptime.as_micros() as f64 / 1_000_000.0;
+    }
+
+    /// Fraction of packets lost and worst jitter in seconds, as reported
+    /// by receivers
+    pub fn reception(&self) -> Option<(f64, f64)> {
+        if self.flags.contains(SourceStatsFlags::HAS_RECEPTION) {
+            Some((self.loss, self.jitter))
+        } else {
+            None
+        }
+    }
+
+    pub fn set_reception(&mut self, loss: f64, jitter: core::time::Duration) {
+        self.loss = loss;
+        self.jitter = jitter.as_micros() as f64 / 1_000_000.0;
+        self.flags.insert(SourceStatsFlags::HAS_RECEPTION);
+    }
+
+    /// Number of receivers playing the stream
+    pub fn receivers(&self) -> u32 {
+        self.receivers
+    }
+
+    pub fn set_receivers(&mut self, receivers: usize) {
+        self.receivers = u32::try_from(receivers).unwrap_or(u32::MAX);
+    }
+}
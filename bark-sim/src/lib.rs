@@ -0,0 +1,184 @@
+//! Runs a source and a receiver in one process over a simulated network
+//! which loses, delays and reorders packets, into a simulated sound card
+//! whose clock drifts from the system clock. Everything runs on a virtual
+//! clock, so a minute of audio takes a moment and any run is reproduced
+//! exactly by its seed.
+//!
+//! The receiver is bark-core's packet queue and decode pipeline, as used
+//! by `bark receive`. At the end the run is checked for continuity, that
+//! every packet the network delivered in time was played without a click,
+//! and for sync, that playback settled to within a tolerance of the
+//! stream's timestamps.
+
+mod link;
+mod output;
+mod receiver;
+mod source;
+
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use crate::link::{Link, LinkOpt};
+use crate::output::Device;
+use crate::receiver::Receiver;
+use crate::source::Source;
+
+pub use crate::receiver::Stats;
+
+/// Time on the virtual clock, in microseconds since the epoch
+pub type Micros = u64;
+
+/// Where the virtual clock starts, around when this was written. Not zero
+/// so that timestamps are of the size they are on real nodes
+const EPOCH: Micros = 1_700_000_000_000_000;
+
+/// Granularity of the simulation
+const TICK: Micros = 250;
+
+/// Size of the simulated sound card's buffer, which the receiver keeps
+/// full as ALSA would
+const DEVICE_BUFFER: Duration = Duration::from_millis(10);
+
+/// Conditions to simulate, defaulting to a perfect network and sound card
+#[derive(Debug, Clone)]
+pub struct Scenario {
+    /// Seconds of audio to simulate
+    pub duration_secs: u64,
+    /// How far ahead of playing it the source sends audio
+    pub delay_ms: u64,
+    /// Percentage of packets the network drops
+    pub loss: f64,
+    /// Milliseconds every packet spends on the network
+    pub latency_ms: f64,
+    /// Packets are held up on the network by a random time up to this
+    pub jitter_ms: f64,
+    /// Percentage of packets the network holds back behind the next two
+    pub reorder: f64,
+    /// How fast the sound card's clock runs against the system clock
+    pub drift_ppm: f64,
+    /// Seconds playback has to settle into sync before it's checked
+    pub settle_secs: u64,
+    /// How far from its pts audio may be heard once settled
+    pub sync_tolerance_ms: f64,
+    /// Seed for the network's randomness
+    pub seed: u64,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Scenario {
+            duration_secs: 30,
+            delay_ms: 20,
+            loss: 0.0,
+            latency_ms: 1.0,
+            jitter_ms: 0.0,
+            reorder: 0.0,
+            drift_ppm: 0.0,
+            settle_secs: 5,
+            sync_tolerance_ms: 1.0,
+            seed: 0,
+        }
+    }
+}
+
+/// What happened over a run
+#[derive(Debug)]
+pub struct Outcome {
+    pub stats: Stats,
+    pub dropped: u64,
+    pub reordered: u64,
+    pub underruns: u64,
+    /// Drift between the sound card and system clock the receiver learned
+    pub drift_ppm: Option<f64>,
+    /// Furthest from its pts audio was heard once settled, in microseconds
+    pub worst_offset: Option<f64>,
+    pub failures: Vec<&'static str>,
+}
+
+impl Outcome {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+pub fn run(scenario: &Scenario) -> Outcome {
+    let mut link = Link::new(LinkOpt {
+        loss: (scenario.loss / 100.0).clamp(0.0, 1.0),
+        latency: Duration::from_secs_f64(scenario.latency_ms / 1000.0),
+        jitter: Duration::from_secs_f64(scenario.jitter_ms / 1000.0),
+        reorder: (scenario.reorder / 100.0).clamp(0.0, 1.0),
+    }, StdRng::seed_from_u64(scenario.seed));
+
+    let mut source = Source::new(EPOCH, Duration::from_millis(scenario.delay_ms));
+    let mut receiver = Receiver::new();
+
+    let buffer_frames = DEVICE_BUFFER.as_micros() as u64 * u64::from(bark_protocol::SAMPLE_RATE.0) / 1_000_000;
+    let mut device = Device::new(buffer_frames as usize, scenario.drift_ppm);
+
+    let end = EPOCH + scenario.duration_secs * 1_000_000;
+    let settled = EPOCH + scenario.settle_secs * 1_000_000;
+
+    let mut worst_offset: Option<f64> = None;
+    let mut now = EPOCH;
+
+    while now < end {
+        while source.due() <= now {
+            let sent = source.due();
+            let audio = source.next_packet();
+            link.send(sent, audio);
+        }
+
+        for audio in link.deliver(now) {
+            receiver.receive(audio);
+        }
+
+        device.advance(TICK);
+
+        // keep the device full, a packet at a time
+        while receiver.is_receiving() && device.room() >= receiver.max_frames() {
+            let Some(offset) = receiver.play(now, &mut device) else {
+                continue;
+            };
+
+            if now >= settled {
+                worst_offset = Some(worst_offset.unwrap_or_default().max(offset.abs()));
+            }
+        }
+
+        now += TICK;
+    }
+
+    let drift_ppm = receiver.drift_ppm();
+    let stats = receiver.stats;
+    let mut failures = Vec::new();
+
+    if stats.missed > 0 || stats.late > 0 {
+        failures.push("packets arrived too late to play, lengthen --delay-ms");
+    }
+
+    if device.underruns > 0 {
+        failures.push("sound card ran out of audio");
+    }
+
+    if stats.clicks > 0 {
+        failures.push("output jumped where no packet was missing");
+    }
+
+    match worst_offset {
+        Some(offset) if offset <= scenario.sync_tolerance_ms * 1000.0 => {}
+        Some(_) => failures.push("playback did not settle into sync"),
+        None => failures.push("nothing played after settling"),
+    }
+
+    Outcome {
+        drift_ppm,
+        stats,
+        dropped: link.dropped,
+        reordered: link.reordered,
+        underruns: device.underruns,
+        worst_offset,
+        failures,
+    }
+}
@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::Rng;
+
+use bark_protocol::packet::Audio;
+
+use crate::Micros;
+
+pub struct LinkOpt {
+    /// Chance of each packet being dropped, from 0 to 1
+    pub loss: f64,
+    /// Time every packet spends in flight
+    pub latency: Duration,
+    /// Packets are further held up by a random time up to this
+    pub jitter: Duration,
+    /// Chance of each packet being held back behind the two after it, from
+    /// 0 to 1
+    pub reorder: f64,
+}
+
+/// Network between the source and receiver, which loses, delays and
+/// reorders packets
+pub struct Link {
+    opt: LinkOpt,
+    rng: StdRng,
+    in_flight: Vec<(Micros, Audio)>,
+    pub dropped: u64,
+    pub reordered: u64,
+}
+
+impl Link {
+    pub fn new(opt: LinkOpt, rng: StdRng) -> Self {
+        Link {
+            opt,
+            rng,
+            in_flight: Vec::new(),
+            dropped: 0,
+            reordered: 0,
+        }
+    }
+
+    pub fn send(&mut self, now: Micros, audio: Audio) {
+        if self.rng.gen_bool(self.opt.loss) {
+            self.dropped += 1;
+            return;
+        }
+
+        let jitter = self.opt.jitter.as_micros() as u64;
+        let mut delay = self.opt.latency.as_micros() as u64 + self.rng.gen_range(0..=jitter);
+
+        if self.rng.gen_bool(self.opt.reorder) {
+            delay += 2 * audio.header().packet_duration().to_micros_lossy();
+            self.reordered += 1;
+        }
+
+        self.in_flight.push((now + delay, audio));
+    }
+
+    /// Packets arriving by `now`, in the order they arrive
+    pub fn deliver(&mut self, now: Micros) -> Vec<Audio> {
+        self.in_flight.sort_by_key(|(arrival, _)| *arrival);
+
+        let arrived = self.in_flight.partition_point(|(arrival, _)| *arrival <= now);
+
+        self.in_flight.drain(0..arrived)
+            .map(|(_, audio)| audio)
+            .collect()
+    }
+}
@@ -0,0 +1,109 @@
+//! Runs a source and a receiver in one process over a simulated network
+//! which loses, delays and reorders packets, into a simulated sound card
+//! whose clock drifts from the system clock. Everything runs on a virtual
+//! clock, so a minute of audio takes a moment and any run is reproduced
+//! exactly by its seed.
+//!
+//! The receiver is bark-core's packet queue and decode pipeline, as used
+//! by `bark receive`. At the end the run is checked for continuity, that
+//! every packet the network delivered in time was played without a click,
+//! and for sync, that playback settled to within a tolerance of the
+//! stream's timestamps. Any failure exits non-zero:
+//!
+//!     cargo run -p bark-sim -- --jitter-ms 5 --loss 1 --drift-ppm 100
+
+use std::process::ExitCode;
+
+use bark_sim::Scenario;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(name = "bark-sim")]
+struct Opt {
+    /// Seconds of audio to simulate
+    #[structopt(long, default_value = "30")]
+    duration_secs: u64,
+
+    /// How far ahead of playing it the source sends audio, as with
+    /// `bark stream --delay-ms`
+    #[structopt(long, default_value = "20")]
+    delay_ms: u64,
+
+    /// Percentage of packets the network drops
+    #[structopt(long, default_value = "0")]
+    loss: f64,
+
+    /// Milliseconds every packet spends on the network
+    #[structopt(long, default_value = "1")]
+    latency_ms: f64,
+
+    /// Packets are held up on the network by a random time up to this
+    #[structopt(long, default_value = "0")]
+    jitter_ms: f64,
+
+    /// Percentage of packets the network holds back behind the next two
+    #[structopt(long, default_value = "0")]
+    reorder: f64,
+
+    /// How fast the sound card's clock runs against the system clock, in
+    /// parts per million
+    #[structopt(long, default_value = "0")]
+    drift_ppm: f64,
+
+    /// Seconds playback has to settle into sync before it's checked
+    #[structopt(long, default_value = "5")]
+    settle_secs: u64,
+
+    /// How far from its pts audio may be heard once settled
+    #[structopt(long, default_value = "1")]
+    sync_tolerance_ms: f64,
+
+    /// Seed for the network's randomness
+    #[structopt(long, default_value = "0")]
+    seed: u64,
+}
+
+fn main() -> ExitCode {
+    let opt = Opt::from_args();
+
+    let scenario = Scenario {
+        duration_secs: opt.duration_secs,
+        delay_ms: opt.delay_ms,
+        loss: opt.loss,
+        latency_ms: opt.latency_ms,
+        jitter_ms: opt.jitter_ms,
+        reorder: opt.reorder,
+        drift_ppm: opt.drift_ppm,
+        settle_secs: opt.settle_secs,
+        sync_tolerance_ms: opt.sync_tolerance_ms,
+        seed: opt.seed,
+    };
+
+    let outcome = bark_sim::run(&scenario);
+    let stats = &outcome.stats;
+
+    println!("network: {} dropped, {} reordered", outcome.dropped, outcome.reordered);
+    println!("receiver: {} lost, {} missed, {} late, {} resets, {} clicks",
+        stats.lost, stats.missed, stats.late, stats.resets, stats.clicks);
+    println!("device: {} underruns", outcome.underruns);
+
+    match outcome.drift_ppm {
+        Some(drift) => println!("sync: learned drift {drift:.1}ppm against {:.1}ppm", opt.drift_ppm),
+        None => println!("sync: nothing received"),
+    }
+
+    if let Some(offset) = outcome.worst_offset {
+        println!("sync: worst offset {:.3}ms after {}s", offset / 1000.0, opt.settle_secs);
+    }
+
+    if outcome.passed() {
+        println!("ok");
+        return ExitCode::SUCCESS;
+    }
+
+    for failure in &outcome.failures {
+        println!("FAIL: {failure}");
+    }
+
+    ExitCode::FAILURE
+}
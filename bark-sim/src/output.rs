@@ -0,0 +1,62 @@
+use bark_protocol::time::SampleDuration;
+use bark_protocol::SAMPLE_RATE;
+
+use crate::Micros;
+
+/// Simulated sound card, playing out of its buffer at its own rate, which
+/// drifts from the system clock as a real sound card's crystal does
+pub struct Device {
+    /// Frames played per microsecond of system time
+    rate: f64,
+    capacity: usize,
+    buffered: f64,
+    started: bool,
+    pub underruns: u64,
+}
+
+impl Device {
+    pub fn new(capacity: usize, drift_ppm: f64) -> Self {
+        Device {
+            rate: f64::from(SAMPLE_RATE.0) * (1.0 + drift_ppm / 1_000_000.0) / 1_000_000.0,
+            capacity,
+            buffered: 0.0,
+            started: false,
+            underruns: 0,
+        }
+    }
+
+    /// Play out `elapsed` microseconds worth of the buffer
+    pub fn advance(&mut self, elapsed: Micros) {
+        self.buffered -= self.rate * elapsed as f64;
+
+        if self.buffered < 0.0 {
+            if self.started {
+                self.underruns += 1;
+            }
+
+            self.buffered = 0.0;
+        }
+    }
+
+    /// Room for more frames in the buffer
+    pub fn room(&self) -> usize {
+        self.capacity.saturating_sub(self.buffered.ceil() as usize)
+    }
+
+    /// Delay as the device reports it, in frames until audio written now
+    /// is played. bark takes this to be at the nominal sample rate
+    pub fn delay(&self) -> SampleDuration {
+        SampleDuration::from_frame_count(self.buffered.ceil() as usize)
+    }
+
+    /// Microseconds of system time until audio written now is played, by
+    /// the device's actual rate
+    pub fn heard_in(&self) -> f64 {
+        self.buffered / self.rate
+    }
+
+    pub fn write(&mut self, frames: usize) {
+        self.buffered += frames as f64;
+        self.started = true;
+    }
+}
@@ -0,0 +1,177 @@
+use std::f32::consts::TAU;
+
+use bark_core::audio::{FrameF32, F32};
+use bark_core::receive::pipeline::Pipeline;
+use bark_core::receive::queue::{AudioPts, Insert, PacketQueue};
+use bark_core::receive::resample;
+use bark_core::receive::timing::Timing;
+use bark_protocol::packet::Audio;
+use bark_protocol::time::{SampleDuration, Timestamp};
+use bark_protocol::types::{AudioPacketHeader, TimestampMicros};
+use bark_protocol::{FRAMES_PER_PACKET, SAMPLE_RATE};
+
+use crate::output::Device;
+use crate::source::{TONE_AMPLITUDE, TONE_HZ};
+use crate::Micros;
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Packets which arrived after their slot was played
+    pub late: u64,
+    /// Packets missing when due because the queue ran dry
+    pub missed: u64,
+    /// Packets missing when due with more queued behind them
+    pub lost: u64,
+    /// Times the queue reset to a packet too far ahead of it
+    pub resets: u64,
+    /// Jumps in the output not explained by a missing packet
+    pub clicks: u64,
+}
+
+/// Receives a stream as `bark receive` does, through bark-core's queue and
+/// decode pipeline, keeping it in sync with a simulated output device
+pub struct Receiver {
+    stream: Option<Stream>,
+    last_sample: Option<f32>,
+    /// Packets after a missing one in which a click is expected
+    excuse_packets: usize,
+    excuse: usize,
+    pub stats: Stats,
+}
+
+struct Stream {
+    queue: PacketQueue,
+    pipeline: Pipeline<F32>,
+    buffer: Vec<FrameF32>,
+    /// Whether the first packet has played, before which missing packets
+    /// are just the queue buffering up
+    playing: bool,
+    /// Sequence number the stream started from. Packets sent before it
+    /// which arrive after it, reordered, never had a slot to be late for
+    first_seq: u64,
+}
+
+impl Stream {
+    fn new(header: &AudioPacketHeader) -> Self {
+        let pipeline = Pipeline::<F32>::new(header);
+
+        // resampler may output more frames than it takes in, leave room:
+        let buffer = vec![FrameF32(0.0, 0.0); pipeline.frames_per_packet() * 2];
+
+        Stream {
+            queue: PacketQueue::new(header, SampleDuration::zero(), None),
+            pipeline,
+            buffer,
+            playing: false,
+            first_seq: header.seq,
+        }
+    }
+}
+
+impl Receiver {
+    pub fn new() -> Self {
+        // a jump into or out of silence comes out of the resampler spread
+        // over its filter, which is centred on its delay
+        let resampler_delay = resample::measure_delay()
+            .map(|delay| delay.to_frame_count() as usize)
+            .unwrap_or_default();
+
+        Receiver {
+            stream: None,
+            last_sample: None,
+            excuse_packets: 2 * resampler_delay / FRAMES_PER_PACKET + 2,
+            excuse: 0,
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn is_receiving(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Most frames a single call to `play` writes to the device
+    pub fn max_frames(&self) -> usize {
+        self.stream.as_ref().map(|stream| stream.buffer.len()).unwrap_or_default()
+    }
+
+    pub fn drift_ppm(&self) -> Option<f64> {
+        self.stream.as_ref().map(|stream| stream.pipeline.drift_ppm())
+    }
+
+    pub fn receive(&mut self, audio: Audio) {
+        let stream = self.stream.get_or_insert_with(|| Stream::new(audio.header()));
+
+        // the simulated nodes share one clock, so stream time is local time
+        let pts = Timestamp::from_micros_lossy(audio.header().pts);
+        let seq = audio.header().seq;
+
+        match stream.queue.insert_packet(AudioPts { pts, audio }) {
+            Insert::Late if seq < stream.first_seq => {}
+            Insert::Late => { self.stats.late += 1; }
+            Insert::Reset => { self.stats.resets += 1; }
+            Insert::Queued | Insert::Duplicate | Insert::BeforeStart => {}
+        }
+    }
+
+    /// Decode the next packet into the device. Returns how far from its
+    /// pts the packet is heard, in microseconds, or None if it was missing
+    pub fn play(&mut self, now: Micros, device: &mut Device) -> Option<f64> {
+        let stream = self.stream.as_mut()?;
+
+        // take len before popping
+        let queue_len = stream.queue.len();
+        let item = stream.queue.pop_front();
+
+        if item.is_none() && stream.playing {
+            if queue_len == 0 {
+                self.stats.missed += 1;
+            } else {
+                self.stats.lost += 1;
+            }
+        }
+
+        // if packet was lost, grab the next packet for forward error correction
+        let next = match item {
+            None if queue_len > 0 => stream.queue.front()
+                .and_then(|next| Audio::new(next.header(), next.audio.buffer_bytes()).ok()),
+            _ => None,
+        };
+
+        let frames = stream.pipeline.process(item.as_ref().map(|item| &item.audio), next.as_ref(), &mut stream.buffer);
+        let output = &stream.buffer[0..frames];
+
+        // look for jumps larger than the tone could make between samples
+        let max_step = 2.0 * TONE_AMPLITUDE * TAU * TONE_HZ / SAMPLE_RATE.0 as f32;
+
+        if item.is_none() {
+            self.excuse = self.excuse_packets;
+        }
+
+        for frame in output {
+            if let Some(last) = self.last_sample {
+                if self.excuse == 0 && (frame.0 - last).abs() > max_step {
+                    self.stats.clicks += 1;
+                }
+            }
+
+            self.last_sample = Some(frame.0);
+        }
+
+        self.excuse = self.excuse.saturating_sub(1);
+
+        // adjust resampler rate based on stream timing info, exactly as
+        // bark receive does with the delay its output reports
+        let real = Timestamp::from_micros_lossy(TimestampMicros(now)).add(device.delay());
+
+        let offset = item.map(|item| {
+            stream.pipeline.set_timing(Timing { real, play: item.pts });
+            stream.playing = true;
+
+            // but measure by when the device really plays it
+            now as f64 + device.heard_in() - item.header().pts.0 as f64
+        });
+
+        device.write(frames);
+        offset
+    }
+}
@@ -0,0 +1,88 @@
+use std::f32::consts::TAU;
+use std::time::Duration;
+
+use bark_core::audio::{Format, FrameF32, F32};
+use bark_core::encode::Encode;
+use bark_core::registry;
+use bark_protocol::packet::Audio;
+use bark_protocol::types::{AudioPacketFormat, AudioPacketHeader, SessionId, TimestampMicros};
+use bark_protocol::{FRAMES_PER_PACKET, SAMPLE_RATE};
+
+use crate::Micros;
+
+pub const TONE_HZ: f32 = 440.0;
+pub const TONE_AMPLITUDE: f32 = 0.5;
+
+/// Sends a tone as a bark stream, a packet's worth of audio at a time on
+/// the simulated clock, as `bark stream` does on the real one
+pub struct Source {
+    encoder: Box<dyn Encode>,
+    header: AudioPacketHeader,
+    start: Micros,
+    delay: Micros,
+    packet_micros: Micros,
+    phase: f32,
+    frames: Vec<FrameF32>,
+    encoded: Box<[u8]>,
+}
+
+impl Source {
+    pub fn new(start: Micros, delay: Duration) -> Self {
+        let encoder = registry::new_encoder(AudioPacketFormat::F32LE)
+            .expect("F32LE encoder is always registered");
+
+        let header = AudioPacketHeader {
+            sid: SessionId(start as i64),
+            seq: 1,
+            pts: TimestampMicros(0),
+            dts: TimestampMicros(0),
+            format: encoder.header_format(),
+            priority: 0,
+            packet_frames: FRAMES_PER_PACKET as u16,
+            delay_ms: delay.as_millis() as u16,
+            min_buffer_ms: 0,
+            start_pts: TimestampMicros(0),
+        };
+
+        let packet_micros = header.packet_duration().to_micros_lossy();
+
+        Source {
+            encoder,
+            header,
+            start,
+            delay: delay.as_micros() as u64,
+            packet_micros,
+            phase: 0.0,
+            frames: vec![FrameF32(0.0, 0.0); FRAMES_PER_PACKET],
+            encoded: vec![0; Audio::MAX_BUFFER_LENGTH].into_boxed_slice(),
+        }
+    }
+
+    /// When the next packet is due to be sent
+    pub fn due(&self) -> Micros {
+        self.start + (self.header.seq - 1) * self.packet_micros
+    }
+
+    pub fn next_packet(&mut self) -> Audio {
+        let step = TAU * TONE_HZ / SAMPLE_RATE.0 as f32;
+
+        for frame in &mut self.frames {
+            let sample = self.phase.sin() * TONE_AMPLITUDE;
+            *frame = FrameF32(sample, sample);
+            self.phase = (self.phase + step) % TAU;
+        }
+
+        let len = self.encoder.encode_packet(F32::frames(&self.frames), &mut self.encoded)
+            .expect("encode packet");
+
+        let now = self.due();
+        self.header.pts = TimestampMicros(now + self.delay);
+        self.header.dts = TimestampMicros(now);
+
+        let audio = Audio::new(&self.header, &self.encoded[0..len])
+            .expect("allocate Audio packet");
+
+        self.header.seq += 1;
+        audio
+    }
+}
@@ -0,0 +1,60 @@
+//! The scenarios from the README, which bark is expected to play through
+//! without a click and in sync
+
+use bark_sim::{Outcome, Scenario};
+
+fn check(outcome: &Outcome, scenario: &Scenario) {
+    let stats = &outcome.stats;
+
+    assert_eq!(stats.missed, 0, "missed packets: {outcome:?}");
+    assert_eq!(stats.late, 0, "late packets: {outcome:?}");
+    assert_eq!(stats.clicks, 0, "clicks: {outcome:?}");
+    assert_eq!(outcome.underruns, 0, "underruns: {outcome:?}");
+
+    let offset = outcome.worst_offset.expect("nothing played after settling");
+    assert!(offset <= scenario.sync_tolerance_ms * 1000.0, "out of sync: {outcome:?}");
+
+    assert!(outcome.passed(), "{outcome:?}");
+}
+
+#[test]
+fn perfect_network() {
+    let scenario = Scenario::default();
+    let outcome = bark_sim::run(&scenario);
+
+    check(&outcome, &scenario);
+    assert_eq!(outcome.dropped, 0);
+    assert_eq!(outcome.reordered, 0);
+    assert_eq!(outcome.stats.lost, 0);
+}
+
+#[test]
+fn poor_network() {
+    let scenario = Scenario {
+        loss: 2.0,
+        jitter_ms: 5.0,
+        reorder: 1.0,
+        ..Scenario::default()
+    };
+
+    let outcome = bark_sim::run(&scenario);
+
+    check(&outcome, &scenario);
+    assert!(outcome.dropped > 0);
+    assert!(outcome.reordered > 0);
+}
+
+#[test]
+fn fast_sound_card() {
+    let scenario = Scenario {
+        drift_ppm: 150.0,
+        delay_ms: 40,
+        seed: 7,
+        ..Scenario::default()
+    };
+
+    let outcome = bark_sim::run(&scenario);
+
+    check(&outcome, &scenario);
+    assert!(outcome.drift_ppm.is_some());
+}
@@ -0,0 +1,100 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use structopt::StructOpt;
+
+use bark_core::audio::{Frames, FrameF32};
+use bark_core::encode::Encode;
+use bark_core::encode::pcm::F32LEEncoder;
+use bark_protocol::packet::Audio;
+use bark_protocol::time::{SampleDuration, Timestamp};
+use bark_protocol::types::{AudioPacketHeader, SessionId};
+use bark_protocol::FRAMES_PER_PACKET;
+
+use crate::socket::{ProtocolSocket, Socket, SocketOpt};
+use crate::stream::pacer::Pacer;
+use crate::{time, wav, RunError};
+
+#[derive(StructOpt)]
+pub struct AnnounceOpt {
+    #[structopt(flatten)]
+    pub socket: SocketOpt,
+
+    /// WAV file to play: 16 bit or float, mono or stereo, at 48 kHz
+    pub file: PathBuf,
+
+    /// Priority of the announcement. Receivers switch to it from any lower
+    /// priority stream, and fade back to that stream once it finishes
+    #[structopt(long, default_value = "100")]
+    pub priority: i8,
+
+    #[structopt(long, default_value = "100")]
+    pub delay_ms: u64,
+}
+
+/// Stream a short clip to every receiver as a session of its own, over the
+/// top of whatever they are playing
+pub fn run(opt: AnnounceOpt) -> Result<(), RunError> {
+    let mut frames = wav::read(&opt.file)?;
+
+    let socket = Socket::open(&opt.socket)
+        .map_err(RunError::Listen)?;
+
+    let protocol = ProtocolSocket::new(socket);
+
+    let delay = SampleDuration::from_std_duration_lossy(Duration::from_millis(opt.delay_ms));
+
+    // trailing silence, so that the clip has finished playing before the
+    // last packet is sent and receivers go back to what they were playing
+    let length = frames.len() + delay.to_frame_count() as usize;
+    let packets = length.div_ceil(FRAMES_PER_PACKET);
+    frames.resize(packets * FRAMES_PER_PACKET, FrameF32(0.0, 0.0));
+
+    let now = time::now();
+    let sid = SessionId(now.0 as i64);
+    let begin = Timestamp::from_micros_lossy(now);
+    let start = begin.add(delay);
+
+    let mut encoder = F32LEEncoder;
+
+    log::info!("announcing {}: {}ms, priority={} sid={}",
+        opt.file.display(),
+        SampleDuration::from_frame_count(length).to_micros_lossy() / 1000,
+        opt.priority,
+        sid.0);
+
+    let mut pacer = Pacer::new(begin);
+
+    for (seq, chunk) in (1..).zip(frames.chunks_exact(FRAMES_PER_PACKET)) {
+        let offset = SampleDuration::from_frame_count((seq as usize - 1) * FRAMES_PER_PACKET);
+
+        // send each packet the stream delay ahead of when it plays
+        let send_at = pacer.wait(offset);
+
+        let header = AudioPacketHeader {
+            sid,
+            seq,
+            pts: send_at.add(delay).to_micros_lossy(),
+            dts: time::now(),
+            format: encoder.header_format(),
+            priority: opt.priority,
+            packet_frames: FRAMES_PER_PACKET as u16,
+            delay_ms: u16::try_from(opt.delay_ms).unwrap_or(u16::MAX),
+            min_buffer_ms: 0,
+            // every receiver begins the announcement together
+            start_pts: start.to_micros_lossy(),
+        };
+
+        let mut buffer = [0; Audio::MAX_BUFFER_LENGTH];
+        let length = encoder.encode_packet(Frames::F32(chunk), &mut buffer)
+            .expect("encode announcement");
+
+        let audio = Audio::new(&header, &buffer[0..length])
+            .expect("allocate Audio packet");
+
+        protocol.broadcast(audio.as_packet())
+            .map_err(RunError::Send)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,84 @@
+//! A2DP output to Bluetooth speakers, through bluez-alsa's ALSA plugin.
+//!
+//! Bluetooth carries audio in bursts, a codec frame at a time, so the
+//! delay the plugin reports swings by tens of milliseconds from one write
+//! to the next while the speaker itself plays steadily out of its own
+//! buffer. Fed straight into stream timing, that would have the resampler
+//! chasing the bursts. Instead the delay is smoothed into an estimate of
+//! the latency underneath them, which is also what changes when the link
+//! does, such as when the speaker renegotiates its codec.
+
+use bark_protocol::time::SampleDuration;
+
+/// Prefix of device names selecting a Bluetooth speaker by address, eg.
+/// `bluetooth:00:11:22:33:44:55`
+pub const PREFIX: &str = "bluetooth:";
+
+/// Smallest buffer Bluetooth outputs are opened with, to ride out the
+/// bursts. 50ms at 48 kHz
+pub const MIN_LATENCY: SampleDuration = SampleDuration::from_frame_count(2400);
+
+/// Weight of each new delay reading in the estimate. Writes come every
+/// packet, so this settles over a second or so
+const SMOOTHING: f64 = 1.0 / 256.0;
+
+/// A reading this far from the estimate is not a burst but a change in
+/// the link, and the estimate starts over from it. 100ms at 48 kHz
+const RESET_FRAMES: f64 = 4800.0;
+
+/// How far the estimate moves before it is logged again. 5ms at 48 kHz
+const LOG_FRAMES: f64 = 240.0;
+
+/// The Bluetooth address a device name selects, if it selects one
+pub fn address(device: &str) -> Option<&str> {
+    device.strip_prefix(PREFIX)
+}
+
+/// ALSA PCM playing to the A2DP sink of the speaker at `address`
+pub fn pcm_name(address: &str) -> String {
+    format!("bluealsa:DEV={address},PROFILE=a2dp")
+}
+
+/// Estimates the latency of a Bluetooth output from the delays bluez-alsa
+/// reports. These include the speaker's own latency only if it reports it
+/// over AVDTP, as many don't. Anything left over is added with
+/// `--device-extra-latency-ms`
+pub struct DelayModel {
+    estimate: Option<f64>,
+    logged: f64,
+}
+
+impl DelayModel {
+    pub fn new() -> Self {
+        DelayModel {
+            estimate: None,
+            logged: 0.0,
+        }
+    }
+
+    /// Take a delay reading from the device, returning the estimate
+    pub fn update(&mut self, delay: SampleDuration) -> SampleDuration {
+        let frames = delay.to_frame_count() as f64;
+
+        let estimate = match self.estimate {
+            Some(estimate) if (frames - estimate).abs() < RESET_FRAMES => {
+                estimate + (frames - estimate) * SMOOTHING
+            }
+            Some(_) => {
+                log::info!("bluetooth output latency jumped, estimating afresh");
+                frames
+            }
+            None => frames,
+        };
+
+        self.estimate = Some(estimate);
+
+        if (estimate - self.logged).abs() >= LOG_FRAMES {
+            let ms = estimate * 1000.0 / f64::from(bark_protocol::SAMPLE_RATE.0);
+            log::info!("bluetooth output latency estimated at {ms:.1}ms");
+            self.logged = estimate;
+        }
+
+        SampleDuration::from_frame_count(estimate.round() as usize)
+    }
+}
@@ -1,10 +1,12 @@
-use alsa::{Direction, PCM, pcm::{HwParams, Format, Access}, ValueOr};
+use alsa::{Direction, PCM, pcm::{HwParams, Format, Frames, Access}, ValueOr};
 use thiserror::Error;
 
 use bark_core::audio::FormatKind;
 use bark_protocol::time::SampleDuration;
 
-use crate::audio::config::DeviceOpt;
+use crate::audio::alsa::bluetooth;
+use crate::audio::config::{DeviceOpt, PERIODS_PER_BUFFER};
+use crate::audio::Sizes;
 
 #[derive(Debug, Error)]
 pub enum OpenError {
@@ -19,8 +21,21 @@ pub enum OpenError {
 pub fn open_pcm(opt: &DeviceOpt, format: FormatKind, direction: Direction)
     -> Result<PCM, OpenError>
 {
-    let device_name = opt.device.as_deref().unwrap_or("default");
-    let pcm = PCM::new(device_name, direction, false)?;
+    let bluetooth = opt.device.as_deref().and_then(bluetooth::address);
+
+    let device_name = match (bluetooth, opt.shared) {
+        (Some(address), _) => bluetooth::pcm_name(address),
+        (None, true) => shared_device_name(opt.device.as_deref()),
+        (None, false) => opt.device.as_deref().unwrap_or("default").to_owned(),
+    };
+
+    // bluetooth needs more buffer than the default to ride out its bursts
+    let opt = &match bluetooth {
+        Some(_) => DeviceOpt { latency: opt.latency.max(bluetooth::MIN_LATENCY), ..opt.clone() },
+        None => opt.clone(),
+    };
+
+    let pcm = PCM::new(&device_name, direction, false)?;
 
     {
         let hwp = HwParams::any(&pcm)?;
@@ -31,8 +46,7 @@ pub fn open_pcm(opt: &DeviceOpt, format: FormatKind, direction: Direction)
             FormatKind::S16 => Format::s16(),
         })?;
         hwp.set_access(Access::RWInterleaved)?;
-        set_period_size(&hwp, opt.period)?;
-        set_buffer_size(&hwp, opt.buffer)?;
+        set_sizes(&hwp, opt)?;
         pcm.hw_params(&hwp)?;
     }
 
@@ -43,11 +57,86 @@ pub fn open_pcm(opt: &DeviceOpt, format: FormatKind, direction: Direction)
     }
 
     let (buffer, period) = pcm.get_params()?;
-    log::info!("opened ALSA with buffer_size={buffer}, period_size={period}");
+    let latency = SampleDuration::from_frame_count_u64(buffer).to_std_duration_lossy();
+    log::info!("opened ALSA with buffer_size={buffer}, period_size={period} ({:.1}ms)",
+        latency.as_secs_f64() * 1000.0);
 
     Ok(pcm)
 }
 
+/// Period and buffer size a device opens with, closing it again straight
+/// away
+pub fn sizes(opt: &DeviceOpt, direction: Direction) -> Result<Sizes, OpenError> {
+    let pcm = open_pcm(opt, FormatKind::F32, direction)?;
+    let (buffer, period) = pcm.get_params()?;
+
+    Ok(Sizes {
+        period: SampleDuration::from_frame_count_u64(period),
+        buffer: SampleDuration::from_frame_count_u64(buffer),
+    })
+}
+
+// sizes any of period and buffer that weren't configured against the target
+// latency, within the limits the device supports. configured sizes are used
+// as given so they can override a bad automatic choice
+fn set_sizes(hwp: &HwParams, opt: &DeviceOpt) -> Result<(), OpenError> {
+    let latency = frames(opt.latency);
+    let periods = PERIODS_PER_BUFFER as Frames;
+
+    let period = match opt.period {
+        // dmix shares one period and buffer size between all its clients,
+        // so configured sizes are only a preference
+        Some(period) if opt.shared => hwp.set_period_size_near(frames(period), ValueOr::Nearest)?,
+        Some(period) => {
+            set_period_size(hwp, period)?;
+            hwp.get_period_size()?
+        }
+        None => {
+            let min = hwp.get_period_size_min()?;
+            let max = hwp.get_period_size_max()?;
+            let buffer = opt.buffer.map(frames).unwrap_or(latency);
+            let period = hwp.set_period_size_near((buffer / periods).clamp(min, max), ValueOr::Nearest)?;
+            log::info!("chose period_size={period} (device supports {min}..={max})");
+            period
+        }
+    };
+
+    match opt.buffer {
+        Some(buffer) if opt.shared => { hwp.set_buffer_size_near(frames(buffer))?; }
+        Some(buffer) => set_buffer_size(hwp, buffer)?,
+        None => {
+            // the buffer must fit enough periods that one is always in
+            // flight, even if that means exceeding the target latency
+            let min = hwp.get_buffer_size_min()?;
+            let max = hwp.get_buffer_size_max()?;
+            let buffer = hwp.set_buffer_size_near(latency.max(period * periods).clamp(min, max))?;
+            log::info!("chose buffer_size={buffer} (device supports {min}..={max})");
+        }
+    }
+
+    Ok(())
+}
+
+// route hardware devices through dmix, and plug to convert to whatever
+// format dmix was configured with. other devices are assumed to be plugins
+// that can already be shared, such as dmix itself or a sound server
+fn shared_device_name(device: Option<&str>) -> String {
+    let device = device.unwrap_or("default");
+
+    let card = device.strip_prefix("hw:")
+        .or_else(|| device.strip_prefix("plughw:"));
+
+    match (device, card) {
+        ("default", _) => "plug:dmix".to_owned(),
+        (_, Some(card)) => format!("plug:'dmix:{card}'"),
+        (device, None) => device.to_owned(),
+    }
+}
+
+fn frames(duration: SampleDuration) -> Frames {
+    duration.to_frame_count().try_into().unwrap_or(Frames::MAX)
+}
+
 // period is the size of the discrete chunks of data that are sent to hardware
 fn set_period_size(hwp: &HwParams, period: SampleDuration)
     -> Result<(), OpenError>
@@ -64,7 +153,7 @@ fn set_period_size(hwp: &HwParams, period: SampleDuration)
     Ok(())
 }
 
-// period is the size of the discrete chunks of data that are sent to hardware
+// buffer is the total amount of audio queued for the hardware
 fn set_buffer_size(hwp: &HwParams, buffer: SampleDuration)
     -> Result<(), OpenError>
 {
@@ -0,0 +1,63 @@
+use std::ffi::CString;
+
+use alsa::device_name::HintIter;
+use alsa::pcm::{Format, HwParams};
+use alsa::{Direction, PCM};
+
+use crate::audio::{Capabilities, DeviceInfo};
+
+/// Sample formats worth reporting, in the order they're listed
+const FORMATS: &[Format] = &[
+    Format::S16LE,
+    Format::S243LE,
+    Format::S24LE,
+    Format::S32LE,
+    Format::FloatLE,
+];
+
+/// Every PCM that ALSA knows of, probed for what it supports in each
+/// direction it can be opened in
+pub fn list() -> Result<Vec<DeviceInfo>, alsa::Error> {
+    let iface = CString::new("pcm").unwrap();
+
+    let devices = HintIter::new(None, &iface)?
+        .filter_map(|hint| {
+            let name = hint.name?;
+
+            // a hint without a direction is usable for both
+            let input = hint.direction != Some(Direction::Playback);
+            let output = hint.direction != Some(Direction::Capture);
+
+            Some(DeviceInfo {
+                input: input.then(|| probe(&name, Direction::Capture)),
+                output: output.then(|| probe(&name, Direction::Playback)),
+                // descriptions run over several lines
+                description: hint.desc.map(|desc| desc.lines().collect::<Vec<_>>().join(", ")),
+                name,
+            })
+        })
+        .collect();
+
+    Ok(devices)
+}
+
+fn probe(name: &str, direction: Direction) -> Result<Capabilities, crate::audio::Error> {
+    // non-blocking, so that a device in use fails straight away with EBUSY
+    // rather than waiting for it to be released
+    let pcm = PCM::new(name, direction, true)?;
+    let hwp = HwParams::any(&pcm)?;
+
+    let formats = FORMATS.iter()
+        .filter(|format| hwp.test_format(**format).is_ok())
+        .map(|format| format.to_string())
+        .collect();
+
+    Ok(Capabilities {
+        rates: hwp.get_rate_min()?..=hwp.get_rate_max()?,
+        channels: hwp.get_channels_min()?..=hwp.get_channels_max()?,
+        formats,
+        native: hwp.test_rate(bark_protocol::SAMPLE_RATE.0).is_ok()
+            && hwp.test_channels(bark_protocol::CHANNELS.0.into()).is_ok()
+            && (hwp.test_format(Format::s16()).is_ok() || hwp.test_format(Format::float()).is_ok()),
+    })
+}
@@ -3,67 +3,139 @@ use std::marker::PhantomData;
 use alsa::Direction;
 use alsa::pcm::{IoFormat, PCM};
 use bark_core::audio::{self, Format, FramesMut, F32, S16};
+use bytemuck::Zeroable;
 use bark_protocol::time::{Timestamp, SampleDuration};
 
 use crate::audio::config::DeviceOpt;
 use crate::audio::alsa::config::{self, OpenError};
+use crate::audio::alsa::reconnect;
+use crate::reconnect::Reconnect;
 use crate::time;
 
 pub struct Input<F: Format> {
+    opt: DeviceOpt,
+    // None while the device is gone, until it can be reopened
+    device: Option<Device>,
+    reconnect: Reconnect,
+    reconnects: u64,
+    _phantom: PhantomData<F>,
+}
+
+struct Device {
     pcm: PCM,
     quantum: SampleDuration,
-    _phantom: PhantomData<F>,
 }
 
-impl<F: Format> Input<F> {
-    pub fn new(opt: &DeviceOpt) -> Result<Self, OpenError> {
+impl Device {
+    fn open<F: Format>(opt: &DeviceOpt) -> Result<Self, OpenError> {
         let pcm = config::open_pcm(opt, F::KIND, Direction::Capture)?;
         let (_buffer, period) = pcm.get_params()?;
-        Ok(Input {
+        Ok(Device {
             pcm,
             quantum: SampleDuration::from_frame_count_u64(period),
+        })
+    }
+
+    fn delay(&self) -> Result<SampleDuration, alsa::Error> {
+        let frames = self.pcm.delay()?;
+        let frames = u64::try_from(frames).expect("pcm delay is negative");
+        Ok(SampleDuration::from_frame_count_u64(frames))
+    }
+}
+
+impl<F: Format> Input<F> {
+    pub fn new(opt: &DeviceOpt) -> Result<Self, OpenError> {
+        Ok(Input {
+            opt: opt.clone(),
+            device: Some(Device::open::<F>(opt)?),
+            reconnect: reconnect::start(),
+            reconnects: 0,
             _phantom: PhantomData,
         })
     }
 
-    pub fn read(&self, frames: &mut [F::Frame]) -> Result<Timestamp, alsa::Error> {
-        match F::frames_mut(frames) {
-            FramesMut::S16(frames) => read_impl::<S16>(&self.pcm, frames)?,
-            FramesMut::F32(frames) => read_impl::<F32>(&self.pcm, frames)?,
+    /// Times the device has been reopened after disconnecting
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.device.is_some()
+    }
+
+    /// Read audio from the device. While the device is disconnected, reads
+    /// return silence at the rate audio would have been captured
+    pub fn read(&mut self, frames: &mut [F::Frame]) -> Result<Timestamp, alsa::Error> {
+        self.reopen();
+
+        let Some(device) = &self.device else {
+            return Ok(silence::<F>(frames));
+        };
+
+        match read_device::<F>(device, frames) {
+            Err(err) if reconnect::is_disconnect(&err) => {
+                log::warn!("audio input device disconnected, will keep trying to reopen it: {err}");
+                self.device = None;
+                self.reconnect = reconnect::start();
+                Ok(silence::<F>(frames))
+            }
+            result => result,
         }
+    }
 
-        // calculate timestamp of this packet of audio.
-        //
-        // each quantum (aka period in ALSA terminology) of audio received
-        // from ALSA is assumed to begin at the timestamp it first enters the
-        // buffer.
-        //
-        // to calculate this time, take the current time, add the quantum, and
-        // subtract the current buffer delay (number of frames currently in the
-        // buffer + HW latency if applicable), making sure to compensate delay
-        // for the number of frames we just read.
-        //
-        // when quantum > bark packet size, we'll make multiple successful
-        // reads here without blocking, so the current time can be assumed to
-        // be ~roughly the same for each packet in a quantum.
-
-        let now = time::now();
-
-        let delay = self.delay()?
-            .add(SampleDuration::from_frame_count(frames.len()));
-
-        let timestamp = Timestamp::from_micros_lossy(now)
-            .add(self.quantum)
-            .saturating_sub(delay);
-
-        Ok(timestamp)
+    /// Try reopening the device if it has gone and a retry is due
+    fn reopen(&mut self) {
+        if self.device.is_none() && self.reconnect.due() {
+            match Device::open::<F>(&self.opt) {
+                Ok(device) => {
+                    log::info!("audio input device reconnected");
+                    self.reconnects += 1;
+                    self.device = Some(device);
+                }
+                Err(err) => reconnect::failed(&mut self.reconnect, &err),
+            }
+        }
     }
+}
 
-    fn delay(&self) -> Result<SampleDuration, alsa::Error> {
-        let frames = self.pcm.delay()?;
-        let frames = u64::try_from(frames).expect("pcm delay is negative");
-        Ok(SampleDuration::from_frame_count_u64(frames))
+/// Stand in for a read while the device is disconnected
+fn silence<F: Format>(frames: &mut [F::Frame]) -> Timestamp {
+    frames.fill(F::Frame::zeroed());
+    reconnect::pace(frames.len());
+    Timestamp::from_micros_lossy(time::now())
+}
+
+fn read_device<F: Format>(device: &Device, frames: &mut [F::Frame]) -> Result<Timestamp, alsa::Error> {
+    match F::frames_mut(frames) {
+        FramesMut::S16(frames) => read_impl::<S16>(&device.pcm, frames)?,
+        FramesMut::F32(frames) => read_impl::<F32>(&device.pcm, frames)?,
     }
+
+    // calculate timestamp of this packet of audio.
+    //
+    // each quantum (aka period in ALSA terminology) of audio received
+    // from ALSA is assumed to begin at the timestamp it first enters the
+    // buffer.
+    //
+    // to calculate this time, take the current time, add the quantum, and
+    // subtract the current buffer delay (number of frames currently in the
+    // buffer + HW latency if applicable), making sure to compensate delay
+    // for the number of frames we just read.
+    //
+    // when quantum > bark packet size, we'll make multiple successful
+    // reads here without blocking, so the current time can be assumed to
+    // be ~roughly the same for each packet in a quantum.
+
+    let now = time::now();
+
+    let delay = device.delay()?
+        .add(SampleDuration::from_frame_count(frames.len()));
+
+    let timestamp = Timestamp::from_micros_lossy(now)
+        .add(device.quantum)
+        .saturating_sub(delay);
+
+    Ok(timestamp)
 }
 
 fn read_impl<F: Format>(pcm: &PCM, mut frames: &mut [F::Frame])
@@ -1,3 +1,6 @@
+pub mod bluetooth;
 pub mod config;
+pub mod devices;
 pub mod input;
 pub mod output;
+pub mod reconnect;
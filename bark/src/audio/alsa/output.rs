@@ -7,47 +7,178 @@ use bark_core::audio::{self, Format, Frames, F32, S16};
 use bark_protocol::time::SampleDuration;
 
 use crate::audio::config::DeviceOpt;
+use crate::audio::alsa::bluetooth::{self, DelayModel};
 use crate::audio::alsa::config::{self, OpenError};
+use crate::audio::alsa::reconnect;
+use crate::reconnect::Reconnect;
 use crate::stats::ReceiverMetrics;
 
 pub struct Output<F: Format> {
-    inner: Inner,
+    opt: DeviceOpt,
+    // None while the device is gone, until it can be reopened
+    device: Option<Device>,
+    reconnect: Reconnect,
+    // whether writes take as long as the audio would to play while the
+    // device is gone, see set_paced
+    paced: bool,
+    metrics: ReceiverMetrics,
     _phantom: PhantomData<F>,
 }
 
-struct Inner {
+struct Device {
     pcm: PCM,
-    metrics: ReceiverMetrics,
+    // added to the delay ALSA reports, see Device::open
+    extra_delay: SampleDuration,
+    // ALSA buffer size
+    buffer: SampleDuration,
+    // smooths the delay of bluetooth outputs, which arrives in bursts
+    model: Option<DelayModel>,
 }
 
-impl<F: Format> Output<F> {
-    pub fn new(opt: &DeviceOpt, metrics: ReceiverMetrics) -> Result<Self, OpenError> {
+impl Device {
+    fn open<F: Format>(opt: &DeviceOpt) -> Result<Self, OpenError> {
         let pcm = config::open_pcm(opt, F::KIND, Direction::Playback)?;
 
+        // dmix reports only the frames ahead of the hardware pointer of its
+        // shared buffer, which moves a whole period at a time. count half a
+        // period on average as still waiting to play, or we run early
+        let (buffer, period) = pcm.get_params()?;
+
+        let extra_delay = if opt.shared {
+            SampleDuration::from_frame_count_u64(period / 2)
+        } else {
+            SampleDuration::zero()
+        };
+
+        let buffer = SampleDuration::from_frame_count_u64(buffer);
+
+        let model = opt.device.as_deref()
+            .and_then(bluetooth::address)
+            .map(|_| DelayModel::new());
+
+        Ok(Device { pcm, extra_delay, buffer, model })
+    }
+}
+
+impl<F: Format> Output<F> {
+    pub fn new(opt: &DeviceOpt, metrics: ReceiverMetrics) -> Result<Self, OpenError> {
         Ok(Output {
-            inner: Inner {
-                pcm,
-                metrics,
-            },
+            opt: opt.clone(),
+            device: Some(Device::open::<F>(opt)?),
+            reconnect: reconnect::start(),
+            paced: true,
+            metrics,
             _phantom: PhantomData,
         })
     }
 
-    pub fn write(&self, frames: &[F::Frame]) -> Result<(), alsa::Error> {
-        match F::frames(frames) {
-            Frames::S16(frames) => write_impl::<S16>(&self.inner, frames),
-            Frames::F32(frames) => write_impl::<F32>(&self.inner, frames),
+    /// Whether writes made while the device is disconnected should wait as
+    /// long as the audio would have taken to play. Only one of several
+    /// devices driven together should pace, or each outage adds up
+    pub fn set_paced(&mut self, paced: bool) {
+        self.paced = paced;
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.device.is_some()
+    }
+
+    fn pace(&self, frames: usize) {
+        if self.paced {
+            reconnect::pace(frames);
+        }
+    }
+
+    /// Write audio to the device. While the device is disconnected, audio
+    /// is dropped at the rate it would have played
+    pub fn write(&mut self, frames: &[F::Frame]) -> Result<(), alsa::Error> {
+        self.reopen();
+
+        let Some(device) = &self.device else {
+            self.pace(frames.len());
+            return Ok(());
+        };
+
+        let result = match F::frames(frames) {
+            Frames::S16(frames) => write_impl::<S16>(device, &self.metrics, frames),
+            Frames::F32(frames) => write_impl::<F32>(device, &self.metrics, frames),
+        };
+
+        match result {
+            Err(err) if reconnect::is_disconnect(&err) => {
+                self.disconnected(&err);
+                self.pace(frames.len());
+                Ok(())
+            }
+            result => result,
         }
     }
 
-    pub fn delay(&self) -> Result<SampleDuration, alsa::Error> {
-        let frames = recover(&self.inner, || self.inner.pcm.delay())?;
+    /// Delay of audio written now. Zero while the device is disconnected
+    pub fn delay(&mut self) -> Result<SampleDuration, alsa::Error> {
+        self.reopen();
+
+        let Some(device) = &mut self.device else {
+            return Ok(SampleDuration::zero());
+        };
+
+        let frames = match recover(&device.pcm, &self.metrics, || device.pcm.delay()) {
+            Ok(frames) => frames,
+            Err(err) if reconnect::is_disconnect(&err) => {
+                self.disconnected(&err);
+                return Ok(SampleDuration::zero());
+            }
+            Err(err) => { return Err(err); }
+        };
+
         let frames = u64::try_from(frames).expect("pcm delay is negative");
-        Ok(SampleDuration::from_frame_count_u64(frames))
+        let delay = SampleDuration::from_frame_count_u64(frames).add(device.extra_delay);
+
+        Ok(match &mut device.model {
+            Some(model) => model.update(delay),
+            None => delay,
+        })
+    }
+
+    /// Delay of audio written now with the device's buffer full, as `delay`
+    /// would report it. None while the device is disconnected
+    pub fn buffer(&self) -> Option<SampleDuration> {
+        self.device.as_ref().map(|device| device.buffer.add(device.extra_delay))
+    }
+
+    /// Try reopening the device if it has gone and a retry is due
+    fn reopen(&mut self) {
+        if self.device.is_none() && self.reconnect.due() {
+            match Device::open::<F>(&self.opt) {
+                Ok(device) => {
+                    log::info!("audio output device reconnected");
+                    self.metrics.output_reconnects.increment();
+                    self.metrics.health.device_restored();
+                    self.device = Some(device);
+                }
+                Err(err) => reconnect::failed(&mut self.reconnect, &err),
+            }
+        }
+    }
+
+    fn disconnected(&mut self, err: &alsa::Error) {
+        log::warn!("audio output device disconnected, will keep trying to reopen it: {err}");
+        self.device = None;
+        self.reconnect = reconnect::start();
+        self.metrics.health.device_lost();
+    }
+}
+
+impl<F: Format> Drop for Output<F> {
+    fn drop(&mut self) {
+        // a device closed while disconnected no longer needs reopening
+        if self.device.is_none() {
+            self.metrics.health.device_restored();
+        }
     }
 }
 
-fn recover<T>(output: &Inner, func: impl Fn() -> Result<T, alsa::Error>) -> Result<T, alsa::Error> {
+fn recover<T>(pcm: &PCM, metrics: &ReceiverMetrics, func: impl Fn() -> Result<T, alsa::Error>) -> Result<T, alsa::Error> {
     loop {
         let err = match func() {
             Ok(value) => { return Ok(value); }
@@ -61,10 +192,10 @@ fn recover<T>(output: &Inner, func: impl Fn() -> Result<T, alsa::Error>) -> Resu
             | libc::EINTR // interrupted syscall
             => {
                 // try to recover
-                output.pcm.recover(err.errno(), false)?;
+                pcm.recover(err.errno(), false)?;
 
                 if err.errno() == libc::EPIPE {
-                    output.metrics.buffer_underruns.increment();
+                    metrics.buffer_underruns.increment();
                 }
             }
             _ => { return Err(err); }
@@ -72,27 +203,27 @@ fn recover<T>(output: &Inner, func: impl Fn() -> Result<T, alsa::Error>) -> Resu
     }
 }
 
-fn write_impl<F: Format>(output: &Inner, mut frames: &[F::Frame])
+fn write_impl<F: Format>(device: &Device, metrics: &ReceiverMetrics, mut frames: &[F::Frame])
     -> Result<(), alsa::Error>
     where F::Sample: IoFormat
 {
     while frames.len() > 0 {
-        let n = write_partial_impl::<F>(output, frames)?;
+        let n = write_partial_impl::<F>(device, metrics, frames)?;
         frames = &frames[n..];
     }
 
     Ok(())
 }
 
-fn write_partial_impl<F: Format>(output: &Inner, samples: &[F::Frame])
+fn write_partial_impl<F: Format>(device: &Device, metrics: &ReceiverMetrics, samples: &[F::Frame])
     -> Result<usize, alsa::Error>
     where F::Sample: IoFormat
 {
     let io = unsafe {
         // the checked versions of this function call
         // snd_pcm_hw_params_current which mallocs under the hood
-        output.pcm.io_unchecked::<F::Sample>()
+        device.pcm.io_unchecked::<F::Sample>()
     };
 
-    recover(output, || io.writei(audio::as_interleaved::<F>(samples)))
+    recover(&device.pcm, metrics, || io.writei(audio::as_interleaved::<F>(samples)))
 }
@@ -0,0 +1,40 @@
+//! Recovery from devices that go away, such as a USB DAC being unplugged.
+//! While a device is gone, reads and writes are paced as though it were
+//! still there so that the rest of bark carries on undisturbed, and the
+//! device is reopened with exponential backoff until it comes back.
+
+use std::time::Duration;
+
+use bark_protocol::time::SampleDuration;
+
+use crate::reconnect::Reconnect;
+
+const MIN_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Whether an error means the device has gone, rather than an xrun or
+/// other condition the pcm can recover from in place
+pub fn is_disconnect(err: &alsa::Error) -> bool {
+    matches!(err.errno(),
+        | libc::ENODEV // device unplugged
+        | libc::ENXIO
+        | libc::EBADFD // pcm left unusable
+        | libc::EIO)
+}
+
+/// Start reopening a device that has gone, with the first attempt
+/// straight away
+pub fn start() -> Reconnect {
+    Reconnect::new(MIN_BACKOFF, MAX_BACKOFF)
+}
+
+/// Back off after a failed attempt at reopening the device
+pub fn failed(reconnect: &mut Reconnect, err: &dyn std::fmt::Display) {
+    let retry = reconnect.failed();
+    log::debug!("reopening audio device failed, retrying in {}ms: {err}", retry.as_millis());
+}
+
+/// Stand in for the time a device would take to play or capture `frames`
+pub fn pace(frames: usize) {
+    std::thread::sleep(SampleDuration::from_frame_count(frames).to_std_duration_lossy());
+}
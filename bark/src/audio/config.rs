@@ -1,10 +1,28 @@
+use std::time::Duration;
+
 use bark_protocol::time::SampleDuration;
 
-pub const DEFAULT_PERIOD: SampleDuration = SampleDuration::from_frame_count(120);
-pub const DEFAULT_BUFFER: SampleDuration = SampleDuration::from_frame_count(360);
+/// Target device buffer latency when none is configured
+pub const DEFAULT_LATENCY: SampleDuration = SampleDuration::from_frame_count(360);
+
+/// Number of periods in the device buffer when sizing automatically
+pub const PERIODS_PER_BUFFER: u64 = 3;
 
+#[derive(Clone)]
 pub struct DeviceOpt {
     pub device: Option<String>,
-    pub period: SampleDuration,
-    pub buffer: SampleDuration,
+    /// Period size, or sized from the target latency if not set
+    pub period: Option<SampleDuration>,
+    /// Buffer size, or sized from the target latency if not set
+    pub buffer: Option<SampleDuration>,
+    pub latency: SampleDuration,
+    /// Open through dmix so other applications can use the device too
+    pub shared: bool,
+}
+
+/// Target latency from a configured number of milliseconds
+pub fn latency(latency_ms: Option<f64>) -> SampleDuration {
+    latency_ms
+        .map(|ms| SampleDuration::from_std_duration_lossy(Duration::from_secs_f64(ms / 1000.0)))
+        .unwrap_or(DEFAULT_LATENCY)
 }
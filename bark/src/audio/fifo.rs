@@ -0,0 +1,82 @@
+//! Audio input from a named pipe carrying raw PCM, such as MPD's fifo
+//! output. Audio must be stereo at 48 kHz in the input format, little
+//! endian: `48000:16:2` for s16 or `48000:f:2` for f32 in MPD's terms.
+//!
+//! Players writing to a pipe pace themselves only roughly, so reads are
+//! paced against the protocol clock and timestamped with when they were
+//! due. Once the player pauses and the pipe runs dry, pacing starts afresh
+//! with the next audio rather than trying to catch up.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use bark_core::audio::Format;
+use bark_protocol::time::{SampleDuration, Timestamp};
+use thiserror::Error;
+
+use crate::stream::pacer::Pacer;
+use crate::time;
+
+/// How long the pipe may run dry before the player is taken to have
+/// paused, and pacing starts again. 100ms at 48 kHz, before the pacer
+/// would slip its schedule itself and warn about it
+const MAX_STALL: SampleDuration = SampleDuration::from_frame_count(4800);
+
+#[derive(Debug, Error)]
+#[error("opening {0}: {1}")]
+pub struct OpenError(PathBuf, io::Error);
+
+pub struct Input<F: Format> {
+    file: File,
+    pacer: Option<Pacer>,
+    // audio read since pacing last started
+    offset: SampleDuration,
+    _phantom: PhantomData<F>,
+}
+
+impl<F: Format> Input<F> {
+    pub fn new(path: &Path) -> Result<Self, OpenError> {
+        log::info!("reading audio from {}", path.display());
+
+        Ok(Input {
+            file: open(path)?,
+            pacer: None,
+            offset: SampleDuration::zero(),
+            _phantom: PhantomData,
+        })
+    }
+
+    /// Read audio from the pipe, waiting for the player to write it. Returns
+    /// the time the first frame was due
+    pub fn read(&mut self, frames: &mut [F::Frame]) -> Result<Timestamp, io::Error> {
+        self.file.read_exact(bytemuck::cast_slice_mut::<F::Frame, u8>(frames))?;
+
+        let now = Timestamp::from_micros_lossy(time::now());
+
+        let stalled = self.pacer.as_ref()
+            .is_some_and(|pacer| now.saturating_duration_since(pacer.due(self.offset)) > MAX_STALL);
+
+        if self.pacer.is_none() || stalled {
+            self.pacer = Some(Pacer::new(now));
+            self.offset = SampleDuration::zero();
+        }
+
+        let timestamp = self.pacer.as_mut().unwrap().wait(self.offset);
+        self.offset = self.offset.add(SampleDuration::from_frame_count(frames.len()));
+
+        Ok(timestamp)
+    }
+}
+
+/// Open the pipe for reading. It is opened for writing too, which on Linux
+/// neither waits for a writer to open it nor sees end of file each time the
+/// player closes it, only a pipe with nothing in it
+fn open(path: &Path) -> Result<File, OpenError> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(|e| OpenError(path.to_owned(), e))
+}
@@ -0,0 +1,216 @@
+//! Test signals generated in place of an audio input, for calibrating
+//! latency offsets between rooms and checking channel mapping without a
+//! source connected. Tones and noise are both generated at -20 dBFS RMS,
+//! so they can be compared by level, and may be confined to one channel
+//! or alternate between the two each second.
+//!
+//! Generated audio is paced against the protocol clock, as a device would
+//! deliver it.
+
+use std::f32::consts::TAU;
+use std::fmt::{self, Display};
+use std::marker::PhantomData;
+use std::str::FromStr;
+
+use bark_core::audio::{self, Format, FramesMut};
+use bark_protocol::time::{SampleDuration, Timestamp};
+use bark_protocol::SAMPLE_RATE;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use thiserror::Error;
+
+use crate::stream::pacer::Pacer;
+use crate::time;
+
+const DEFAULT_TONE_HZ: f32 = 1000.0;
+
+// -20 dBFS RMS
+const TONE_AMPLITUDE: f32 = 0.1 * std::f32::consts::SQRT_2;
+// the pink noise filter below has an RMS gain of about 1.72 on uniform
+// white noise in -1..1
+const NOISE_GAIN: f32 = 0.1 / 1.72;
+
+/// How long alternating signals stay in each channel. 1s at 48 kHz
+const ALTERNATE_PERIOD: u64 = 48000;
+
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error("unknown signal {0:?}, expected tone or noise")]
+    Signal(String),
+    #[error("invalid tone frequency {0:?}, expected Hz between 1 and 24000")]
+    Frequency(String),
+    #[error("unknown channel {0:?}, expected left, right, both or alternate")]
+    Channels(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Signal {
+    Tone(f32),
+    Noise,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channels {
+    Both,
+    Left,
+    Right,
+    Alternate,
+}
+
+/// A test signal, given as `tone[:HZ]` or `noise`, optionally followed by
+/// `:left`, `:right`, or `:alternate`, eg. `tone:440:left`
+#[derive(Debug, Clone, Copy)]
+pub struct Generator {
+    pub signal: Signal,
+    pub channels: Channels,
+}
+
+impl FromStr for Generator {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':').peekable();
+
+        let signal = match parts.next().unwrap_or_default() {
+            "tone" => {
+                let hz = match parts.peek() {
+                    Some(part) if part.starts_with(|c: char| c.is_ascii_digit()) => {
+                        let hz = part.parse::<f32>()
+                            .ok()
+                            .filter(|hz| (1.0..=24000.0).contains(hz))
+                            .ok_or_else(|| ParseError::Frequency(part.to_string()))?;
+
+                        parts.next();
+                        hz
+                    }
+                    _ => DEFAULT_TONE_HZ,
+                };
+
+                Signal::Tone(hz)
+            }
+            "noise" => Signal::Noise,
+            other => { return Err(ParseError::Signal(other.to_owned())); }
+        };
+
+        let channels = match parts.next() {
+            None | Some("both") => Channels::Both,
+            Some("left") => Channels::Left,
+            Some("right") => Channels::Right,
+            Some("alternate") => Channels::Alternate,
+            Some(other) => { return Err(ParseError::Channels(other.to_owned())); }
+        };
+
+        if let Some(extra) = parts.next() {
+            return Err(ParseError::Channels(extra.to_owned()));
+        }
+
+        Ok(Generator { signal, channels })
+    }
+}
+
+impl Display for Generator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.signal {
+            Signal::Tone(hz) => write!(f, "tone:{hz}")?,
+            Signal::Noise => write!(f, "noise")?,
+        }
+
+        match self.channels {
+            Channels::Both => Ok(()),
+            Channels::Left => write!(f, ":left"),
+            Channels::Right => write!(f, ":right"),
+            Channels::Alternate => write!(f, ":alternate"),
+        }
+    }
+}
+
+pub struct Input<F: Format> {
+    generator: Generator,
+    pacer: Option<Pacer>,
+    // audio generated since pacing started
+    offset: SampleDuration,
+    phase: f32,
+    rng: StdRng,
+    // pink noise filter state
+    pink: [f32; 3],
+    _phantom: PhantomData<F>,
+}
+
+impl<F: Format> Input<F> {
+    pub fn new(generator: Generator) -> Self {
+        log::info!("generating test signal: {generator}");
+
+        Input {
+            generator,
+            pacer: None,
+            offset: SampleDuration::zero(),
+            phase: 0.0,
+            rng: StdRng::from_entropy(),
+            pink: [0.0; 3],
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Generate audio, waiting until it is due. Returns the time the first
+    /// frame was due
+    pub fn read(&mut self, frames: &mut [F::Frame]) -> Timestamp {
+        let start = self.offset.to_frame_count();
+
+        match F::frames_mut(frames) {
+            FramesMut::S16(frames) => {
+                for (n, frame) in frames.iter_mut().enumerate() {
+                    let (left, right) = self.next_frame(start + n as u64);
+                    frame.0 = audio::f32_to_s16(left);
+                    frame.1 = audio::f32_to_s16(right);
+                }
+            }
+            FramesMut::F32(frames) => {
+                for (n, frame) in frames.iter_mut().enumerate() {
+                    let (left, right) = self.next_frame(start + n as u64);
+                    frame.0 = left;
+                    frame.1 = right;
+                }
+            }
+        }
+
+        let pacer = self.pacer.get_or_insert_with(|| Pacer::new(Timestamp::from_micros_lossy(time::now())));
+        let timestamp = pacer.wait(self.offset);
+        self.offset = self.offset.add(SampleDuration::from_frame_count(frames.len()));
+
+        timestamp
+    }
+
+    /// The frame `position` frames into the signal
+    fn next_frame(&mut self, position: u64) -> (f32, f32) {
+        let sample = match self.generator.signal {
+            Signal::Tone(hz) => {
+                let sample = self.phase.sin() * TONE_AMPLITUDE;
+                self.phase = (self.phase + TAU * hz / SAMPLE_RATE.0 as f32) % TAU;
+                sample
+            }
+            Signal::Noise => self.pink_noise() * NOISE_GAIN,
+        };
+
+        let left = match self.generator.channels {
+            Channels::Both => return (sample, sample),
+            Channels::Left => true,
+            Channels::Right => false,
+            Channels::Alternate => (position / ALTERNATE_PERIOD).is_multiple_of(2),
+        };
+
+        if left { (sample, 0.0) } else { (0.0, sample) }
+    }
+
+    /// Paul Kellet's economy pink noise filter, close enough to -3dB per
+    /// octave for listening by ear
+    fn pink_noise(&mut self) -> f32 {
+        let white: f32 = self.rng.gen_range(-1.0..1.0);
+        let [b0, b1, b2] = &mut self.pink;
+
+        *b0 = 0.99765 * *b0 + white * 0.0990460;
+        *b1 = 0.96300 * *b1 + white * 0.2965164;
+        *b2 = 0.57000 * *b2 + white * 1.0526913;
+
+        *b0 + *b1 + *b2 + white * 0.1848
+    }
+}
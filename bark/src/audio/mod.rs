@@ -1,58 +1,217 @@
+use std::net::SocketAddrV4;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
 use bark_core::audio::Format;
 use bark_protocol::time::{SampleDuration, Timestamp};
+use bytemuck::Zeroable;
 use thiserror::Error;
 
+use crate::config::RtpPayload;
 use crate::stats::ReceiverMetrics;
 
 use self::config::DeviceOpt;
 
 pub mod alsa;
 pub mod config;
+pub mod fifo;
+pub mod generator;
+pub mod rtp;
 
 #[derive(Debug, Error)]
 #[error(transparent)]
 pub enum OpenError {
     Alsa(#[from] alsa::config::OpenError),
+    Rtp(#[from] rtp::OpenError),
+    Fifo(#[from] fifo::OpenError),
 }
 
 #[derive(Debug, Error)]
 #[error(transparent)]
 pub enum Error {
     Alsa(#[from] ::alsa::Error),
+    Io(#[from] std::io::Error),
+}
+
+/// An audio device as reported by `bark devices`
+pub struct DeviceInfo {
+    /// Name to pass as `--input-device` or `--output-device`
+    pub name: String,
+    pub description: Option<String>,
+    /// What the device supports for capture, `None` if it can't capture
+    pub input: Option<Result<Capabilities, Error>>,
+    /// What the device supports for playback, `None` if it can't play
+    pub output: Option<Result<Capabilities, Error>>,
+}
+
+pub struct Capabilities {
+    pub rates: RangeInclusive<u32>,
+    pub channels: RangeInclusive<u32>,
+    pub formats: Vec<String>,
+    /// Whether bark can use the device as it is, without resampling or
+    /// channel conversion from a plug device
+    pub native: bool,
 }
 
-pub struct Input<F: Format> {
-    alsa: alsa::input::Input<F>,
+/// Period and buffer size of a device as opened
+pub struct Sizes {
+    pub period: SampleDuration,
+    pub buffer: SampleDuration,
+}
+
+/// Sizes an input device opens with, for reporting without using it
+pub fn input_sizes(opt: &DeviceOpt) -> Result<Sizes, OpenError> {
+    Ok(alsa::config::sizes(opt, ::alsa::Direction::Capture)?)
+}
+
+/// Sizes an output device opens with, for reporting without using it
+pub fn output_sizes(opt: &DeviceOpt) -> Result<Sizes, OpenError> {
+    Ok(alsa::config::sizes(opt, ::alsa::Direction::Playback)?)
+}
+
+/// List the devices available to the audio backend
+pub fn devices() -> Result<Vec<DeviceInfo>, Error> {
+    Ok(alsa::devices::list()?)
+}
+
+/// Audio input from a capture device, an RTP stream, a named pipe, or a
+/// test signal generator
+pub enum Input<F: Format> {
+    Alsa(alsa::input::Input<F>),
+    Rtp(Box<rtp::Input<F>>),
+    Fifo(fifo::Input<F>),
+    Generator(Box<generator::Input<F>>),
 }
 
 impl<F: Format> Input<F> {
     pub fn new(opt: &DeviceOpt) -> Result<Self, OpenError> {
-        Ok(Input {
-            alsa: alsa::input::Input::new(opt)?,
-        })
+        Ok(Input::Alsa(alsa::input::Input::new(opt)?))
+    }
+
+    pub fn rtp(addr: SocketAddrV4, payload: RtpPayload) -> Result<Self, OpenError> {
+        Ok(Input::Rtp(Box::new(rtp::Input::new(addr, payload)?)))
+    }
+
+    pub fn fifo(path: &Path) -> Result<Self, OpenError> {
+        Ok(Input::Fifo(fifo::Input::new(path)?))
+    }
+
+    pub fn generator(generator: generator::Generator) -> Self {
+        Input::Generator(Box::new(generator::Input::new(generator)))
     }
 
-    pub fn read(&self, audio: &mut [F::Frame]) -> Result<Timestamp, Error> {
-        Ok(self.alsa.read(audio)?)
+    pub fn read(&mut self, audio: &mut [F::Frame]) -> Result<Timestamp, Error> {
+        match self {
+            Input::Alsa(alsa) => Ok(alsa.read(audio)?),
+            Input::Rtp(rtp) => Ok(rtp.read(audio)?),
+            Input::Fifo(fifo) => Ok(fifo.read(audio)?),
+            Input::Generator(generator) => Ok(generator.read(audio)),
+        }
+    }
+
+    /// Times the device has been reopened after disconnecting
+    pub fn reconnects(&self) -> u64 {
+        match self {
+            Input::Alsa(alsa) => alsa.reconnects(),
+            Input::Rtp(_) | Input::Fifo(_) | Input::Generator(_) => 0,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        match self {
+            Input::Alsa(alsa) => alsa.is_connected(),
+            Input::Rtp(_) | Input::Fifo(_) | Input::Generator(_) => true,
+        }
     }
 }
 
+/// How far a secondary output device may drift from the first before it
+/// is brought back in line. 2ms at 48 kHz
+const ALIGN_TOLERANCE: SampleDuration = SampleDuration::from_frame_count(96);
+
+/// One or more output devices playing the same audio in sync. The first
+/// device is the clock the stream is timed against, the others are kept
+/// in line with it by padding them with silence when they run ahead and
+/// skipping audio when they fall behind
 pub struct Output<F: Format> {
-    alsa: alsa::output::Output<F>,
+    alsa: Vec<alsa::output::Output<F>>,
+    // written to devices that need holding back
+    silence: Vec<F::Frame>,
 }
 
 impl<F: Format> Output<F> {
-    pub fn new(opt: &DeviceOpt, metrics: ReceiverMetrics) -> Result<Self, OpenError> {
-        Ok(Output {
-            alsa: alsa::output::Output::new(opt, metrics)?,
-        })
+    pub fn new(opts: &[DeviceOpt], metrics: ReceiverMetrics) -> Result<Self, OpenError> {
+        let mut alsa = Vec::new();
+
+        for opt in opts {
+            let mut output = alsa::output::Output::new(opt, metrics.clone())?;
+
+            // the first device times writes, even while disconnected
+            output.set_paced(alsa.is_empty());
+            alsa.push(output);
+        }
+
+        assert!(!alsa.is_empty(), "no output devices");
+
+        Ok(Output { alsa, silence: Vec::new() })
+    }
+
+    pub fn write(&mut self, audio: &[F::Frame]) -> Result<(), Error> {
+        let (primary, secondary) = self.alsa.split_first_mut().unwrap();
+
+        if secondary.is_empty() {
+            return Ok(primary.write(audio)?);
+        }
+
+        primary.write(audio)?;
+
+        // nothing to line up with while the first device is gone
+        if !primary.is_connected() {
+            for output in secondary {
+                output.write(audio)?;
+            }
+
+            return Ok(());
+        }
+
+        // the first device's delay to the end of what was just written
+        let target = primary.delay()?;
+        let written = SampleDuration::from_frame_count(audio.len());
+
+        for output in secondary {
+            let delay = output.delay()?.add(written);
+
+            if !output.is_connected() {
+                // dropped until it reconnects, then lined up
+                output.write(audio)?;
+            } else if delay.add(ALIGN_TOLERANCE) < target {
+                // plays early, hold it back
+                let frames = target.sub(delay).to_frame_count() as usize;
+                log::debug!("padding secondary output device by {frames} frames to stay in sync");
+                self.silence.resize(frames, F::Frame::zeroed());
+                output.write(&self.silence[..frames])?;
+                output.write(audio)?;
+            } else if delay > target.add(ALIGN_TOLERANCE) {
+                // plays late, catch it up
+                let frames = (delay.sub(target).to_frame_count() as usize).min(audio.len());
+                log::debug!("skipping {frames} frames on secondary output device to stay in sync");
+                output.write(&audio[frames..])?;
+            } else {
+                output.write(audio)?;
+            }
+        }
+
+        Ok(())
     }
 
-    pub fn write(&self, audio: &[F::Frame]) -> Result<(), Error> {
-        Ok(self.alsa.write(audio)?)
+    /// Delay of audio written now, as played by the first device
+    pub fn delay(&mut self) -> Result<SampleDuration, Error> {
+        Ok(self.alsa[0].delay()?)
     }
 
-    pub fn delay(&self) -> Result<SampleDuration, Error> {
-        Ok(self.alsa.delay()?)
+    /// Delay of audio written now with the first device's buffer full,
+    /// when writes start to block. None while it is disconnected
+    pub fn buffer(&self) -> Option<SampleDuration> {
+        self.alsa[0].buffer()
     }
 }
@@ -0,0 +1,244 @@
+//! Audio input from an RTP stream, so that `bark stream` can carry audio
+//! from tools that send RTP, such as an existing multicast stream.
+//!
+//! RTP timestamps count samples on the sender's clock, which isn't ours.
+//! Each packet is timestamped relative to an anchor pairing an RTP
+//! timestamp with the time it arrived, which follows the earliest arrivals
+//! so that network jitter doesn't reach the stream's timestamps. Should the
+//! sender's clock run slow, packets arrive ever later against the anchor,
+//! so it is also moved forward by however late the earliest packet of each
+//! `DRIFT_WINDOW` was.
+
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::net::{SocketAddrV4, UdpSocket};
+
+use bark_core::audio::Format;
+use bark_core::decode::pcm::S16BEDecoder;
+use bark_core::decode::{Decode, NewDecoderError};
+use bark_protocol::time::{SampleDuration, Timestamp};
+use bytemuck::Zeroable;
+use thiserror::Error;
+
+#[cfg(feature = "opus")]
+use bark_core::decode::opus::OpusDecoder;
+
+use crate::config::RtpPayload;
+use crate::socket::{self, ListenError};
+use crate::{rtp, time};
+
+/// How often the anchor catches up with a sender whose clock runs slow.
+/// 1s at 48 kHz
+const DRIFT_WINDOW: SampleDuration = SampleDuration::from_frame_count(48000);
+
+/// Longest run of lost audio filled with silence, beyond which the stream
+/// is started afresh. 100ms at 48 kHz
+const MAX_GAP: u32 = 4800;
+
+/// Largest packet decoded, longer than the longest Opus packet at 120ms
+const MAX_PACKET_FRAMES: usize = 8192;
+
+#[derive(Debug, Error)]
+pub enum OpenError {
+    #[error("opening RTP socket: {0}")]
+    Socket(#[from] ListenError),
+    #[error("opening RTP decoder: {0}")]
+    Decoder(#[from] NewDecoderError),
+}
+
+/// RTP timestamp paired with when it arrived on our clock
+struct Anchor {
+    rtp: u32,
+    local: Timestamp,
+    // least late any packet has been since the window began
+    window_start: Timestamp,
+    window_lateness: Option<SampleDuration>,
+}
+
+impl Anchor {
+    fn new(rtp: u32, local: Timestamp) -> Self {
+        Anchor {
+            rtp,
+            local,
+            window_start: local,
+            window_lateness: None,
+        }
+    }
+
+    /// Local time of an RTP timestamp, which may be earlier than the anchor
+    fn local(&self, rtp: u32) -> Timestamp {
+        let offset = i64::from(rtp.wrapping_sub(self.rtp) as i32);
+        let offset = SampleDuration::from_frame_count_u64(offset.unsigned_abs());
+
+        if rtp.wrapping_sub(self.rtp) as i32 >= 0 {
+            self.local.add(offset)
+        } else {
+            self.local.saturating_sub(offset)
+        }
+    }
+}
+
+pub struct Input<F: Format> {
+    socket: UdpSocket,
+    packet: Vec<u8>,
+    stream: Stream<F>,
+}
+
+/// Decoded audio from the followed sender, waiting to be read
+struct Stream<F: Format> {
+    decoder: Box<dyn Decode>,
+    // sender we are following, a new one starts over
+    ssrc: Option<u32>,
+    anchor: Option<Anchor>,
+    // sequence number and RTP timestamp of the next packet expected
+    next: Option<(u16, u32)>,
+    // decoded audio not yet read, the first frame at `pending_rtp`
+    pending: VecDeque<F::Frame>,
+    pending_rtp: u32,
+    decode_buffer: Vec<F::Frame>,
+    _phantom: PhantomData<F>,
+}
+
+impl<F: Format> Input<F> {
+    pub fn new(addr: SocketAddrV4, payload: RtpPayload) -> Result<Self, OpenError> {
+        let socket = socket::open_udp(*addr.ip(), addr)?;
+
+        let decoder: Box<dyn Decode> = match payload {
+            RtpPayload::L16 => Box::new(S16BEDecoder),
+            #[cfg(feature = "opus")]
+            RtpPayload::Opus => Box::new(OpusDecoder::new().map_err(NewDecoderError::from)?),
+        };
+
+        log::info!("receiving RTP {} from {addr}", payload.rtpmap());
+
+        Ok(Input {
+            socket,
+            packet: vec![0; 65536],
+            stream: Stream {
+                decoder,
+                ssrc: None,
+                anchor: None,
+                next: None,
+                pending: VecDeque::new(),
+                pending_rtp: 0,
+                decode_buffer: vec![F::Frame::zeroed(); MAX_PACKET_FRAMES],
+                _phantom: PhantomData,
+            },
+        })
+    }
+
+    /// Read audio from the stream, waiting for packets as needed. Returns
+    /// the time the first frame arrived
+    pub fn read(&mut self, frames: &mut [F::Frame]) -> Result<Timestamp, std::io::Error> {
+        while self.stream.pending.len() < frames.len() {
+            let length = self.socket.recv(&mut self.packet)?;
+            let now = Timestamp::from_micros_lossy(time::now());
+
+            if let Some((header, payload)) = rtp::Header::parse(&self.packet[0..length]) {
+                self.stream.receive(header, payload, now);
+            }
+        }
+
+        Ok(self.stream.read(frames))
+    }
+}
+
+impl<F: Format> Stream<F> {
+    fn read(&mut self, frames: &mut [F::Frame]) -> Timestamp {
+        let timestamp = match &self.anchor {
+            Some(anchor) => anchor.local(self.pending_rtp),
+            None => Timestamp::from_micros_lossy(time::now()),
+        };
+
+        let length = frames.len();
+
+        for (frame, pending) in frames.iter_mut().zip(self.pending.drain(0..length)) {
+            *frame = pending;
+        }
+
+        self.pending_rtp = self.pending_rtp.wrapping_add(length as u32);
+
+        timestamp
+    }
+
+    fn receive(&mut self, header: rtp::Header, payload: &[u8], now: Timestamp) {
+
+        if self.ssrc != Some(header.ssrc) {
+            log::info!("new RTP source: ssrc={:08x}", header.ssrc);
+            self.ssrc = Some(header.ssrc);
+            self.restart();
+        }
+
+        if let Some((seq, rtp)) = self.next {
+            if (header.seq.wrapping_sub(seq) as i16) < 0 {
+                // late or duplicated
+                return;
+            }
+
+            let missing = header.timestamp.wrapping_sub(rtp);
+
+            if header.seq != seq && missing > MAX_GAP {
+                log::warn!("lost {} RTP packets, restarting", header.seq.wrapping_sub(seq));
+                self.restart();
+            } else if header.seq != seq {
+                // fill lost audio with silence, which keeps what follows
+                // in step with its timestamp
+                if self.pending.is_empty() {
+                    self.pending_rtp = rtp;
+                }
+
+                let missing = usize::try_from(missing).unwrap_or_default();
+                self.pending.extend(std::iter::repeat_n(F::Frame::zeroed(), missing));
+            }
+        }
+
+        let frames = match self.decoder.decode_packet(Some(payload), F::frames_mut(&mut self.decode_buffer)) {
+            Ok(frames) => frames,
+            Err(e) => {
+                log::warn!("error decoding RTP packet: {e}");
+                return;
+            }
+        };
+
+        if self.pending.is_empty() {
+            self.pending_rtp = header.timestamp;
+        }
+
+        self.pending.extend(&self.decode_buffer[0..frames]);
+        self.next = Some((header.seq.wrapping_add(1), header.timestamp.wrapping_add(frames as u32)));
+
+        self.observe(header.timestamp, now);
+    }
+
+    /// Move the anchor back for a packet arriving earlier than it
+    /// predicts, and forward once every packet in a window was late
+    fn observe(&mut self, rtp: u32, now: Timestamp) {
+        let Some(anchor) = &mut self.anchor else {
+            self.anchor = Some(Anchor::new(rtp, now));
+            return;
+        };
+
+        let expected = anchor.local(rtp);
+
+        if now < expected {
+            self.anchor = Some(Anchor::new(rtp, now));
+            return;
+        }
+
+        let lateness = now.duration_since(expected);
+        let least = anchor.window_lateness.map_or(lateness, |least| least.min(lateness));
+        anchor.window_lateness = Some(least);
+
+        if now.saturating_duration_since(anchor.window_start) >= DRIFT_WINDOW {
+            anchor.local = anchor.local.add(least);
+            anchor.window_start = now;
+            anchor.window_lateness = None;
+        }
+    }
+
+    fn restart(&mut self) {
+        self.anchor = None;
+        self.next = None;
+        self.pending.clear();
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use bark_protocol::auth::MAC_LENGTH;
+use bark_protocol::packet::{Packet, Signed};
+use bark_protocol::types::{Magic, TimestampMicros};
+
+use crate::socket::PeerId;
+use crate::time;
+
+// signed packets must have been signed within this long of now, either
+// way, to allow for clocks that aren't perfectly in sync
+const MAX_AGE: Duration = Duration::from_secs(5);
+
+// bound on signed packets remembered for replay detection. control packets
+// are rare, so this is only reached under attack
+const MAX_SEEN: usize = 1024;
+
+/// Decides which control packets a node acts on. Control packets must be
+/// signed with the shared secret, and recently, unless unsigned control is
+/// explicitly allowed
+pub struct ControlAuth {
+    key: Option<Vec<u8>>,
+    allow_unsigned: bool,
+    // signed packets accepted within MAX_AGE, so a captured packet can't
+    // be replayed while its timestamp is still fresh
+    seen: VecDeque<(TimestampMicros, [u8; MAC_LENGTH])>,
+    // counts each packet rejected
+    rejected: Box<dyn Fn() + Send>,
+}
+
+impl ControlAuth {
+    pub fn new(key: Option<&[u8]>, allow_unsigned: bool, rejected: impl Fn() + Send + 'static) -> Self {
+        if key.is_none() && !allow_unsigned {
+            log::warn!("no control secret set, ignoring control packets. set --control-secret, or --allow-unsigned-control to accept them unsigned");
+        }
+
+        ControlAuth {
+            key: key.map(<[u8]>::to_vec),
+            allow_unsigned,
+            seen: VecDeque::new(),
+            rejected: Box::new(rejected),
+        }
+    }
+
+    /// Returns the packet to act on, unwrapping it if signed, or `None` if
+    /// it should be ignored
+    pub fn check(&mut self, packet: Packet, peer: PeerId) -> Option<Packet> {
+        if packet.header().magic == Magic::SIGNED {
+            let signed = Signed::parse(packet)?;
+            return self.verify(signed, peer);
+        }
+
+        if packet.is_control() && !self.allow_unsigned {
+            return self.reject(peer, "unsigned");
+        }
+
+        Some(packet)
+    }
+
+    fn verify(&mut self, signed: Signed, peer: PeerId) -> Option<Packet> {
+        let Some(key) = &self.key else {
+            return self.reject(peer, "signed, but we have no control secret to verify it");
+        };
+
+        if !signed.verify(key) {
+            return self.reject(peer, "bad signature");
+        }
+
+        let now = time::now();
+        let timestamp = signed.timestamp();
+        let age = std::cmp::max(
+            now.saturating_duration_since(timestamp),
+            timestamp.saturating_duration_since(now),
+        );
+
+        if age > MAX_AGE {
+            return self.reject(peer, "signed too long ago, check clocks are in sync");
+        }
+
+        // anything older can no longer pass the check above
+        let oldest = now.saturating_sub(MAX_AGE);
+        self.seen.retain(|(timestamp, _)| *timestamp >= oldest);
+
+        if self.seen.iter().any(|(_, mac)| mac == signed.mac()) {
+            // also happens when a packet broadcast to several groups we
+            // are in arrives once per group
+            log::debug!("ignoring repeated control packet from {peer}");
+            return None;
+        }
+
+        if self.seen.len() >= MAX_SEEN {
+            return self.reject(peer, "too many control packets");
+        }
+
+        self.seen.push_back((timestamp, *signed.mac()));
+
+        let packet = signed.into_inner()
+            .expect("allocate control packet")?;
+
+        if !packet.is_control() {
+            return self.reject(peer, "signed packet is not a control packet");
+        }
+
+        Some(packet)
+    }
+
+    fn reject(&self, peer: PeerId, reason: &str) -> Option<Packet> {
+        log::warn!("rejected control packet from {peer}: {reason}");
+        (self.rejected)();
+        None
+    }
+}
@@ -0,0 +1,184 @@
+//! Bridges re-export the bark stream over other protocols, so that players
+//! which only speak those can join in alongside bark receivers.
+
+pub mod raop;
+pub mod rtp;
+pub mod snapcast;
+
+use bytemuck::Zeroable;
+use structopt::StructOpt;
+
+use bark_core::audio::{FrameS16, FramesMut};
+use bark_core::decode::Decoder;
+use bark_protocol::packet::{Audio, PacketKind};
+use bark_protocol::time::Timestamp;
+use bark_protocol::types::{SessionId, TimestampMicros};
+use bark_protocol::MAX_FRAMES_PER_PACKET;
+
+use crate::socket::{ProtocolSocket, Socket};
+use crate::{time, RunError};
+
+#[derive(StructOpt)]
+pub enum BridgeOpt {
+    /// Serve the stream to snapcast clients, as a snapserver would
+    Snapcast(snapcast::SnapcastOpt),
+    /// Send the stream as RTP, for standard receivers
+    Rtp(rtp::RtpOpt),
+    /// Send the stream to an AirPlay 1 (RAOP) speaker
+    Raop(raop::RaopOpt),
+}
+
+pub async fn run(opt: BridgeOpt) -> Result<(), RunError> {
+    match opt {
+        BridgeOpt::Snapcast(opt) => snapcast::run(opt).await,
+        BridgeOpt::Rtp(opt) => rtp::run(opt).await,
+        BridgeOpt::Raop(opt) => raop::run(opt).await,
+    }
+}
+
+/// How long the followed session may go without packets before another
+/// session can take over, same as on receivers
+const STREAM_TIMEOUT_MICROS: u64 = 100_000;
+
+/// Audio from the followed session, decoded to 16 bit stereo
+pub struct Decoded<'a> {
+    /// When the first frame plays on bark receivers
+    pub pts: Timestamp,
+    pub frames: &'a [FrameS16],
+    /// Whether this does not follow on directly from the last audio, such
+    /// as after lost packets or a change of session
+    pub discontinuity: bool,
+}
+
+struct Followed {
+    sid: SessionId,
+    priority: i8,
+    // None if the stream's format can't be decoded
+    decoder: Option<Decoder>,
+    next_seq: u64,
+    received_last_packet: TimestampMicros,
+}
+
+/// Follows the session a receiver would play: the highest priority, with
+/// newer sessions taking over from older ones of the same priority
+struct Follow {
+    stream: Option<Followed>,
+    ended: Option<SessionId>,
+    buffer: Vec<FrameS16>,
+}
+
+impl Follow {
+    fn new() -> Self {
+        Follow {
+            stream: None,
+            ended: None,
+            buffer: vec![FrameS16::zeroed(); MAX_FRAMES_PER_PACKET],
+        }
+    }
+
+    fn stream_end(&mut self, sid: SessionId) {
+        if self.stream.as_ref().is_some_and(|stream| stream.sid == sid) {
+            log::info!("session {} ended by source", sid.0);
+            self.stream = None;
+        }
+
+        self.ended = Some(sid);
+    }
+
+    fn receive_audio(&mut self, audio: &Audio) -> Option<Decoded<'_>> {
+        let header = audio.header();
+        let now = time::now();
+
+        if self.ended == Some(header.sid) {
+            // stragglers after the end of a session
+            return None;
+        }
+
+        let new_stream = match &self.stream {
+            Some(current) if current.received_last_packet.0 + STREAM_TIMEOUT_MICROS > now.0 => {
+                header.priority > current.priority ||
+                    (header.priority == current.priority && header.sid > current.sid)
+            }
+            _ => true,
+        };
+
+        let mut discontinuity = false;
+
+        if new_stream && self.stream.as_ref().is_none_or(|stream| stream.sid != header.sid) {
+            log::info!("following new stream: priority={} sid={}", header.priority, header.sid.0);
+
+            let decoder = Decoder::new(header)
+                .map_err(|e| log::error!("error creating decoder for new stream: {e}"))
+                .ok();
+
+            self.stream = Some(Followed {
+                sid: header.sid,
+                priority: header.priority,
+                decoder,
+                next_seq: header.seq,
+                received_last_packet: now,
+            });
+
+            discontinuity = true;
+        }
+
+        let stream = self.stream.as_mut()?;
+
+        if stream.sid != header.sid || header.seq < stream.next_seq {
+            // another session, or a packet late or duplicated
+            return None;
+        }
+
+        discontinuity |= header.seq != stream.next_seq;
+        stream.next_seq = header.seq + 1;
+        stream.received_last_packet = now;
+
+        let decoder = stream.decoder.as_mut()?;
+
+        let frames = match decoder.decode(Some(audio), FramesMut::S16(&mut self.buffer)) {
+            Ok(frames) => frames,
+            Err(e) => {
+                log::warn!("error decoding packet: {e}");
+                return None;
+            }
+        };
+
+        Some(Decoded {
+            pts: Timestamp::from_micros_lossy(header.pts),
+            frames: &self.buffer[0..frames],
+            discontinuity,
+        })
+    }
+}
+
+/// Sleep until `at`, if it is still to come
+fn hold_until(at: Timestamp) {
+    let now = Timestamp::from_micros_lossy(time::now());
+    std::thread::sleep(at.saturating_duration_since(now).to_std_duration_lossy());
+}
+
+/// Receive the bark stream, passing each packet of the followed session
+/// on to `sink` as it is decoded
+fn network_thread(socket: Socket, mut sink: impl FnMut(Decoded)) -> Result<(), RunError> {
+    let protocol = ProtocolSocket::new(socket);
+    let mut follow = Follow::new();
+
+    loop {
+        let (packet, _) = protocol.recv_from()
+            .map_err(RunError::Receive)?;
+
+        match packet.parse() {
+            Some(PacketKind::Audio(audio)) => {
+                if let Some(decoded) = follow.receive_audio(&audio) {
+                    sink(decoded);
+                }
+            }
+            Some(PacketKind::StreamEnd(end)) => {
+                follow.stream_end(end.sid());
+            }
+            _ => {
+                // bridges only carry audio, control is for receivers
+            }
+        }
+    }
+}
@@ -0,0 +1,611 @@
+//! RAOP (AirPlay 1) output, for rooms whose only speakers are AirPlay
+//! speakers. The stream is resampled to the 44.1 kHz RAOP requires and sent
+//! as uncompressed ALAC, unencrypted, so speakers which insist on
+//! encryption or a password are not supported.
+//!
+//! The speaker syncs its clock to ours through the timing port, and is
+//! told through sync packets which RTP timestamp plays when. RTP timestamps
+//! are the bark timestamp each frame plays at, on a 44.1 kHz clock. Speakers
+//! need audio their fixed latency ahead of when it plays, which is usually
+//! far more than the stream delay, so the speaker is told to play that
+//! much later than bark receivers unless the stream delay covers it.
+//!
+//! Requests from the speaker to resend lost packets are not answered.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, SocketAddrV4, TcpStream, UdpSocket};
+use std::time::Duration;
+
+use bytemuck::Zeroable;
+use rand::Rng;
+use structopt::StructOpt;
+use thiserror::Error;
+
+use bark_core::audio::{FrameS16, S16};
+use bark_core::receive::resample::Resampler;
+use bark_protocol::time::{SampleDuration, Timestamp};
+use bark_protocol::types::TimestampMicros;
+use bark_protocol::{MAX_FRAMES_PER_PACKET, SAMPLE_RATE};
+
+use bark_app::thread;
+
+use crate::socket::{Socket, SocketOpt};
+use crate::{rtp, time, version, RunError};
+
+use super::{hold_until, Decoded};
+
+/// The only sample rate RAOP speakers take
+const RAOP_RATE: u32 = 44100;
+
+/// Frames of audio in each packet, as every RAOP sender uses
+const PACKET_FRAMES: usize = 352;
+
+const PAYLOAD_TYPE: u8 = 0x60;
+const SYNC: u8 = 0x54;
+const TIMING_REQUEST: u8 = 0x52;
+const TIMING_REPLY: u8 = 0x53;
+
+/// How often the speaker is reminded which RTP timestamp is playing
+const SYNC_INTERVAL: SampleDuration = SampleDuration::from_frame_count(48000);
+
+/// Seconds from the NTP epoch of 1900 to the unix epoch
+const NTP_EPOCH_OFFSET: u64 = 2_208_988_800;
+
+#[derive(StructOpt)]
+pub struct RaopOpt {
+    #[structopt(flatten)]
+    pub socket: SocketOpt,
+
+    /// Address of the speaker's RAOP service, usually on port 5000 or 7000
+    #[structopt(long, env = "BARK_BRIDGE_RAOP_SPEAKER")]
+    pub speaker: SocketAddrV4,
+
+    /// How far ahead of playing the speaker needs audio, in milliseconds.
+    /// AirPlay 1 speakers buffer 2 seconds
+    #[structopt(long, env = "BARK_BRIDGE_RAOP_LATENCY_MS", default_value = "2000")]
+    pub latency_ms: u64,
+
+    /// Volume to set on the speaker, in dB from -30 to 0
+    #[structopt(long, env = "BARK_BRIDGE_RAOP_VOLUME_DB", default_value = "0", allow_hyphen_values = true)]
+    pub volume_db: f32,
+}
+
+#[derive(Debug, Error)]
+pub enum RaopError {
+    #[error("connecting to {0}: {1}")]
+    Connect(SocketAddrV4, io::Error),
+    #[error("opening RTP socket: {0}")]
+    Socket(io::Error),
+    #[error("talking to speaker: {0}")]
+    Rtsp(#[from] io::Error),
+    #[error("speaker refused {0}: {1} {2}")]
+    Refused(&'static str, u16, String),
+    #[error("speaker's reply to SETUP has no {0}")]
+    MissingPort(&'static str),
+}
+
+pub async fn run(opt: RaopOpt) -> Result<(), RunError> {
+    let socket = Socket::open(&opt.socket)
+        .map_err(RunError::Listen)?;
+
+    let control = bind_udp()?;
+    let timing = bind_udp()?;
+
+    let mut rtsp = Rtsp::connect(opt.speaker)?;
+    let ports = rtsp.setup(local_port(&control)?, local_port(&timing)?)?;
+
+    let seq = rand::thread_rng().gen();
+    let now = Timestamp::from_micros_lossy(time::now());
+    rtsp.record(seq, timestamp(now))?;
+    rtsp.set_volume(opt.volume_db)?;
+
+    let speaker = *opt.speaker.ip();
+
+    let audio = bind_udp()?;
+    audio.connect(SocketAddrV4::new(speaker, ports.server))
+        .map_err(RaopError::Socket)?;
+    control.connect(SocketAddrV4::new(speaker, ports.control))
+        .map_err(RaopError::Socket)?;
+
+    log::info!("sending to RAOP speaker at {}", opt.speaker);
+
+    std::thread::spawn(move || {
+        thread::set_name("bark/raop");
+
+        if let Err(e) = timing_thread(timing) {
+            log::error!("error answering RAOP timing requests: {e}");
+        }
+    });
+
+    let latency = SampleDuration::from_std_duration_lossy(Duration::from_millis(opt.latency_ms));
+    let mut sender = Sender::new(audio, control, latency, seq);
+
+    thread::start("bark/network", move || {
+        // the session lasts as long as the RTSP connection
+        let _rtsp = rtsp;
+
+        super::network_thread(socket, |decoded| {
+            sender.push(decoded);
+        })
+    }).await
+}
+
+fn bind_udp() -> Result<UdpSocket, RaopError> {
+    UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+        .map_err(RaopError::Socket)
+}
+
+fn local_port(socket: &UdpSocket) -> Result<u16, RaopError> {
+    Ok(socket.local_addr().map_err(RaopError::Socket)?.port())
+}
+
+/// Ports the speaker receives on, from its reply to SETUP
+struct Ports {
+    server: u16,
+    control: u16,
+}
+
+/// RTSP connection to the speaker, which holds the session open
+struct Rtsp {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    url: String,
+    cseq: u32,
+    client_instance: String,
+    session: Option<String>,
+}
+
+struct Response {
+    status: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl Rtsp {
+    fn connect(speaker: SocketAddrV4) -> Result<Self, RaopError> {
+        let stream = TcpStream::connect(speaker)
+            .map_err(|e| RaopError::Connect(speaker, e))?;
+
+        let _ = stream.set_nodelay(true);
+
+        let reader = BufReader::new(stream.try_clone()?);
+        let local = stream.local_addr()?.ip();
+
+        let mut rng = rand::thread_rng();
+        let url = format!("rtsp://{local}/{}", rng.gen::<u32>());
+        let client_instance = format!("{:016X}", rng.gen::<u64>());
+
+        let mut rtsp = Rtsp {
+            stream,
+            reader,
+            url,
+            cseq: 0,
+            client_instance,
+            session: None,
+        };
+
+        rtsp.request("OPTIONS", "*", &[], None)?;
+        rtsp.announce(speaker)?;
+
+        Ok(rtsp)
+    }
+
+    /// Describe the stream: uncompressed ALAC, 16 bit stereo at 44.1 kHz
+    fn announce(&mut self, speaker: SocketAddrV4) -> Result<(), RaopError> {
+        let local = self.stream.local_addr()?.ip();
+        let session = self.url.rsplit('/').next().unwrap_or_default().to_owned();
+
+        let sdp = format!(
+            "v=0\r\n\
+            o=iTunes {session} 0 IN IP4 {local}\r\n\
+            s=iTunes\r\n\
+            c=IN IP4 {speaker}\r\n\
+            t=0 0\r\n\
+            m=audio 0 RTP/AVP 96\r\n\
+            a=rtpmap:96 AppleLossless\r\n\
+            a=fmtp:96 {PACKET_FRAMES} 0 16 40 10 14 2 255 0 0 {RAOP_RATE}\r\n",
+            speaker = speaker.ip(),
+        );
+
+        let url = self.url.clone();
+        self.request("ANNOUNCE", &url, &[], Some(("application/sdp", sdp.as_bytes())))?;
+        Ok(())
+    }
+
+    fn setup(&mut self, control_port: u16, timing_port: u16) -> Result<Ports, RaopError> {
+        let transport = format!("RTP/AVP/UDP;unicast;interleaved=0-1;mode=record;control_port={control_port};timing_port={timing_port}");
+
+        let url = self.url.clone();
+        let response = self.request("SETUP", &url, &[("Transport", transport)], None)?;
+
+        self.session = response.header("Session")
+            .map(|session| session.split(';').next().unwrap_or_default().trim().to_owned());
+
+        let transport = response.header("Transport").unwrap_or_default();
+
+        Ok(Ports {
+            server: transport_port(transport, "server_port")
+                .ok_or(RaopError::MissingPort("server_port"))?,
+            control: transport_port(transport, "control_port")
+                .ok_or(RaopError::MissingPort("control_port"))?,
+        })
+    }
+
+    fn record(&mut self, seq: u16, rtptime: u32) -> Result<(), RaopError> {
+        let headers = [
+            ("Range", "npt=0-".to_owned()),
+            ("RTP-Info", format!("seq={seq};rtptime={rtptime}")),
+        ];
+
+        let url = self.url.clone();
+        let response = self.request("RECORD", &url, &headers, None)?;
+
+        if let Some(latency) = response.header("Audio-Latency") {
+            log::debug!("speaker reports audio latency of {latency} frames");
+        }
+
+        Ok(())
+    }
+
+    fn set_volume(&mut self, volume_db: f32) -> Result<(), RaopError> {
+        let body = format!("volume: {:.6}\r\n", volume_db.clamp(-30.0, 0.0));
+
+        let url = self.url.clone();
+        self.request("SET_PARAMETER", &url, &[], Some(("text/parameters", body.as_bytes())))?;
+        Ok(())
+    }
+
+    fn request(&mut self, method: &'static str, url: &str, headers: &[(&str, String)], body: Option<(&str, &[u8])>)
+        -> Result<Response, RaopError>
+    {
+        self.cseq += 1;
+
+        let mut request = format!("{method} {url} RTSP/1.0\r\n");
+        request += &format!("CSeq: {}\r\n", self.cseq);
+        request += &format!("User-Agent: bark/{}\r\n", version());
+        request += &format!("Client-Instance: {}\r\n", self.client_instance);
+
+        if let Some(session) = &self.session {
+            request += &format!("Session: {session}\r\n");
+        }
+
+        for (name, value) in headers {
+            request += &format!("{name}: {value}\r\n");
+        }
+
+        if let Some((content_type, body)) = body {
+            request += &format!("Content-Type: {content_type}\r\n");
+            request += &format!("Content-Length: {}\r\n", body.len());
+        }
+
+        request += "\r\n";
+
+        self.stream.write_all(request.as_bytes())?;
+
+        if let Some((_, body)) = body {
+            self.stream.write_all(body)?;
+        }
+
+        let response = self.read_response()?;
+
+        if response.status != 200 {
+            return Err(RaopError::Refused(method, response.status, response.reason));
+        }
+
+        Ok(response)
+    }
+
+    fn read_response(&mut self) -> Result<Response, io::Error> {
+        let status_line = self.read_line()?;
+
+        let mut parts = status_line.splitn(3, ' ');
+        let _version = parts.next();
+        let status = parts.next()
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("bad status line: {status_line:?}")))?;
+        let reason = parts.next().unwrap_or_default().to_owned();
+
+        let mut headers = Vec::new();
+
+        loop {
+            let line = self.read_line()?;
+
+            if line.is_empty() {
+                break;
+            }
+
+            if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.trim().to_owned(), value.trim().to_owned()));
+            }
+        }
+
+        let response = Response { status, reason, headers };
+
+        // bodies of replies are of no use to us, but must be read past
+        let length = response.header("Content-Length")
+            .and_then(|length| length.parse::<u64>().ok())
+            .unwrap_or(0);
+
+        io::copy(&mut (&mut self.reader).take(length), &mut io::sink())?;
+
+        Ok(response)
+    }
+
+    fn read_line(&mut self) -> Result<String, io::Error> {
+        let mut line = String::new();
+
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        Ok(line.trim_end().to_owned())
+    }
+}
+
+/// Find a port in an RTSP Transport header, eg. `server_port=6000`
+fn transport_port(transport: &str, name: &str) -> Option<u16> {
+    transport.split(';')
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, port)| port.parse().ok())
+}
+
+/// Answer the speaker's requests for our clock, which it syncs to
+fn timing_thread(socket: UdpSocket) -> Result<(), io::Error> {
+    let mut request = [0u8; 128];
+
+    loop {
+        let (length, peer) = socket.recv_from(&mut request)?;
+        let now = ntp_time(time::now());
+
+        if length < 32 || request[1] & 0x7f != TIMING_REQUEST {
+            continue;
+        }
+
+        let mut reply = [0u8; 32];
+        reply[0] = 0x80;
+        reply[1] = 0x80 | TIMING_REPLY;
+        reply[2..4].copy_from_slice(&7u16.to_be_bytes());
+        // their transmit time becomes our reply's origin time
+        reply[8..16].copy_from_slice(&request[24..32]);
+        reply[16..24].copy_from_slice(&now.to_be_bytes());
+        reply[24..32].copy_from_slice(&now.to_be_bytes());
+
+        socket.send_to(&reply, peer)?;
+    }
+}
+
+/// Resamples the followed stream and sends it to the speaker in time for
+/// it to play
+struct Sender {
+    audio: UdpSocket,
+    control: UdpSocket,
+    latency: SampleDuration,
+    resampler: Resampler<S16>,
+    resampled: Vec<FrameS16>,
+    // resampled audio not yet sent, at 44.1 kHz
+    frames: Vec<FrameS16>,
+    // when the first frame since the last discontinuity plays on bark
+    // receivers, and how many frames have been sent since
+    start: Timestamp,
+    sent: u64,
+    // how much later than bark receivers the speaker plays, when the
+    // stream delay doesn't cover its latency
+    offset: SampleDuration,
+    seq: u16,
+    ssrc: u32,
+    // set until the first packet after a discontinuity is sent
+    marker: bool,
+    last_sync: Option<Timestamp>,
+    packet: Vec<u8>,
+}
+
+impl Sender {
+    fn new(audio: UdpSocket, control: UdpSocket, latency: SampleDuration, seq: u16) -> Self {
+        Sender {
+            audio,
+            control,
+            latency,
+            resampler: Resampler::with_output_rate(RAOP_RATE),
+            resampled: vec![FrameS16::zeroed(); MAX_FRAMES_PER_PACKET],
+            frames: Vec::new(),
+            start: Timestamp::from_micros_lossy(TimestampMicros(0)),
+            sent: 0,
+            offset: SampleDuration::zero(),
+            seq,
+            ssrc: rand::thread_rng().gen(),
+            marker: true,
+            last_sync: None,
+            packet: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, decoded: Decoded) {
+        if decoded.discontinuity {
+            self.restart(decoded.pts);
+        }
+
+        let mut input = decoded.frames;
+
+        while !input.is_empty() {
+            let result = match self.resampler.process(input, &mut self.resampled) {
+                Ok(result) => result,
+                Err(e) => {
+                    log::warn!("error resampling for RAOP: {e}");
+                    return;
+                }
+            };
+
+            input = &input[result.input_read.0..];
+            self.frames.extend_from_slice(&self.resampled[0..result.output_written.0]);
+
+            if result.input_read.0 == 0 {
+                break;
+            }
+        }
+
+        while self.frames.len() >= PACKET_FRAMES {
+            self.send_packet();
+            self.frames.drain(0..PACKET_FRAMES);
+        }
+    }
+
+    /// Start afresh from audio playing at `pts`, working out how late the
+    /// speaker must play it to have it its latency ahead
+    fn restart(&mut self, pts: Timestamp) {
+        let now = Timestamp::from_micros_lossy(time::now());
+        let lead = pts.saturating_duration_since(now);
+        let offset = self.latency.sub(lead.min(self.latency));
+
+        let offset_ms = offset.to_micros_lossy() / 1000;
+
+        if offset_ms > 0 && offset_ms != self.offset.to_micros_lossy() / 1000 {
+            log::info!("speaker plays {}ms behind bark receivers, raise the stream delay to {}ms to play in sync",
+                offset_ms,
+                self.latency.to_micros_lossy() / 1000);
+        }
+
+        self.resampler = Resampler::with_output_rate(RAOP_RATE);
+        self.frames.clear();
+        self.start = pts;
+        self.sent = 0;
+        self.offset = offset;
+        self.marker = true;
+        self.last_sync = None;
+    }
+
+    fn send_packet(&mut self) {
+        let pts = self.start.add(SampleDuration::from_frame_count_u64(
+            self.sent * u64::from(SAMPLE_RATE.0) / u64::from(RAOP_RATE)));
+
+        // hold audio until the speaker's latency ahead of when it plays
+        hold_until(pts.add(self.offset).saturating_sub(self.latency));
+
+        let now = Timestamp::from_micros_lossy(time::now());
+
+        if self.last_sync.is_none_or(|last| now.saturating_duration_since(last) >= SYNC_INTERVAL) {
+            self.send_sync(now);
+        }
+
+        self.packet.clear();
+
+        rtp::Header {
+            marker: self.marker,
+            payload_type: PAYLOAD_TYPE,
+            seq: self.seq,
+            timestamp: timestamp(self.start).wrapping_add(self.sent as u32),
+            ssrc: self.ssrc,
+        }.write(&mut self.packet);
+
+        alac(&self.frames[0..PACKET_FRAMES], &mut self.packet);
+
+        if let Err(e) = self.audio.send(&self.packet) {
+            log::warn!("error sending RAOP packet: {e}");
+        }
+
+        self.seq = self.seq.wrapping_add(1);
+        self.sent += PACKET_FRAMES as u64;
+        self.marker = false;
+    }
+
+    /// Tell the speaker which RTP timestamp plays now, and how far ahead
+    /// of that audio is being sent
+    fn send_sync(&mut self, now: Timestamp) {
+        let playing = timestamp(now.saturating_sub(self.offset));
+        let latency = timestamp_frames(self.latency);
+
+        let mut packet = [0u8; 20];
+        // the first sync packet after a discontinuity has the extension bit set
+        packet[0] = if self.last_sync.is_none() { 0x90 } else { 0x80 };
+        packet[1] = 0x80 | SYNC;
+        packet[2..4].copy_from_slice(&7u16.to_be_bytes());
+        packet[4..8].copy_from_slice(&playing.to_be_bytes());
+        packet[8..16].copy_from_slice(&ntp_time(now.to_micros_lossy()).to_be_bytes());
+        packet[16..20].copy_from_slice(&playing.wrapping_add(latency).to_be_bytes());
+
+        if let Err(e) = self.control.send(&packet) {
+            log::warn!("error sending RAOP sync packet: {e}");
+        }
+
+        self.last_sync = Some(now);
+    }
+}
+
+/// RTP timestamp of a bark timestamp, counting samples at 44.1 kHz
+fn timestamp(ts: Timestamp) -> u32 {
+    let micros = u128::from(ts.to_micros_lossy().0);
+    (micros * u128::from(RAOP_RATE) / 1_000_000) as u32
+}
+
+/// Length of a duration in samples at 44.1 kHz
+fn timestamp_frames(duration: SampleDuration) -> u32 {
+    (u128::from(duration.to_micros_lossy()) * u128::from(RAOP_RATE) / 1_000_000) as u32
+}
+
+/// 64 bit NTP timestamp: seconds since 1900 and a 32 bit fraction
+fn ntp_time(micros: TimestampMicros) -> u64 {
+    let secs = micros.0 / 1_000_000 + NTP_EPOCH_OFFSET;
+    let frac = ((micros.0 % 1_000_000) << 32) / 1_000_000;
+    secs << 32 | frac
+}
+
+/// Encode frames as an uncompressed ALAC packet: a stereo element header
+/// flagged as not compressed, then each sample as 16 bits big endian,
+/// which leaves samples unaligned to bytes, then the end tag
+fn alac(frames: &[FrameS16], out: &mut Vec<u8>) {
+    let mut bits = BitWriter::new(out);
+
+    bits.write(1, 3); // channel pair element
+    bits.write(0, 4); // element instance tag
+    bits.write(0, 12); // unused
+    bits.write(0, 1); // no sample count, the packet is the default size
+    bits.write(0, 2); // no extra bytes
+    bits.write(1, 1); // not compressed
+
+    for frame in frames {
+        bits.write(u32::from(frame.0 as u16), 16);
+        bits.write(u32::from(frame.1 as u16), 16);
+    }
+
+    bits.write(7, 3); // end of frame
+    bits.finish();
+}
+
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(out: &'a mut Vec<u8>) -> Self {
+        BitWriter { out, acc: 0, bits: 0 }
+    }
+
+    fn write(&mut self, value: u32, bits: u32) {
+        self.acc = self.acc << bits | u64::from(value);
+        self.bits += bits;
+
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.out.push((self.acc >> self.bits) as u8);
+        }
+
+        self.acc &= (1 << self.bits) - 1;
+    }
+
+    /// Pad the last byte out with zero bits
+    fn finish(mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+}
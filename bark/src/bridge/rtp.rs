@@ -0,0 +1,273 @@
+//! RTP output, so that standard receivers such as trx, gstreamer and
+//! ffmpeg can play the bark stream. RTP timestamps are the bark timestamp
+//! each packet plays at, so they advance in step with the protocol clock
+//! and stay meaningful across changes of session.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use rand::Rng;
+use structopt::StructOpt;
+
+use bark_core::audio::FrameS16;
+use bark_protocol::time::{SampleDuration, Timestamp};
+use bark_protocol::types::TimestampMicros;
+use bark_protocol::SAMPLE_RATE;
+
+#[cfg(feature = "opus")]
+use bark_core::audio::Frames;
+#[cfg(feature = "opus")]
+use bark_core::encode::Encode;
+#[cfg(feature = "opus")]
+use bark_core::encode::opus::{OpusEncoder, OpusEncoderOpt};
+
+use bark_app::thread;
+
+use crate::config::RtpPayload;
+use crate::socket::{self, Socket, SocketOpt};
+use crate::{rtp, RunError};
+
+use super::{hold_until, Decoded};
+
+/// Largest RTP packet sent, to stay within a 1500 byte MTU after IP and
+/// UDP headers
+const MAX_PACKET: usize = 1472;
+
+/// Frame counts Opus can encode in one packet, 2.5ms up to 60ms
+#[cfg(feature = "opus")]
+const OPUS_FRAMES: [usize; 6] = [120, 240, 480, 960, 1920, 2880];
+
+#[derive(StructOpt)]
+pub struct RtpOpt {
+    #[structopt(flatten)]
+    pub socket: SocketOpt,
+
+    /// Address to send RTP to, multicast or unicast
+    #[structopt(long, env = "BARK_BRIDGE_RTP_DEST")]
+    pub dest: SocketAddrV4,
+
+    /// Payload to send: l16 or opus, stereo at 48 kHz
+    #[structopt(long, env = "BARK_BRIDGE_RTP_PAYLOAD", default_value = "l16")]
+    pub payload: RtpPayload,
+
+    /// Dynamic payload type to send, which receivers must be configured
+    /// with, eg. through an SDP file
+    #[structopt(long, env = "BARK_BRIDGE_RTP_PAYLOAD_TYPE", default_value = "96")]
+    pub payload_type: u8,
+
+    /// Duration of audio in each RTP packet in milliseconds. Opus packets
+    /// must be 2.5, 5, 10, 20, 40 or 60ms, L16 packets must fit in 1472 bytes
+    #[structopt(long, env = "BARK_BRIDGE_RTP_PACKET_MS", default_value = "5")]
+    pub packet_ms: f64,
+
+    /// Latency of the RTP receivers in milliseconds. Packets are held back
+    /// so that they play in time with bark receivers, as far as the stream
+    /// delay allows. Sent as soon as received if not set
+    #[structopt(long, env = "BARK_BRIDGE_RTP_SYNC_LATENCY_MS")]
+    pub sync_latency_ms: Option<u64>,
+
+    /// Write an SDP file describing the stream, for players such as ffplay
+    /// and VLC
+    #[structopt(long)]
+    pub sdp: Option<PathBuf>,
+}
+
+pub async fn run(opt: RtpOpt) -> Result<(), RunError> {
+    let socket = Socket::open(&opt.socket)
+        .map_err(RunError::Listen)?;
+
+    let packet_frames = packet_frames(opt.payload, opt.packet_ms)?;
+
+    let output = socket::open_udp(*opt.dest.ip(), SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+        .map_err(RunError::Listen)?;
+
+    let mut packetizer = Packetizer::new(&opt, packet_frames)?;
+
+    if let Some(path) = &opt.sdp {
+        std::fs::write(path, sdp(&opt, packetizer.ssrc))
+            .map_err(RunError::WriteSdp)?;
+    }
+
+    log::info!("sending RTP {} to {}, payload type {}", opt.payload.rtpmap(), opt.dest, opt.payload_type);
+
+    let dest = opt.dest;
+    let sync_latency = opt.sync_latency_ms
+        .map(|ms| SampleDuration::from_std_duration_lossy(Duration::from_millis(ms)));
+
+    thread::start("bark/network", move || {
+        super::network_thread(socket, |decoded| {
+            packetizer.push(decoded, |packet, pts| {
+                if let Some(latency) = sync_latency {
+                    hold_until(pts.saturating_sub(latency));
+                }
+
+                if let Err(e) = output.send_to(packet, dest) {
+                    log::warn!("error sending RTP packet: {e}");
+                }
+            });
+        })
+    }).await
+}
+
+fn packet_frames(payload: RtpPayload, packet_ms: f64) -> Result<usize, RunError> {
+    let frames = (packet_ms * f64::from(SAMPLE_RATE.0) / 1000.0).round();
+    let invalid = || RunError::InvalidPacketDuration(packet_ms);
+
+    if frames < 1.0 {
+        return Err(invalid());
+    }
+
+    let frames = frames as usize;
+
+    let valid = match payload {
+        RtpPayload::L16 => rtp::HEADER_LENGTH + frames * size_of::<FrameS16>() <= MAX_PACKET,
+        #[cfg(feature = "opus")]
+        RtpPayload::Opus => OPUS_FRAMES.contains(&frames),
+    };
+
+    if !valid {
+        return Err(invalid());
+    }
+
+    Ok(frames)
+}
+
+enum Encoder {
+    L16,
+    #[cfg(feature = "opus")]
+    Opus(OpusEncoder),
+}
+
+/// Collects decoded audio into RTP packets
+struct Packetizer {
+    encoder: Encoder,
+    payload_type: u8,
+    ssrc: u32,
+    seq: u16,
+    // set on the first packet after a discontinuity
+    marker: bool,
+    frames: Vec<FrameS16>,
+    packet_frames: usize,
+    // when the first of `frames` plays
+    pts: Timestamp,
+    packet: Vec<u8>,
+}
+
+impl Packetizer {
+    fn new(opt: &RtpOpt, packet_frames: usize) -> Result<Self, RunError> {
+        let encoder = match opt.payload {
+            RtpPayload::L16 => Encoder::L16,
+            #[cfg(feature = "opus")]
+            RtpPayload::Opus => Encoder::Opus(OpusEncoder::new(&OpusEncoderOpt::default())?),
+        };
+
+        let mut rng = rand::thread_rng();
+
+        Ok(Packetizer {
+            encoder,
+            payload_type: opt.payload_type,
+            ssrc: rng.gen(),
+            seq: rng.gen(),
+            marker: true,
+            frames: Vec::with_capacity(packet_frames),
+            packet_frames,
+            pts: Timestamp::from_micros_lossy(TimestampMicros(0)),
+            packet: Vec::with_capacity(MAX_PACKET),
+        })
+    }
+
+    /// Add decoded audio, passing each packet it completes to `send` along
+    /// with when it plays on bark receivers
+    fn push(&mut self, decoded: Decoded, mut send: impl FnMut(&[u8], Timestamp)) {
+        if decoded.discontinuity {
+            self.frames.clear();
+            self.marker = true;
+        }
+
+        if self.frames.is_empty() {
+            self.pts = decoded.pts;
+        }
+
+        self.frames.extend_from_slice(decoded.frames);
+
+        while self.frames.len() >= self.packet_frames {
+            if self.encode() {
+                send(&self.packet, self.pts);
+                self.marker = false;
+            }
+
+            self.seq = self.seq.wrapping_add(1);
+            self.frames.drain(0..self.packet_frames);
+            self.pts = self.pts.add(SampleDuration::from_frame_count(self.packet_frames));
+        }
+    }
+
+    /// Encode the next packet's worth of frames into `packet`
+    fn encode(&mut self) -> bool {
+        let frames = &self.frames[0..self.packet_frames];
+
+        self.packet.clear();
+
+        rtp::Header {
+            marker: self.marker,
+            payload_type: self.payload_type,
+            seq: self.seq,
+            timestamp: rtp::timestamp(self.pts),
+            ssrc: self.ssrc,
+        }.write(&mut self.packet);
+
+        match &mut self.encoder {
+            Encoder::L16 => {
+                for frame in frames {
+                    self.packet.extend_from_slice(&frame.0.to_be_bytes());
+                    self.packet.extend_from_slice(&frame.1.to_be_bytes());
+                }
+            }
+            #[cfg(feature = "opus")]
+            Encoder::Opus(opus) => {
+                let mut buffer = [0; MAX_PACKET - rtp::HEADER_LENGTH];
+
+                match opus.encode_packet(Frames::S16(frames), &mut buffer) {
+                    Ok(length) => self.packet.extend_from_slice(&buffer[0..length]),
+                    Err(e) => {
+                        log::warn!("error encoding RTP packet: {e}");
+                        return false;
+                    }
+                }
+            }
+        }
+
+        true
+    }
+}
+
+/// Session description for players to receive the stream with
+fn sdp(opt: &RtpOpt, ssrc: u32) -> String {
+    let pt = opt.payload_type;
+    let ip = opt.dest.ip();
+
+    // multicast connections carry a TTL, ours is the default of 1
+    let connection = if ip.is_multicast() { format!("{ip}/1") } else { ip.to_string() };
+
+    let fmtp = match opt.payload {
+        RtpPayload::L16 => String::new(),
+        #[cfg(feature = "opus")]
+        RtpPayload::Opus => format!("a=fmtp:{pt} stereo=1; sprop-stereo=1\r\n"),
+    };
+
+    format!(
+        "v=0\r\n\
+        o=- {ssrc} 0 IN IP4 0.0.0.0\r\n\
+        s=bark\r\n\
+        c=IN IP4 {connection}\r\n\
+        t=0 0\r\n\
+        m=audio {port} RTP/AVP {pt}\r\n\
+        a=rtpmap:{pt} {rtpmap}\r\n\
+        a=ptime:{ptime}\r\n\
+        {fmtp}",
+        port = opt.dest.port(),
+        rtpmap = opt.payload.rtpmap(),
+        ptime = opt.packet_ms,
+    )
+}
@@ -0,0 +1,366 @@
+//! A snapserver stand-in, so that snapclient players can play the bark
+//! stream during a migration. Clients sync their clocks to the bridge's,
+//! which is the bark clock, and chunks are timestamped so that they play
+//! out together with bark receivers.
+//!
+//! Messages share a 26 byte little endian header: type, id, and the id
+//! of the message replied to, each u16, then the time sent and received as
+//! pairs of i32 seconds and microseconds, and the payload size as u32.
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use structopt::StructOpt;
+
+use bark_core::audio::FrameS16;
+use bark_protocol::time::{SampleDuration, Timestamp};
+use bark_protocol::types::TimestampMicros;
+use bark_protocol::{CHANNELS, SAMPLE_RATE};
+
+use bark_app::thread;
+
+use crate::socket::{Socket, SocketOpt};
+use crate::{time, RunError};
+
+use super::Decoded;
+
+const HEADER_LENGTH: usize = 26;
+
+// offset of the sent time in the header, filled in as a message is written
+const SENT_OFFSET: usize = 6;
+
+const CODEC_HEADER: u16 = 1;
+const WIRE_CHUNK: u16 = 2;
+const SERVER_SETTINGS: u16 = 3;
+const TIME: u16 = 4;
+const HELLO: u16 = 5;
+
+/// Largest message accepted from a client, clients only send small ones
+const MAX_MESSAGE: usize = 64 * 1024;
+
+/// Messages queued to a client before it is dropped for not keeping up
+const CLIENT_QUEUE: usize = 256;
+
+#[derive(StructOpt)]
+pub struct SnapcastOpt {
+    #[structopt(flatten)]
+    pub socket: SocketOpt,
+
+    /// Address to serve snapcast clients on
+    #[structopt(long, env = "BARK_BRIDGE_SNAPCAST_LISTEN", default_value = "0.0.0.0:1704")]
+    pub listen: SocketAddr,
+
+    /// Buffer size clients are told to use. Chunks are timestamped this far
+    /// ahead of when bark receivers play them, so that they play in sync
+    #[structopt(long, env = "BARK_BRIDGE_SNAPCAST_BUFFER_MS", default_value = "1000")]
+    pub buffer_ms: u64,
+
+    /// Length of audio carried in each chunk sent to clients
+    #[structopt(long, env = "BARK_BRIDGE_SNAPCAST_CHUNK_MS", default_value = "20")]
+    pub chunk_ms: u64,
+}
+
+pub async fn run(opt: SnapcastOpt) -> Result<(), RunError> {
+    let socket = Socket::open(&opt.socket)
+        .map_err(RunError::Listen)?;
+
+    let listener = TcpListener::bind(opt.listen)
+        .map_err(RunError::BridgeListen)?;
+
+    log::info!("serving snapcast clients on {}", opt.listen);
+
+    let clients = Clients::default();
+    let buffer = SampleDuration::from_std_duration_lossy(Duration::from_millis(opt.buffer_ms));
+    let chunk_frames = SampleDuration::from_std_duration_lossy(Duration::from_millis(opt.chunk_ms))
+        .to_frame_count()
+        .max(1) as usize;
+
+    std::thread::spawn({
+        let clients = clients.clone();
+        let buffer_ms = opt.buffer_ms;
+        move || {
+            thread::set_name("bark/snapcast");
+            accept_thread(listener, clients, buffer_ms)
+        }
+    });
+
+    thread::start("bark/network", move || {
+        let mut chunk = Chunk::new(chunk_frames);
+
+        super::network_thread(socket, |decoded| {
+            if let Some(message) = chunk.push(decoded, buffer) {
+                clients.send(&message);
+            }
+        })
+    }).await
+}
+
+/// Audio collected into a chunk for clients
+struct Chunk {
+    frames: Vec<FrameS16>,
+    length: usize,
+    pts: Timestamp,
+}
+
+impl Chunk {
+    fn new(length: usize) -> Self {
+        Chunk {
+            frames: Vec::with_capacity(length),
+            length,
+            pts: Timestamp::from_micros_lossy(TimestampMicros(0)),
+        }
+    }
+
+    /// Add decoded audio, returning a chunk message to send once full. A
+    /// discontinuity starts a new chunk, as the chunk's frames must play
+    /// back to back from its timestamp
+    fn push(&mut self, decoded: Decoded, buffer: SampleDuration) -> Option<Vec<u8>> {
+        if decoded.discontinuity {
+            self.frames.clear();
+        }
+
+        if self.frames.is_empty() {
+            self.pts = decoded.pts;
+        }
+
+        self.frames.extend_from_slice(decoded.frames);
+
+        if self.frames.len() < self.length {
+            return None;
+        }
+
+        // clients play chunks the buffer length after their timestamp
+        let timestamp = self.pts.saturating_sub(buffer).to_micros_lossy();
+        let samples = bytemuck::cast_slice::<FrameS16, u8>(&self.frames);
+
+        let mut payload = Vec::with_capacity(12 + samples.len());
+        payload.extend_from_slice(&timeval(timestamp.0 as i64));
+        payload.extend_from_slice(&(samples.len() as u32).to_le_bytes());
+        payload.extend_from_slice(samples);
+
+        self.frames.clear();
+        Some(message(WIRE_CHUNK, 0, &payload))
+    }
+}
+
+struct Client {
+    stream: TcpStream,
+    queue: SyncSender<Vec<u8>>,
+}
+
+#[derive(Clone, Default)]
+struct Clients(Arc<Mutex<Vec<Client>>>);
+
+impl Clients {
+    fn add(&self, client: Client) {
+        self.0.lock().unwrap().push(client);
+    }
+
+    /// Queue a message to every client, dropping any that have fallen too
+    /// far behind or gone away
+    fn send(&self, message: &[u8]) {
+        self.0.lock().unwrap().retain(|client| {
+            match client.queue.try_send(message.to_vec()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("dropping snapcast client not keeping up: {}", peer(&client.stream));
+                    let _ = client.stream.shutdown(std::net::Shutdown::Both);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+fn accept_thread(listener: TcpListener, clients: Clients, buffer_ms: u64) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("error accepting snapcast client: {e}");
+                continue;
+            }
+        };
+
+        let _ = stream.set_nodelay(true);
+
+        let (queue, rx) = mpsc::sync_channel(CLIENT_QUEUE);
+
+        let writer = match stream.try_clone() {
+            Ok(writer) => writer,
+            Err(e) => {
+                log::warn!("error accepting snapcast client: {e}");
+                continue;
+            }
+        };
+
+        std::thread::spawn(move || {
+            thread::set_name("bark/snapcast");
+            let _ = write_thread(writer, rx);
+        });
+
+        let clients = clients.clone();
+
+        std::thread::spawn(move || {
+            thread::set_name("bark/snapcast");
+
+            let peer = peer(&stream);
+
+            match read_thread(stream, queue, clients, buffer_ms) {
+                Ok(()) => log::info!("snapcast client disconnected: {peer}"),
+                Err(e) => log::info!("snapcast client disconnected: {peer}: {e}"),
+            }
+        });
+    }
+}
+
+/// Handle messages from a client: its hello, after which it is sent audio,
+/// and the time requests it syncs its clock with
+fn read_thread(mut stream: TcpStream, queue: SyncSender<Vec<u8>>, clients: Clients, buffer_ms: u64) -> Result<(), io::Error> {
+    loop {
+        let mut header = [0; HEADER_LENGTH];
+
+        match stream.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+
+        let received = time::now().0 as i64;
+
+        let kind = u16::from_le_bytes([header[0], header[1]]);
+        let id = u16::from_le_bytes([header[2], header[3]]);
+        let sent = read_timeval(&header[SENT_OFFSET..SENT_OFFSET + 8]);
+        let size = u32::from_le_bytes([header[22], header[23], header[24], header[25]]) as usize;
+
+        if size > MAX_MESSAGE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("message too large: {size} bytes")));
+        }
+
+        let mut payload = vec![0; size];
+        stream.read_exact(&mut payload)?;
+
+        match kind {
+            HELLO => {
+                log::info!("snapcast client connected: {}: {}", peer(&stream), hello(&payload));
+
+                let settings = serde_json::json!({
+                    "bufferMs": buffer_ms,
+                    "latency": 0,
+                    "muted": false,
+                    "volume": 100,
+                });
+
+                let _ = queue.send(message(SERVER_SETTINGS, id, &string(settings.to_string().as_bytes())));
+                let _ = queue.send(message(CODEC_HEADER, id, &codec_header()));
+
+                clients.add(Client {
+                    stream: stream.try_clone()?,
+                    queue: queue.clone(),
+                });
+            }
+            TIME => {
+                // the client works out its clock offset from the one way
+                // latency each way, this being the way here
+                let latency = timeval(received - sent);
+                let _ = queue.send(message(TIME, id, &latency));
+            }
+            _ => {
+                // client info and anything newer, which a bridge has no
+                // use for
+            }
+        }
+    }
+}
+
+fn write_thread(mut stream: TcpStream, rx: Receiver<Vec<u8>>) -> Result<(), io::Error> {
+    for mut message in rx {
+        let sent = timeval(time::now().0 as i64);
+        message[SENT_OFFSET..SENT_OFFSET + 8].copy_from_slice(&sent);
+        stream.write_all(&message)?;
+    }
+
+    Ok(())
+}
+
+/// Build a message, with its sent time left to fill in as it is written
+fn message(kind: u16, refers_to: u16, payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(HEADER_LENGTH + payload.len());
+    message.extend_from_slice(&kind.to_le_bytes());
+    message.extend_from_slice(&0u16.to_le_bytes());
+    message.extend_from_slice(&refers_to.to_le_bytes());
+    message.extend_from_slice(&[0; 16]);
+    message.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    message.extend_from_slice(payload);
+    message
+}
+
+/// Raw 16 bit stereo, described with the header of a WAV file as
+/// snapserver does for its pcm codec
+fn codec_header() -> Vec<u8> {
+    let channels = CHANNELS.0;
+    let rate = SAMPLE_RATE.0;
+    let block_align = channels * 2;
+
+    let mut wav = Vec::with_capacity(44);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&36u32.to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&channels.to_le_bytes());
+    wav.extend_from_slice(&rate.to_le_bytes());
+    wav.extend_from_slice(&(rate * u32::from(block_align)).to_le_bytes());
+    wav.extend_from_slice(&block_align.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&0u32.to_le_bytes());
+
+    let mut payload = string(b"pcm");
+    payload.extend_from_slice(&string(&wav));
+    payload
+}
+
+/// Length prefixed bytes
+fn string(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(4 + bytes.len());
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+    out
+}
+
+/// Client's name for itself, for logging, from the JSON in its hello
+fn hello(payload: &[u8]) -> String {
+    let json = payload.get(4..).unwrap_or_default();
+
+    serde_json::from_slice::<serde_json::Value>(json).ok()
+        .and_then(|hello| hello.get("HostName")?.as_str().map(str::to_owned))
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+fn timeval(micros: i64) -> [u8; 8] {
+    let sec = micros.div_euclid(1_000_000) as i32;
+    let usec = micros.rem_euclid(1_000_000) as i32;
+
+    let mut out = [0; 8];
+    out[0..4].copy_from_slice(&sec.to_le_bytes());
+    out[4..8].copy_from_slice(&usec.to_le_bytes());
+    out
+}
+
+fn read_timeval(bytes: &[u8]) -> i64 {
+    let sec = i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    let usec = i32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+    i64::from(sec) * 1_000_000 + i64::from(usec)
+}
+
+fn peer(stream: &TcpStream) -> String {
+    stream.peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|_| "unknown".to_owned())
+}
@@ -1,19 +1,97 @@
 use std::env;
-use std::net::SocketAddr;
-use std::path::Path;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 
+use bark_app::config::{self as app_config, env_name, setting, Setting};
+use bark_core::audio::{channels, protection};
+use bark_protocol::types::AudioPacketFormat;
 use derive_more::{Display, FromStr};
 use serde::Deserialize;
+use structopt::StructOpt;
+use thiserror::Error;
 
-#[derive(Deserialize)]
+use crate::RunError;
+
+#[derive(Deserialize, Default)]
 pub struct Config {
-    multicast: Option<SocketAddr>,
-    #[serde(default)]
+    multicast: Option<Multicast>,
+    clock: Option<String>,
+    control_secret: Option<String>,
+    transport: Option<Transport>,
+    connect: Option<String>,
+    tcp_listen: Option<SocketAddr>,
+    multicast_if: Option<Ipv4Addr>,
+    multicast_ttl: Option<u32>,
+    dscp: Option<u8>,
+    #[serde(default, alias = "stream")]
     source: Source,
     #[serde(default)]
     receive: Receive,
     #[serde(default)]
+    stats: Stats,
+    #[serde(default)]
     metrics: Metrics,
+    #[serde(default)]
+    sched: Sched,
+}
+
+/// One or more multicast groups
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Multicast {
+    One(SocketAddr),
+    Many(Vec<SocketAddr>),
+}
+
+impl fmt::Display for Multicast {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Multicast::One(addr) => write!(f, "{addr}"),
+            Multicast::Many(addrs) => {
+                let addrs = addrs.iter().map(|addr| addr.to_string()).collect::<Vec<_>>();
+                write!(f, "{}", addrs.join(","))
+            }
+        }
+    }
+}
+
+/// One value or a list of them
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum List<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T: fmt::Display> fmt::Display for List<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            List::One(value) => write!(f, "{value}"),
+            List::Many(values) => {
+                let values = values.iter().map(|value| value.to_string()).collect::<Vec<_>>();
+                write!(f, "{}", values.join(","))
+            }
+        }
+    }
+}
+
+/// One or more output devices, played in sync
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum DeviceNames {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl fmt::Display for DeviceNames {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceNames::One(name) => write!(f, "{name}"),
+            // device names may contain commas, eg. hw:0,0
+            DeviceNames::Many(names) => write!(f, "{}", names.join(";")),
+        }
+    }
 }
 
 #[derive(Deserialize, Default)]
@@ -23,6 +101,45 @@ pub struct Source {
     delay_ms: Option<u64>,
     codec: Option<Codec>,
     priority: Option<i8>,
+    packet_ms: Option<f64>,
+    buffer_hint_ms: Option<u16>,
+    start_delay_ms: Option<u64>,
+    redundancy: Option<u8>,
+    redundancy_spacing_ms: Option<u64>,
+    pace: Option<bool>,
+    txtime_lead_ms: Option<u64>,
+    replay_history_secs: Option<u64>,
+    loudness_target: Option<f64>,
+    standby_ms: Option<u64>,
+    input_watchdog_secs: Option<u64>,
+    input_watchdog_hook: Option<String>,
+    rtp_input: Option<SocketAddr>,
+    rtp_input_payload: Option<RtpPayload>,
+    fifo_input: Option<PathBuf>,
+    generator: Option<String>,
+    mpd_metadata: Option<String>,
+    adaptive_codec: Option<bool>,
+    negotiate_format: Option<bool>,
+    web_ui: Option<bool>,
+    allow_unsigned_control: Option<bool>,
+    #[serde(default)]
+    opus: Opus,
+}
+
+#[derive(Deserialize, Default)]
+pub struct Opus {
+    bitrate: Option<i32>,
+    inband_fec: Option<bool>,
+    complexity: Option<u8>,
+    dtx: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct Stats {
+    log_csv: Option<PathBuf>,
+    log_csv_max_mb: Option<u64>,
+    log_csv_keep: Option<usize>,
+    interval: Option<String>,
 }
 
 #[derive(Deserialize, Default)]
@@ -30,6 +147,13 @@ pub struct Metrics {
     listen: Option<SocketAddr>,
 }
 
+#[derive(Deserialize, Default)]
+pub struct Sched {
+    policy: Option<String>,
+    priority: Option<i32>,
+    nice: Option<i32>,
+}
+
 #[derive(Deserialize, Display, FromStr, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
 pub enum Codec {
@@ -37,23 +161,206 @@ pub enum Codec {
     S16LE,
     #[display("f32le")]
     F32LE,
+    #[display("s24le")]
+    S24LE,
+    #[display("s32le")]
+    S32LE,
     #[cfg(feature = "opus")]
     #[display("opus")]
     Opus,
 }
 
+impl Codec {
+    pub fn packet_format(&self) -> AudioPacketFormat {
+        match self {
+            Codec::S16LE => AudioPacketFormat::S16LE,
+            Codec::F32LE => AudioPacketFormat::F32LE,
+            Codec::S24LE => AudioPacketFormat::S24LE,
+            Codec::S32LE => AudioPacketFormat::S32LE,
+            #[cfg(feature = "opus")]
+            Codec::Opus => AudioPacketFormat::OPUS,
+        }
+    }
+
+    /// Bytes each sample takes on the wire, None for compressed codecs
+    pub fn sample_bytes(&self) -> Option<usize> {
+        match self {
+            Codec::S16LE => Some(2),
+            Codec::F32LE => Some(4),
+            Codec::S24LE => Some(3),
+            Codec::S32LE => Some(4),
+            #[cfg(feature = "opus")]
+            Codec::Opus => None,
+        }
+    }
+}
+
+/// How packets travel between nodes
+#[derive(Deserialize, Display, FromStr, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    #[display("udp")]
+    Udp,
+    #[display("tcp")]
+    Tcp,
+}
+
+/// Payload of RTP streams, always stereo at 48 kHz
+#[derive(Deserialize, Display, FromStr, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum RtpPayload {
+    #[display("l16")]
+    L16,
+    #[cfg(feature = "opus")]
+    #[display("opus")]
+    Opus,
+}
+
+impl RtpPayload {
+    /// Encoding name and parameters for an SDP rtpmap attribute
+    pub fn rtpmap(&self) -> &'static str {
+        match self {
+            RtpPayload::L16 => "L16/48000/2",
+            #[cfg(feature = "opus")]
+            RtpPayload::Opus => "opus/48000/2",
+        }
+    }
+}
+
+#[derive(Deserialize, Display, FromStr, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelMap {
+    #[display("stereo")]
+    Stereo,
+    #[display("mono")]
+    Mono,
+    #[display("swap")]
+    Swap,
+    #[display("left")]
+    Left,
+    #[display("right")]
+    Right,
+}
+
+impl ChannelMap {
+    pub fn channel_map(&self) -> channels::ChannelMap {
+        match self {
+            ChannelMap::Stereo => channels::ChannelMap::Stereo,
+            ChannelMap::Mono => channels::ChannelMap::Mono,
+            ChannelMap::Swap => channels::ChannelMap::Swap,
+            ChannelMap::Left => channels::ChannelMap::Left,
+            ChannelMap::Right => channels::ChannelMap::Right,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum Protection {
+    SmallFullrange,
+    Bookshelf,
+}
+
+impl Protection {
+    pub fn profile(&self) -> protection::Profile {
+        match self {
+            Protection::SmallFullrange => protection::Profile::SmallFullrange,
+            Protection::Bookshelf => protection::Profile::Bookshelf,
+        }
+    }
+}
+
+impl From<protection::Profile> for Protection {
+    fn from(profile: protection::Profile) -> Self {
+        match profile {
+            protection::Profile::SmallFullrange => Protection::SmallFullrange,
+            protection::Profile::Bookshelf => Protection::Bookshelf,
+        }
+    }
+}
+
+impl fmt::Display for Protection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protection::SmallFullrange => write!(f, "small-fullrange"),
+            Protection::Bookshelf => write!(f, "bookshelf"),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("protection profile must be small-fullrange or bookshelf")]
+pub struct ParseProtectionError;
+
+impl std::str::FromStr for Protection {
+    type Err = ParseProtectionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "small-fullrange" => Ok(Protection::SmallFullrange),
+            "bookshelf" => Ok(Protection::Bookshelf),
+            _ => Err(ParseProtectionError),
+        }
+    }
+}
+
 #[derive(Deserialize, Default)]
 pub struct Receive {
     #[serde(default)]
-    output: Device,
+    output: Device<DeviceNames>,
+    latency_offset_ms: Option<i64>,
+    device_extra_latency_ms: Option<u64>,
+    min_buffer_ms: Option<u64>,
+    adaptive_buffer_max_ms: Option<u64>,
+    channel_map: Option<ChannelMap>,
+    max_volume: Option<f32>,
+    volume_lock: Option<bool>,
+    noise_shaping: Option<bool>,
+    limiter: Option<bool>,
+    protection: Option<Protection>,
+    duck_input: Option<String>,
+    duck_threshold: Option<f32>,
+    duck_ratio: Option<f32>,
+    zone: Option<String>,
+    allow_unsigned_control: Option<bool>,
+    mix: Option<bool>,
+    event_loop: Option<bool>,
+    sparse_priority: Option<i8>,
+    switch_after_packets: Option<u32>,
+    lock_source: Option<List<IpAddr>>,
+    accept_sid: Option<List<i64>>,
+    idle_release_secs: Option<u64>,
+    fallback_playlist: Option<PathBuf>,
+    fallback_after_secs: Option<u64>,
+    fallback_volume: Option<f32>,
+    remember_drift: Option<bool>,
+    drift_file: Option<PathBuf>,
+    name: Option<String>,
+    id_file: Option<PathBuf>,
 }
 
-#[derive(Deserialize, Default)]
-pub struct Device {
-    device: Option<String>,
+#[derive(Deserialize)]
+pub struct Device<Name = String> {
+    device: Option<Name>,
     period: Option<u64>,
     buffer: Option<u64>,
+    latency_ms: Option<f64>,
     format: Option<Format>,
+    shared: Option<bool>,
+}
+
+// derived Default would require Name: Default
+impl<Name> Default for Device<Name> {
+    fn default() -> Self {
+        Device {
+            device: None,
+            period: None,
+            buffer: None,
+            latency_ms: None,
+            format: None,
+            shared: None,
+        }
+    }
 }
 
 #[derive(Deserialize, Display, FromStr, Clone, Copy)]
@@ -65,61 +372,232 @@ pub enum Format {
     F32,
 }
 
-fn set_env<T: ToString>(name: &str, value: T) {
-    env::set_var(name, value.to_string());
+/// Every setting in the config file, by its dotted path in the file. Each
+/// is passed on through the environment variable named after its path, eg.
+/// `source.delay_ms` sets `BARK_SOURCE_DELAY_MS`
+fn settings(config: &Config) -> Vec<Setting> {
+    vec![
+        setting("multicast", config.multicast.as_ref()),
+        setting("clock", config.clock.as_ref()),
+        setting("control_secret", config.control_secret.as_ref()),
+        setting("transport", config.transport),
+        setting("connect", config.connect.as_ref()),
+        setting("tcp_listen", config.tcp_listen),
+        setting("multicast_if", config.multicast_if),
+        setting("multicast_ttl", config.multicast_ttl),
+        setting("dscp", config.dscp),
+        setting("source.delay_ms", config.source.delay_ms),
+        setting("source.input.device", config.source.input.device.as_ref()),
+        setting("source.input.period", config.source.input.period),
+        setting("source.input.buffer", config.source.input.buffer),
+        setting("source.input.latency_ms", config.source.input.latency_ms),
+        setting("source.input.format", config.source.input.format),
+        setting("source.codec", config.source.codec),
+        setting("source.priority", config.source.priority),
+        setting("source.packet_ms", config.source.packet_ms),
+        setting("source.buffer_hint_ms", config.source.buffer_hint_ms),
+        setting("source.start_delay_ms", config.source.start_delay_ms),
+        setting("source.redundancy", config.source.redundancy),
+        setting("source.redundancy_spacing_ms", config.source.redundancy_spacing_ms),
+        setting("source.pace", config.source.pace),
+        setting("source.txtime_lead_ms", config.source.txtime_lead_ms),
+        setting("source.replay_history_secs", config.source.replay_history_secs),
+        setting("source.loudness_target", config.source.loudness_target),
+        setting("source.standby_ms", config.source.standby_ms),
+        setting("source.input_watchdog_secs", config.source.input_watchdog_secs),
+        setting("source.input_watchdog_hook", config.source.input_watchdog_hook.as_ref()),
+        setting("source.rtp_input", config.source.rtp_input),
+        setting("source.rtp_input_payload", config.source.rtp_input_payload),
+        setting("source.fifo_input", config.source.fifo_input.as_ref().map(|path| path.display())),
+        setting("source.generator", config.source.generator.as_ref()),
+        setting("source.mpd_metadata", config.source.mpd_metadata.as_ref()),
+        setting("source.adaptive_codec", config.source.adaptive_codec),
+        setting("source.negotiate_format", config.source.negotiate_format),
+        setting("source.web_ui", config.source.web_ui),
+        setting("source.allow_unsigned_control", config.source.allow_unsigned_control),
+        setting("source.opus.bitrate", config.source.opus.bitrate),
+        setting("source.opus.inband_fec", config.source.opus.inband_fec),
+        setting("source.opus.complexity", config.source.opus.complexity),
+        setting("source.opus.dtx", config.source.opus.dtx),
+        setting("receive.output.device", config.receive.output.device.as_ref()),
+        setting("receive.output.period", config.receive.output.period),
+        setting("receive.output.buffer", config.receive.output.buffer),
+        setting("receive.output.latency_ms", config.receive.output.latency_ms),
+        setting("receive.output.format", config.receive.output.format),
+        setting("receive.output.shared", config.receive.output.shared),
+        setting("receive.latency_offset_ms", config.receive.latency_offset_ms),
+        setting("receive.device_extra_latency_ms", config.receive.device_extra_latency_ms),
+        setting("receive.min_buffer_ms", config.receive.min_buffer_ms),
+        setting("receive.adaptive_buffer_max_ms", config.receive.adaptive_buffer_max_ms),
+        setting("receive.channel_map", config.receive.channel_map),
+        setting("receive.max_volume", config.receive.max_volume),
+        setting("receive.volume_lock", config.receive.volume_lock),
+        setting("receive.noise_shaping", config.receive.noise_shaping),
+        setting("receive.limiter", config.receive.limiter),
+        setting("receive.protection", config.receive.protection),
+        setting("receive.duck_input", config.receive.duck_input.as_ref()),
+        setting("receive.duck_threshold", config.receive.duck_threshold),
+        setting("receive.duck_ratio", config.receive.duck_ratio),
+        setting("receive.zone", config.receive.zone.as_ref()),
+        setting("receive.allow_unsigned_control", config.receive.allow_unsigned_control),
+        setting("receive.mix", config.receive.mix),
+        setting("receive.event_loop", config.receive.event_loop),
+        setting("receive.sparse_priority", config.receive.sparse_priority),
+        setting("receive.switch_after_packets", config.receive.switch_after_packets),
+        setting("receive.lock_source", config.receive.lock_source.as_ref()),
+        setting("receive.accept_sid", config.receive.accept_sid.as_ref()),
+        setting("receive.idle_release_secs", config.receive.idle_release_secs),
+        setting("receive.fallback_playlist", config.receive.fallback_playlist.as_ref().map(|path| path.display())),
+        setting("receive.fallback_after_secs", config.receive.fallback_after_secs),
+        setting("receive.fallback_volume", config.receive.fallback_volume),
+        setting("receive.remember_drift", config.receive.remember_drift),
+        setting("receive.drift_file", config.receive.drift_file.as_ref().map(|path| path.display())),
+        setting("receive.name", config.receive.name.as_ref()),
+        setting("receive.id_file", config.receive.id_file.as_ref().map(|path| path.display())),
+        setting("stats.log_csv", config.stats.log_csv.as_ref().map(|path| path.display())),
+        setting("stats.log_csv_max_mb", config.stats.log_csv_max_mb),
+        setting("stats.log_csv_keep", config.stats.log_csv_keep),
+        setting("stats.interval", config.stats.interval.as_ref()),
+        setting("metrics.listen", config.metrics.listen),
+        setting("sched.policy", config.sched.policy.as_ref()),
+        setting("sched.priority", config.sched.priority),
+        setting("sched.nice", config.sched.nice),
+    ]
+}
+
+/// Settings whose values are never printed
+const SECRET_SETTINGS: &[&str] = &["control_secret"];
+
+pub fn load_into_env(config: &Config) {
+    app_config::load_into_env(settings(config));
+}
+
+/// Read the config file if there is one. Unknown settings are warned about
+/// but otherwise ignored, so that older versions of bark can share a config
+/// file with newer ones
+pub fn read() -> Result<Option<Config>, ConfigError> {
+    let Some(path) = find() else {
+        return Ok(None);
+    };
+
+    let (config, unknown) = load(&path)?;
+
+    log::info!("reading config from {}", path.display());
+
+    for setting in unknown {
+        log::warn!("{}: unknown setting: {setting}", path.display());
+    }
+
+    Ok(Some(config))
+}
+
+/// Locate the config file, see `bark_app::config::find`
+fn find() -> Option<PathBuf> {
+    app_config::find("bark.toml")
 }
 
-fn set_env_option<T: ToString>(name: &str, value: Option<T>) {
-    if let Some(value) = value {
-        set_env(name, value)
+/// Load a config file, along with any settings in it that bark doesn't know
+fn load(path: &Path) -> Result<(Config, Vec<String>), ConfigError> {
+    let (config, mut table) = app_config::load::<Config>(path)?;
+
+    // [stream] is an alias for [source], named after the command
+    if let Some(stream) = table.remove("stream") {
+        table.insert("source".to_owned(), stream);
     }
+
+    let known = settings(&Config::default()).into_iter()
+        .map(|(path, _)| path)
+        .collect::<Vec<_>>();
+
+    let unknown = app_config::unknown_settings(&table, &known);
+
+    Ok((config, unknown))
 }
 
-pub fn load_into_env(config: &Config) {
-    set_env_option("BARK_MULTICAST", config.multicast);
-    set_env_option("BARK_SOURCE_DELAY_MS", config.source.delay_ms);
-    set_env_option("BARK_SOURCE_INPUT_DEVICE", config.source.input.device.as_ref());
-    set_env_option("BARK_SOURCE_INPUT_PERIOD", config.source.input.period);
-    set_env_option("BARK_SOURCE_INPUT_BUFFER", config.source.input.buffer);
-    set_env_option("BARK_SOURCE_INPUT_FORMAT", config.source.input.format);
-    set_env_option("BARK_SOURCE_CODEC", config.source.codec);
-    set_env_option("BARK_SOURCE_PRIORITY", config.source.priority);
-    set_env_option("BARK_RECEIVE_OUTPUT_DEVICE", config.receive.output.device.as_ref());
-    set_env_option("BARK_RECEIVE_OUTPUT_PERIOD", config.receive.output.period);
-    set_env_option("BARK_RECEIVE_OUTPUT_BUFFER", config.receive.output.buffer);
-    set_env_option("BARK_RECEIVE_OUTPUT_FORMAT", config.receive.output.format);
-    set_env_option("BARK_METRICS_LISTEN", config.metrics.listen);
-}
-
-fn load_file(path: &Path) -> Option<Config> {
-    log::debug!("looking for config in {}", path.display());
-
-    let contents = std::fs::read_to_string(path).ok()?;
-
-    match toml::from_str(&contents) {
-        Ok(config) => {
-            log::info!("reading config from {}", path.display());
-            Some(config)
-        },
-        Err(e) => {
-            log::error!("error reading config: {}", e);
-            std::process::exit(1);
-        }
+#[derive(StructOpt)]
+pub enum ConfigOpt {
+    /// Check a config file for errors and unknown settings
+    Check(CheckOpt),
+    /// Print every setting in effect and where it was set
+    PrintEffective,
+}
+
+#[derive(StructOpt)]
+pub struct CheckOpt {
+    /// Config file to check, defaults to the one bark would read
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("no config file found")]
+    NotFound,
+    #[error(transparent)]
+    Load(#[from] app_config::LoadError),
+    #[error("{0}: {1} unknown setting(s)")]
+    UnknownSettings(PathBuf, usize),
+}
+
+pub fn run(opt: ConfigOpt) -> Result<(), RunError> {
+    match opt {
+        ConfigOpt::Check(opt) => Ok(check(opt)?),
+        ConfigOpt::PrintEffective => Ok(print_effective()?),
+    }
+}
+
+fn check(opt: CheckOpt) -> Result<(), ConfigError> {
+    let path = opt.path.or_else(find)
+        .ok_or(ConfigError::NotFound)?;
+
+    let (_, unknown) = load(&path)?;
+
+    for setting in &unknown {
+        println!("{}: unknown setting: {setting}", path.display());
+    }
+
+    if !unknown.is_empty() {
+        return Err(ConfigError::UnknownSettings(path, unknown.len()));
     }
+
+    println!("{}: ok", path.display());
+    Ok(())
 }
 
-pub fn read() -> Option<Config> {
-    // try current directory first
-    if let Some(config) = load_file(Path::new("bark.toml")) {
-        return Some(config);
+fn print_effective() -> Result<(), ConfigError> {
+    let path = find();
+
+    let config = match &path {
+        Some(path) => load(path)?.0,
+        None => Config::default(),
+    };
+
+    match &path {
+        Some(path) => println!("# config file: {}", path.display()),
+        None => println!("# no config file found"),
     }
 
-    // otherwise try xdg config dirs
-    let dirs = xdg::BaseDirectories::new().unwrap();
-    if let Some(config) = dirs.find_config_file("bark.toml") {
-        return load_file(&config);
+    println!("# command line options take precedence over all of these");
+
+    for (setting, value) in settings(&config) {
+        let env_name = env_name(setting);
+
+        // config file settings are loaded into the environment at startup,
+        // unless the environment already has a value of its own
+        let (value, origin) = match (env::var(&env_name), value) {
+            (Ok(value), Some(file)) if value != file => (value, format!("environment {env_name}")),
+            (_, Some(value)) => (value, "config file".to_owned()),
+            (Ok(value), None) => (value, format!("environment {env_name}")),
+            (Err(_), None) => continue,
+        };
+
+        // don't leak secrets into terminals and bug reports
+        if SECRET_SETTINGS.contains(&setting) {
+            println!("{setting} = (hidden)  # {origin}");
+            continue;
+        }
+
+        println!("{setting} = {value:?}  # {origin}");
     }
 
-    // found nothing
-    None
+    Ok(())
 }
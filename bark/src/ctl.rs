@@ -0,0 +1,436 @@
+use std::net::{SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+use bytemuck::Zeroable;
+use structopt::StructOpt;
+
+use bark_protocol::packet::{DspRequest, DumpRequest, Identify, OutputRequest, Packet, PacketKind, ReplayRequest, StatsRequest, Takeover, VolumeRequest};
+use bark_protocol::types::{DumpReplyFlags, OutputReplyFlags, ReplayReplyFlags, SessionId, StatsReplyFlags};
+
+use crate::config;
+use crate::receive::dsp;
+use crate::socket::{PeerId, ProtocolSocket, Socket, SocketOpt};
+use crate::zones;
+use crate::RunError;
+
+// how long to wait for a node to reply before asking again, and how many
+// times to ask before giving up on it
+const REPLY_TIMEOUT: Duration = Duration::from_millis(500);
+const REPLY_ATTEMPTS: usize = 3;
+
+#[derive(StructOpt)]
+pub enum CtlOpt {
+    /// Record a receiver's decoded audio to files on that receiver
+    Dump(DumpOpt),
+    /// Switch a receiver to a different output device
+    Output(OutputOpt),
+    /// Replay recent audio from a stream source
+    Replay(ReplayOpt),
+    /// Set a receiver's volume or mute it
+    Volume(VolumeOpt),
+    /// Play a chime on a receiver to find which speaker it is
+    Identify(IdentifyOpt),
+    /// Change a receiver's limiter and speaker protection while it plays
+    Dsp(DspOpt),
+}
+
+#[derive(StructOpt)]
+pub struct DumpOpt {
+    #[structopt(flatten)]
+    pub socket: SocketOpt,
+
+    /// Address of the receiver, as shown in `bark stats`
+    #[structopt(long)]
+    pub peer: SocketAddr,
+
+    /// Number of seconds of audio to record
+    #[structopt(long, default_value = "10")]
+    pub seconds: u64,
+}
+
+#[derive(StructOpt)]
+pub struct OutputOpt {
+    #[structopt(flatten)]
+    pub socket: SocketOpt,
+
+    /// Address of the receiver, as shown in `bark stats`
+    #[structopt(long)]
+    pub peer: SocketAddr,
+
+    /// Output device name, or the receiver's default device if omitted
+    #[structopt(long)]
+    pub device: Option<String>,
+}
+
+#[derive(StructOpt)]
+pub struct ReplayOpt {
+    #[structopt(flatten)]
+    pub socket: SocketOpt,
+
+    /// Address of the stream source, as shown in `bark stats`
+    #[structopt(long)]
+    pub peer: SocketAddr,
+
+    /// Number of seconds of recent audio to replay
+    #[structopt(long, default_value = "10")]
+    pub seconds: u64,
+
+    /// Receivers to play the replay on, defaults to all receivers
+    #[structopt(long)]
+    pub to: Vec<SocketAddrV4>,
+
+    /// Play the replay on every receiver in this zone
+    #[structopt(long, conflicts_with = "to", parse(try_from_str = zones::parse_zone))]
+    pub zone: Option<String>,
+}
+
+#[derive(StructOpt)]
+pub struct VolumeOpt {
+    #[structopt(flatten)]
+    pub socket: SocketOpt,
+
+    /// Address of the receiver, as shown in `bark stats`
+    #[structopt(long)]
+    pub peer: SocketAddr,
+
+    /// Volume as a percentage
+    #[structopt(long)]
+    pub volume: Option<f32>,
+
+    #[structopt(long, conflicts_with = "unmute")]
+    pub mute: bool,
+
+    #[structopt(long)]
+    pub unmute: bool,
+
+    /// Maximum volume as a percentage, which the receiver enforces
+    /// whatever volume is requested
+    #[structopt(long)]
+    pub max: Option<f32>,
+
+    /// Lock the maximum volume so it can't be changed again until the
+    /// receiver restarts
+    #[structopt(long)]
+    pub lock: bool,
+}
+
+#[derive(StructOpt)]
+pub struct IdentifyOpt {
+    #[structopt(flatten)]
+    pub socket: SocketOpt,
+
+    /// Address of the receiver, as shown in `bark stats`
+    #[structopt(long)]
+    pub peer: SocketAddr,
+}
+
+#[derive(StructOpt)]
+pub struct DspOpt {
+    #[structopt(flatten)]
+    pub socket: SocketOpt,
+
+    /// Address of the receiver, as shown in `bark stats`
+    #[structopt(long)]
+    pub peer: SocketAddr,
+
+    #[structopt(long, conflicts_with = "no-limiter")]
+    pub limiter: bool,
+
+    #[structopt(long)]
+    pub no_limiter: bool,
+
+    /// Speaker protection profile: small-fullrange or bookshelf
+    #[structopt(long, conflicts_with = "no-protection")]
+    pub protection: Option<config::Protection>,
+
+    #[structopt(long)]
+    pub no_protection: bool,
+
+    /// Bypass the limiter and speaker protection, to compare settings by
+    /// ear. Switching crossfades, so the two can be flipped between freely
+    #[structopt(long, conflicts_with = "no-bypass")]
+    pub bypass: bool,
+
+    #[structopt(long)]
+    pub no_bypass: bool,
+}
+
+#[derive(StructOpt)]
+pub struct TakeoverOpt {
+    #[structopt(flatten)]
+    pub socket: SocketOpt,
+
+    /// Address of the stream source to switch receivers to, as shown in
+    /// `bark stats`
+    #[structopt(long, required_unless = "release")]
+    pub source: Option<SocketAddr>,
+
+    /// Release any previous takeover, returning to priority based stream
+    /// selection
+    #[structopt(long, conflicts_with = "source")]
+    pub release: bool,
+}
+
+pub fn run(opt: CtlOpt) -> Result<(), RunError> {
+    match opt {
+        CtlOpt::Dump(opt) => dump(opt),
+        CtlOpt::Output(opt) => output(opt),
+        CtlOpt::Replay(opt) => replay(opt),
+        CtlOpt::Volume(opt) => volume(opt),
+        CtlOpt::Identify(opt) => identify(opt),
+        CtlOpt::Dsp(opt) => dsp(opt),
+    }
+}
+
+fn dump(opt: DumpOpt) -> Result<(), RunError> {
+    let socket = Socket::open(&opt.socket)
+        .map_err(RunError::Listen)?;
+
+    let protocol = ProtocolSocket::new(socket);
+    let peer = PeerId::from(opt.peer);
+
+    let request = DumpRequest::new(Duration::from_secs(opt.seconds))
+        .expect("allocate DumpRequest packet");
+
+    let reply = request_reply(&protocol, request.as_packet(), peer, |packet| match packet {
+        PacketKind::DumpReply(reply) => Some(reply),
+        _ => None,
+    })?;
+
+    if reply.flags().contains(DumpReplyFlags::ERROR) {
+        return Err(RunError::Ctl(reply.message().to_owned()));
+    }
+
+    println!("{peer}: dumping audio to {}", reply.message());
+    Ok(())
+}
+
+fn output(opt: OutputOpt) -> Result<(), RunError> {
+    let socket = Socket::open(&opt.socket)
+        .map_err(RunError::Listen)?;
+
+    let protocol = ProtocolSocket::new(socket);
+    let peer = PeerId::from(opt.peer);
+
+    let request = OutputRequest::new(opt.device.as_deref())
+        .expect("allocate OutputRequest packet");
+
+    let reply = request_reply(&protocol, request.as_packet(), peer, |packet| match packet {
+        PacketKind::OutputReply(reply) => Some(reply),
+        _ => None,
+    })?;
+
+    if reply.flags().contains(OutputReplyFlags::ERROR) {
+        return Err(RunError::Ctl(reply.message().to_owned()));
+    }
+
+    println!("{peer}: playing to {}", reply.message());
+    Ok(())
+}
+
+fn replay(opt: ReplayOpt) -> Result<(), RunError> {
+    let socket = Socket::open(&opt.socket)
+        .map_err(RunError::Listen)?;
+
+    let protocol = ProtocolSocket::new(socket);
+    let peer = PeerId::from(opt.peer);
+
+    let targets = match &opt.zone {
+        Some(zone) => zone_receivers(&protocol, zone)?,
+        None => opt.to,
+    };
+
+    let request = ReplayRequest::new(Duration::from_secs(opt.seconds), &targets)
+        .expect("allocate ReplayRequest packet");
+
+    let reply = request_reply(&protocol, request.as_packet(), peer, |packet| match packet {
+        PacketKind::ReplayReply(reply) => Some(reply),
+        _ => None,
+    })?;
+
+    if reply.flags().contains(ReplayReplyFlags::ERROR) {
+        return Err(RunError::Ctl(reply.message().to_owned()));
+    }
+
+    println!("{peer}: {}", reply.message());
+    Ok(())
+}
+
+/// Addresses of the receivers in `zone`, from their stats replies
+fn zone_receivers(protocol: &ProtocolSocket, zone: &str) -> Result<Vec<SocketAddrV4>, RunError> {
+    let targets = zones::receivers(protocol)?.into_iter()
+        .filter(|(_, reply)| reply.data().receiver.zone() == Some(zone))
+        .filter_map(|(peer, _)| match peer.addr() {
+            SocketAddr::V4(addr) => Some(addr),
+            SocketAddr::V6(_) => None,
+        })
+        .collect::<Vec<_>>();
+
+    if targets.is_empty() {
+        return Err(RunError::Ctl(format!("no receivers in zone {zone}")));
+    }
+
+    Ok(targets)
+}
+
+fn volume(opt: VolumeOpt) -> Result<(), RunError> {
+    let socket = Socket::open(&opt.socket)
+        .map_err(RunError::Listen)?;
+
+    let protocol = ProtocolSocket::new(socket);
+    let peer = PeerId::from(opt.peer);
+
+    let volume = opt.volume.map(|percent| percent / 100.0);
+
+    let mute = if opt.mute {
+        Some(true)
+    } else if opt.unmute {
+        Some(false)
+    } else {
+        None
+    };
+
+    // set the limit first so a requested volume can't briefly exceed it
+    if opt.max.is_some() || opt.lock {
+        let request = VolumeRequest::limit(opt.max.map(|percent| percent / 100.0), opt.lock)
+            .expect("allocate VolumeRequest packet");
+
+        protocol.send_to(request.as_packet(), peer)
+            .map_err(RunError::Send)?;
+    }
+
+    if volume.is_some() || mute.is_some() {
+        let request = VolumeRequest::new(volume, mute)
+            .expect("allocate VolumeRequest packet");
+
+        protocol.send_to(request.as_packet(), peer)
+            .map_err(RunError::Send)?;
+    }
+
+    Ok(())
+}
+
+fn identify(opt: IdentifyOpt) -> Result<(), RunError> {
+    let socket = Socket::open(&opt.socket)
+        .map_err(RunError::Listen)?;
+
+    let protocol = ProtocolSocket::new(socket);
+    let peer = PeerId::from(opt.peer);
+
+    let request = Identify::new()
+        .expect("allocate Identify packet");
+
+    protocol.send_to(request.as_packet(), peer)
+        .map_err(RunError::Send)
+}
+
+fn dsp(opt: DspOpt) -> Result<(), RunError> {
+    let socket = Socket::open(&opt.socket)
+        .map_err(RunError::Listen)?;
+
+    let protocol = ProtocolSocket::new(socket);
+    let peer = PeerId::from(opt.peer);
+
+    let flag = |on: bool, off: bool| {
+        if on {
+            Some(true)
+        } else if off {
+            Some(false)
+        } else {
+            None
+        }
+    };
+
+    let protection = if opt.no_protection {
+        Some(dsp::profile_code(None))
+    } else {
+        opt.protection.map(|protection| dsp::profile_code(Some(protection.profile())))
+    };
+
+    let request = DspRequest::new(
+        flag(opt.limiter, opt.no_limiter),
+        protection,
+        flag(opt.bypass, opt.no_bypass),
+    ).expect("allocate DspRequest packet");
+
+    protocol.send_to(request.as_packet(), peer)
+        .map_err(RunError::Send)
+}
+
+/// Force all receivers to switch to a source's stream immediately
+pub fn takeover(opt: TakeoverOpt) -> Result<(), RunError> {
+    let socket = Socket::open(&opt.socket)
+        .map_err(RunError::Listen)?;
+
+    let protocol = ProtocolSocket::new(socket);
+
+    let sid = match opt.source {
+        Some(source) if !opt.release => source_session(&protocol, PeerId::from(source))?,
+        _ => SessionId::zeroed(),
+    };
+
+    let takeover = Takeover::new(sid)
+        .expect("allocate Takeover packet");
+
+    protocol.broadcast(takeover.as_packet())
+        .map_err(RunError::Send)?;
+
+    match opt.source {
+        Some(source) if !opt.release => println!("receivers switching to {source}: sid={}", sid.0),
+        _ => println!("released takeover"),
+    }
+
+    Ok(())
+}
+
+/// Ask a stream source for its current session id
+fn source_session(protocol: &ProtocolSocket, source: PeerId) -> Result<SessionId, RunError> {
+    let request = StatsRequest::new()
+        .expect("allocate StatsRequest packet");
+
+    let reply = request_reply(protocol, request.as_packet(), source, |packet| match packet {
+        PacketKind::StatsReply(reply) => Some(reply),
+        _ => None,
+    })?;
+
+    if !reply.flags().contains(StatsReplyFlags::IS_STREAM) {
+        return Err(RunError::Ctl(format!("{source} is not a stream source")));
+    }
+
+    Ok(reply.data().sid)
+}
+
+/// Send `request` to `peer` and wait for the first reply from it that
+/// `accept` takes, asking again if none comes within REPLY_TIMEOUT
+fn request_reply<T>(
+    protocol: &ProtocolSocket,
+    request: &Packet,
+    peer: PeerId,
+    mut accept: impl FnMut(PacketKind) -> Option<T>,
+) -> Result<T, RunError> {
+    for _ in 0..REPLY_ATTEMPTS {
+        protocol.send_to(request, peer)
+            .map_err(RunError::Send)?;
+
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+
+        while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+            let received = protocol.recv_from_timeout(Some(timeout))
+                .map_err(RunError::Receive)?;
+
+            let Some((packet, from)) = received else {
+                break;
+            };
+
+            if from != peer {
+                continue;
+            }
+
+            if let Some(reply) = packet.parse().and_then(&mut accept) {
+                return Ok(reply);
+            }
+        }
+    }
+
+    Err(RunError::Ctl(format!("no reply from {peer}")))
+}
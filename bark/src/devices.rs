@@ -0,0 +1,74 @@
+use structopt::StructOpt;
+
+use crate::audio::{self, Capabilities};
+use crate::RunError;
+
+#[derive(StructOpt)]
+pub struct DevicesOpt {
+    /// Only list devices that can capture, for `bark stream --input-device`
+    #[structopt(long, conflicts_with = "output")]
+    pub input: bool,
+
+    /// Only list devices that can play, for `bark receive --output-device`
+    #[structopt(long)]
+    pub output: bool,
+}
+
+pub fn run(opt: DevicesOpt) -> Result<(), RunError> {
+    let devices = audio::devices()
+        .map_err(RunError::AudioInput)?;
+
+    for device in devices {
+        let input = device.input.filter(|_| !opt.output);
+        let output = device.output.filter(|_| !opt.input);
+
+        if input.is_none() && output.is_none() {
+            continue;
+        }
+
+        println!("{}", device.name);
+
+        if let Some(description) = &device.description {
+            println!("    {description}");
+        }
+
+        let directions = [("input", input), ("output", output)];
+
+        for (direction, capabilities) in directions {
+            match capabilities {
+                Some(Ok(capabilities)) => println!("    {direction:<6}  {}", describe(&capabilities)),
+                Some(Err(e)) => println!("    {direction:<6}  unavailable: {e}"),
+                None => {}
+            }
+        }
+
+        println!();
+    }
+
+    Ok(())
+}
+
+fn describe(capabilities: &Capabilities) -> String {
+    let range = |range: &std::ops::RangeInclusive<u32>| {
+        if range.start() == range.end() {
+            range.start().to_string()
+        } else {
+            format!("{}-{}", range.start(), range.end())
+        }
+    };
+
+    let formats = match capabilities.formats.as_slice() {
+        [] => "other".to_owned(),
+        formats => formats.join(" "),
+    };
+
+    let note = if capabilities.native {
+        ""
+    } else {
+        ", can't play 48000 Hz stereo S16_LE or FLOAT_LE as is, use a plug device"
+    };
+
+    format!("{} Hz, {} channels, {formats}{note}",
+        range(&capabilities.rates),
+        range(&capabilities.channels))
+}
@@ -0,0 +1,144 @@
+use bark_core::audio::dsp::{Chain, Settings};
+use bark_core::consts::MAX_QUEUED_DECODE_SEGMENTS;
+use bark_core::receive::resample;
+use bark_protocol::time::SampleDuration;
+use bark_protocol::{FRAMES_PER_PACKET, MAX_FRAMES_PER_PACKET};
+use structopt::StructOpt;
+
+use crate::audio::config::{self as audio_config, DeviceOpt};
+use crate::audio::{self, OpenError, Sizes};
+use crate::RunError;
+
+#[derive(StructOpt)]
+pub struct DiagOpt {
+    /// Input device to report period and buffer sizes for
+    #[structopt(long, env = "BARK_SOURCE_INPUT_DEVICE")]
+    pub input_device: Option<String>,
+
+    #[structopt(long, env = "BARK_SOURCE_INPUT_PERIOD")]
+    pub input_period: Option<usize>,
+
+    #[structopt(long, env = "BARK_SOURCE_INPUT_BUFFER")]
+    pub input_buffer: Option<usize>,
+
+    #[structopt(long, env = "BARK_SOURCE_INPUT_LATENCY_MS")]
+    pub input_latency_ms: Option<f64>,
+
+    /// Output device to report period and buffer sizes for
+    #[structopt(long, env = "BARK_RECEIVE_OUTPUT_DEVICE")]
+    pub output_device: Option<String>,
+
+    #[structopt(long, env = "BARK_RECEIVE_OUTPUT_PERIOD")]
+    pub output_period: Option<usize>,
+
+    #[structopt(long, env = "BARK_RECEIVE_OUTPUT_BUFFER")]
+    pub output_buffer: Option<usize>,
+
+    #[structopt(long, env = "BARK_RECEIVE_OUTPUT_LATENCY_MS")]
+    pub output_latency_ms: Option<f64>,
+
+    #[structopt(
+        long,
+        env = "BARK_RECEIVE_OUTPUT_SHARED",
+        default_value = "false",
+        parse(try_from_str),
+    )]
+    pub output_shared: bool,
+
+    /// Only report what's fixed by this build, without opening any devices
+    #[structopt(long)]
+    pub no_devices: bool,
+}
+
+/// Print the latency each stage of the pipeline contributes, as built and
+/// as configured, for reasoning about end to end latency
+pub fn run(opt: DiagOpt) -> Result<(), RunError> {
+    let packet = SampleDuration::from_frame_count(FRAMES_PER_PACKET);
+    let max_packet = SampleDuration::from_frame_count(MAX_FRAMES_PER_PACKET);
+    let queue = SampleDuration::from_frame_count(MAX_QUEUED_DECODE_SEGMENTS * FRAMES_PER_PACKET);
+    let max_queue = SampleDuration::from_frame_count(MAX_QUEUED_DECODE_SEGMENTS * MAX_FRAMES_PER_PACKET);
+
+    println!("bark {}", crate::version());
+    println!();
+    println!("pipeline");
+    println!("  packet duration      {} by default, up to {}", describe(packet), describe(max_packet));
+    println!("  receive queue        {MAX_QUEUED_DECODE_SEGMENTS} packets, {} of default packets, up to {}",
+        ms(queue), ms(max_queue));
+
+    println!("  resampler            {}", resample::BACKEND);
+
+    match resample::measure_delay() {
+        Some(delay) => println!("  resampler delay      {}", describe(delay)),
+        None => println!("  resampler delay      unknown"),
+    }
+
+    println!("  opus lookahead       {}", opus_lookahead());
+
+    let chain = Chain::new(Settings { limiter: true, protection: None });
+    println!("  output chain         {} (limiter lookahead, whether enabled or not)", describe(chain.latency()));
+
+    if opt.no_devices {
+        return Ok(());
+    }
+
+    let input = DeviceOpt {
+        device: opt.input_device,
+        period: opt.input_period.map(SampleDuration::from_frame_count),
+        buffer: opt.input_buffer.map(SampleDuration::from_frame_count),
+        latency: audio_config::latency(opt.input_latency_ms),
+        shared: false,
+    };
+
+    let output = DeviceOpt {
+        device: opt.output_device,
+        period: opt.output_period.map(SampleDuration::from_frame_count),
+        buffer: opt.output_buffer.map(SampleDuration::from_frame_count),
+        latency: audio_config::latency(opt.output_latency_ms),
+        shared: opt.output_shared,
+    };
+
+    println!();
+    println!("devices");
+    print_sizes("input", &input, audio::input_sizes(&input));
+    print_sizes("output", &output, audio::output_sizes(&output));
+
+    Ok(())
+}
+
+#[cfg(feature = "opus")]
+fn opus_lookahead() -> String {
+    use bark_core::encode::opus::{OpusEncoder, OpusEncoderOpt};
+
+    let lookahead = OpusEncoder::new(&OpusEncoderOpt::default())
+        .map_err(|e| e.to_string())
+        .and_then(|mut encoder| encoder.lookahead().map_err(|e| e.to_string()));
+
+    match lookahead {
+        Ok(lookahead) => describe(lookahead),
+        Err(e) => format!("unknown: {e}"),
+    }
+}
+
+#[cfg(not(feature = "opus"))]
+fn opus_lookahead() -> String {
+    "not built with opus".to_owned()
+}
+
+fn print_sizes(direction: &str, opt: &DeviceOpt, sizes: Result<Sizes, OpenError>) {
+    let device = opt.device.as_deref().unwrap_or("default");
+
+    match sizes {
+        Ok(sizes) => println!("  {direction:<6}  {device}: period {}, buffer {}",
+            describe(sizes.period),
+            describe(sizes.buffer)),
+        Err(e) => println!("  {direction:<6}  {device}: {e}"),
+    }
+}
+
+fn describe(duration: SampleDuration) -> String {
+    format!("{} ({} frames)", ms(duration), duration.to_frame_count())
+}
+
+fn ms(duration: SampleDuration) -> String {
+    format!("{:.2} ms", duration.to_micros_lossy() as f64 / 1000.0)
+}
@@ -0,0 +1,192 @@
+use std::collections::HashSet;
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant};
+
+use derive_more::Display;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use structopt::StructOpt;
+
+use bark_protocol::MAX_FRAMES_PER_PACKET;
+
+use crate::stats;
+use crate::RunError;
+
+const SERVICE_TYPE: &str = "_bark._udp.local.";
+
+/// How long to search for a multicast group when none is configured
+pub const DISCOVER_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Display, Clone, Copy)]
+pub enum Role {
+    #[display("source")]
+    Source,
+    #[display("receiver")]
+    Receiver,
+}
+
+#[derive(StructOpt)]
+pub struct DiscoverOpt {
+    /// Number of seconds to listen for announcements
+    #[structopt(long, default_value = "3")]
+    pub seconds: u64,
+}
+
+/// Advertises this node over mDNS for as long as it is held
+pub struct Announcement {
+    mdns: ServiceDaemon,
+}
+
+impl Drop for Announcement {
+    fn drop(&mut self) {
+        let _ = self.mdns.shutdown();
+    }
+}
+
+/// Announce this node and the multicast group it is using. Failure to
+/// announce is not fatal, nodes can still be configured manually.
+pub fn announce(role: Role, group: SocketAddrV4) -> Option<Announcement> {
+    match try_announce(role, group) {
+        Ok(announcement) => Some(announcement),
+        Err(e) => {
+            log::warn!("failed to announce over mDNS: {e}");
+            None
+        }
+    }
+}
+
+fn try_announce(role: Role, group: SocketAddrV4) -> Result<Announcement, mdns_sd::Error> {
+    let mdns = ServiceDaemon::new()?;
+
+    let port = group.port();
+    let hostname = stats::node::hostname(&stats::node::get()).to_owned();
+    let instance = format!("bark {role} on {hostname}");
+    let group = group.to_string();
+    let max_packet_frames = MAX_FRAMES_PER_PACKET.to_string();
+
+    let mut properties = vec![
+        ("role", role.to_string()),
+        ("group", group),
+    ];
+
+    // so sources can fit their packets to receivers before streaming
+    if let Role::Receiver = role {
+        properties.push(("max_packet_frames", max_packet_frames));
+    }
+
+    let info = ServiceInfo::new(
+        SERVICE_TYPE,
+        &instance,
+        &format!("{hostname}.local."),
+        "",
+        port,
+        &properties[..],
+    )?.enable_addr_auto();
+
+    mdns.register(info)?;
+
+    Ok(Announcement { mdns })
+}
+
+/// Search for a multicast group announced by another node
+pub fn find_group(timeout: Duration) -> Option<SocketAddrV4> {
+    let mut group = None;
+
+    browse(timeout, |info| {
+        group = info.get_property_val_str("group")
+            .and_then(|group| group.parse().ok());
+
+        group.is_none()
+    });
+
+    if let Some(group) = group {
+        log::info!("discovered multicast group {group}");
+    }
+
+    group
+}
+
+/// Smallest packet limit announced by receivers in `group`, in frames.
+/// None if none announce one
+pub fn max_packet_frames(group: SocketAddrV4, timeout: Duration) -> Option<usize> {
+    let group = group.to_string();
+    let mut max = None::<usize>;
+
+    browse(timeout, |info| {
+        let frames = Some(info)
+            .filter(|info| info.get_property_val_str("role") == Some("receiver"))
+            .filter(|info| info.get_property_val_str("group") == Some(group.as_str()))
+            .and_then(|info| info.get_property_val_str("max_packet_frames"))
+            .and_then(|frames| frames.parse().ok());
+
+        if let Some(frames) = frames {
+            max = Some(max.map_or(frames, |max| max.min(frames)));
+        }
+
+        true
+    });
+
+    max
+}
+
+pub fn run(opt: DiscoverOpt) -> Result<(), RunError> {
+    let mut seen = HashSet::new();
+
+    browse(Duration::from_secs(opt.seconds), |info| {
+        if seen.insert(info.get_fullname().to_owned()) {
+            let role = info.get_property_val_str("role").unwrap_or("unknown");
+            let group = info.get_property_val_str("group").unwrap_or("unknown");
+
+            let mut addrs = info.get_addresses_v4().into_iter()
+                .map(|addr| addr.to_string())
+                .collect::<Vec<_>>();
+            addrs.sort();
+
+            let max_packet = info.get_property_val_str("max_packet_frames")
+                .map(|frames| format!("  max {frames} frames"))
+                .unwrap_or_default();
+
+            println!("{role:<8}  {group:<21}  {}  {}{max_packet}", info.get_hostname(), addrs.join(","));
+        }
+
+        true
+    });
+
+    Ok(())
+}
+
+/// Browse for bark nodes, calling `f` for each resolved node until it
+/// returns false or the timeout elapses
+fn browse(timeout: Duration, mut f: impl FnMut(&ServiceInfo) -> bool) {
+    let mdns = match ServiceDaemon::new() {
+        Ok(mdns) => mdns,
+        Err(e) => {
+            log::warn!("failed to start mDNS: {e}");
+            return;
+        }
+    };
+
+    let events = match mdns.browse(SERVICE_TYPE) {
+        Ok(events) => events,
+        Err(e) => {
+            log::warn!("failed to browse mDNS: {e}");
+            let _ = mdns.shutdown();
+            return;
+        }
+    };
+
+    let deadline = Instant::now() + timeout;
+
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        let Ok(event) = events.recv_timeout(remaining) else {
+            break;
+        };
+
+        if let ServiceEvent::ServiceResolved(info) = event {
+            if !f(&info) {
+                break;
+            }
+        }
+    }
+
+    let _ = mdns.shutdown();
+}
@@ -1,15 +1,30 @@
+mod announce;
 mod audio;
+mod auth;
+mod bridge;
 mod config;
+mod ctl;
+mod devices;
+mod diag;
+mod discover;
+mod measure;
+mod ping;
 mod receive;
+mod reconnect;
+mod rtp;
+mod selftest;
+mod service;
 mod socket;
 mod stats;
 mod stream;
-mod thread;
+mod sync_check;
 mod time;
+mod wav;
+mod zones;
 
+use std::future::Future;
 use std::process::ExitCode;
 
-use log::LevelFilter;
 use structopt::StructOpt;
 use thiserror::Error;
 
@@ -19,13 +34,48 @@ enum Cmd {
     Stream(stream::StreamOpt),
     Receive(receive::ReceiveOpt),
     Stats(stats::StatsOpt),
+    /// Send control commands to receivers
+    Ctl(ctl::CtlOpt),
+    /// Measure relative acoustic delay and polarity of two receivers
+    Measure(measure::MeasureOpt),
+    /// Measure round trip time and packet loss to other nodes
+    Ping(ping::PingOpt),
+    /// Measure how closely receivers play together
+    SyncCheck(sync_check::SyncCheckOpt),
+    /// List bark nodes announced on the local network
+    Discover(discover::DiscoverOpt),
+    /// List audio devices and what they support
+    Devices(devices::DevicesOpt),
+    /// Print the latency each stage of the audio pipeline adds
+    Diag(diag::DiagOpt),
+    /// Manage receiver zones
+    Zones(zones::ZonesOpt),
+    /// Switch all receivers to a source's stream immediately
+    Takeover(ctl::TakeoverOpt),
+    /// Play a short clip on every receiver, over whatever is playing
+    Announce(announce::AnnounceOpt),
+    /// Re-export the stream to players that speak other protocols
+    Bridge(bridge::BridgeOpt),
+    /// Install a systemd service running a bark command
+    InstallService(service::InstallServiceOpt),
+    /// Validate and inspect configuration
+    Config(config::ConfigOpt),
+    /// Check that this install works end to end
+    Test(selftest::TestOpt),
 }
 
 #[derive(StructOpt)]
 #[structopt(version = version())]
 struct Opt {
     #[structopt(flatten)]
-    metrics: stats::server::MetricsOpt,
+    metrics: bark_app::metrics::MetricsOpt,
+    /// Clock to timestamp audio with: realtime, or ptp[:<device>] for a PTP
+    /// hardware clock such as one disciplined by linuxptp. Every node must
+    /// use clocks synchronised with each other
+    #[structopt(long, env = "BARK_CLOCK", default_value = "realtime")]
+    clock: time::Clock,
+    #[structopt(flatten)]
+    sched: bark_app::sched::SchedOpt,
     #[structopt(flatten)]
     cmd: Cmd,
 }
@@ -36,30 +86,95 @@ pub enum RunError {
     Listen(#[from] socket::ListenError),
     #[error("opening audio device: {0}")]
     OpenAudioDevice(#[from] audio::OpenError),
+    #[error("reading audio device: {0}")]
+    AudioInput(#[from] audio::Error),
+    #[error("writing audio device: {0}")]
+    AudioOutput(audio::Error),
     #[error("receiving from network: {0}")]
     Receive(std::io::Error),
+    #[error("sending to network: {0}")]
+    Send(std::io::Error),
     #[error("opening encoder: {0}")]
     OpenEncoder(#[from] bark_core::encode::NewEncoderError),
+    #[error("invalid packet duration: {0}ms")]
+    InvalidPacketDuration(f64),
+    #[error("opus can't encode {0}ms packets, use 2.5, 5, 10 or 20")]
+    InvalidOpusPacketDuration(f64),
     #[error(transparent)]
     Disconnected(#[from] receive::queue::Disconnected),
     #[error(transparent)]
-    Metrics(#[from] stats::server::StartError)
+    #[cfg(feature = "http")]
+    Metrics(#[from] bark_app::metrics::StartError),
+    #[error("peer reported error: {0}")]
+    Ctl(String),
+    #[error("writing stats log: {0}")]
+    StatsLog(std::io::Error),
+    #[error("installing service: {0}")]
+    Service(#[from] service::ServiceError),
+    #[error("config: {0}")]
+    Config(#[from] config::ConfigError),
+    #[error("reading audio file: {0}")]
+    Wav(#[from] wav::WavError),
+    #[error("opening bridge listener: {0}")]
+    BridgeListen(std::io::Error),
+    #[error("RAOP speaker: {0}")]
+    Raop(#[from] bridge::raop::RaopError),
+    #[error("writing SDP file: {0}")]
+    WriteSdp(std::io::Error),
+    #[error("{0} self test checks failed")]
+    TestFailed(usize),
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<(), ExitCode> {
-    init_log();
+fn main() -> Result<(), ExitCode> {
+    // built by hand rather than with #[tokio::main], which would pull in
+    // tokio's proc macros for no benefit
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("build tokio runtime");
+
+    runtime.block_on(run())
+}
 
-    if let Some(config) = config::read() {
-        config::load_into_env(&config);
+async fn run() -> Result<(), ExitCode> {
+    bark_app::logging::init();
+
+    match config::read() {
+        Ok(Some(config)) => config::load_into_env(&config),
+        Ok(None) => {}
+        Err(e) => {
+            log::error!("fatal: config: {e}");
+            return Err(ExitCode::FAILURE);
+        }
     }
 
     let opt = Opt::from_args();
 
+    if let Err(e) = time::set_clock(&opt.clock) {
+        log::error!("fatal: opening clock: {e}");
+        return Err(ExitCode::FAILURE);
+    }
+
+    bark_app::sched::configure(opt.sched);
+
     let result = match opt.cmd {
-        Cmd::Stream(cmd) => stream::run(cmd, opt.metrics).await,
-        Cmd::Receive(cmd) => receive::run(cmd, opt.metrics).await,
+        Cmd::Stream(cmd) => until_shutdown(stream::run(cmd, opt.metrics)).await,
+        Cmd::Receive(cmd) => until_shutdown(receive::run(cmd, opt.metrics)).await,
         Cmd::Stats(cmd) => stats::run(cmd),
+        Cmd::Ctl(cmd) => ctl::run(cmd),
+        Cmd::Measure(cmd) => measure::run(cmd),
+        Cmd::Ping(cmd) => ping::run(cmd),
+        Cmd::SyncCheck(cmd) => sync_check::run(cmd),
+        Cmd::Discover(cmd) => discover::run(cmd),
+        Cmd::Devices(cmd) => devices::run(cmd),
+        Cmd::Diag(cmd) => diag::run(cmd),
+        Cmd::Zones(cmd) => zones::run(cmd),
+        Cmd::Takeover(cmd) => ctl::takeover(cmd),
+        Cmd::Announce(cmd) => announce::run(cmd),
+        Cmd::Bridge(cmd) => until_shutdown(bridge::run(cmd)).await,
+        Cmd::InstallService(cmd) => service::run(cmd),
+        Cmd::Config(cmd) => config::run(cmd),
+        Cmd::Test(cmd) => selftest::run(cmd),
     };
 
     result.map_err(|err| {
@@ -68,23 +183,12 @@ async fn main() -> Result<(), ExitCode> {
     })
 }
 
-fn init_log() {
-    env_logger::builder()
-        .format_timestamp_millis()
-        .filter_level(default_log_level())
-        .parse_default_env()
-        .init();
-}
-
-fn default_log_level() -> LevelFilter {
-    if cfg!(debug_assertions) {
-        LevelFilter::Debug
-    } else {
-        LevelFilter::Info
-    }
+/// Run a long running command, exiting cleanly when asked to stop
+async fn until_shutdown(run: impl Future<Output = Result<(), RunError>>) -> Result<(), RunError> {
+    bark_app::signal::until_shutdown(run).await.unwrap_or(Ok(()))
 }
 
-const fn version() -> &'static str {
+pub const fn version() -> &'static str {
     match option_env!("BARK_PKG_VERSION") {
         Some(ver) => ver,
         None => env!("CARGO_PKG_VERSION"),
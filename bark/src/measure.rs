@@ -0,0 +1,245 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bytemuck::Zeroable;
+use rand::Rng;
+use structopt::StructOpt;
+
+use bark_core::audio::{Frames, FrameF32, F32};
+use bark_core::encode::Encode;
+use bark_core::encode::pcm::F32LEEncoder;
+use bark_protocol::FRAMES_PER_PACKET;
+use bark_protocol::packet::Audio;
+use bark_protocol::time::{SampleDuration, Timestamp};
+use bark_protocol::types::{AudioPacketHeader, SessionId, TimestampMicros};
+
+use crate::audio::config::{DeviceOpt, DEFAULT_LATENCY};
+use crate::audio::Input;
+use crate::socket::{PeerId, ProtocolSocket, Socket, SocketOpt};
+use crate::{time, RunError};
+
+// silence sent before the noise burst, giving the receiver time to take over
+// the stream and settle into sync
+const WARMUP: Duration = Duration::from_millis(1000);
+const BURST: Duration = Duration::from_millis(250);
+// longest acoustic delay we search for
+const MAX_LAG: Duration = Duration::from_millis(250);
+
+#[derive(StructOpt)]
+pub struct MeasureOpt {
+    #[structopt(flatten)]
+    pub socket: SocketOpt,
+
+    /// Addresses of the two receivers to compare, as shown in `bark stats`
+    #[structopt(long, number_of_values = 2, required = true)]
+    pub peer: Vec<SocketAddr>,
+
+    /// Microphone device name
+    #[structopt(long, env = "BARK_SOURCE_INPUT_DEVICE")]
+    pub input_device: Option<String>,
+
+    #[structopt(long, default_value = "100")]
+    pub delay_ms: u64,
+}
+
+struct Measurement {
+    delay: SampleDuration,
+    inverted: bool,
+    correlation: f32,
+}
+
+pub fn run(opt: MeasureOpt) -> Result<(), RunError> {
+    let socket = Socket::open(&opt.socket)
+        .map_err(RunError::Listen)?;
+
+    let protocol = ProtocolSocket::new(socket);
+
+    let mut input = Input::<F32>::new(&DeviceOpt {
+        device: opt.input_device.clone(),
+        period: None,
+        buffer: None,
+        latency: DEFAULT_LATENCY,
+        shared: false,
+    })?;
+
+    let delay = SampleDuration::from_std_duration_lossy(Duration::from_millis(opt.delay_ms));
+    let noise = generate_noise(SampleDuration::from_std_duration_lossy(BURST));
+
+    let mut results = Vec::new();
+
+    for peer in &opt.peer {
+        let peer = PeerId::from(*peer);
+        let result = measure_peer(&mut input, &protocol, peer, delay, &noise)?;
+
+        let polarity = if result.inverted { "inverted" } else { "normal" };
+        println!("{peer}: delay {:.2} ms, polarity {polarity} (correlation {:.2})",
+            result.delay.to_micros_lossy() as f64 / 1000.0,
+            result.correlation);
+
+        results.push(result);
+    }
+
+    if let [a, b] = results.as_slice() {
+        let a_ms = a.delay.to_micros_lossy() as f64 / 1000.0;
+        let b_ms = b.delay.to_micros_lossy() as f64 / 1000.0;
+        let polarity = if a.inverted == b.inverted { "match" } else { "MISMATCH" };
+        println!("relative delay: {:+.2} ms, polarity {polarity}", b_ms - a_ms);
+    }
+
+    Ok(())
+}
+
+/// Stream a noise burst to a single receiver while recording the microphone,
+/// then locate the burst in the recording
+fn measure_peer(
+    input: &mut Input<F32>,
+    protocol: &ProtocolSocket,
+    peer: PeerId,
+    delay: SampleDuration,
+    noise: &[f32],
+) -> Result<Measurement, RunError> {
+    let warmup = SampleDuration::from_std_duration_lossy(WARMUP).to_frame_count();
+    let max_lag = SampleDuration::from_std_duration_lossy(MAX_LAG).to_frame_count();
+
+    let sid = SessionId(time::now().0 as i64);
+    let mut encoder = F32LEEncoder;
+
+    let mut header = AudioPacketHeader {
+        sid,
+        seq: 1,
+        pts: TimestampMicros(0),
+        dts: TimestampMicros(0),
+        format: encoder.header_format(),
+        // take over from any stream currently playing
+        priority: i8::MAX,
+        packet_frames: 0,
+        delay_ms: 0,
+        min_buffer_ms: 0,
+        start_pts: TimestampMicros(0),
+    };
+
+    // total frames to send, including trailing silence to cover the
+    // stream delay and acoustic delay while we are still recording
+    let total = warmup + noise.len() as u64 + delay.to_frame_count() + max_lag;
+
+    let mut recording = Vec::new();
+    let mut record_start: Option<Timestamp> = None;
+    let mut burst_start: Option<Timestamp> = None;
+    let mut sent = 0u64;
+
+    while sent < total {
+        let mut buffer = [FrameF32::zeroed(); FRAMES_PER_PACKET];
+        let timestamp = input.read(&mut buffer)?;
+
+        record_start.get_or_insert(timestamp);
+        recording.extend(buffer.iter().map(|frame| (frame.0 + frame.1) / 2.0));
+
+        // fill outgoing packet with noise once warmup has passed
+        let mut packet = [FrameF32::zeroed(); FRAMES_PER_PACKET];
+        for (i, frame) in packet.iter_mut().enumerate() {
+            let n = (sent + i as u64).checked_sub(warmup)
+                .and_then(|n| noise.get(n as usize));
+
+            if let Some(sample) = n {
+                *frame = FrameF32(*sample, *sample);
+            }
+        }
+
+        let pts = timestamp.add(delay);
+
+        // note the presentation time of the first frame of noise
+        if let Some(into_packet) = warmup.checked_sub(sent) {
+            if into_packet < FRAMES_PER_PACKET as u64 {
+                burst_start = Some(pts.add(SampleDuration::from_frame_count_u64(into_packet)));
+            }
+        }
+
+        let mut data = [0u8; Audio::MAX_BUFFER_LENGTH];
+        let len = encoder.encode_packet(Frames::F32(&packet), &mut data)
+            .expect("encode measurement packet");
+
+        header.pts = pts.to_micros_lossy();
+        header.dts = time::now();
+
+        let audio = Audio::new(&header, &data[0..len])
+            .expect("allocate Audio packet");
+
+        protocol.send_to(audio.as_packet(), peer)
+            .map_err(RunError::Send)?;
+
+        header.seq += 1;
+        sent += FRAMES_PER_PACKET as u64;
+    }
+
+    let (Some(record_start), Some(burst_start)) = (record_start, burst_start) else {
+        unreachable!("total frames sent always exceeds warmup");
+    };
+
+    // index into the recording where the burst is scheduled to play
+    let offset = burst_start.saturating_duration_since(record_start).to_frame_count() as usize;
+
+    Ok(correlate(&recording[offset.min(recording.len())..], noise, max_lag as usize))
+}
+
+fn correlate(recording: &[f32], noise: &[f32], max_lag: usize) -> Measurement {
+    let noise_energy = noise.iter().map(|s| s * s).sum::<f32>();
+
+    let mut best = Measurement {
+        delay: SampleDuration::zero(),
+        inverted: false,
+        correlation: 0.0,
+    };
+
+    for lag in 0..max_lag {
+        let Some(window) = recording.get(lag..lag + noise.len()) else {
+            break;
+        };
+
+        let energy = window.iter().map(|s| s * s).sum::<f32>();
+        let dot = window.iter().zip(noise).map(|(a, b)| a * b).sum::<f32>();
+
+        let norm = (energy * noise_energy).sqrt();
+        if norm == 0.0 {
+            continue;
+        }
+
+        let correlation = dot / norm;
+
+        if correlation.abs() > best.correlation.abs() {
+            best = Measurement {
+                delay: SampleDuration::from_frame_count(lag),
+                inverted: correlation < 0.0,
+                correlation,
+            };
+        }
+    }
+
+    best
+}
+
+/// White noise band-limited to roughly 200 Hz - 4 kHz, so that it is
+/// reproducible by small speakers and picked up well by laptop microphones
+fn generate_noise(duration: SampleDuration) -> Vec<f32> {
+    let rate = bark_protocol::SAMPLE_RATE.0 as f32;
+    let alpha = |cutoff: f32| {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+        let dt = 1.0 / rate;
+        dt / (rc + dt)
+    };
+
+    let low = alpha(4000.0);
+    let high = alpha(200.0);
+
+    let mut rng = rand::thread_rng();
+    let mut lowpass = 0.0;
+    let mut highpass = 0.0;
+
+    (0..duration.to_frame_count())
+        .map(|_| {
+            let white = rng.gen_range(-1.0..1.0);
+            lowpass += low * (white - lowpass);
+            highpass += high * (lowpass - highpass);
+            (lowpass - highpass) * 0.5
+        })
+        .collect()
+}
@@ -0,0 +1,159 @@
+//! Round trip time and loss to other nodes, for finding which receiver is
+//! on a bad link. Pings go out in rounds, and replies that don't arrive
+//! before the next round count as lost.
+
+use std::collections::{BTreeMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use structopt::StructOpt;
+
+use bark_protocol::packet::{PacketKind, Ping};
+
+use crate::socket::{PeerId, ProtocolSocket, Socket, SocketOpt};
+use crate::RunError;
+
+#[derive(StructOpt)]
+pub struct PingOpt {
+    #[structopt(flatten)]
+    pub socket: SocketOpt,
+
+    /// Addresses of the nodes to ping, as shown in `bark stats`
+    #[structopt(required_unless = "all")]
+    pub peers: Vec<SocketAddr>,
+
+    /// Ping every node on the multicast group instead
+    #[structopt(long, conflicts_with = "peers")]
+    pub all: bool,
+
+    /// Rounds of pings to send
+    #[structopt(long, short = "c", default_value = "10")]
+    pub count: u64,
+
+    /// Milliseconds between rounds, which is also how long replies are
+    /// waited for
+    #[structopt(long, default_value = "1000")]
+    pub interval_ms: u64,
+}
+
+#[derive(Default)]
+struct PeerStats {
+    sent: u64,
+    rtts: Vec<Duration>,
+}
+
+pub fn run(opt: PingOpt) -> Result<(), RunError> {
+    let socket = Socket::open(&opt.socket)
+        .map_err(RunError::Listen)?;
+
+    let protocol = ProtocolSocket::new(socket);
+    let interval = Duration::from_millis(opt.interval_ms);
+
+    let mut peers = opt.peers.iter()
+        .map(|addr| (PeerId::from(*addr), PeerStats::default()))
+        .collect::<BTreeMap<_, _>>();
+
+    for seq in 1..=opt.count {
+        let ping = Ping::new(seq).expect("allocate Ping packet");
+        let sent = Instant::now();
+
+        if opt.all {
+            protocol.broadcast(ping.as_packet()).map_err(RunError::Send)?;
+        } else {
+            for peer in peers.keys() {
+                protocol.send_to(ping.as_packet(), *peer).map_err(RunError::Send)?;
+            }
+        }
+
+        // peers first heard from during this round are added as they reply
+        for stats in peers.values_mut() {
+            stats.sent += 1;
+        }
+
+        let mut replied = HashSet::new();
+
+        loop {
+            let remaining = interval.saturating_sub(sent.elapsed());
+
+            if remaining.is_zero() {
+                break;
+            }
+
+            let Some((packet, peer)) = protocol.recv_from_timeout(Some(remaining))
+                .map_err(RunError::Receive)? else {
+                break;
+            };
+
+            let Some(PacketKind::Pong(pong)) = packet.parse() else {
+                continue;
+            };
+
+            // a reply to an earlier round, already counted as lost. older
+            // nodes don't echo seq, so their replies are taken as this round's
+            if pong.seq().is_some_and(|reply_seq| reply_seq != seq) {
+                continue;
+            }
+
+            if !opt.all && !peers.contains_key(&peer) {
+                continue;
+            }
+
+            if !replied.insert(peer) {
+                continue;
+            }
+
+            let rtt = sent.elapsed();
+
+            peers.entry(peer)
+                .or_insert_with(|| PeerStats { sent: 1, rtts: Vec::new() })
+                .rtts.push(rtt);
+
+            println!("reply from {peer}: seq={seq} time={:.2}ms", millis(rtt));
+        }
+    }
+
+    summary(&peers);
+    Ok(())
+}
+
+fn summary(peers: &BTreeMap<PeerId, PeerStats>) {
+    println!();
+
+    if peers.is_empty() {
+        println!("no replies");
+        return;
+    }
+
+    println!("{:<21}  {:>4}  {:>4}  {:>6}  {:>8}  {:>8}  {:>8}",
+        "peer", "sent", "recv", "loss", "min", "avg", "max");
+
+    for (peer, stats) in peers {
+        let received = stats.rtts.len() as u64;
+        let lost = stats.sent.saturating_sub(received);
+        let loss = 100.0 * lost as f64 / stats.sent.max(1) as f64;
+
+        let min = stats.rtts.iter().min().copied();
+        let max = stats.rtts.iter().max().copied();
+        let avg = (received > 0).then(|| stats.rtts.iter().sum::<Duration>() / received as u32);
+
+        println!("{:<21}  {:>4}  {:>4}  {:>5.1}%  {:>8}  {:>8}  {:>8}",
+            peer.to_string(),
+            stats.sent,
+            received,
+            loss,
+            rtt(min),
+            rtt(avg),
+            rtt(max));
+    }
+}
+
+fn rtt(rtt: Option<Duration>) -> String {
+    match rtt {
+        Some(rtt) => format!("{:.2}ms", millis(rtt)),
+        None => "-".to_owned(),
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
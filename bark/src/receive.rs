@@ -1,95 +1,387 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
+use bark_app::thread;
+use bark_core::audio::dsp::Settings;
 use bark_core::audio::{Format, F32, S16};
 use bytemuck::Zeroable;
 use structopt::StructOpt;
 
-use bark_core::receive::queue::AudioPts;
+use bark_core::receive::queue::{AudioPts, Insert};
+use bark_core::receive::timing::LatencyFilter;
+use bark_core::registry;
 
-use bark_protocol::time::{Timestamp, SampleDuration};
-use bark_protocol::types::{AudioPacketHeader, SessionId, TimestampMicros};
-use bark_protocol::types::stats::receiver::ReceiverStats;
-use bark_protocol::packet::{Audio, PacketKind, Pong, StatsReply};
+use bark_protocol::time::{Timestamp, SampleDuration, TimestampDelta};
+use bark_protocol::types::{AudioPacketHeader, SessionId, TimestampMicros, ZoneFlags};
+use bark_protocol::types::stats::receiver::{ReceiverStats, StreamStatus};
+use bark_protocol::packet::{Audio, DspRequest, DumpReply, Metadata, OutputReply, PacketKind, Pong, ReceiverReport, StatsReply, SyncReply, SyncRequest, ZoneRequest};
 
-use crate::audio::config::{DEFAULT_PERIOD, DEFAULT_BUFFER, DeviceOpt};
+use crate::audio::config::{self as audio_config, DeviceOpt};
 use crate::audio::Output;
+use crate::auth::ControlAuth;
 use crate::config;
-use crate::receive::output::OutputRef;
-use crate::socket::{ProtocolSocket, Socket, SocketOpt};
+use crate::discover::{self, Role};
+use crate::socket::{monitor, PeerId, ProtocolSocket, Socket, SocketOpt};
 use crate::stats::{self, ReceiverMetrics};
-use crate::{thread, time};
+use crate::time;
 use crate::RunError;
 
+use self::adaptive::AdaptiveBuffer;
+use self::drift::DriftMemory;
+use self::dsp::Dsp;
+use self::dump::Dump;
+use self::fallback::{Fallback, FallbackOpt};
+use self::mix::Mixer;
 use self::output::OwnedOutput;
 use self::queue::Disconnected;
-use self::stream::DecodeStream;
+use self::report::Reception;
+use self::stream::{DecodeOpt, DecodeStream, StreamOutput};
+use self::sync::Pending;
+use self::volume::Volume;
 
+pub mod adaptive;
+pub mod chime;
+pub mod drift;
+pub mod dsp;
+pub mod duck;
+pub mod dump;
+pub mod fallback;
+pub mod identity;
+pub mod mix;
 pub mod output;
 pub mod queue;
+pub mod reaper;
+pub mod report;
 pub mod stream;
+pub mod sync;
+pub mod volume;
 
 pub struct Receiver<F: Format> {
     stream: Option<Stream>,
+    // in mix mode, streams of lower priority playing under the current one
+    mixed: Vec<Stream>,
+    // in mix mode, mixes every stream into the output
+    mixer: Option<Mixer>,
+    // which streams may play, whether they mix, and how readily they take
+    // over
+    select: SelectOpt,
+    // stream that would take over from the current one, waiting to be
+    // heard for long enough: its sid, packets heard, and the last one
+    challenger: Option<(SessionId, u32, TimestampMicros)>,
+    // in sparse mode, the lowest priority that wakes the receiver. Below
+    // it, packets are ignored and the output device is closed
+    sparse: Option<i8>,
+    // how long the output device is kept open with nothing to play
+    idle_release: Option<Duration>,
+    // when the receiver last had something to play
+    last_active: TimestampMicros,
+    // local playlist played once the network has been quiet for a while
+    fallback: Option<Fallback>,
+    // when a packet from a real stream last arrived
+    last_stream: TimestampMicros,
+    // when reopening the output device last failed, so that a device in
+    // use elsewhere isn't retried for every packet
+    wake_failed: Option<TimestampMicros>,
     output: OwnedOutput<F>,
+    devices: Vec<DeviceOpt>,
+    zone: Option<String>,
+    // session forced by a takeover, which plays regardless of priority
+    takeover: Option<SessionId>,
+    // locally generated identify chime, which plays over everything else
+    // until it finishes
+    identify: Option<(SessionId, TimestampMicros)>,
+    // sessions their sources have ended, and when, so that stragglers
+    // arriving after the end don't start them up again
+    ended: Vec<(SessionId, TimestampMicros)>,
+    // what the current session's source last said it is playing
+    now_playing: Option<(SessionId, String)>,
+    // when reports were last sent to the sources of the streams playing
+    last_report: TimestampMicros,
+    // requests from `bark sync-check` waiting for their packet to play
+    sync_requests: Vec<Pending>,
+    // set to drop the current stream, so timing is reacquired from scratch
+    resync: Arc<AtomicBool>,
+    latency_filter: LatencyFilter,
     metrics: ReceiverMetrics,
+    opt: DecodeOpt,
 }
 
 struct Stream {
     sid: SessionId,
+    // where the stream is coming from, None for streams generated locally
+    source: Option<PeerId>,
+    // how its packets are arriving, reported back to the source
+    reception: Reception,
+    // when the first packet arrived
+    began: TimestampMicros,
+    // where stream position is counted from: the session's announced
+    // start, or failing that the first packet we received
+    session_start: Timestamp,
     decode: DecodeStream,
     receieved_last_packet: TimestampMicros,
     priority: i8,
+    // added to pts of every packet, extending the buffer of streams with
+    // a shorter delay than the minimum
+    extra_delay: SampleDuration,
+    // adjusts extra_delay to network conditions when enabled
+    adaptive: Option<AdaptiveBuffer>,
+    // whether the last packet arrived too late to play
+    late: bool,
+    // packets missed by the decoder when last checked
+    missed: u64,
+    // whether the source has ended the stream, which is fading out
+    ended: bool,
+    metrics: ReceiverMetrics,
 }
 
 const STREAM_TIMEOUT: Duration = Duration::from_millis(100);
 
+/// How long a stream may go without packets before it is dropped, along
+/// with its thread and queue
+const STALE_AFTER: Duration = Duration::from_secs(5);
+
+/// How often the network thread checks for stale streams and idle output
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the network thread tops up the fallback playlist's packets
+/// while it plays, well within how far ahead they are queued
+const FALLBACK_TICK: Duration = Duration::from_millis(100);
+
+/// How long a sparse receiver stays awake after its last packet. Longer
+/// than any stream delay, so buffered audio finishes playing first
+const DORMANT_AFTER: Duration = Duration::from_secs(2);
+
+/// How long to wait before trying to reopen the output device again after
+/// failing to
+const WAKE_RETRY: Duration = Duration::from_secs(1);
+
+/// Which streams a receiver will play, and how readily a new stream takes
+/// over from the one playing
+pub struct SelectOpt {
+    /// Mix lower priority streams under the current one
+    pub mix: bool,
+    /// Consecutive packets a stream must send before it takes over
+    pub switch_after_packets: u32,
+    /// Only play streams from these sources, any if empty
+    pub lock_source: Vec<IpAddr>,
+    /// Only play these sessions, any if empty
+    pub accept_sid: Vec<SessionId>,
+}
+
+impl SelectOpt {
+    fn accepts(&self, sid: SessionId, source: PeerId) -> bool {
+        (self.lock_source.is_empty() || self.lock_source.contains(&source.ip())) &&
+            (self.accept_sid.is_empty() || self.accept_sid.contains(&sid))
+    }
+}
+
+/// What a receiver does when there's nothing to play
+pub struct IdleOpt {
+    /// Lowest priority that wakes the receiver, ignoring everything below
+    /// it with the output device closed
+    pub sparse_priority: Option<i8>,
+    /// Close the output device after this long with nothing to play
+    pub release_after: Option<Duration>,
+    /// Play a local playlist once no stream has been seen for a while
+    pub fallback: Option<FallbackOpt>,
+}
+
 impl Stream {
     pub fn new<F: Format>(
         header: &AudioPacketHeader,
-        output: OutputRef<F>,
+        source: Option<PeerId>,
+        fade_in: bool,
+        output: StreamOutput<F>,
         metrics: ReceiverMetrics,
+        opt: DecodeOpt,
         now: TimestampMicros,
     ) -> Self {
-        let decode = DecodeStream::new(header, output, metrics);
+        let extra_delay = extra_delay(header, opt.min_buffer);
+
+        if extra_delay != SampleDuration::zero() {
+            log::info!("extending stream buffer by {}ms to meet minimum", extra_delay.to_micros_lossy() / 1000);
+        }
+
+        let adaptive = opt.adaptive_buffer
+            .map(|max| AdaptiveBuffer::new(extra_delay, max));
+
+        let missed = metrics.packets_missed.get();
+
+        // join the synchronised start if it is still to come, otherwise
+        // start as soon as buffered
+        let start = header.start()
+            .map(|start| start.add(extra_delay))
+            .filter(|start| *start > Timestamp::from_micros_lossy(now));
+
+        if let Some(start) = start {
+            let wait = start.saturating_duration_since(Timestamp::from_micros_lossy(now));
+            log::info!("stream starting in {}ms", wait.to_micros_lossy() / 1000);
+        }
+
+        let decode = DecodeStream::new(header, extra_delay, start, fade_in, output, metrics.clone(), opt);
+
+        let session_start = header.start()
+            .unwrap_or(Timestamp::from_micros_lossy(header.pts));
 
         Stream {
             sid: header.sid,
+            source,
+            reception: Reception::new(),
+            began: now,
+            session_start,
             decode,
             receieved_last_packet: now,
             priority: header.priority,
+            extra_delay,
+            adaptive,
+            late: false,
+            missed,
+            ended: false,
+            metrics,
         }
     }
 
     pub fn is_active(&self, now: TimestampMicros) -> bool {
-        self.receieved_last_packet > now.saturating_sub(STREAM_TIMEOUT)
+        !self.ended && self.receieved_last_packet > now.saturating_sub(STREAM_TIMEOUT)
+    }
+
+    fn end(&mut self) {
+        self.decode.end();
+        self.ended = true;
     }
 
-    pub fn receive_packet(&mut self, audio: Audio, now: TimestampMicros) -> Result<(), Disconnected> {
+    pub fn receive_packet(&mut self, audio: Audio, now: TimestampMicros) -> Result<Insert, Disconnected> {
         let pts = Timestamp::from_micros_lossy(audio.header().pts);
-        self.decode.send(AudioPts { pts, audio })?;
+
+        if let Some(adaptive) = &mut self.adaptive {
+            let missed = self.metrics.packets_missed.get();
+            let underrun = self.late || missed != self.missed;
+            self.missed = missed;
+
+            self.extra_delay = adaptive.update(pts, audio.header().packet_duration(), now, underrun);
+            self.metrics.buffer_target.observe(adaptive.target());
+        }
+
+        let header = *audio.header();
+        let pts = pts.add(self.extra_delay);
+        let insert = self.decode.send(AudioPts { pts, audio })?;
+        self.late = insert == Insert::Late;
+
+        if insert != Insert::Duplicate {
+            self.reception.observe(&header, now);
+        }
         self.receieved_last_packet = now;
-        Ok(())
+        Ok(insert)
+    }
+}
+
+/// How much later than its pts to play a stream so that at least the
+/// larger of the source's hint and our own minimum is buffered. Streams
+/// that don't report their delay are played as is. This depends only on
+/// the stream and local config, so receivers with the same config stay in
+/// sync with each other.
+fn extra_delay(header: &AudioPacketHeader, min_buffer: SampleDuration) -> SampleDuration {
+    let Some(delay) = header.delay() else {
+        return SampleDuration::zero();
+    };
+
+    let min_buffer = std::cmp::max(min_buffer, header.min_buffer());
+
+    if min_buffer > delay {
+        min_buffer.sub(delay)
+    } else {
+        SampleDuration::zero()
     }
 }
 
 impl<F: Format> Receiver<F> {
-    pub fn new(output: Output<F>, metrics: ReceiverMetrics) -> Self {
-        Receiver {
+    pub fn new(
+        devices: Vec<DeviceOpt>,
+        zone: Option<String>,
+        resync: Arc<AtomicBool>,
+        select: SelectOpt,
+        idle: IdleOpt,
+        metrics: ReceiverMetrics,
+        opt: DecodeOpt,
+    ) -> Result<Self, RunError> {
+        let sparse = idle.sparse_priority;
+
+        // open the device even when sparse, so that misconfiguration is
+        // reported at startup rather than at the first announcement
+        let output = Output::new(&devices, metrics.clone())
+            .map_err(RunError::OpenAudioDevice)?;
+
+        let output = match sparse {
+            Some(priority) => {
+                log::info!("sparse mode, dormant until a stream of priority {priority} or above");
+                drop(output);
+                OwnedOutput::closed()
+            }
+            None => OwnedOutput::new(output),
+        };
+
+        if let Some(release_after) = idle.release_after {
+            log::info!("releasing output device after {}s idle", release_after.as_secs());
+        }
+
+        if !select.lock_source.is_empty() {
+            let sources = select.lock_source.iter().map(ToString::to_string).collect::<Vec<_>>();
+            log::info!("only playing streams from {}", sources.join(", "));
+        }
+
+        Ok(Receiver {
             stream: None,
-            output: OwnedOutput::new(output),
+            mixed: Vec::new(),
+            mixer: None,
+            select,
+            challenger: None,
+            sparse,
+            idle_release: idle.release_after,
+            last_active: time::now(),
+            fallback: idle.fallback.map(Fallback::new),
+            last_stream: time::now(),
+            wake_failed: None,
+            output,
+            devices,
+            zone,
+            takeover: None,
+            identify: None,
+            ended: Vec::new(),
+            now_playing: None,
+            last_report: time::now(),
+            sync_requests: Vec::new(),
+            resync,
+            latency_filter: LatencyFilter::new(),
             metrics,
-        }
+            opt,
+        })
     }
 
     pub fn stats(&self) -> ReceiverStats {
         let mut stats = ReceiverStats::new();
+        stats.set_output_device(&self.output_device());
+
+        let volume = &self.opt.volume;
+        stats.set_volume(volume.level(), volume.muted());
+        stats.set_max_volume(volume.max(), volume.locked());
+        stats.set_zone(self.zone.as_deref(), volume.zone_muted());
+        stats.set_decoders(registry::decoder_formats());
+        stats.set_max_packet_frames(bark_protocol::MAX_FRAMES_PER_PACKET);
 
         if let Some(stream) = &self.stream {
             let decode = stream.decode.stats();
             stats.set_stream(decode.status);
             stats.set_audio_latency(decode.audio_latency);
+            stats.set_clock_drift(decode.clock_drift_ppm);
             stats.set_output_latency(decode.output_latency);
 
+            if let Some(pts) = decode.stream_pts {
+                stats.set_stream_position(pts.saturating_duration_since(stream.session_start));
+            }
+
             let latency = self.metrics.network_latency.get()
                 .and_then(|micros| u64::try_from(micros).ok())
                 .map(Duration::from_micros);
@@ -102,44 +394,696 @@ impl<F: Format> Receiver<F> {
         stats
     }
 
+    /// Dump audio from the current stream, returning where it will be written
+    pub fn dump(&self, duration: Duration) -> Result<PathBuf, String> {
+        let Some(stream) = &self.stream else {
+            return Err("no active stream".to_owned());
+        };
+
+        // audio is dumped from the pipeline before conversion to the output
+        // format, which is always f32
+        let (dump, path) = Dump::start::<F32>(duration)
+            .map_err(|e| format!("starting dump: {e}"))?;
+
+        stream.decode.set_dump(dump);
+        Ok(path)
+    }
+
+    pub fn set_volume(&self, level: Option<f32>, mute: Option<bool>) {
+        if let Some(level) = level {
+            self.opt.volume.set_level(level);
+        }
+
+        if let Some(mute) = mute {
+            self.opt.volume.set_muted(mute);
+        }
+    }
+
+    /// Change the maximum volume and/or lock it. Once locked, the limit
+    /// can only be changed by restarting the receiver with different options.
+    pub fn limit_volume(&self, max: Option<f32>, lock: bool) {
+        if max.is_none() && !lock {
+            return;
+        }
+
+        match self.opt.volume.set_limit(max, lock) {
+            Ok(()) => log::info!("maximum volume {:.0}%{}",
+                self.opt.volume.max() * 100.0,
+                if lock { ", locked" } else { "" }),
+            Err(_) => log::warn!("maximum volume is locked, ignoring request"),
+        }
+    }
+
+    /// Change output processing at runtime. Playing streams ramp over to
+    /// the new settings rather than restarting their filters
+    pub fn dsp_request(&self, request: &DspRequest) {
+        let dsp = &self.opt.dsp;
+
+        if let Some(limiter) = request.limiter() {
+            log::info!("limiter {}", if limiter { "on" } else { "off" });
+            dsp.set_limiter(limiter);
+        }
+
+        if let Some(code) = request.protection() {
+            match dsp::profile_from_code(code) {
+                Some(profile) => {
+                    log::info!("speaker protection: {}", profile
+                        .map(|profile| config::Protection::from(profile).to_string())
+                        .as_deref()
+                        .unwrap_or("none"));
+                    dsp.set_protection(profile);
+                }
+                None => log::warn!("unknown protection profile {code}, ignoring"),
+            }
+        }
+
+        if let Some(bypass) = request.bypass() {
+            log::info!("output processing {}", if bypass { "bypassed" } else { "restored" });
+            dsp.set_bypass(bypass);
+        }
+    }
+
+    /// Switch to the given session as soon as its next packet arrives, and
+    /// keep playing it regardless of priority. `None` returns to normal
+    /// priority based stream selection.
+    pub fn takeover(&mut self, sid: Option<SessionId>) {
+        match sid {
+            Some(sid) => log::info!("takeover by sid={}", sid.0),
+            None => log::info!("takeover released"),
+        }
+
+        // a takeover plays on its own
+        if sid.is_some() {
+            self.mixed.clear();
+        }
+
+        self.takeover = sid;
+    }
+
+    /// The source of a session has stopped: fade it out and drop it, rather
+    /// than waiting for it to time out. Only the stream's own source, or
+    /// None for local streams, can end it
+    pub fn stream_end(&mut self, sid: SessionId, source: Option<PeerId>) {
+        if self.ended.iter().any(|(ended, _)| *ended == sid) {
+            // sent more than once
+            return;
+        }
+
+        let streams = self.stream.iter_mut().chain(&mut self.mixed)
+            .filter(|stream| stream.sid == sid && stream.source == source);
+
+        let mut ended = false;
+
+        for stream in streams {
+            log::info!("session {} ended by source", sid.0);
+            stream.end();
+            ended = true;
+        }
+
+        // nodes other than the source mustn't be able to shut a session
+        // out, so its packets are only dropped once it has really ended
+        if ended {
+            self.ended.push((sid, time::now()));
+        }
+    }
+
+    /// Log what the current session is playing whenever it changes
+    pub fn metadata(&mut self, metadata: &Metadata) {
+        let sid = metadata.sid();
+
+        if self.current_session() != Some(sid) {
+            return;
+        }
+
+        let playing = match (metadata.artist(), metadata.title()) {
+            (Some(artist), Some(title)) => format!("{artist} - {title}"),
+            (None, Some(title)) => title.to_owned(),
+            (Some(artist), None) => artist.to_owned(),
+            (None, None) => return,
+        };
+
+        if self.now_playing.as_ref() == Some(&(sid, playing.clone())) {
+            return;
+        }
+
+        log::info!("now playing: {playing}");
+        self.now_playing = Some((sid, playing));
+    }
+
+    /// Play a chime on this receiver only, so it can be located
+    pub fn identify(&mut self) -> Result<(), Disconnected> {
+        let now = time::now();
+        let sid = SessionId(i64::try_from(now.0).unwrap_or(i64::MAX));
+        let (packets, end) = chime::packets(sid, now);
+
+        if !self.wake() {
+            return Ok(());
+        }
+
+        log::info!("playing identify chime");
+        self.identify = Some((sid, end));
+
+        for packet in packets {
+            if let Some(stream) = self.prepare_stream(packet.header(), None, now) {
+                stream.receive_packet(packet, now)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn zone_request(&mut self, request: &ZoneRequest) {
+        let flags = request.flags();
+        let zone = request.zone();
+
+        if flags.contains(ZoneFlags::ASSIGN) {
+            log::info!("assigned to zone: {}", zone.unwrap_or("(none)"));
+            self.zone = zone.map(str::to_owned);
+            return;
+        }
+
+        // requests without a zone name apply to every receiver
+        let in_zone = zone.is_none() || zone == self.zone.as_deref();
+
+        if flags.contains(ZoneFlags::SOLO) {
+            self.opt.volume.set_zone_muted(!in_zone);
+        } else if in_zone && flags.contains(ZoneFlags::MUTE) {
+            self.opt.volume.set_zone_muted(true);
+        } else if in_zone && flags.contains(ZoneFlags::UNMUTE) {
+            self.opt.volume.set_zone_muted(false);
+        }
+    }
+
+    pub fn output_device(&self) -> String {
+        self.devices.iter()
+            .map(|opt| opt.device.as_deref().unwrap_or("default"))
+            .collect::<Vec<_>>()
+            .join(" + ")
+    }
+
+    /// Switch to a different output device, in place of every device in
+    /// use. The current stream is dropped and picked up again with the next
+    /// audio packet, so that playback resyncs against the new device's
+    /// latency.
+    pub fn set_output_device(&mut self, device: Option<String>) -> Result<(), String> {
+        // close the current device first, it may be the one we are reopening
+        self.stream = None;
+        self.mixed.clear();
+        self.identify = None;
+        self.mixer = None;
+        drop(self.output.close());
+
+        let opts = vec![DeviceOpt { device, ..self.devices[0].clone() }];
+
+        match Output::new(&opts, self.metrics.clone()) {
+            Ok(output) => {
+                self.output = OwnedOutput::new(output);
+                self.devices = opts;
+                self.metrics.health.set_open_failed(false);
+                log::info!("switched output device: {}", self.output_device());
+                Ok(())
+            }
+            Err(err) => {
+                let message = format!("opening output device: {err}");
+
+                // fall back to the device we were using before
+                match Output::new(&self.devices, self.metrics.clone()) {
+                    Ok(output) => { self.output = OwnedOutput::new(output); }
+                    Err(e) => {
+                        log::error!("error reopening previous output device: {e}");
+                        self.metrics.health.set_open_failed(true);
+                    }
+                }
+
+                Err(message)
+            }
+        }
+    }
+
     pub fn current_session(&self) -> Option<SessionId> {
         self.stream.as_ref().map(|s| s.sid)
     }
 
-    fn prepare_stream(&mut self, header: &AudioPacketHeader, now: TimestampMicros) -> &mut Stream {
-        let new_stream = match &self.stream {
-            Some(current) if current.is_active(now) => {
-                if header.priority > current.priority {
-                    true
-                } else if header.priority == current.priority {
-                    header.sid > current.sid
+    /// Where a new stream's audio should go
+    fn stream_output(&mut self, priority: i8) -> StreamOutput<F> {
+        if !self.select.mix {
+            return StreamOutput::Direct(self.output.steal());
+        }
+
+        let mixer = match &mut self.mixer {
+            Some(mixer) => mixer,
+            None => self.mixer.insert(Mixer::start(self.output.steal(), &self.opt)),
+        };
+
+        StreamOutput::Mix(mixer.input(priority))
+    }
+
+    /// Options for a new stream from `source`, starting from the drift last
+    /// learned with it
+    fn stream_opt(&self, source: Option<PeerId>) -> DecodeOpt {
+        let initial_drift_ppm = source.map(|peer| peer.ip()).and_then(|source| {
+            let ppm = self.opt.drift.as_ref()?.recall(source, &self.output_device())?;
+            log::info!("starting from clock drift of {ppm:.1} ppm remembered for {source}");
+            Some(ppm)
+        });
+
+        DecodeOpt { initial_drift_ppm, ..self.opt.clone() }
+    }
+
+    /// Stream for a packet with `header` to go to, starting it if it should
+    /// take over. None if there is no stream for it to go to, as when the
+    /// identify chime is holding on to the output but its stream is gone
+    fn prepare_stream(&mut self, header: &AudioPacketHeader, source: Option<PeerId>, now: TimestampMicros) -> Option<&mut Stream> {
+        let identify = self.identify
+            .filter(|(_, end)| now < *end)
+            .map(|(sid, _)| sid);
+
+        // mix mode keeps lower priority streams playing under the current
+        // one, ducked by the mixer, rather than dropping them
+        let mixing = self.select.mix && identify.is_none() && self.takeover.is_none();
+
+        if mixing {
+            self.mixed.retain(|stream| stream.is_active(now));
+
+            // when the current stream stops, the highest priority stream
+            // playing under it takes its place
+            if !self.stream.as_ref().is_some_and(|stream| stream.is_active(now)) {
+                let highest = self.mixed.iter()
+                    .enumerate()
+                    .max_by_key(|(_, stream)| stream.priority)
+                    .map(|(idx, _)| idx);
+
+                if let Some(idx) = highest {
+                    self.stream = Some(self.mixed.remove(idx));
+                }
+            }
+
+            if let Some(idx) = self.mixed.iter().position(|stream| stream.sid == header.sid) {
+                return Some(&mut self.mixed[idx]);
+            }
+        }
+
+        let current = self.stream.as_ref()
+            .filter(|stream| stream.is_active(now))
+            .map(|stream| (stream.sid, stream.priority));
+
+        let new_stream = match current {
+            // the chime was queued all at once, so hold on to it until it
+            // has finished playing even though no more packets arrive
+            _ if identify.is_some() => {
+                identify == Some(header.sid) && self.current_session() != identify
+            }
+            Some((sid, priority)) => {
+                if self.takeover == Some(header.sid) {
+                    header.sid != sid
+                } else if self.takeover == Some(sid) {
+                    false
+                } else if header.priority > priority || (header.priority == priority && header.sid > sid) {
+                    self.challenge(header.sid, now)
                 } else {
                     false
                 }
             }
-            _ => true,
+            None => true,
         };
 
         if new_stream {
+            self.challenger = None;
+
+            // a lower priority stream only takes over once the one that
+            // interrupted it has finished, fade it back in gently
+            let resuming = identify.is_none() && self.stream.as_ref()
+                .is_some_and(|current| current.priority > header.priority);
+
             // start new stream
-            let stream = Stream::new(header, self.output.steal(), self.metrics.clone(), now);
+            let output = self.stream_output(header.priority);
+            let opt = self.stream_opt(source);
+            let stream = Stream::new(header, source, resuming, output, self.metrics.clone(), opt, now);
 
             // new stream is taking over! switch over to it
             log::info!("new stream beginning: priority={} sid={}", header.priority, header.sid.0);
-            self.stream = Some(stream);
+            let previous = self.stream.replace(stream);
+
+            // carry on playing the stream we took over from underneath
+            if let Some(previous) = previous.filter(|previous| mixing && previous.priority < header.priority) {
+                log::info!("mixing stream under new stream: priority={} sid={}", previous.priority, previous.sid.0);
+                self.mixed.push(previous);
+            }
+        } else if mixing {
+            let current = self.stream.as_ref().unwrap();
+
+            // one stream is mixed per priority, the newest session wins
+            let superseded = |stream: &Stream| stream.priority == header.priority && stream.sid > header.sid;
+
+            if header.priority < current.priority && !self.mixed.iter().any(superseded) {
+                self.mixed.retain(|stream| stream.priority != header.priority);
+
+                let output = self.stream_output(header.priority);
+                let opt = self.stream_opt(source);
+                let stream = Stream::new(header, source, false, output, self.metrics.clone(), opt, now);
+
+                log::info!("new stream mixing: priority={} sid={}", header.priority, header.sid.0);
+                self.mixed.push(stream);
+                return self.mixed.last_mut();
+            }
+        }
+
+        self.stream.as_mut()
+    }
+
+    /// Count a packet from a stream that would take over from the current
+    /// one, returning whether it has now sent enough packets in a row to.
+    /// A challenger that pauses for longer than a stream timeout starts
+    /// counting again
+    fn challenge(&mut self, sid: SessionId, now: TimestampMicros) -> bool {
+        let heard = match self.challenger {
+            Some((challenger, heard, last)) if challenger == sid
+                && now.saturating_duration_since(last) < STREAM_TIMEOUT => heard + 1,
+            _ => 1,
+        };
+
+        if heard < self.select.switch_after_packets {
+            self.challenger = Some((sid, heard, now));
+            return false;
+        }
+
+        true
+    }
+
+    /// Reopen the output device if it has been closed while dormant or
+    /// idle, returning whether it is ready to play
+    fn wake(&mut self) -> bool {
+        let now = time::now();
+        self.last_active = now;
+
+        if self.output.is_open() {
+            return true;
+        }
+
+        if self.wake_failed.is_some_and(|failed| failed > now.saturating_sub(WAKE_RETRY)) {
+            return false;
+        }
+
+        match Output::new(&self.devices, self.metrics.clone()) {
+            Ok(output) => {
+                log::info!("reopening output device");
+                self.output = OwnedOutput::new(output);
+                self.wake_failed = None;
+                self.metrics.health.set_open_failed(false);
+                true
+            }
+            Err(e) => {
+                // only report the first failure of each outage
+                if self.wake_failed.is_none() {
+                    log::error!("error opening output device: {e}");
+                }
+                self.wake_failed = Some(now);
+                self.metrics.health.set_open_failed(true);
+                false
+            }
+        }
+    }
+
+    /// How long the receiver keeps the output device open with nothing to
+    /// play, `None` to keep it open for good
+    fn release_after(&self) -> Option<Duration> {
+        let dormant = self.sparse.map(|_| DORMANT_AFTER);
+        dormant.into_iter().chain(self.idle_release).min()
+    }
+
+    /// How long the network thread may wait for a packet before calling
+    /// `tick`, `None` when there is nothing to do without one
+    pub fn tick_interval(&self) -> Option<Duration> {
+        match &self.fallback {
+            Some(fallback) if fallback.session().is_some() => Some(FALLBACK_TICK),
+            // tick even with nothing to do, so that health checks can see
+            // the network thread is still going round
+            _ => Some(TICK_INTERVAL),
+        }
+    }
+
+    /// Decode the next packet of streams running on the network thread
+    /// whose output has room for it, returning how long until one is due
+    pub fn decode(&mut self) -> Option<Duration> {
+        self.stream.iter_mut()
+            .chain(&mut self.mixed)
+            .filter_map(|stream| stream.decode.poll())
+            .min()
+    }
+
+    /// Drop streams that have stopped, stopping their threads, play the
+    /// fallback playlist once the network has been quiet for a while, and
+    /// close the output device once there has been nothing to play
+    pub fn tick(&mut self) -> Result<(), Disconnected> {
+        let now = time::now();
+        self.metrics.health.heartbeat();
+        let identifying = self.identify.is_some_and(|(_, end)| end > now);
+
+        self.collect_stale(now, identifying);
+        self.tick_fallback(now)?;
+        self.remember_drift(now);
+
+        let Some(release_after) = self.release_after() else {
+            return Ok(());
+        };
+
+        if !self.output.is_open() || identifying {
+            return Ok(());
         }
 
-        self.stream.as_mut().unwrap()
+        if self.last_active > now.saturating_sub(release_after) {
+            return Ok(());
+        }
+
+        match self.sparse {
+            Some(_) => log::info!("going dormant"),
+            None => log::info!("idle, releasing output device"),
+        }
+
+        self.stream = None;
+        self.mixed.clear();
+        self.mixer = None;
+        self.identify = None;
+        drop(self.output.close());
+
+        Ok(())
     }
 
-    pub fn receive_audio(&mut self, packet: Audio) -> Result<(), Disconnected> {
+    /// Reports on how each stream playing is arriving, to send to its
+    /// source, once every `REPORT_INTERVAL`
+    pub fn reports(&mut self) -> Vec<(PeerId, ReceiverReport)> {
         let now = time::now();
 
+        if self.last_report > now.saturating_sub(report::REPORT_INTERVAL) {
+            return Vec::new();
+        }
+
+        self.last_report = now;
+
+        self.stream.iter()
+            .chain(&self.mixed)
+            .filter_map(|stream| {
+                let source = stream.source?;
+                let report = stream.reception.report(stream.sid)?;
+                Some((source, report))
+            })
+            .collect()
+    }
+
+    /// Note a request to report when a packet plays, if it is from a
+    /// session playing here. Replies come from `sync_replies` once it has
+    pub fn sync_request(&mut self, request: SyncRequest, peer: PeerId) {
+        let playing = self.stream.iter()
+            .chain(&self.mixed)
+            .any(|stream| stream.sid == request.sid());
+
+        if playing {
+            self.sync_requests.push(Pending { peer, request, received: time::now() });
+        }
+    }
+
+    /// Replies to sync requests whose packet has now played, or never will
+    pub fn sync_replies(&mut self) -> Vec<(PeerId, SyncReply)> {
+        if self.sync_requests.is_empty() {
+            return Vec::new();
+        }
+
+        let now = time::now();
+        let mut replies = Vec::new();
+
+        self.sync_requests.retain(|pending| {
+            let stream = self.stream.iter()
+                .chain(&self.mixed)
+                .find(|stream| stream.sid == pending.request.sid());
+
+            let reply = match stream {
+                Some(stream) => pending.reply(stream.decode.history(), now),
+                // the session has stopped playing since the request
+                None => Some(SyncReply::missed(&pending.request, pending.received, now)
+                    .expect("allocate SyncReply packet")),
+            };
+
+            match reply {
+                Some(reply) => {
+                    replies.push((pending.peer, reply));
+                    false
+                }
+                None => true,
+            }
+        });
+
+        replies
+    }
+
+    /// Remember the drift learned with the current stream's source, once it
+    /// has played in sync for long enough to have settled
+    fn remember_drift(&self, now: TimestampMicros) {
+        let Some(memory) = &self.opt.drift else {
+            return;
+        };
+
+        let Some(stream) = &self.stream else {
+            return;
+        };
+
+        let Some(source) = stream.source else {
+            return;
+        };
+
+        if stream.began > now.saturating_sub(drift::SETTLE_TIME) {
+            return;
+        }
+
+        let decode = stream.decode.stats();
+
+        if let StreamStatus::Sync = decode.status {
+            memory.record(source.ip(), &self.output_device(), decode.clock_drift_ppm);
+        }
+    }
+
+    /// Start the fallback playlist once no stream has been seen for long
+    /// enough, and queue its audio while it plays
+    fn tick_fallback(&mut self, now: TimestampMicros) -> Result<(), Disconnected> {
+        let Some(fallback) = &mut self.fallback else {
+            return Ok(());
+        };
+
+        if fallback.session().is_none() {
+            if self.last_stream > now.saturating_sub(fallback.after()) {
+                return Ok(());
+            }
+
+            if !fallback.start(now) {
+                // try again after another wait, rather than every tick
+                self.last_stream = now;
+                return Ok(());
+            }
+        }
+
+        let packets = fallback.packets(now);
+
+        let Some(sid) = fallback.session() else {
+            // nothing in the playlist could be played
+            self.last_stream = now;
+            return Ok(());
+        };
+
+        if packets.is_empty() || !self.wake() {
+            return Ok(());
+        }
+
+        for packet in packets {
+            let Some(stream) = self.prepare_stream(packet.header(), None, now) else {
+                continue;
+            };
+
+            if stream.sid == sid {
+                stream.receive_packet(packet, now)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drop streams that have not received a packet for a long time, rather
+    /// than keeping them until another stream takes over
+    fn collect_stale(&mut self, now: TimestampMicros, identifying: bool) {
+        let stale_since = now.saturating_sub(STALE_AFTER);
+
+        // streams whose source ended them go once they have faded out
+        let finished = |stream: &Stream| stream.ended && stream.decode.is_finished();
+
+        if self.stream.as_ref().is_some_and(finished) {
+            self.stream = None;
+        }
+
+        self.mixed.retain(|stream| !finished(stream));
+
+        // stragglers from an ended session have all arrived by now
+        self.ended.retain(|(_, at)| *at >= stale_since);
+
+        let stale = self.stream.as_ref()
+            .is_some_and(|stream| stream.receieved_last_packet < stale_since);
+
+        if stale && !identifying {
+            if let Some(stream) = self.stream.take() {
+                log::info!("session {} timed out", stream.sid.0);
+            }
+        }
+
+        self.mixed.retain(|stream| stream.receieved_last_packet >= stale_since);
+
+        if self.stream.is_none() && self.mixed.is_empty() {
+            self.mixer = None;
+        }
+    }
+
+    pub fn receive_audio(&mut self, packet: Audio, peer: PeerId) -> Result<(), Disconnected> {
+        let now = time::now();
+
+        // late and redundant copies of packets from an ended session
+        if self.ended.iter().any(|(sid, _)| *sid == packet.header().sid) {
+            return Ok(());
+        }
+
+        // streams we aren't allowed to play are as good as not there
+        if !self.select.accepts(packet.header().sid, peer) {
+            return Ok(());
+        }
+
+        // the network is back, make way for it
+        self.last_stream = now;
+        self.metrics.health.audio();
+
+        if let Some(sid) = self.fallback.as_mut().and_then(Fallback::stop) {
+            log::info!("stream received, stopping fallback playlist");
+            self.stream_end(sid, None);
+        }
+
+        // sparse receivers only wake for high priority streams
+        if self.sparse.is_some_and(|priority| packet.header().priority < priority) {
+            return Ok(());
+        }
+
+        if !self.wake() {
+            return Ok(());
+        }
+
+        // restart the stream from this packet, rebuffering and resyncing
+        if self.resync.swap(false, Ordering::Relaxed) {
+            log::info!("resyncing stream");
+            self.stream = None;
+            self.mixed.clear();
+            self.identify = None;
+        }
+
         let header = packet.header();
         let dts = header.dts;
 
         // prepare stream for incoming packet
-        let stream = self.prepare_stream(header, now);
+        let Some(stream) = self.prepare_stream(header, Some(peer), now) else {
+            return Ok(());
+        };
 
         // if packet does not match current stream, exit early
         if header.sid != stream.sid {
@@ -147,11 +1091,18 @@ impl<F: Format> Receiver<F> {
         }
 
         // feed packet to stream
-        stream.receive_packet(packet, now)?;
+        let insert = stream.receive_packet(packet, now)?;
+
+        if insert == Insert::Duplicate {
+            self.metrics.packets_duplicate.increment();
+        }
 
         // update metrics
         let latency = now.saturating_duration_since(dts);
-        self.metrics.network_latency.observe(latency);
+        match self.latency_filter.filter(latency) {
+            Some(latency) => self.metrics.network_latency.observe(latency),
+            None => self.metrics.latency_outliers.increment(),
+        }
         self.metrics.packets_received.increment();
 
         Ok(())
@@ -163,61 +1114,380 @@ pub struct ReceiveOpt {
     #[structopt(flatten)]
     pub socket: SocketOpt,
 
-    /// Audio device name
-    #[structopt(long, env = "BARK_RECEIVE_OUTPUT_DEVICE")]
-    pub output_device: Option<String>,
+    /// Audio device name. May be given more than once to play on several
+    /// devices in sync, timed against the first. Separate names with `;`
+    /// when setting this through the environment
+    #[structopt(long, env = "BARK_RECEIVE_OUTPUT_DEVICE", value_delimiter = ";")]
+    pub output_device: Vec<String>,
 
-    /// Size of discrete audio transfer buffer in frames
+    /// Size of discrete audio transfer buffer in frames, chosen from the
+    /// target latency if not set
     #[structopt(long, env = "BARK_RECEIVE_OUTPUT_PERIOD")]
     pub output_period: Option<usize>,
 
-    /// Size of decoded audio buffer in frames
+    /// Size of decoded audio buffer in frames, chosen from the target
+    /// latency if not set
     #[structopt(long, env = "BARK_RECEIVE_OUTPUT_BUFFER")]
     pub output_buffer: Option<usize>,
 
+    /// Target latency of the audio device buffer in milliseconds, used to
+    /// size period and buffer within the limits the device supports
+    #[structopt(long, env = "BARK_RECEIVE_OUTPUT_LATENCY_MS")]
+    pub output_latency_ms: Option<f64>,
+
     #[structopt(long, env = "BARK_RECEIVE_OUTPUT_FORMAT", default_value = "f32")]
     pub output_format: config::Format,
+
+    /// Share the output device with other applications by playing through
+    /// ALSA's dmix plugin. Period and buffer sizes become preferences, as
+    /// dmix decides them for all its clients
+    #[structopt(
+        long,
+        env = "BARK_RECEIVE_OUTPUT_SHARED",
+        default_value = "false",
+        parse(try_from_str),
+    )]
+    pub output_shared: bool,
+
+    /// Play audio earlier (positive) or later (negative) by this many
+    /// milliseconds, to compensate for latency added after the output device
+    #[structopt(
+        long,
+        env = "BARK_RECEIVE_LATENCY_OFFSET_MS",
+        default_value = "0",
+        allow_hyphen_values = true,
+    )]
+    pub latency_offset_ms: i64,
+
+    /// Latency in milliseconds added by whatever the output device feeds,
+    /// such as an HDMI TV or AV receiver, which the device can't report.
+    /// Counted as part of the output's latency, so audio is played that
+    /// much earlier, and shown in `bark stats`
+    #[structopt(
+        long,
+        env = "BARK_RECEIVE_DEVICE_EXTRA_LATENCY_MS",
+        default_value = "0",
+    )]
+    pub device_extra_latency_ms: u64,
+
+    /// Minimum audio to buffer in milliseconds. Streams with a shorter
+    /// delay, or a source hint asking for more, are played later to allow
+    /// for it. Receivers playing together should use the same value
+    #[structopt(
+        long,
+        env = "BARK_RECEIVE_MIN_BUFFER_MS",
+        default_value = "0",
+    )]
+    pub min_buffer_ms: u64,
+
+    /// Adapt buffering to the network, growing it up to this many
+    /// milliseconds when packets arrive too late to play and shrinking it
+    /// when they arrive comfortably early. Receivers adapt independently,
+    /// so this trades tight sync between receivers for fewer dropouts
+    #[structopt(long, env = "BARK_RECEIVE_ADAPTIVE_BUFFER_MAX_MS")]
+    pub adaptive_buffer_max_ms: Option<u64>,
+
+    /// How to play stereo audio on this receiver's speakers: stereo, mono
+    /// (downmix), swap (left and right), left or right (one channel on both)
+    #[structopt(
+        long,
+        env = "BARK_RECEIVE_CHANNEL_MAP",
+        default_value = "stereo",
+    )]
+    pub channel_map: config::ChannelMap,
+
+    /// Maximum volume as a percentage, enforced whatever volume is requested
+    #[structopt(
+        long,
+        env = "BARK_RECEIVE_MAX_VOLUME",
+        default_value = "100",
+    )]
+    pub max_volume: f32,
+
+    /// Prevent control requests from changing the maximum volume
+    #[structopt(
+        long,
+        env = "BARK_RECEIVE_VOLUME_LOCK",
+        default_value = "false",
+        parse(try_from_str),
+    )]
+    pub volume_lock: bool,
+
+    /// Shape dither noise towards less audible frequencies when playing to
+    /// a 16 bit output device
+    #[structopt(
+        long,
+        env = "BARK_RECEIVE_NOISE_SHAPING",
+        default_value = "false",
+        parse(try_from_str),
+    )]
+    pub noise_shaping: bool,
+
+    /// Limit peaks that would otherwise clip, such as after a channel
+    /// downmix. Can also be switched at runtime with `bark ctl dsp`
+    #[structopt(
+        long,
+        env = "BARK_RECEIVE_LIMITER",
+        default_value = "false",
+        parse(try_from_str),
+    )]
+    pub limiter: bool,
+
+    /// Protect small speakers from bass they can't reproduce and from
+    /// clipping, as the last stage before output: small-fullrange (80 Hz
+    /// high-pass, -3 dBFS limit) or bookshelf (45 Hz, -1 dBFS)
+    #[structopt(long, env = "BARK_RECEIVE_PROTECTION")]
+    pub protection: Option<config::Protection>,
+
+    /// Capture device of a microphone in the room. Playback is ducked
+    /// while sustained speech is heard on it
+    #[structopt(long, env = "BARK_RECEIVE_DUCK_INPUT")]
+    pub duck_input: Option<String>,
+
+    /// Level in dBFS of speech at the ducking microphone above which
+    /// playback is ducked
+    #[structopt(
+        long,
+        env = "BARK_RECEIVE_DUCK_THRESHOLD",
+        default_value = "-40",
+        allow_hyphen_values = true,
+    )]
+    pub duck_threshold: f32,
+
+    /// Fraction of normal volume to play at while ducked
+    #[structopt(
+        long,
+        env = "BARK_RECEIVE_DUCK_RATIO",
+        default_value = "0.3",
+    )]
+    pub duck_ratio: f32,
+
+    /// Mix concurrent streams rather than playing only the highest
+    /// priority one, eg. to hear a doorbell announcement over music. Lower
+    /// priority streams are ducked while a higher priority one plays
+    #[structopt(
+        long,
+        env = "BARK_RECEIVE_MIX",
+        default_value = "false",
+        parse(try_from_str),
+    )]
+    pub mix: bool,
+
+    /// Packets a new stream must send in a row before it takes over from
+    /// the one playing, so that a stray stream can't interrupt with a few
+    /// packets. Explicit takeovers still switch straight away
+    #[structopt(long, env = "BARK_RECEIVE_SWITCH_AFTER_PACKETS", default_value = "1")]
+    pub switch_after_packets: u32,
+
+    /// Only play streams from these source addresses, ignoring any other
+    /// source on the network
+    #[structopt(long, env = "BARK_RECEIVE_LOCK_SOURCE", use_delimiter = true)]
+    pub lock_source: Vec<IpAddr>,
+
+    /// Only play streams with these session ids, as shown in `bark stats`
+    #[structopt(long, env = "BARK_RECEIVE_ACCEPT_SID", use_delimiter = true, allow_hyphen_values = true)]
+    pub accept_sid: Vec<i64>,
+
+    /// Stay dormant, with the output device closed and nothing decoding,
+    /// until a stream of at least this priority arrives, such as a doorbell
+    /// announcement. Lower priority streams are ignored entirely
+    #[structopt(long, env = "BARK_RECEIVE_SPARSE_PRIORITY", allow_hyphen_values = true)]
+    pub sparse_priority: Option<i8>,
+
+    /// Close the output device once there has been nothing to play for
+    /// this many seconds, so that other software can use it, and reopen it
+    /// when a stream arrives
+    #[structopt(long, env = "BARK_RECEIVE_IDLE_RELEASE_SECS")]
+    pub idle_release_secs: Option<u64>,
+
+    /// WAV file, or playlist of them with one path per line, to play on a
+    /// loop once no stream has been seen for a while, such as background
+    /// music while the network is down. Stops as soon as a stream returns
+    #[structopt(long, env = "BARK_RECEIVE_FALLBACK_PLAYLIST")]
+    pub fallback_playlist: Option<PathBuf>,
+
+    /// How many seconds without a stream before the fallback playlist plays
+    #[structopt(long, env = "BARK_RECEIVE_FALLBACK_AFTER_SECS", default_value = "300")]
+    pub fallback_after_secs: u64,
+
+    /// Volume of the fallback playlist in percent, so it plays quieter
+    /// than real streams
+    #[structopt(long, env = "BARK_RECEIVE_FALLBACK_VOLUME", default_value = "30")]
+    pub fallback_volume: f32,
+
+    /// Remember the clock drift learned with each source, so that later
+    /// sessions with it start in sync sooner
+    #[structopt(
+        long,
+        env = "BARK_RECEIVE_REMEMBER_DRIFT",
+        default_value = "true",
+        parse(try_from_str),
+    )]
+    pub remember_drift: bool,
+
+    /// File to remember clock drift in, bark/drift in the XDG state
+    /// directory if not set
+    #[structopt(long, env = "BARK_RECEIVE_DRIFT_FILE")]
+    pub drift_file: Option<PathBuf>,
+
+    /// Name to show for this receiver in `bark stats` and the web UI, such
+    /// as the room it's in. Defaults to user@host
+    #[structopt(long, env = "BARK_RECEIVE_NAME")]
+    pub name: Option<String>,
+
+    /// File to keep this receiver's persistent id in, bark/receiver-id in
+    /// the XDG config directory if not set
+    #[structopt(long, env = "BARK_RECEIVE_ID_FILE")]
+    pub id_file: Option<PathBuf>,
+
+    /// Zone this receiver belongs to, for muting groups of receivers
+    /// together with `bark zones`
+    #[structopt(long, env = "BARK_RECEIVE_ZONE")]
+    pub zone: Option<String>,
+
+    /// Act on control packets that aren't signed with the control secret,
+    /// such as from older versions of bark. Anyone on the network can then
+    /// change this receiver's volume, zone, and output
+    #[structopt(
+        long,
+        env = "BARK_RECEIVE_ALLOW_UNSIGNED_CONTROL",
+        default_value = "false",
+        parse(try_from_str),
+    )]
+    pub allow_unsigned_control: bool,
+
+    /// Decode on the network thread, in one loop waking for packets and
+    /// for when the output device has room for more audio, rather than on
+    /// a thread per stream. Less context switching and locking for very
+    /// weak CPUs. Streams mixed with --mix still decode on threads
+    #[structopt(
+        long,
+        env = "BARK_RECEIVE_EVENT_LOOP",
+        default_value = "false",
+        parse(try_from_str),
+    )]
+    pub event_loop: bool,
 }
 
-pub async fn run(opt: ReceiveOpt, metrics: stats::server::MetricsOpt) -> Result<(), RunError> {
+pub async fn run(opt: ReceiveOpt, metrics: bark_app::metrics::MetricsOpt) -> Result<(), RunError> {
+    let id_file = opt.id_file.clone().or_else(identity::default_path);
+
+    stats::node::set_identity(stats::node::Identity {
+        id: identity::load(id_file.as_deref()),
+        name: opt.name.clone(),
+    });
+
     let socket = Socket::open(&opt.socket)
         .map_err(RunError::Listen)?;
 
-    let metrics = stats::server::start_receiver(&metrics).await?;
+    let _announce = socket.multicast().and_then(|group| discover::announce(Role::Receiver, group));
+
+    // follow the network if we fail over to another interface
+    match socket.membership() {
+        Ok(membership) => monitor::start(membership),
+        Err(e) => log::warn!("failed to monitor network changes: {e}"),
+    }
+
+    let resync = Arc::new(AtomicBool::new(false));
+    let metrics = stats::start_receiver(&metrics, resync.clone()).await?;
 
     match opt.output_format {
-        config::Format::S16 => run_format::<S16>(opt, socket, metrics).await,
-        config::Format::F32 => run_format::<F32>(opt, socket, metrics).await,
+        config::Format::S16 => run_format::<S16>(opt, socket, resync, metrics).await,
+        config::Format::F32 => run_format::<F32>(opt, socket, resync, metrics).await,
     }
 }
 
 async fn run_format<F: Format>(
     opt: ReceiveOpt,
     socket: Socket,
+    resync: Arc<AtomicBool>,
     metrics: stats::ReceiverMetrics,
 ) -> Result<(), RunError> {
-    let device_opt = DeviceOpt {
-        device: opt.output_device,
-        period: opt.output_period
-            .map(SampleDuration::from_frame_count)
-            .unwrap_or(DEFAULT_PERIOD),
-        buffer: opt.output_buffer
-            .map(SampleDuration::from_frame_count)
-            .unwrap_or(DEFAULT_BUFFER),
+    // the system default device if none are given
+    let devices = match opt.output_device.len() {
+        0 => vec![None],
+        _ => opt.output_device.into_iter().map(Some).collect(),
+    };
+
+    let devices = devices.into_iter()
+        .map(|device| DeviceOpt {
+            device,
+            period: opt.output_period.map(SampleDuration::from_frame_count),
+            buffer: opt.output_buffer.map(SampleDuration::from_frame_count),
+            latency: audio_config::latency(opt.output_latency_ms),
+            shared: opt.output_shared,
+        })
+        .collect();
+
+    let duck = match opt.duck_input {
+        Some(device) => Some(duck::start(duck::DuckOpt {
+            device,
+            threshold_db: opt.duck_threshold,
+            ratio: opt.duck_ratio.clamp(0.0, 1.0),
+        })?),
+        None => None,
     };
 
-    let output = Output::<F>::new(&device_opt, metrics.clone())
-        .map_err(RunError::OpenAudioDevice)?;
+    let drift = match (opt.remember_drift, opt.drift_file.or_else(drift::default_path)) {
+        (false, _) => None,
+        (true, Some(path)) => Some(Arc::new(DriftMemory::open(path))),
+        (true, None) => {
+            log::warn!("nowhere to remember clock drift, set --drift-file");
+            None
+        }
+    };
+
+    let decode_opt = DecodeOpt {
+        latency_offset: TimestampDelta::from_micros_lossy(opt.latency_offset_ms * 1000),
+        device_latency: SampleDuration::from_std_duration_lossy(Duration::from_millis(opt.device_extra_latency_ms)),
+        min_buffer: SampleDuration::from_std_duration_lossy(Duration::from_millis(opt.min_buffer_ms)),
+        adaptive_buffer: opt.adaptive_buffer_max_ms
+            .map(|ms| SampleDuration::from_std_duration_lossy(Duration::from_millis(ms))),
+        volume: Arc::new(Volume::new(opt.max_volume / 100.0, opt.volume_lock)),
+        channel_map: opt.channel_map.channel_map(),
+        noise_shaping: opt.noise_shaping,
+        dsp: Arc::new(Dsp::new(Settings {
+            limiter: opt.limiter,
+            protection: opt.protection.map(|protection| protection.profile()),
+        })),
+        duck,
+        drift,
+        initial_drift_ppm: None,
+        event_loop: opt.event_loop,
+    };
+
+    let auth = ControlAuth::new(socket.control_secret(), opt.allow_unsigned_control, {
+        let metrics = metrics.clone();
+        move || metrics.control_rejected.increment()
+    });
+
+    let idle = IdleOpt {
+        sparse_priority: opt.sparse_priority,
+        release_after: opt.idle_release_secs.map(Duration::from_secs),
+        fallback: opt.fallback_playlist.map(|playlist| FallbackOpt {
+            playlist,
+            after: Duration::from_secs(opt.fallback_after_secs),
+            volume: opt.fallback_volume.clamp(0.0, 100.0) / 100.0,
+        }),
+    };
+
+    let select = SelectOpt {
+        mix: opt.mix,
+        switch_after_packets: opt.switch_after_packets,
+        lock_source: opt.lock_source,
+        accept_sid: opt.accept_sid.into_iter().map(SessionId).collect(),
+    };
 
-    let receiver = Receiver::new(output, metrics.clone());
+    let receiver = Receiver::<F>::new(devices, opt.zone, resync, select, idle, metrics.clone(), decode_opt)?;
 
     thread::start("bark/network", move || {
-        network_thread(socket, receiver)
+        network_thread(socket, auth, receiver)
     }).await
 }
 
 fn network_thread<F: Format>(
     socket: Socket,
+    mut auth: ControlAuth,
     mut receiver: Receiver<F>,
 ) -> Result<(), RunError> {
     thread::set_realtime_priority();
@@ -226,11 +1496,37 @@ fn network_thread<F: Format>(
     let protocol = ProtocolSocket::new(socket);
 
     loop {
-        let (packet, peer) = protocol.recv_from().map_err(RunError::Receive)?;
+        // poll waits in whole milliseconds, round up rather than spinning
+        // until the next stream is due
+        let due = receiver.decode()
+            .map(|due| Duration::from_millis(due.as_micros().div_ceil(1000) as u64));
+
+        let timeout = receiver.tick_interval().into_iter().chain(due).min();
+
+        let received = protocol.recv_from_timeout(timeout)
+            .map_err(RunError::Receive)?;
+
+        receiver.tick()?;
+
+        for (source, report) in receiver.reports() {
+            let _ = protocol.send_to(report.as_packet(), source);
+        }
+
+        for (peer, reply) in receiver.sync_replies() {
+            let _ = protocol.send_to(reply.as_packet(), peer);
+        }
+
+        let Some((packet, peer)) = received else {
+            continue;
+        };
+
+        let Some(packet) = auth.check(packet, peer) else {
+            continue;
+        };
 
         match packet.parse() {
             Some(PacketKind::Audio(packet)) => {
-                receiver.receive_audio(packet)?;
+                receiver.receive_audio(packet, peer)?;
             }
             Some(PacketKind::StatsRequest(_)) => {
                 let sid = receiver.current_session().unwrap_or(SessionId::zeroed());
@@ -244,13 +1540,80 @@ fn network_thread<F: Format>(
             Some(PacketKind::StatsReply(_)) => {
                 // ignore
             }
-            Some(PacketKind::Ping(_)) => {
-                let pong = Pong::new().expect("allocate Pong packet");
+            Some(PacketKind::Ping(ping)) => {
+                let pong = Pong::reply(&ping).expect("allocate Pong packet");
                 let _ = protocol.send_to(pong.as_packet(), peer);
             }
             Some(PacketKind::Pong(_)) => {
                 // ignore
             }
+            Some(PacketKind::DumpRequest(request)) => {
+                let reply = match receiver.dump(request.duration()) {
+                    Ok(path) => DumpReply::location(&path.display().to_string()),
+                    Err(message) => DumpReply::error(&message),
+                };
+
+                let reply = reply.expect("allocate DumpReply packet");
+                let _ = protocol.send_to(reply.as_packet(), peer);
+            }
+            Some(PacketKind::DumpReply(_)) => {
+                // ignore
+            }
+            Some(PacketKind::OutputRequest(request)) => {
+                let device = request.device().map(str::to_owned);
+
+                let reply = match receiver.set_output_device(device) {
+                    Ok(()) => OutputReply::device(&receiver.output_device()),
+                    Err(message) => OutputReply::error(&message),
+                };
+
+                let reply = reply.expect("allocate OutputReply packet");
+                let _ = protocol.send_to(reply.as_packet(), peer);
+            }
+            Some(PacketKind::OutputReply(_)) => {
+                // ignore
+            }
+            Some(PacketKind::ReplayRequest(_)) => {
+                // ignore
+            }
+            Some(PacketKind::ReplayReply(_)) => {
+                // ignore
+            }
+            Some(PacketKind::VolumeRequest(request)) => {
+                receiver.set_volume(request.volume(), request.mute());
+                receiver.limit_volume(request.max_volume(), request.lock());
+            }
+            Some(PacketKind::ZoneRequest(request)) => {
+                receiver.zone_request(&request);
+            }
+            Some(PacketKind::Takeover(takeover)) => {
+                let sid = Some(takeover.sid()).filter(|sid| *sid != SessionId::zeroed());
+                receiver.takeover(sid);
+            }
+            Some(PacketKind::Identify(_)) => {
+                receiver.identify()?;
+            }
+            Some(PacketKind::Signed(_)) => {
+                // unwrapped by auth above, so only nested signing is left
+            }
+            Some(PacketKind::DspRequest(request)) => {
+                receiver.dsp_request(&request);
+            }
+            Some(PacketKind::StreamEnd(end)) => {
+                receiver.stream_end(end.sid(), Some(peer));
+            }
+            Some(PacketKind::Metadata(metadata)) => {
+                receiver.metadata(&metadata);
+            }
+            Some(PacketKind::ReceiverReport(_)) => {
+                // ignore
+            }
+            Some(PacketKind::SyncRequest(request)) => {
+                receiver.sync_request(request, peer);
+            }
+            Some(PacketKind::SyncReply(_)) => {
+                // ignore
+            }
             None => {
                 // unknown packet type, ignore
             }
@@ -0,0 +1,134 @@
+use std::time::Duration;
+
+use bark_protocol::time::{SampleDuration, Timestamp};
+use bark_protocol::types::TimestampMicros;
+
+// grow quickly when packets arrive too late to play
+const GROW: Duration = Duration::from_millis(5);
+
+// shrink slowly once the network has been calm for a while
+const SHRINK: Duration = Duration::from_millis(1);
+const SHRINK_WINDOW: Duration = Duration::from_secs(10);
+
+// packets must have arrived at least this early throughout a window to
+// shrink, so that shrinking doesn't cause the next underrun
+const SHRINK_MARGIN: Duration = Duration::from_millis(5);
+
+// the delay moves towards its target by at most this fraction of stream
+// time, well within how fast the receiver can slew playback
+const SLEW_RATE: f64 = 0.005;
+
+/// Adaptive jitter buffer: grows the delay added to a stream when packets
+/// arrive too late to be played, and shrinks it again when they have been
+/// arriving comfortably early
+pub struct AdaptiveBuffer {
+    min: SampleDuration,
+    max: SampleDuration,
+    target: SampleDuration,
+    current: SampleDuration,
+    // fractional frames of slew accumulated towards the next frame
+    slew: f64,
+    window: SampleDuration,
+    window_margin: Option<i64>,
+}
+
+impl AdaptiveBuffer {
+    pub fn new(initial: SampleDuration, max: SampleDuration) -> Self {
+        let max = std::cmp::max(initial, max);
+
+        AdaptiveBuffer {
+            min: initial,
+            max,
+            target: initial,
+            current: initial,
+            slew: 0.0,
+            window: SampleDuration::zero(),
+            window_margin: None,
+        }
+    }
+
+    /// Target delay the buffer is moving towards
+    pub fn target(&self) -> SampleDuration {
+        self.target
+    }
+
+    /// Called for each packet received with its pts, whether any packet
+    /// has underrun since the last call. Returns the delay to add to pts.
+    pub fn update(
+        &mut self,
+        pts: Timestamp,
+        packet: SampleDuration,
+        now: TimestampMicros,
+        underrun: bool,
+    ) -> SampleDuration {
+        if underrun {
+            self.grow();
+        } else {
+            self.observe(pts, packet, now);
+        }
+
+        self.step(packet);
+        self.current
+    }
+
+    fn grow(&mut self) {
+        let target = std::cmp::min(self.max, self.target.add(duration(GROW)));
+
+        if target != self.target {
+            log::info!("packets arriving late, growing buffer to {}ms", target.to_micros_lossy() / 1000);
+            self.target = target;
+        }
+
+        self.window = SampleDuration::zero();
+        self.window_margin = None;
+    }
+
+    fn observe(&mut self, pts: Timestamp, packet: SampleDuration, now: TimestampMicros) {
+        // how long before it is due to play this packet arrived
+        let margin = pts.add(self.current)
+            .delta(Timestamp::from_micros_lossy(now))
+            .as_frames();
+
+        self.window_margin = Some(self.window_margin.map_or(margin, |min| min.min(margin)));
+        self.window = self.window.add(packet);
+
+        if self.window < duration(SHRINK_WINDOW) {
+            return;
+        }
+
+        let shrink_margin = i64::try_from(duration(SHRINK_MARGIN).to_frame_count()).unwrap_or(i64::MAX);
+
+        if self.window_margin.is_some_and(|margin| margin > shrink_margin) && self.target > self.min {
+            let shrink = std::cmp::min(duration(SHRINK), self.target.sub(self.min));
+            self.target = self.target.sub(shrink);
+            log::debug!("network calm, shrinking buffer to {}ms", self.target.to_micros_lossy() / 1000);
+        }
+
+        self.window = SampleDuration::zero();
+        self.window_margin = None;
+    }
+
+    fn step(&mut self, packet: SampleDuration) {
+        if self.current == self.target {
+            self.slew = 0.0;
+            return;
+        }
+
+        self.slew += packet.to_frame_count() as f64 * SLEW_RATE;
+
+        let frames = self.slew.floor();
+        self.slew -= frames;
+
+        let step = SampleDuration::from_frame_count_u64(frames as u64);
+
+        self.current = if self.current < self.target {
+            std::cmp::min(self.target, self.current.add(step))
+        } else {
+            std::cmp::max(self.target, self.current.sub(std::cmp::min(step, self.current)))
+        };
+    }
+}
+
+fn duration(duration: Duration) -> SampleDuration {
+    SampleDuration::from_std_duration_lossy(duration)
+}
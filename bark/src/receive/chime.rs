@@ -0,0 +1,90 @@
+use std::f32::consts::TAU;
+use std::time::Duration;
+
+use bark_core::audio::{FrameF32, Frames};
+use bark_core::encode::Encode;
+use bark_core::encode::pcm::F32LEEncoder;
+use bark_protocol::packet::Audio;
+use bark_protocol::time::{SampleDuration, Timestamp};
+use bark_protocol::types::{AudioPacketHeader, SessionId, TimestampMicros};
+use bark_protocol::{MAX_FRAMES_PER_PACKET, SAMPLE_RATE};
+
+// two rising notes, repeated so there's time to walk around and listen
+const NOTES: [f32; 2] = [880.0, 1318.5];
+const NOTE_LENGTH: Duration = Duration::from_millis(200);
+const REPEAT: usize = 3;
+const GAP: Duration = Duration::from_millis(300);
+const AMPLITUDE: f32 = 0.4;
+
+// leave time for the packets to be queued before they're due
+const LEAD: Duration = Duration::from_millis(50);
+
+/// Packets for a locally generated identify chime, as a stream of its own
+/// beginning shortly after `now`. Returns the packets along with the time
+/// the chime finishes playing.
+pub fn packets(sid: SessionId, now: TimestampMicros) -> (Vec<Audio>, TimestampMicros) {
+    let frames = generate();
+
+    let start = Timestamp::from_micros_lossy(now)
+        .add(SampleDuration::from_std_duration_lossy(LEAD));
+
+    let end = start.add(SampleDuration::from_frame_count(frames.len()));
+
+    let mut encoder = F32LEEncoder;
+    let mut packets = Vec::new();
+
+    for (seq, chunk) in (1..).zip(frames.chunks_exact(MAX_FRAMES_PER_PACKET)) {
+        let offset = SampleDuration::from_frame_count((seq as usize - 1) * MAX_FRAMES_PER_PACKET);
+
+        let header = AudioPacketHeader {
+            sid,
+            seq,
+            pts: start.add(offset).to_micros_lossy(),
+            dts: now,
+            format: encoder.header_format(),
+            // local playback takes over from any stream
+            priority: i8::MAX,
+            packet_frames: MAX_FRAMES_PER_PACKET as u16,
+            delay_ms: 0,
+            min_buffer_ms: 0,
+            start_pts: TimestampMicros(0),
+        };
+
+        let mut buffer = [0; Audio::MAX_BUFFER_LENGTH];
+        let length = encoder.encode_packet(Frames::F32(chunk), &mut buffer)
+            .expect("encode chime");
+
+        packets.push(Audio::new(&header, &buffer[0..length])
+            .expect("allocate Audio packet"));
+    }
+
+    (packets, end.to_micros_lossy())
+}
+
+fn generate() -> Vec<FrameF32> {
+    let rate = SAMPLE_RATE.0 as f32;
+    let note_frames = SampleDuration::from_std_duration_lossy(NOTE_LENGTH).to_frame_count() as usize;
+    let gap_frames = SampleDuration::from_std_duration_lossy(GAP).to_frame_count() as usize;
+
+    let mut frames = Vec::new();
+
+    for _ in 0..REPEAT {
+        for freq in NOTES {
+            for n in 0..note_frames {
+                let t = n as f32 / rate;
+                // quick exponential decay so the note sounds like a bell
+                let envelope = (-t * 12.0).exp();
+                let sample = (TAU * freq * t).sin() * envelope * AMPLITUDE;
+                frames.push(FrameF32(sample, sample));
+            }
+        }
+
+        frames.resize(frames.len() + gap_frames, FrameF32(0.0, 0.0));
+    }
+
+    // fill out the last packet with silence
+    let packets = frames.len().div_ceil(MAX_FRAMES_PER_PACKET);
+    frames.resize(packets * MAX_FRAMES_PER_PACKET, FrameF32(0.0, 0.0));
+
+    frames
+}
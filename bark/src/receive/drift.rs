@@ -0,0 +1,150 @@
+//! Remembers the clock drift learned between each source machine and this
+//! receiver's output device, so that the next session with the same pair
+//! starts its clock servo from there rather than from zero. Installations
+//! where the same devices always play together then sync up without the
+//! initial period of slewing while drift is learned.
+//!
+//! Drift is stored as lines of `<source ip>\t<output device>\t<ppm>`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a stream must have played in sync before its drift is trusted,
+/// giving the servo time to settle
+pub const SETTLE_TIME: Duration = Duration::from_secs(60);
+
+/// How often learned drift is written out while streams play
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Drift changes smaller than this aren't worth writing out, in ppm
+const SAVE_THRESHOLD: f64 = 0.1;
+
+type Key = (IpAddr, String);
+
+pub struct DriftMemory {
+    path: PathBuf,
+    state: Mutex<State>,
+}
+
+struct State {
+    drift: HashMap<Key, f64>,
+    // drift as last written out
+    saved: HashMap<Key, f64>,
+    last_save: Instant,
+}
+
+/// Where drift is remembered if no file is given: in the XDG state
+/// directory, or failing that under `$HOME`
+pub fn default_path() -> Option<PathBuf> {
+    let state = std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            let home = std::env::var_os("HOME").filter(|dir| !dir.is_empty())?;
+            Some(Path::new(&home).join(".local/state"))
+        })?;
+
+    Some(state.join("bark/drift"))
+}
+
+impl DriftMemory {
+    pub fn open(path: PathBuf) -> Self {
+        let drift = match fs::read_to_string(&path) {
+            Ok(contents) => parse(&path, &contents),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                log::warn!("error reading clock drift from {}, starting afresh: {e}", path.display());
+                HashMap::new()
+            }
+        };
+
+        log::debug!("remembering clock drift in {}", path.display());
+
+        DriftMemory {
+            path,
+            state: Mutex::new(State {
+                saved: drift.clone(),
+                drift,
+                last_save: Instant::now(),
+            }),
+        }
+    }
+
+    /// Drift last learned between `source` and `device`, in ppm
+    pub fn recall(&self, source: IpAddr, device: &str) -> Option<f64> {
+        let state = self.state.lock().unwrap();
+        state.drift.get(&(source, device.to_owned())).copied()
+    }
+
+    /// Record drift learned between `source` and `device`, in ppm, writing
+    /// it out every so often
+    pub fn record(&self, source: IpAddr, device: &str, ppm: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.drift.insert((source, device.to_owned()), ppm);
+
+        if state.last_save.elapsed() < SAVE_INTERVAL {
+            return;
+        }
+
+        let changed = state.drift.iter().any(|(key, ppm)| {
+            state.saved.get(key).is_none_or(|saved| (saved - ppm).abs() >= SAVE_THRESHOLD)
+        });
+
+        if !changed {
+            return;
+        }
+
+        state.last_save = Instant::now();
+
+        if let Err(e) = save(&self.path, &state.drift) {
+            log::warn!("error saving clock drift to {}: {e}", self.path.display());
+            return;
+        }
+
+        state.saved = state.drift.clone();
+    }
+}
+
+fn parse(path: &Path, contents: &str) -> HashMap<Key, f64> {
+    let mut drift = HashMap::new();
+
+    for (idx, line) in contents.lines().enumerate() {
+        match parse_line(line) {
+            Some((key, ppm)) => { drift.insert(key, ppm); }
+            None => log::warn!("ignoring invalid clock drift at {}:{}", path.display(), idx + 1),
+        }
+    }
+
+    drift
+}
+
+fn parse_line(line: &str) -> Option<(Key, f64)> {
+    let mut fields = line.split('\t');
+    let source = fields.next()?.parse().ok()?;
+    let device = fields.next()?.to_owned();
+    let ppm = fields.next()?.parse::<f64>().ok().filter(|ppm| ppm.is_finite())?;
+    Some(((source, device), ppm))
+}
+
+/// Write drift out to a temporary file and move it into place, so that
+/// the file is never left half written
+fn save(path: &Path, drift: &HashMap<Key, f64>) -> Result<(), io::Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp)?;
+
+    for ((source, device), ppm) in drift {
+        writeln!(file, "{source}\t{device}\t{ppm:.3}")?;
+    }
+
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
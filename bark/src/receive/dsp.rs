@@ -0,0 +1,71 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use bark_core::audio::dsp::{Chain, Settings};
+use bark_core::audio::protection::Profile;
+
+/// Output chain settings, shared with the audio threads. Changes are picked
+/// up by each chain as it processes its next packet, and ramped in there
+pub struct Dsp {
+    limiter: AtomicBool,
+    // profile code, as in the protocol's DspPacket
+    protection: AtomicU32,
+    bypass: AtomicBool,
+}
+
+impl Dsp {
+    pub fn new(settings: Settings) -> Self {
+        Dsp {
+            limiter: AtomicBool::new(settings.limiter),
+            protection: AtomicU32::new(profile_code(settings.protection)),
+            bypass: AtomicBool::new(false),
+        }
+    }
+
+    pub fn settings(&self) -> Settings {
+        Settings {
+            limiter: self.limiter.load(Ordering::Relaxed),
+            protection: profile_from_code(self.protection.load(Ordering::Relaxed)).flatten(),
+        }
+    }
+
+    pub fn set_limiter(&self, limiter: bool) {
+        self.limiter.store(limiter, Ordering::Relaxed);
+    }
+
+    pub fn set_protection(&self, protection: Option<Profile>) {
+        self.protection.store(profile_code(protection), Ordering::Relaxed);
+    }
+
+    pub fn bypass(&self) -> bool {
+        self.bypass.load(Ordering::Relaxed)
+    }
+
+    pub fn set_bypass(&self, bypass: bool) {
+        self.bypass.store(bypass, Ordering::Relaxed);
+    }
+
+    /// Bring a chain up to date with the current settings
+    pub fn update(&self, chain: &mut Chain) {
+        chain.configure(self.settings());
+        chain.set_bypass(self.bypass());
+    }
+}
+
+/// Protection profile as sent in control packets
+pub fn profile_code(profile: Option<Profile>) -> u32 {
+    match profile {
+        None => 0,
+        Some(Profile::SmallFullrange) => 1,
+        Some(Profile::Bookshelf) => 2,
+    }
+}
+
+/// Protection profile from a control packet, `None` if the code is unknown
+pub fn profile_from_code(code: u32) -> Option<Option<Profile>> {
+    match code {
+        0 => Some(None),
+        1 => Some(Some(Profile::SmallFullrange)),
+        2 => Some(Some(Profile::Bookshelf)),
+        _ => None,
+    }
+}
@@ -0,0 +1,199 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bark_app::thread;
+use bark_core::audio::{FrameF32, F32};
+use bark_protocol::time::SampleDuration;
+use bark_protocol::SAMPLE_RATE;
+
+use crate::audio::config::{self as audio_config, DeviceOpt};
+use crate::audio::{Input, OpenError};
+
+// how much audio to measure at a time
+const BLOCK: Duration = Duration::from_millis(10);
+
+// speech must be sustained this long to duck, so that door slams and the
+// like don't
+const SPEECH_ONSET: Duration = Duration::from_millis(250);
+
+// keep ducking through pauses between words and sentences
+const SPEECH_HOLD: Duration = Duration::from_millis(1500);
+
+const ATTACK: Duration = Duration::from_millis(100);
+const RELEASE: Duration = Duration::from_secs(1);
+
+// speech band, to ignore rumble and hiss
+const HIGHPASS_HZ: f32 = 300.0;
+const LOWPASS_HZ: f32 = 3400.0;
+
+pub struct DuckOpt {
+    /// Capture device of the microphone to listen to
+    pub device: String,
+    /// Level of speech band audio at the microphone in dBFS above which
+    /// the room is considered to be talking
+    pub threshold_db: f32,
+    /// Gain to play at while ducked, between 0.0 and 1.0
+    pub ratio: f32,
+}
+
+/// Gain to apply to playback, lowered while speech is heard in the room
+pub struct Duck {
+    // f32 bits
+    gain: AtomicU32,
+}
+
+impl Duck {
+    pub fn gain(&self) -> f32 {
+        f32::from_bits(self.gain.load(Ordering::Relaxed))
+    }
+
+    fn set_gain(&self, gain: f32) {
+        self.gain.store(gain.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Open the sidechain microphone and start listening for speech on a
+/// thread of its own
+pub fn start(opt: DuckOpt) -> Result<Arc<Duck>, OpenError> {
+    let input = Input::<F32>::new(&DeviceOpt {
+        device: Some(opt.device.clone()),
+        period: None,
+        buffer: None,
+        latency: audio_config::latency(None),
+        shared: false,
+    })?;
+
+    log::info!("ducking playback on speech from {}", opt.device);
+
+    let duck = Arc::new(Duck {
+        gain: AtomicU32::new(1.0f32.to_bits()),
+    });
+
+    std::thread::spawn({
+        let duck = duck.clone();
+        move || {
+            thread::set_name("bark/duck");
+            run(input, &opt, &duck);
+
+            // don't leave playback ducked if we stop listening
+            duck.set_gain(1.0);
+        }
+    });
+
+    Ok(duck)
+}
+
+fn run(mut input: Input<F32>, opt: &DuckOpt, duck: &Duck) {
+    let block = SampleDuration::from_std_duration_lossy(BLOCK).to_frame_count() as usize;
+    let mut buffer = vec![FrameF32(0.0, 0.0); block];
+
+    let mut filter = SpeechFilter::new();
+    let mut detector = Detector::new();
+    let mut gain = 1.0;
+
+    let attack = smoothing(ATTACK);
+    let release = smoothing(RELEASE);
+
+    loop {
+        if let Err(e) = input.read(&mut buffer) {
+            log::error!("error reading ducking input: {e}");
+            return;
+        }
+
+        let level = filter.level_db(&buffer);
+        let speaking = detector.update(level > opt.threshold_db);
+
+        let target = if speaking { opt.ratio } else { 1.0 };
+        let coeff = if target < gain { attack } else { release };
+        gain += (target - gain) * coeff;
+
+        duck.set_gain(gain);
+    }
+}
+
+// one pole smoothing coefficient per block for a given time constant
+fn smoothing(time: Duration) -> f32 {
+    1.0 - (-BLOCK.as_secs_f32() / time.as_secs_f32()).exp()
+}
+
+/// Tracks whether speech is sustained, with hold time between words
+struct Detector {
+    // time above threshold without a break
+    onset: Duration,
+    // time since last above threshold while speaking
+    since: Duration,
+    speaking: bool,
+}
+
+impl Detector {
+    fn new() -> Self {
+        Detector {
+            onset: Duration::ZERO,
+            since: Duration::ZERO,
+            speaking: false,
+        }
+    }
+
+    fn update(&mut self, above: bool) -> bool {
+        if above {
+            self.onset += BLOCK;
+            self.since = Duration::ZERO;
+
+            if self.onset >= SPEECH_ONSET && !self.speaking {
+                log::debug!("speech detected, ducking");
+                self.speaking = true;
+            }
+        } else {
+            self.onset = Duration::ZERO;
+            self.since += BLOCK;
+
+            if self.since >= SPEECH_HOLD && self.speaking {
+                log::debug!("speech ended, unducking");
+                self.speaking = false;
+            }
+        }
+
+        self.speaking
+    }
+}
+
+/// Band passes mono microphone audio to the speech band and measures its
+/// level, with a pair of one pole filters
+struct SpeechFilter {
+    highpass: f32,
+    highpass_coeff: f32,
+    lowpass: f32,
+    lowpass_coeff: f32,
+}
+
+impl SpeechFilter {
+    fn new() -> Self {
+        let coeff = |hz: f32| 1.0 - (-std::f32::consts::TAU * hz / SAMPLE_RATE.0 as f32).exp();
+
+        SpeechFilter {
+            highpass: 0.0,
+            highpass_coeff: coeff(HIGHPASS_HZ),
+            lowpass: 0.0,
+            lowpass_coeff: coeff(LOWPASS_HZ),
+        }
+    }
+
+    fn level_db(&mut self, frames: &[FrameF32]) -> f32 {
+        let mut sum = 0.0;
+
+        for frame in frames {
+            let sample = (frame.0 + frame.1) * 0.5;
+
+            // the highpass output is what's left after removing the lows
+            self.highpass += (sample - self.highpass) * self.highpass_coeff;
+            let sample = sample - self.highpass;
+
+            self.lowpass += (sample - self.lowpass) * self.lowpass_coeff;
+            sum += self.lowpass * self.lowpass;
+        }
+
+        let mean_square = sum / frames.len().max(1) as f32;
+        10.0 * mean_square.max(f32::MIN_POSITIVE).log10()
+    }
+}
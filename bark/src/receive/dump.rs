@@ -0,0 +1,165 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, TrySendError};
+use std::time::Duration;
+
+use bark_core::audio::{Format, FormatKind};
+use bark_protocol::time::SampleDuration;
+use bark_protocol::MAX_FRAMES_PER_PACKET;
+
+use crate::time;
+
+/// Chunks of audio in flight to the writer thread. If it falls this far
+/// behind, audio is left out of the dump rather than holding up playback
+const CHUNKS: usize = 64;
+
+/// Records a receiver's audio to raw PCM files, for debugging receivers
+/// remotely: as decoded, before resampling; before the limiter and speaker
+/// protection; and after them, as sent to the output. Audio goes to a
+/// writer thread through a bounded channel in buffers it hands back for
+/// reuse, so the audio thread never blocks on disk or allocates.
+pub struct Dump {
+    tx: mpsc::SyncSender<Chunk>,
+    // buffers the writer thread has finished with
+    free: mpsc::Receiver<Vec<u8>>,
+    // buffer of a chunk the writer had no room for
+    spare: Option<Vec<u8>>,
+    remaining: u64,
+    dropped: u64,
+    failed: bool,
+}
+
+/// Point in the decode path audio is recorded from
+#[derive(Clone, Copy)]
+pub enum Tap {
+    Decoded,
+    PreDsp,
+    PostDsp,
+}
+
+struct Chunk {
+    tap: Tap,
+    data: Vec<u8>,
+}
+
+impl Dump {
+    pub fn start<F: Format>(duration: Duration) -> Result<(Dump, PathBuf), io::Error> {
+        let dir = std::env::temp_dir()
+            .join(format!("bark-dump-{}", time::now().0));
+
+        std::fs::create_dir_all(&dir)?;
+
+        let format = match F::KIND {
+            FormatKind::S16 => "s16le",
+            FormatKind::F32 => "f32le",
+        };
+
+        let rate = bark_protocol::SAMPLE_RATE.0;
+        let channels = bark_protocol::CHANNELS.0;
+        let suffix = format!("{rate}hz-{channels}ch-{format}.raw");
+
+        let files = Files {
+            decoded: BufWriter::new(File::create(dir.join(format!("decoded-{suffix}")))?),
+            pre_dsp: BufWriter::new(File::create(dir.join(format!("pre-dsp-{suffix}")))?),
+            post_dsp: BufWriter::new(File::create(dir.join(format!("post-dsp-{suffix}")))?),
+        };
+
+        let (tx, rx) = mpsc::sync_channel(CHUNKS);
+        let (free_tx, free) = mpsc::sync_channel(CHUNKS);
+
+        // allocate up front, the resampler may output more than a packet
+        let capacity = MAX_FRAMES_PER_PACKET * 2 * size_of::<F::Frame>();
+
+        for _ in 0..CHUNKS {
+            let _ = free_tx.try_send(Vec::with_capacity(capacity));
+        }
+
+        std::thread::spawn(move || {
+            bark_app::thread::set_name("bark/dump");
+
+            if let Err(e) = write_thread(rx, free_tx, files) {
+                log::error!("error writing audio dump: {e}");
+            }
+        });
+
+        let remaining = SampleDuration::from_std_duration_lossy(duration).to_frame_count();
+
+        log::info!("dumping {}ms of audio to {}", duration.as_millis(), dir.display());
+
+        let dump = Dump {
+            tx,
+            free,
+            spare: None,
+            remaining,
+            dropped: 0,
+            failed: false,
+        };
+
+        Ok((dump, dir))
+    }
+
+    /// Record audio at one point in the decode path
+    pub fn tap<F: Format>(&mut self, tap: Tap, frames: &[F::Frame]) {
+        let mut data = self.spare.take()
+            .or_else(|| self.free.try_recv().ok())
+            .unwrap_or_default();
+
+        data.clear();
+        data.extend_from_slice(bytemuck::cast_slice::<F::Frame, u8>(frames));
+
+        match self.tx.try_send(Chunk { tap, data }) {
+            Ok(()) => {}
+            Err(TrySendError::Full(chunk)) => {
+                self.dropped += 1;
+                self.spare = Some(chunk.data);
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                self.failed = true;
+            }
+        }
+    }
+
+    /// Count `frames` of output as recorded. Returns true when the
+    /// requested duration has been recorded, or the dump can't continue
+    pub fn played(&mut self, frames: usize) -> bool {
+        let frames = u64::try_from(frames).unwrap_or(u64::MAX);
+        self.remaining = self.remaining.saturating_sub(frames);
+
+        let done = self.failed || self.remaining == 0;
+
+        if done && self.dropped > 0 {
+            log::warn!("audio dump writer fell behind, {} chunks of audio left out", self.dropped);
+        }
+
+        done
+    }
+}
+
+struct Files {
+    decoded: BufWriter<File>,
+    pre_dsp: BufWriter<File>,
+    post_dsp: BufWriter<File>,
+}
+
+fn write_thread(rx: mpsc::Receiver<Chunk>, free: mpsc::SyncSender<Vec<u8>>, mut files: Files) -> Result<(), io::Error> {
+    // runs until the Dump is dropped
+    while let Ok(chunk) = rx.recv() {
+        let file = match chunk.tap {
+            Tap::Decoded => &mut files.decoded,
+            Tap::PreDsp => &mut files.pre_dsp,
+            Tap::PostDsp => &mut files.post_dsp,
+        };
+
+        file.write_all(&chunk.data)?;
+
+        // hand the buffer back for reuse
+        let _ = free.try_send(chunk.data);
+    }
+
+    files.decoded.flush()?;
+    files.pre_dsp.flush()?;
+    files.post_dsp.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,251 @@
+//! Local playback for when the network goes quiet, such as background
+//! music in a café during network maintenance. Once no stream has been
+//! seen for a while, a playlist of WAV files is played on a loop as a
+//! session of the lowest priority, until a real stream returns.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use bark_core::audio::{FrameF32, Frames};
+use bark_core::encode::Encode;
+use bark_core::encode::pcm::F32LEEncoder;
+use bark_protocol::packet::Audio;
+use bark_protocol::time::{SampleDuration, Timestamp};
+use bark_protocol::types::{AudioPacketHeader, SessionId, TimestampMicros};
+use bark_protocol::MAX_FRAMES_PER_PACKET;
+
+use crate::wav;
+
+/// How far ahead of playback packets are queued
+const LEAD: Duration = Duration::from_millis(500);
+
+/// Leave time for the first packets to be queued before they're due
+const START: Duration = Duration::from_millis(50);
+
+pub struct FallbackOpt {
+    /// A WAV file, or a playlist of them with one path per line
+    pub playlist: PathBuf,
+    /// How long without a stream before playing
+    pub after: Duration,
+    /// Gain applied to the playlist, so it plays quieter than real streams
+    pub volume: f32,
+}
+
+pub struct Fallback {
+    opt: FallbackOpt,
+    playing: Option<Playing>,
+}
+
+struct Playing {
+    sid: SessionId,
+    seq: u64,
+    // when the next packet plays
+    pts: Timestamp,
+    tracks: Vec<PathBuf>,
+    track: usize,
+    reader: Option<wav::Reader>,
+    // tracks in a row that couldn't be played
+    failures: usize,
+}
+
+impl Fallback {
+    pub fn new(opt: FallbackOpt) -> Self {
+        log::info!("playing {} after {}s without a stream", opt.playlist.display(), opt.after.as_secs());
+        Fallback { opt, playing: None }
+    }
+
+    pub fn after(&self) -> Duration {
+        self.opt.after
+    }
+
+    pub fn session(&self) -> Option<SessionId> {
+        self.playing.as_ref().map(|playing| playing.sid)
+    }
+
+    /// Begin playing, reading the playlist afresh so that changes made
+    /// since it last played are picked up. Returns whether there is
+    /// anything to play
+    pub fn start(&mut self, now: TimestampMicros) -> bool {
+        let tracks = match playlist(&self.opt.playlist) {
+            Ok(tracks) if !tracks.is_empty() => tracks,
+            Ok(_) => {
+                log::error!("fallback playlist {} is empty", self.opt.playlist.display());
+                return false;
+            }
+            Err(e) => {
+                log::error!("error reading fallback playlist {}: {e}", self.opt.playlist.display());
+                return false;
+            }
+        };
+
+        log::info!("no stream for {}s, playing fallback playlist", self.opt.after.as_secs());
+
+        self.playing = Some(Playing {
+            sid: SessionId(i64::try_from(now.0).unwrap_or(i64::MAX)),
+            seq: 1,
+            pts: Timestamp::from_micros_lossy(now).add(SampleDuration::from_std_duration_lossy(START)),
+            tracks,
+            track: 0,
+            reader: None,
+            failures: 0,
+        });
+
+        true
+    }
+
+    /// Stop playing, returning the session that was playing
+    pub fn stop(&mut self) -> Option<SessionId> {
+        self.playing.take().map(|playing| playing.sid)
+    }
+
+    /// Packets to queue by `now`, keeping playback `LEAD` ahead. Stops
+    /// playing if none of the playlist can be read
+    pub fn packets(&mut self, now: TimestampMicros) -> Vec<Audio> {
+        let Some(playing) = &mut self.playing else {
+            return Vec::new();
+        };
+
+        let now = Timestamp::from_micros_lossy(now);
+        let until = now.add(SampleDuration::from_std_duration_lossy(LEAD));
+
+        // after a stall, such as the network thread being busy, carry on
+        // from now rather than queueing audio that's already late
+        if playing.pts < now {
+            playing.pts = now.add(SampleDuration::from_std_duration_lossy(START));
+        }
+
+        let mut packets = Vec::new();
+
+        while playing.pts < until {
+            let mut frames = [FrameF32(0.0, 0.0); MAX_FRAMES_PER_PACKET];
+
+            if !playing.fill(&mut frames) {
+                log::error!("nothing in fallback playlist {} could be played", self.opt.playlist.display());
+                self.playing = None;
+                break;
+            }
+
+            for frame in &mut frames {
+                frame.0 *= self.opt.volume;
+                frame.1 *= self.opt.volume;
+            }
+
+            packets.push(playing.packet(&frames));
+        }
+
+        packets
+    }
+}
+
+impl Playing {
+    /// Fill `frames` from the playlist, moving on through it as tracks end
+    /// and looping back to the start. Returns false once every track has
+    /// failed in a row
+    fn fill(&mut self, mut frames: &mut [FrameF32]) -> bool {
+        while !frames.is_empty() {
+            if self.failures >= self.tracks.len() {
+                return false;
+            }
+
+            let opened = self.reader.is_none();
+
+            let reader = match &mut self.reader {
+                Some(reader) => reader,
+                None => {
+                    let path = &self.tracks[self.track];
+
+                    match wav::Reader::open(path) {
+                        Ok(reader) => {
+                            log::info!("fallback playing {}", path.display());
+                            self.reader.insert(reader)
+                        }
+                        Err(e) => {
+                            log::warn!("skipping fallback track: {e}");
+                            self.failures += 1;
+                            self.next_track();
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            let n = match reader.read(frames) {
+                Ok(n) => n,
+                Err(e) => {
+                    log::warn!("skipping fallback track: {e}");
+                    0
+                }
+            };
+
+            if n == 0 {
+                // a track with nothing in it counts as failing to play
+                if opened {
+                    self.failures += 1;
+                }
+
+                self.next_track();
+                continue;
+            }
+
+            self.failures = 0;
+            frames = &mut frames[n..];
+        }
+
+        true
+    }
+
+    fn next_track(&mut self) {
+        self.reader = None;
+        self.track = (self.track + 1) % self.tracks.len();
+    }
+
+    fn packet(&mut self, frames: &[FrameF32]) -> Audio {
+        let mut encoder = F32LEEncoder;
+
+        let header = AudioPacketHeader {
+            sid: self.sid,
+            seq: self.seq,
+            pts: self.pts.to_micros_lossy(),
+            dts: self.pts.to_micros_lossy(),
+            format: encoder.header_format(),
+            // any real stream takes over
+            priority: i8::MIN,
+            packet_frames: frames.len() as u16,
+            delay_ms: 0,
+            min_buffer_ms: 0,
+            start_pts: TimestampMicros(0),
+        };
+
+        let mut buffer = [0; Audio::MAX_BUFFER_LENGTH];
+        let length = encoder.encode_packet(Frames::F32(frames), &mut buffer)
+            .expect("encode fallback audio");
+
+        self.seq += 1;
+        self.pts = self.pts.add(SampleDuration::from_frame_count(frames.len()));
+
+        Audio::new(&header, &buffer[0..length])
+            .expect("allocate Audio packet")
+    }
+}
+
+/// Tracks to play: the file itself if it is a WAV file, otherwise each
+/// line of it that isn't blank or a comment, relative to the playlist
+fn playlist(path: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    let is_wav = path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("wav"));
+
+    if is_wav {
+        return Ok(vec![path.to_owned()]);
+    }
+
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    let tracks = std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| dir.join(line))
+        .collect();
+
+    Ok(tracks)
+}
@@ -0,0 +1,86 @@
+//! Persistent identity for a receiver, so that it can be told apart across
+//! restarts and address changes. A random uuid is generated the first time
+//! a receiver runs and kept in a file from then on.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::stats::node;
+
+/// Where the id is kept if no file is given: in the XDG config directory,
+/// or failing that under `$HOME`
+pub fn default_path() -> Option<PathBuf> {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            let home = std::env::var_os("HOME").filter(|dir| !dir.is_empty())?;
+            Some(Path::new(&home).join(".config"))
+        })?;
+
+    Some(config.join("bark/receiver-id"))
+}
+
+/// Read the id kept in `path`, generating and writing out a new one if
+/// there is none yet. Without anywhere to keep it, the id lasts only as
+/// long as this run
+pub fn load(path: Option<&Path>) -> [u8; 16] {
+    let Some(path) = path else {
+        log::warn!("nowhere to keep receiver id, set --id-file");
+        return generate();
+    };
+
+    match fs::read_to_string(path) {
+        Ok(contents) => match parse(contents.trim()) {
+            Some(id) => return id,
+            None => log::warn!("invalid receiver id in {}, generating a new one", path.display()),
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => {
+            log::warn!("error reading receiver id from {}: {e}", path.display());
+            return generate();
+        }
+    }
+
+    let id = generate();
+
+    if let Err(e) = save(path, &id) {
+        log::warn!("error saving receiver id to {}: {e}", path.display());
+    }
+
+    id
+}
+
+/// Random version 4 uuid
+fn generate() -> [u8; 16] {
+    let mut id = rand::random::<[u8; 16]>();
+    id[6] = (id[6] & 0x0f) | 0x40;
+    id[8] = (id[8] & 0x3f) | 0x80;
+    id
+}
+
+fn parse(s: &str) -> Option<[u8; 16]> {
+    let hex = s.replace('-', "");
+
+    if hex.len() != 32 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut id = [0; 16];
+
+    for (idx, byte) in id.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[idx * 2..idx * 2 + 2], 16).ok()?;
+    }
+
+    // all zeroes means no id on the wire
+    (id != [0; 16]).then_some(id)
+}
+
+fn save(path: &Path, id: &[u8; 16]) -> Result<(), io::Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    fs::write(path, format!("{}\n", node::format_id(id)))
+}
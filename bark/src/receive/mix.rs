@@ -0,0 +1,249 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::Duration;
+
+use bark_app::thread;
+use bark_core::audio::format::{self, Dither};
+use bark_core::audio::dsp::Chain;
+use bark_core::audio::{Format, FrameF32};
+use bark_protocol::time::SampleDuration;
+use bark_protocol::FRAMES_PER_PACKET;
+use bytemuck::Zeroable;
+
+use crate::receive::dsp::Dsp;
+use crate::receive::output::OutputRef;
+use crate::receive::stream::DecodeOpt;
+
+// streams can get this far ahead of the mixer before their writes block
+const MAX_BUFFERED: Duration = Duration::from_millis(5);
+
+// how often blocked streams check the mixer is still running
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+// streams below the highest priority playing are ducked to -12 dB
+const DUCK_GAIN: f32 = 0.25;
+const DUCK_ATTACK: Duration = Duration::from_millis(50);
+const DUCK_RELEASE: Duration = Duration::from_millis(500);
+
+/// Sums concurrent streams into one output, ducking each under any stream
+/// of higher priority. Owns the output for as long as it runs, and streams
+/// feed it through a `MixInput` each
+pub struct Mixer {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    inputs: Mutex<Vec<Weak<Input>>>,
+    // signalled when an input is added or the mixer is stopped
+    changed: Condvar,
+    stopped: AtomicBool,
+    // frames between the mixer and the listener: output delay, plus the
+    // latency of stages after the mix
+    delay: AtomicU64,
+}
+
+struct Input {
+    priority: i8,
+    buffer: Mutex<VecDeque<FrameF32>>,
+    // signalled when the mixer takes audio from the buffer
+    drained: Condvar,
+    // f32 bits, only touched by the mixer thread
+    gain: AtomicU32,
+}
+
+impl Mixer {
+    pub fn start<F: Format>(output: OutputRef<F>, opt: &DecodeOpt) -> Self {
+        let shared = Arc::new(Shared {
+            inputs: Mutex::new(Vec::new()),
+            changed: Condvar::new(),
+            stopped: AtomicBool::new(false),
+            delay: AtomicU64::new(0),
+        });
+
+        let state = MixState {
+            output,
+            dither: Dither::new(opt.noise_shaping),
+            chain: Chain::new(opt.dsp.settings()),
+            dsp: opt.dsp.clone(),
+        };
+
+        std::thread::spawn({
+            let shared = shared.clone();
+            move || {
+                thread::set_name("bark/mix");
+                thread::set_realtime_priority();
+                run(&shared, state);
+
+                // release any streams blocked writing to us
+                shared.stopped.store(true, Ordering::Relaxed);
+                for input in shared.inputs.lock().unwrap().iter().filter_map(Weak::upgrade) {
+                    input.drained.notify_all();
+                }
+            }
+        });
+
+        Mixer { shared }
+    }
+
+    /// New input for a stream of the given priority, mixed until dropped
+    pub fn input(&self, priority: i8) -> MixInput {
+        let input = Arc::new(Input {
+            priority,
+            buffer: Mutex::new(VecDeque::new()),
+            drained: Condvar::new(),
+            gain: AtomicU32::new(1.0f32.to_bits()),
+        });
+
+        self.shared.inputs.lock().unwrap().push(Arc::downgrade(&input));
+        self.shared.changed.notify_all();
+
+        MixInput {
+            input,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for Mixer {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::Relaxed);
+        self.shared.changed.notify_all();
+    }
+}
+
+/// A stream's feed into the mixer
+pub struct MixInput {
+    input: Arc<Input>,
+    shared: Arc<Shared>,
+}
+
+impl MixInput {
+    pub fn is_running(&self) -> bool {
+        !self.shared.stopped.load(Ordering::Relaxed)
+    }
+
+    /// Time until audio written now is heard, including audio already
+    /// waiting to be mixed
+    pub fn delay(&self) -> SampleDuration {
+        let buffered = self.input.buffer.lock().unwrap().len();
+        let delay = self.shared.delay.load(Ordering::Relaxed);
+
+        SampleDuration::from_frame_count_u64(delay + buffered as u64)
+    }
+
+    /// Queue audio to be mixed, blocking while the mixer is behind, in the
+    /// same way a write to the output device does
+    pub fn write(&self, frames: &[FrameF32]) {
+        let max = SampleDuration::from_std_duration_lossy(MAX_BUFFERED).to_frame_count() as usize;
+
+        let mut buffer = self.input.buffer.lock().unwrap();
+
+        while buffer.len() >= max {
+            if !self.is_running() {
+                return;
+            }
+
+            buffer = self.input.drained.wait_timeout(buffer, STOP_POLL).unwrap().0;
+        }
+
+        buffer.extend(frames);
+    }
+}
+
+struct MixState<F: Format> {
+    output: OutputRef<F>,
+    dither: Dither,
+    // limiter and speaker protection, run on the mix
+    chain: Chain,
+    dsp: Arc<Dsp>,
+}
+
+fn run<F: Format>(shared: &Shared, mut state: MixState<F>) {
+    let mut mix = vec![FrameF32(0.0, 0.0); FRAMES_PER_PACKET];
+    let mut output_buffer = vec![F::Frame::zeroed(); FRAMES_PER_PACKET];
+
+    let attack = smoothing(DUCK_ATTACK);
+    let release = smoothing(DUCK_RELEASE);
+
+    loop {
+        let inputs = {
+            let mut inputs = shared.inputs.lock().unwrap();
+
+            loop {
+                if shared.stopped.load(Ordering::Relaxed) {
+                    return;
+                }
+
+                // inputs go away with the stream feeding them
+                inputs.retain(|input| input.strong_count() > 0);
+
+                if !inputs.is_empty() {
+                    break;
+                }
+
+                inputs = shared.changed.wait(inputs).unwrap();
+            }
+
+            inputs.iter().filter_map(Weak::upgrade).collect::<Vec<_>>()
+        };
+
+        let Some(mut output) = state.output.lock() else {
+            // output has been taken away from us
+            return;
+        };
+
+        let delay = match output.delay() {
+            Ok(delay) => delay,
+            Err(e) => {
+                log::error!("error reading output delay: {e}");
+                return;
+            }
+        };
+
+        let delay = delay.add(state.chain.latency());
+        shared.delay.store(delay.to_frame_count(), Ordering::Relaxed);
+
+        let top = inputs.iter().map(|input| input.priority).max();
+
+        mix.fill(FrameF32(0.0, 0.0));
+
+        for input in &inputs {
+            let target = if Some(input.priority) < top { DUCK_GAIN } else { 1.0 };
+
+            let from = f32::from_bits(input.gain.load(Ordering::Relaxed));
+            let coeff = if target < from { attack } else { release };
+            let to = from + (target - from) * coeff;
+            input.gain.store(to.to_bits(), Ordering::Relaxed);
+
+            let mut buffer = input.buffer.lock().unwrap();
+            let count = std::cmp::min(buffer.len(), mix.len());
+
+            // ramp across the chunk so gain changes are smooth
+            for (i, (frame, sample)) in mix.iter_mut().zip(buffer.drain(..count)).enumerate() {
+                let gain = from + (to - from) * (i as f32 / count as f32);
+                frame.0 += sample.0 * gain;
+                frame.1 += sample.1 * gain;
+            }
+
+            drop(buffer);
+            input.drained.notify_all();
+        }
+
+        state.dsp.update(&mut state.chain);
+        state.chain.process(&mut mix);
+
+        format::convert::<F>(&mut state.dither, &mix, &mut output_buffer);
+
+        if let Err(e) = output.write(&output_buffer) {
+            log::error!("error playing audio: {e}");
+            return;
+        }
+    }
+}
+
+// one pole smoothing coefficient per chunk for a given time constant
+fn smoothing(time: Duration) -> f32 {
+    let chunk = SampleDuration::from_frame_count(FRAMES_PER_PACKET).to_std_duration_lossy();
+    1.0 - (-chunk.as_secs_f32() / time.as_secs_f32()).exp()
+}
@@ -1,17 +1,35 @@
 use std::ops::{Deref, DerefMut};
 use std::sync::{Arc, Mutex, MutexGuard};
 
-use bark_core::audio::Format;
+use bark_core::audio::{Format, FrameF32};
 
 use crate::audio::Output;
 
 pub struct OwnedOutput<F: Format> {
     output: Arc<Mutex<Option<Output<F>>>>,
+    // last frame played, before conversion to the output format, so that
+    // the next stream to take the output can carry on smoothly from it
+    last_frame: Arc<Mutex<FrameF32>>,
 }
 
 impl<F: Format> OwnedOutput<F> {
     pub fn new(output: Output<F>) -> Self {
-        Self { output: Arc::new(Mutex::new(Some(output))) }
+        Self {
+            output: Arc::new(Mutex::new(Some(output))),
+            last_frame: Arc::new(Mutex::new(FrameF32(0.0, 0.0))),
+        }
+    }
+
+    /// No output device open, as for a sparse receiver while it is dormant
+    pub fn closed() -> Self {
+        Self {
+            output: Arc::new(Mutex::new(None)),
+            last_frame: Arc::new(Mutex::new(FrameF32(0.0, 0.0))),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.output.lock().unwrap().is_some()
     }
 
     /// TODO - this may block for the duration of an alsa_pcm_write
@@ -20,13 +38,23 @@ impl<F: Format> OwnedOutput<F> {
         let output = self.output.lock().unwrap().take();
         self.output = Arc::new(Mutex::new(output));
 
-        OutputRef { output: self.output.clone() }
+        OutputRef {
+            output: self.output.clone(),
+            last_frame: self.last_frame.clone(),
+        }
+    }
+
+    /// Take the output away from any stream holding a reference to it,
+    /// returning it so that it can be closed
+    pub fn close(&mut self) -> Option<Output<F>> {
+        self.output.lock().unwrap().take()
     }
 }
 
 #[derive(Clone)]
 pub struct OutputRef<F: Format> {
     output: Arc<Mutex<Option<Output<F>>>>,
+    last_frame: Arc<Mutex<FrameF32>>,
 }
 
 impl<F: Format> OutputRef<F> {
@@ -39,6 +67,14 @@ impl<F: Format> OutputRef<F> {
             None
         }
     }
+
+    pub fn last_frame(&self) -> FrameF32 {
+        *self.last_frame.lock().unwrap()
+    }
+
+    pub fn set_last_frame(&self, frame: FrameF32) {
+        *self.last_frame.lock().unwrap() = frame;
+    }
 }
 
 pub struct OutputLock<'a, F: Format> {
@@ -1,6 +1,7 @@
 use std::sync::{Arc, Mutex};
 
-use bark_core::receive::queue::{PacketQueue, AudioPts};
+use bark_core::receive::queue::{PacketQueue, AudioPts, Insert};
+use bark_protocol::packet::Audio;
 use thiserror::Error;
 
 pub struct QueueSender {
@@ -38,15 +39,21 @@ pub fn channel(queue: PacketQueue) -> (QueueSender, QueueReceiver) {
 pub struct Disconnected;
 
 impl QueueSender {
-    pub fn send(&self, packet: AudioPts) -> Result<(), Disconnected> {
+    pub fn send(&self, packet: AudioPts) -> Result<Insert, Disconnected> {
         let mut queue = self.shared.queue.lock().unwrap();
 
         let Some(queue) = queue.as_mut() else {
             return Err(Disconnected);
         };
 
-        queue.insert_packet(packet);
-        Ok(())
+        Ok(queue.insert_packet(packet))
+    }
+}
+
+impl QueueSender {
+    /// Stop the receiving end, freeing the queue straight away
+    pub fn disconnect(&self) {
+        self.shared.disconnect();
     }
 }
 
@@ -57,6 +64,10 @@ impl Drop for QueueSender {
 }
 
 impl QueueReceiver {
+    pub fn is_disconnected(&self) -> bool {
+        self.shared.queue.lock().unwrap().is_none()
+    }
+
     pub fn recv(&self) -> Result<(Option<AudioPts>, usize), Disconnected> {
         let mut queue_lock = self.shared.queue.lock().unwrap();
 
@@ -68,6 +79,22 @@ impl QueueReceiver {
         let len = queue.len();
         return Ok((queue.pop_front(), len));
     }
+
+    /// Copies the packet at the front of the queue if it has been received.
+    /// Used to recover a lost packet from forward error correction data.
+    pub fn copy_front(&self) -> Result<Option<Audio>, Disconnected> {
+        let queue_lock = self.shared.queue.lock().unwrap();
+
+        let Some(queue) = queue_lock.as_ref() else {
+            return Err(Disconnected);
+        };
+
+        let copy = queue.front().and_then(|next| {
+            Audio::new(next.header(), next.audio.buffer_bytes()).ok()
+        });
+
+        Ok(copy)
+    }
 }
 
 impl Drop for QueueReceiver {
@@ -0,0 +1,96 @@
+//! Collects the threads of streams that have been dropped. Waiting for them
+//! on the network thread would hold up packets for every other stream, so
+//! they are handed off here instead and joined as they finish.
+
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::OnceLock;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::stats::ReceiverMetrics;
+
+// a stream thread notices it has been stopped within a packet or an output
+// period, anything longer than this is worth knowing about
+const SLOW: Duration = Duration::from_millis(250);
+// give up on a thread that has not finished by now, it is stuck
+const LEAKED: Duration = Duration::from_secs(10);
+// how often to check on threads still stopping
+const POLL: Duration = Duration::from_millis(20);
+
+struct Stopping {
+    thread: JoinHandle<()>,
+    since: Instant,
+    slow: bool,
+    metrics: ReceiverMetrics,
+}
+
+static REAPER: OnceLock<Sender<Stopping>> = OnceLock::new();
+
+/// Join a stream thread that has been told to stop, in the background
+pub fn reap(thread: JoinHandle<()>, metrics: ReceiverMetrics) {
+    let reaper = REAPER.get_or_init(|| {
+        let (tx, rx) = mpsc::channel();
+
+        std::thread::spawn(move || {
+            bark_app::thread::set_name("bark/reaper");
+            run(rx);
+        });
+
+        tx
+    });
+
+    let _ = reaper.send(Stopping {
+        thread,
+        since: Instant::now(),
+        slow: false,
+        metrics,
+    });
+}
+
+fn run(rx: Receiver<Stopping>) {
+    let mut stopping = Vec::<Stopping>::new();
+
+    loop {
+        // sleep until there is a thread to wait for
+        let received = if stopping.is_empty() {
+            rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            rx.recv_timeout(POLL)
+        };
+
+        match received {
+            Ok(thread) => stopping.push(thread),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        let (finished, running) = stopping.drain(..)
+            .partition::<Vec<_>, _>(|thread| thread.thread.is_finished());
+
+        for thread in finished {
+            if thread.thread.join().is_err() {
+                log::error!("stream thread panicked");
+            }
+        }
+
+        stopping = running;
+
+        stopping.retain_mut(|thread| {
+            let elapsed = thread.since.elapsed();
+
+            if !thread.slow && elapsed >= SLOW {
+                log::warn!("stream thread slow to stop, still running after {}ms", elapsed.as_millis());
+                thread.metrics.stream_stops_slow.increment();
+                thread.slow = true;
+            }
+
+            if elapsed >= LEAKED {
+                log::error!("stream thread did not stop after {}s, abandoning it", elapsed.as_secs());
+                thread.metrics.stream_stops_leaked.increment();
+                return false;
+            }
+
+            true
+        });
+    }
+}
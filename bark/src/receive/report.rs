@@ -0,0 +1,67 @@
+use std::time::Duration;
+
+use bark_protocol::packet::ReceiverReport;
+use bark_protocol::types::{AudioPacketHeader, SessionId, TimestampMicros};
+
+/// How often receivers report to the source of each stream they play
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tracks how a stream's packets are arriving, for reporting back to its
+/// source: loss by seq, and interarrival jitter as RTP computes it
+pub struct Reception {
+    first_seq: Option<u64>,
+    highest_seq: u64,
+    received: u64,
+    // arrival time and dts of the last packet, for jitter
+    last: Option<(TimestampMicros, TimestampMicros)>,
+    // smoothed jitter, in microseconds
+    jitter: f64,
+}
+
+impl Reception {
+    pub fn new() -> Self {
+        Reception {
+            first_seq: None,
+            highest_seq: 0,
+            received: 0,
+            last: None,
+            jitter: 0.0,
+        }
+    }
+
+    /// Record a packet arriving at `now`, not counting duplicates
+    pub fn observe(&mut self, header: &AudioPacketHeader, now: TimestampMicros) {
+        let first_seq = *self.first_seq.get_or_insert(header.seq);
+
+        // packets reordered from before the first one we saw don't count
+        if header.seq < first_seq {
+            return;
+        }
+
+        self.received += 1;
+        self.highest_seq = self.highest_seq.max(header.seq);
+
+        // difference in transit time between successive packets, ignoring
+        // the clock offset between source and receiver which cancels out
+        if let Some((last_arrival, last_dts)) = self.last {
+            let arrival = now.0 as i64 - last_arrival.0 as i64;
+            let sent = header.dts.0 as i64 - last_dts.0 as i64;
+            let delta = (arrival - sent).abs() as f64;
+            self.jitter += (delta - self.jitter) / 16.0;
+        }
+
+        self.last = Some((now, header.dts));
+    }
+
+    pub fn report(&self, sid: SessionId) -> Option<ReceiverReport> {
+        let first_seq = self.first_seq?;
+        let expected = self.highest_seq - first_seq + 1;
+        let lost = expected.saturating_sub(self.received);
+        let jitter = Duration::from_micros(self.jitter.round() as u64);
+
+        let report = ReceiverReport::new(sid, self.highest_seq, self.received, lost, jitter)
+            .expect("allocate ReceiverReport packet");
+
+        Some(report)
+    }
+}
@@ -1,76 +1,354 @@
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
 
-use bark_core::audio::Format;
+use bark_app::thread;
+use bark_core::audio::{self, Format, FrameF32, F32};
+use bark_core::audio::channels::ChannelMap;
+use bark_core::audio::declick::Declick;
+use bark_core::audio::format::{self, Dither};
+use bark_core::audio::dsp::Chain;
+use bark_core::audio::fade::{FadeIn, FadeOut};
 use bark_core::receive::pipeline::Pipeline;
-use bark_core::receive::queue::{AudioPts, PacketQueue};
+use bark_core::receive::queue::{AudioPts, Insert, PacketQueue};
 use bark_core::receive::timing::Timing;
 use bark_protocol::time::{SampleDuration, Timestamp, TimestampDelta};
 use bark_protocol::types::stats::receiver::StreamStatus;
 use bark_protocol::types::AudioPacketHeader;
-use bark_protocol::FRAMES_PER_PACKET;
 use bytemuck::Zeroable;
 
+use crate::receive::drift::DriftMemory;
+use crate::receive::duck::Duck;
+use crate::receive::dump::{Dump, Tap};
+use crate::receive::dsp::Dsp;
+use crate::receive::volume::Volume;
 use crate::stats::ReceiverMetrics;
 use crate::time;
-use crate::receive::output::OutputRef;
+use crate::audio::Error as OutputError;
+use crate::receive::mix::MixInput;
+use crate::receive::output::{OutputLock, OutputRef};
 use crate::receive::queue::{self, Disconnected, QueueReceiver, QueueSender};
-use crate::thread;
+use crate::receive::reaper;
+use crate::receive::sync::PlayHistory;
+
+// how long a stream takes to fade back in after an interruption
+const FADE_IN: Duration = Duration::from_millis(500);
+
+// how long a stream takes to fade out once its source has ended it
+const FADE_OUT: Duration = Duration::from_millis(50);
+
+/// Receiver options applied to every stream
+#[derive(Clone)]
+pub struct DecodeOpt {
+    /// Shifts the time audio is due to be played, compensating for latency
+    /// added downstream of the output device (eg. an AV receiver)
+    pub latency_offset: TimestampDelta,
+    /// Latency of the sink downstream of the output device, such as an
+    /// HDMI TV, which the device's own delay doesn't include
+    pub device_latency: SampleDuration,
+    /// Minimum audio to buffer. Streams with a shorter delay are played
+    /// late by the difference
+    pub min_buffer: SampleDuration,
+    /// Largest delay adaptive buffering may add, or None to disable it
+    pub adaptive_buffer: Option<SampleDuration>,
+    /// Shared volume control, adjusted at runtime
+    pub volume: Arc<Volume>,
+    /// Mapping of stereo audio onto the output's channels
+    pub channel_map: ChannelMap,
+    /// Shape dither noise when converting to 16 bit output
+    pub noise_shaping: bool,
+    /// Shared output chain settings, limiter and speaker protection,
+    /// adjusted at runtime
+    pub dsp: Arc<Dsp>,
+    /// Lowers playback while people are talking in the room
+    pub duck: Option<Arc<Duck>>,
+    /// Clock drift remembered between sessions, per source and device
+    pub drift: Option<Arc<DriftMemory>>,
+    /// Drift to start the clock servo from, recalled for the stream's
+    /// source when it begins
+    pub initial_drift_ppm: Option<f64>,
+    /// Decode streams playing straight to the output on the network
+    /// thread, stepped by `DecodeStream::poll`, rather than each on a
+    /// thread of its own
+    pub event_loop: bool,
+}
+
+/// Where a stream's audio goes once decoded
+pub enum StreamOutput<F: Format> {
+    /// Straight to the output device, which the stream has to itself
+    Direct(OutputRef<F>),
+    /// Into a mixer, to be summed with other streams
+    Mix(MixInput),
+}
+
+impl<F: Format> StreamOutput<F> {
+    /// `None` once the output has been taken away from this stream
+    fn lock(&self) -> Option<Sink<'_, F>> {
+        match self {
+            StreamOutput::Direct(output) => output.lock().map(Sink::Direct),
+            StreamOutput::Mix(input) => input.is_running().then_some(Sink::Mix(input)),
+        }
+    }
+
+    fn last_frame(&self) -> FrameF32 {
+        match self {
+            StreamOutput::Direct(output) => output.last_frame(),
+            // mixed streams fade in from silence
+            StreamOutput::Mix(_) => FrameF32(0.0, 0.0),
+        }
+    }
+
+    fn set_last_frame(&self, frame: FrameF32) {
+        if let StreamOutput::Direct(output) = self {
+            output.set_last_frame(frame);
+        }
+    }
+}
+
+enum Sink<'a, F: Format> {
+    Direct(OutputLock<'a, F>),
+    Mix(&'a MixInput),
+}
+
+impl<F: Format> Sink<'_, F> {
+    fn delay(&mut self) -> Result<SampleDuration, OutputError> {
+        match self {
+            Sink::Direct(output) => output.delay(),
+            Sink::Mix(input) => Ok(input.delay()),
+        }
+    }
+
+    /// Delay once the output is full and writes to it block, if known
+    fn buffer(&self) -> Option<SampleDuration> {
+        match self {
+            Sink::Direct(output) => output.buffer(),
+            Sink::Mix(_) => None,
+        }
+    }
+
+    /// Write audio, converting it to the output format if playing directly.
+    /// Mixed audio is converted once mixed
+    fn write(
+        &mut self,
+        dither: &mut Dither,
+        frames: &[FrameF32],
+        output_buffer: &mut [F::Frame],
+    ) -> Result<(), OutputError> {
+        match self {
+            Sink::Direct(output) => {
+                let output_buffer = &mut output_buffer[0..frames.len()];
+                format::convert::<F>(dither, frames, output_buffer);
+                output.write(output_buffer)
+            }
+            Sink::Mix(input) => {
+                input.write(frames);
+                Ok(())
+            }
+        }
+    }
+}
 
 pub struct DecodeStream {
     tx: QueueSender,
     stats: Arc<Mutex<DecodeStats>>,
+    // dumps requested, picked up by the decoder at its next packet
+    dump: mpsc::Sender<Dump>,
+    history: Arc<PlayHistory>,
+    ending: Arc<AtomicBool>,
+    runner: Runner,
+    metrics: ReceiverMetrics,
+}
+
+/// Where a stream is decoded
+enum Runner {
+    /// On a thread of its own, paced by blocking writes to the output
+    Thread(Option<JoinHandle<()>>),
+    /// On the network thread, a packet at a time whenever the output has
+    /// room for one. None once the stream has stopped
+    Inline(Option<Box<dyn Step>>),
+}
+
+/// A stream decoded on the network thread
+trait Step: Send {
+    /// How long until the output has room for another packet
+    fn due(&mut self) -> Duration;
+
+    /// Decode and play the next packet, returning false once the stream
+    /// has stopped
+    fn step(&mut self) -> bool;
 }
 
 impl DecodeStream {
-    pub fn new<F: Format>(header: &AudioPacketHeader, output: OutputRef<F>, metrics: ReceiverMetrics) -> Self {
-        let queue = PacketQueue::new(header);
+    /// Start decoding a stream to output. If `start` is given, playback is
+    /// held back until then, so that it starts together with other receivers.
+    /// If `fade_in` is set, the stream fades in once its audio begins
+    pub fn new<F: Format>(
+        header: &AudioPacketHeader,
+        extra_delay: SampleDuration,
+        start: Option<Timestamp>,
+        fade_in: bool,
+        output: StreamOutput<F>,
+        metrics: ReceiverMetrics,
+        opt: DecodeOpt,
+    ) -> Self {
+        let queue = PacketQueue::new(header, extra_delay, start);
         let (tx, rx) = queue::channel(queue);
 
+        // mixed streams are limited and protected after mixing instead
+        let chain = match output {
+            StreamOutput::Direct(_) => Some(Chain::new(opt.dsp.settings())),
+            StreamOutput::Mix(_) => None,
+        };
+
+        let ending = Arc::new(AtomicBool::new(false));
+
+        let mut pipeline = Pipeline::new(header);
+
+        if let Some(ppm) = opt.initial_drift_ppm {
+            pipeline.set_drift_ppm(ppm);
+        }
+
         let state = State {
             queue: rx,
-            pipeline: Pipeline::new(header),
+            start,
+            fade_in: fade_in.then(|| FadeIn::new(FADE_IN)),
+            ending: ending.clone(),
+            fade_out: None,
+            pipeline,
+            dither: Dither::new(opt.noise_shaping),
+            chain,
             output,
-            metrics,
+            metrics: metrics.clone(),
+            opt,
         };
 
         let stats = Arc::new(Mutex::new(DecodeStats::default()));
+        let (dump, dump_rx) = mpsc::channel();
+        let history = Arc::new(PlayHistory::new());
+
+        // mixed streams are paced by the mixer, which has a thread of its
+        // own, so only direct output can be decoded inline
+        let inline = state.opt.event_loop && matches!(state.output, StreamOutput::Direct(_));
 
-        std::thread::spawn({
-            let stats = stats.clone();
-            move || {
+        let decoder = Decoder::new(state, stats.clone(), dump_rx, history.clone());
+
+        let runner = if inline {
+            Runner::Inline(Some(Box::new(decoder)))
+        } else {
+            Runner::Thread(Some(std::thread::spawn(move || {
                 thread::set_name("bark/audio");
                 thread::set_realtime_priority();
-                run_stream(state, stats);
-            }
-        });
+                decoder.run();
+            })))
+        };
 
         DecodeStream {
             tx,
             stats,
+            dump,
+            history,
+            ending,
+            runner,
+            metrics,
+        }
+    }
+
+    /// Decode the next packet of an inline stream if the output has room
+    /// for it, returning how long until it has room for another. Only one
+    /// packet is decoded at a time, so that packets arriving meanwhile are
+    /// seen to. None for streams decoded on a thread of their own, and once
+    /// the stream has stopped
+    pub fn poll(&mut self) -> Option<Duration> {
+        let Runner::Inline(inline) = &mut self.runner else {
+            return None;
+        };
+
+        let decoder = inline.as_mut()?;
+
+        if decoder.due() == Duration::ZERO && !decoder.step() {
+            *inline = None;
+            return None;
         }
+
+        Some(decoder.due())
     }
 
-    pub fn send(&self, audio: AudioPts) -> Result<(), Disconnected> {
+    pub fn send(&self, audio: AudioPts) -> Result<Insert, Disconnected> {
         self.tx.send(audio)
     }
 
     pub fn stats(&self) -> DecodeStats {
         self.stats.lock().unwrap().clone()
     }
+
+    /// When recently played packets were heard
+    pub fn history(&self) -> &PlayHistory {
+        &self.history
+    }
+
+    /// Begin dumping audio from this stream, replacing any dump in progress
+    pub fn set_dump(&self, dump: Dump) {
+        let _ = self.dump.send(dump);
+    }
+
+    /// Fade the stream out and stop, as its source has ended it
+    pub fn end(&self) {
+        self.ending.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the stream has stopped, such as once it has faded out
+    /// after `end`
+    pub fn is_finished(&self) -> bool {
+        match &self.runner {
+            Runner::Thread(thread) => thread.as_ref().is_none_or(|thread| thread.is_finished()),
+            Runner::Inline(decoder) => decoder.is_none(),
+        }
+    }
+}
+
+impl Drop for DecodeStream {
+    /// Stop the stream's thread and free its queue now, rather than leaving
+    /// the thread to notice once it next needs a packet
+    fn drop(&mut self) {
+        self.tx.disconnect();
+
+        if let Runner::Thread(thread) = &mut self.runner {
+            if let Some(thread) = thread.take() {
+                reaper::reap(thread, self.metrics.clone());
+            }
+        }
+    }
 }
 
 struct State<F: Format> {
     queue: QueueReceiver,
-    pipeline: Pipeline<F>,
-    output: OutputRef<F>,
+    // synchronised start we are waiting for, if any
+    start: Option<Timestamp>,
+    // fading in after taking over from an interrupting stream
+    fade_in: Option<FadeIn>,
+    // set once the source has ended the stream
+    ending: Arc<AtomicBool>,
+    fade_out: Option<FadeOut>,
+    // the pipeline always runs in f32, and is converted to the output
+    // format only at the very end
+    pipeline: Pipeline<F32>,
+    dither: Dither,
+    // limiter and speaker protection, the last stages before output
+    chain: Option<Chain>,
+    output: StreamOutput<F>,
     metrics: ReceiverMetrics,
+    opt: DecodeOpt,
 }
 
 #[derive(Clone)]
 pub struct DecodeStats {
     pub status: StreamStatus,
     pub audio_latency: TimestampDelta,
+    pub clock_drift_ppm: f64,
     pub output_latency: SampleDuration,
+    /// Original pts of the audio now playing, before any extra delay
+    pub stream_pts: Option<Timestamp>,
 }
 
 impl Default for DecodeStats {
@@ -78,19 +356,148 @@ impl Default for DecodeStats {
         DecodeStats {
             status: StreamStatus::Seek,
             audio_latency: TimestampDelta::zero(),
+            clock_drift_ppm: 0.0,
             output_latency: SampleDuration::zero(),
+            stream_pts: None,
+        }
+    }
+}
+
+/// Decodes a stream and plays it, a packet at a time
+struct Decoder<F: Format> {
+    stream: State<F>,
+    stats: DecodeStats,
+    stats_tx: Arc<Mutex<DecodeStats>>,
+    dump_rx: mpsc::Receiver<Dump>,
+    dump: Option<Dump>,
+    history: Arc<PlayHistory>,
+    buffer: Vec<FrameF32>,
+    output_buffer: Vec<F::Frame>,
+    packet_frames: usize,
+    declick: Declick,
+    expected_pts: Option<Timestamp>,
+}
+
+impl<F: Format> Decoder<F> {
+    fn new(
+        stream: State<F>,
+        stats_tx: Arc<Mutex<DecodeStats>>,
+        dump_rx: mpsc::Receiver<Dump>,
+        history: Arc<PlayHistory>,
+    ) -> Self {
+        // resampler may output more frames than it takes in, leave room:
+        let buffer = vec![<F32 as Format>::Frame::zeroed(); stream.pipeline.frames_per_packet() * 2];
+        let output_buffer = vec![F::Frame::zeroed(); buffer.len()];
+        let packet_frames = stream.pipeline.frames_per_packet();
+
+        // carry on smoothly from wherever the last stream left the output
+        let declick = Declick::new(stream.output.last_frame());
+
+        Decoder {
+            stream,
+            stats: DecodeStats::default(),
+            stats_tx,
+            dump_rx,
+            dump: None,
+            history,
+            buffer,
+            output_buffer,
+            packet_frames,
+            declick,
+            expected_pts: None,
         }
     }
+
+    /// Decode until the stream stops, blocking on writes to the output
+    fn run(mut self) {
+        while self.step() {}
+    }
 }
 
-fn run_stream<F: Format>(mut stream: State<F>, stats_tx: Arc<Mutex<DecodeStats>>) {
-    let mut stats = DecodeStats::default();
+impl<F: Format> Step for Decoder<F> {
+    fn due(&mut self) -> Duration {
+        let Some(mut output) = self.stream.output.lock() else {
+            // step notices the output has gone
+            return Duration::ZERO;
+        };
+
+        let (Some(buffer), Ok(delay)) = (output.buffer(), output.delay()) else {
+            // writes to a disconnected device are paced for us, and step
+            // reports errors
+            return Duration::ZERO;
+        };
+
+        // keep the output as full as blocking writes would, short of the
+        // room the next packet needs
+        let buffer = buffer.to_frame_count();
+        let target = buffer - (self.packet_frames as u64).min(buffer / 2);
+
+        let wait = delay.to_frame_count().saturating_sub(target);
+        SampleDuration::from_frame_count_u64(wait).to_std_duration_lossy()
+    }
+
+    fn step(&mut self) -> bool {
+        let Decoder {
+            stream,
+            stats,
+            stats_tx,
+            dump_rx,
+            dump,
+            history,
+            buffer,
+            output_buffer,
+            packet_frames,
+            declick,
+            expected_pts,
+        } = self;
+
+        let packet_frames = *packet_frames;
+
+        // play silence until the synchronised start, filling the output so
+        // that the first frame of the stream is played right on it
+        if let Some(start) = stream.start {
+            if stream.queue.is_disconnected() || stream.ending.load(Ordering::Relaxed) {
+                return false;
+            }
+
+            let Some(mut output) = stream.output.lock() else {
+                return false;
+            };
+
+            let delay = match output.delay() {
+                Ok(delay) => delay,
+                Err(e) => {
+                    log::error!("error reading output delay: {e}");
+                    return false;
+                }
+            };
+            let pts = output_pts(stream, delay);
+
+            let frames = start.saturating_duration_since(pts).to_frame_count();
+            let frames = std::cmp::min(frames, packet_frames as u64) as usize;
+
+            if frames > 0 {
+                let silence = &mut buffer[0..frames];
+                silence.fill(FrameF32(0.0, 0.0));
+                declick.process(silence, true);
+                stream.output.set_last_frame(declick.last());
+
+                if let Err(e) = output.write(&mut stream.dither, silence, output_buffer) {
+                    log::error!("error playing audio: {e}");
+                    return false;
+                }
+
+                return true;
+            }
+
+            log::debug!("reached synchronised start of stream");
+            stream.start = None;
+        }
 
-    loop {
         // get next packet from queue, or None if missing (packet loss)
         let (queue_item, queue_len) = match stream.queue.recv() {
             Ok(rx) => rx,
-            Err(_) => { return; } // disconnected
+            Err(_) => { return false; } // disconnected
         };
 
         // update queue related metrics
@@ -111,29 +518,134 @@ fn run_stream<F: Format>(mut stream: State<F>, stats_tx: Arc<Mutex<DecodeStats>>
             .map(|item| (Some(&item.audio), Some(item.pts)))
             .unwrap_or_default();
 
+        // if packet was lost, grab the next packet for forward error correction
+        let next = if queue_item.is_none() && queue_len > 0 {
+            match stream.queue.copy_front() {
+                Ok(next) => next,
+                Err(_) => { return false; } // disconnected
+            }
+        } else {
+            None
+        };
+
         // pass packet through decode pipeline
-        let mut buffer = [F::Frame::zeroed(); FRAMES_PER_PACKET * 2];
-        let frames = stream.pipeline.process(packet, &mut buffer);
-        let buffer = &buffer[0..frames];
+        let frames = stream.pipeline.process(packet, next.as_ref(), buffer);
 
         // increment frames decoded metric
         stream.metrics.frames_decoded.add(frames);
 
+        // track audible impact of lost packets
+        if queue_item.is_none() {
+            if stream.pipeline.conceals_loss() {
+                stream.metrics.frames_concealed.add(frames);
+            } else {
+                stream.metrics.frames_silenced.add(frames);
+            }
+        }
+
+        // fade in from the first audio, declick below then smooths the
+        // way in from whatever was playing before
+        if let Some(fade_in) = stream.fade_in.as_mut() {
+            if queue_item.is_some() {
+                fade_in.process(&mut buffer[0..frames]);
+            }
+
+            if fade_in.is_done() {
+                stream.fade_in = None;
+            }
+        }
+
+        // crossfade over jumps in the stream, such as after the queue
+        // resets, and into and out of silence inserted for lost packets
+        let packet_duration = SampleDuration::from_frame_count(packet_frames);
+        let tolerance = SampleDuration::from_frame_count(packet_frames / 2);
+
+        if let (Some(pts), Some(expected)) = (stream_pts, *expected_pts) {
+            if pts.delta(expected).abs() > tolerance {
+                declick.discontinuity();
+            }
+        }
+
+        *expected_pts = stream_pts.or(*expected_pts).map(|pts| pts.add(packet_duration));
+
+        let silent = queue_item.is_none() && !stream.pipeline.conceals_loss();
+        declick.process(&mut buffer[0..frames], silent);
+
+        // fade out once the source has ended the stream, which stops after
+        // writing the end of the fade below
+        if stream.fade_out.is_none() && stream.ending.load(Ordering::Relaxed) {
+            log::debug!("stream ended by source, fading out");
+            stream.fade_out = Some(FadeOut::new(FADE_OUT));
+        }
+
+        if let Some(fade_out) = stream.fade_out.as_mut() {
+            fade_out.process(&mut buffer[0..frames]);
+        }
+
+        // the next stream carries on from what is heard, faded or not
+        let last = buffer[..frames].last().copied().unwrap_or(declick.last());
+        stream.output.set_last_frame(last);
+
+        // apply volume
+        let mut gain = stream.opt.volume.gain();
+
+        if let Some(duck) = &stream.opt.duck {
+            gain *= duck.gain();
+        }
+        if gain != 1.0 {
+            audio::apply_gain(F32::frames_mut(&mut buffer[0..frames]), gain);
+        }
+
+        // map channels for the speakers attached to this receiver
+        stream.opt.channel_map.apply(&mut buffer[0..frames]);
+
+        // pick up a dump requested since the last packet, replacing any
+        // in progress
+        if let Ok(requested) = dump_rx.try_recv() {
+            *dump = Some(requested);
+        }
+
+        if let Some(active) = dump.as_mut() {
+            active.tap::<F32>(Tap::Decoded, stream.pipeline.decoded());
+            active.tap::<F32>(Tap::PreDsp, &buffer[0..frames]);
+        }
+
+        // catch anything the stages above have pushed past full scale, and
+        // protect the speakers from whatever they produce
+        if let Some(chain) = stream.chain.as_mut() {
+            stream.opt.dsp.update(chain);
+            chain.process(&mut buffer[0..frames]);
+        }
+
+        let buffer = &buffer[0..frames];
+
+        if let Some(active) = dump.as_mut() {
+            active.tap::<F32>(Tap::PostDsp, buffer);
+
+            if active.played(buffer.len()) {
+                log::info!("finished audio dump");
+                *dump = None;
+            }
+        }
+
         // lock output
-        let Some(output) = stream.output.lock() else {
-            // output has been stolen from us, exit thread
-            break;
+        let Some(mut output) = stream.output.lock() else {
+            // output has been stolen from us, stop
+            return false;
         };
 
         // get current output delay
-        let delay = output.delay().unwrap();
-        stats.output_latency = delay;
+        let delay = match output.delay() {
+            Ok(delay) => delay,
+            Err(e) => {
+                log::error!("error reading output delay: {e}");
+                return false;
+            }
+        };
+        stats.output_latency = delay.add(stream.opt.device_latency);
         stream.metrics.buffer_delay.observe(delay);
 
-        // calculate presentation timestamp based on output delay
-        let pts = time::now();
-        let pts = Timestamp::from_micros_lossy(pts);
-        let pts = pts.add(delay);
+        let pts = output_pts(stream, delay);
 
         let timing = stream_pts.map(|stream_pts| Timing {
             real: pts,
@@ -143,9 +655,15 @@ fn run_stream<F: Format>(mut stream: State<F>, stats_tx: Arc<Mutex<DecodeStats>>
         // adjust resampler rate based on stream timing info
         if let Some(timing) = timing {
             stream.pipeline.set_timing(timing);
+            stream.metrics.resample_rate.observe(stream.pipeline.sample_rate());
+
+            let drift = stream.pipeline.drift_ppm();
+            stats.clock_drift_ppm = drift;
+            stream.metrics.clock_drift.observe((drift * 1000.0).round() as i64);
 
             if stream.pipeline.slew() {
                 stats.status = StreamStatus::Slew;
+                stream.metrics.frames_slewed.add(frames);
             } else {
                 stats.status = StreamStatus::Sync;
             }
@@ -162,18 +680,50 @@ fn run_stream<F: Format>(mut stream: State<F>, stats_tx: Arc<Mutex<DecodeStats>>
         }
 
         // update stats
+        if let Some(item) = &queue_item {
+            history.record(item.audio.header().seq, pts.to_micros_lossy());
+            stats.stream_pts = Some(Timestamp::from_micros_lossy(item.audio.header().pts));
+        }
+
         *stats_tx.lock().unwrap() = stats.clone();
 
         // increment frames output metric
         stream.metrics.frames_played.add(buffer.len());
 
-        // send audio to ALSA
-        match output.write(buffer) {
+        // send audio to ALSA, or to the mixer
+        match output.write(&mut stream.dither, buffer, output_buffer) {
             Ok(()) => {}
             Err(e) => {
                 log::error!("error playing audio: {e}");
-                break;
+                return false;
             }
         }
+
+        if stream.fade_out.as_ref().is_some_and(|fade_out| fade_out.is_done()) {
+            return false;
+        }
+
+        true
     }
 }
+
+/// Time that audio written to the output now will be heard, given the
+/// output's current delay
+fn output_pts<F: Format>(stream: &State<F>, delay: SampleDuration) -> Timestamp {
+    let pts = time::now();
+    let pts = Timestamp::from_micros_lossy(pts);
+    let pts = pts.add(delay);
+
+    // and by the sink the output device feeds, which it can't report
+    let pts = pts.add(stream.opt.device_latency);
+
+    // audio is held back in the output chain before reaching the output
+    let pts = match &stream.chain {
+        Some(chain) => pts.add(chain.latency()),
+        None => pts,
+    };
+
+    // apply configured latency offset. a positive offset means audio
+    // reaches the listener later than the output delay alone indicates
+    pts.adjust(stream.opt.latency_offset)
+}
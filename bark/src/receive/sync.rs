@@ -0,0 +1,86 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use bark_protocol::packet::{SyncReply, SyncRequest};
+use bark_protocol::types::TimestampMicros;
+
+use crate::socket::PeerId;
+
+/// How many packets a stream remembers the play time of, a little over a
+/// second's worth at the default packet size
+const HISTORY_LENGTH: usize = 256;
+
+/// How long a sync request waits for its packet to play before it is
+/// answered as missed, such as when the stream stops first
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// When a stream's most recent packets were heard, recorded by its decode
+/// thread for answering `bark sync-check`
+pub struct PlayHistory {
+    played: Mutex<VecDeque<(u64, TimestampMicros)>>,
+}
+
+pub enum Lookup {
+    Played(TimestampMicros),
+    /// Played past without the packet, which must have been lost
+    Missed,
+    /// Not played yet
+    Pending,
+}
+
+impl PlayHistory {
+    pub fn new() -> Self {
+        PlayHistory {
+            played: Mutex::new(VecDeque::with_capacity(HISTORY_LENGTH)),
+        }
+    }
+
+    /// Record the packet `seq` being written to the output, to be heard at
+    /// `heard`
+    pub fn record(&self, seq: u64, heard: TimestampMicros) {
+        let mut played = self.played.lock().unwrap();
+
+        if played.len() == HISTORY_LENGTH {
+            played.pop_front();
+        }
+
+        played.push_back((seq, heard));
+    }
+
+    pub fn lookup(&self, seq: u64) -> Lookup {
+        let played = self.played.lock().unwrap();
+
+        if let Some((_, heard)) = played.iter().find(|(played, _)| *played == seq) {
+            return Lookup::Played(*heard);
+        }
+
+        match played.back() {
+            Some((latest, _)) if *latest > seq => Lookup::Missed,
+            _ => Lookup::Pending,
+        }
+    }
+}
+
+/// A sync request waiting on its packet to play
+pub struct Pending {
+    pub peer: PeerId,
+    pub request: SyncRequest,
+    pub received: TimestampMicros,
+}
+
+impl Pending {
+    /// Reply to the request, if it can be answered from `history` by `now`
+    pub fn reply(&self, history: &PlayHistory, now: TimestampMicros) -> Option<SyncReply> {
+        let reply = match history.lookup(self.request.seq()) {
+            Lookup::Played(heard) => SyncReply::heard(&self.request, self.received, heard, now),
+            Lookup::Missed => SyncReply::missed(&self.request, self.received, now),
+            Lookup::Pending if self.received < now.saturating_sub(REQUEST_TIMEOUT) => {
+                SyncReply::missed(&self.request, self.received, now)
+            }
+            Lookup::Pending => { return None; }
+        };
+
+        Some(reply.expect("allocate SyncReply packet"))
+    }
+}
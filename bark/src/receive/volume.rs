@@ -0,0 +1,89 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+/// Receiver volume and mute state, shared with the audio thread
+pub struct Volume {
+    // f32 bits
+    level: AtomicU32,
+    // f32 bits, ceiling on gain whatever the level is set to
+    max: AtomicU32,
+    // max can no longer be changed by control requests
+    locked: AtomicBool,
+    muted: AtomicBool,
+    // muted by zone control, independently of the receiver's own mute
+    zone_muted: AtomicBool,
+}
+
+impl Volume {
+    pub fn new(max: f32, locked: bool) -> Self {
+        Volume {
+            level: AtomicU32::new(1.0f32.to_bits()),
+            max: AtomicU32::new(clamp(max).to_bits()),
+            locked: AtomicBool::new(locked),
+            muted: AtomicBool::new(false),
+            zone_muted: AtomicBool::new(false),
+        }
+    }
+
+    /// Linear volume between 0.0 and 1.0
+    pub fn level(&self) -> f32 {
+        f32::from_bits(self.level.load(Ordering::Relaxed))
+    }
+
+    pub fn set_level(&self, level: f32) {
+        self.level.store(clamp(level).to_bits(), Ordering::Relaxed);
+    }
+
+    /// Linear maximum volume between 0.0 and 1.0
+    pub fn max(&self) -> f32 {
+        f32::from_bits(self.max.load(Ordering::Relaxed))
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// Change the maximum volume and/or lock it, failing if already locked
+    pub fn set_limit(&self, max: Option<f32>, lock: bool) -> Result<(), Locked> {
+        if self.locked() {
+            return Err(Locked);
+        }
+
+        if let Some(max) = max {
+            self.max.store(clamp(max).to_bits(), Ordering::Relaxed);
+        }
+
+        if lock {
+            self.locked.store(true, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
+    pub fn muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed);
+    }
+
+    pub fn zone_muted(&self) -> bool {
+        self.zone_muted.load(Ordering::Relaxed)
+    }
+
+    pub fn set_zone_muted(&self, muted: bool) {
+        self.zone_muted.store(muted, Ordering::Relaxed);
+    }
+
+    /// Gain to apply to output audio
+    pub fn gain(&self) -> f32 {
+        if self.muted() || self.zone_muted() { 0.0 } else { self.level().min(self.max()) }
+    }
+}
+
+#[derive(Debug)]
+pub struct Locked;
+
+fn clamp(level: f32) -> f32 {
+    if level.is_nan() { 0.0 } else { level.clamp(0.0, 1.0) }
+}
@@ -0,0 +1,41 @@
+//! Exponential backoff between attempts at something that keeps failing,
+//! such as reopening an unplugged audio device or sending while the network
+//! is down.
+
+use std::time::{Duration, Instant};
+
+pub struct Reconnect {
+    max: Duration,
+    backoff: Duration,
+    next_attempt: Instant,
+}
+
+impl Reconnect {
+    /// Start reconnecting, with the first attempt straight away, then
+    /// waiting from `min` up to `max` between failed attempts
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Reconnect {
+            max,
+            backoff: min,
+            next_attempt: Instant::now(),
+        }
+    }
+
+    /// Whether it's time for another attempt
+    pub fn due(&self) -> bool {
+        Instant::now() >= self.next_attempt
+    }
+
+    /// Time left until the next attempt is due
+    pub fn delay(&self) -> Duration {
+        self.next_attempt.saturating_duration_since(Instant::now())
+    }
+
+    /// Back off after a failed attempt, returning how long until the next
+    pub fn failed(&mut self) -> Duration {
+        let wait = self.backoff;
+        self.next_attempt = Instant::now() + wait;
+        self.backoff = std::cmp::min(self.backoff * 2, self.max);
+        wait
+    }
+}
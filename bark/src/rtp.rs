@@ -0,0 +1,73 @@
+//! RTP (RFC 3550) framing, for interoperating with standard audio tools
+//! such as trx, gstreamer and ffmpeg. Audio is carried as 16 bit big
+//! endian linear PCM (RFC 3551 L16) or as Opus (RFC 7587), always stereo
+//! at 48 kHz, which both sides must agree on out of band, eg. with SDP.
+
+use bark_protocol::time::Timestamp;
+use bark_protocol::SAMPLE_RATE;
+
+pub const HEADER_LENGTH: usize = 12;
+
+const VERSION: u8 = 2;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    /// Set on the first packet after a discontinuity
+    pub marker: bool,
+    pub payload_type: u8,
+    pub seq: u16,
+    /// Sample clock at 48 kHz, wrapping
+    pub timestamp: u32,
+    pub ssrc: u32,
+}
+
+impl Header {
+    /// Parse a packet, returning its header and payload. Contributing
+    /// sources and header extensions are skipped, padding is removed
+    pub fn parse(packet: &[u8]) -> Option<(Header, &[u8])> {
+        if packet.len() < HEADER_LENGTH || packet[0] >> 6 != VERSION {
+            return None;
+        }
+
+        let padding = packet[0] & 0x20 != 0;
+        let extension = packet[0] & 0x10 != 0;
+        let csrc_count = usize::from(packet[0] & 0x0f);
+
+        let header = Header {
+            marker: packet[1] & 0x80 != 0,
+            payload_type: packet[1] & 0x7f,
+            seq: u16::from_be_bytes([packet[2], packet[3]]),
+            timestamp: u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]),
+            ssrc: u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]),
+        };
+
+        let mut payload = packet.get(HEADER_LENGTH + csrc_count * 4..)?;
+
+        if extension {
+            let words = usize::from(u16::from_be_bytes([*payload.get(2)?, *payload.get(3)?]));
+            payload = payload.get(4 + words * 4..)?;
+        }
+
+        if padding {
+            let pad = usize::from(*payload.last()?);
+            payload = payload.get(..payload.len().checked_sub(pad)?)?;
+        }
+
+        Some((header, payload))
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.push(VERSION << 6);
+        out.push(u8::from(self.marker) << 7 | (self.payload_type & 0x7f));
+        out.extend_from_slice(&self.seq.to_be_bytes());
+        out.extend_from_slice(&self.timestamp.to_be_bytes());
+        out.extend_from_slice(&self.ssrc.to_be_bytes());
+    }
+}
+
+/// RTP timestamp of a bark timestamp. Both count samples at 48 kHz, so
+/// this is the bark timestamp truncated to 32 bits
+pub fn timestamp(ts: Timestamp) -> u32 {
+    let micros = u128::from(ts.to_micros_lossy().0);
+    (micros * u128::from(SAMPLE_RATE.0) / 1_000_000) as u32
+}
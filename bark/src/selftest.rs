@@ -0,0 +1,526 @@
+//! End to end checks of this install, for trying bark out on a new device
+//! before putting it to use.
+
+use std::f32::consts::TAU;
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bytemuck::Zeroable;
+use structopt::StructOpt;
+
+use bark_core::audio::{FrameF32, Frames, F32};
+use bark_core::encode::Encode;
+use bark_core::receive::pipeline::Pipeline;
+use bark_core::receive::queue::{AudioPts, PacketQueue};
+use bark_core::receive::timing::Timing;
+use bark_core::registry;
+use bark_protocol::packet::{Audio, PacketKind};
+use bark_protocol::time::{SampleDuration, Timestamp, TimestampDelta};
+use bark_protocol::types::{AudioPacketHeader, SessionId, TimestampMicros};
+use bark_protocol::SAMPLE_RATE;
+
+use crate::audio::config::{self as audio_config, DeviceOpt};
+use crate::audio::{self, Output};
+use crate::config::{self, Transport};
+use crate::socket::{ProtocolSocket, Socket, SocketOpt};
+use crate::stats::metrics::ReceiverMetricsData;
+use crate::stats::ReceiverMetrics;
+use crate::{time, RunError};
+
+// 2.5ms, the shortest packet opus takes
+const PACKET_FRAMES: usize = 120;
+
+const TONE_HZ: f32 = 440.0;
+const TONE_AMPLITUDE: f32 = 0.25;
+
+// the tone heard must be within these of the tone sent
+const PITCH_TOLERANCE_HZ: f64 = 5.0;
+const LEVEL_TOLERANCE_DB: f64 = 1.0;
+
+// time for the receiver to settle into sync before anything is checked
+const SETTLE: Duration = Duration::from_secs(1);
+const SYNC_TOLERANCE: Duration = Duration::from_millis(1);
+
+// how long to wait for the first packet
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(StructOpt)]
+pub enum TestOpt {
+    /// Stream a tone to a receiver in this process over localhost
+    /// multicast, and check that it arrives, decodes and plays in sync
+    Loopback(LoopbackOpt),
+}
+
+#[derive(StructOpt)]
+pub struct LoopbackOpt {
+    /// Multicast group to test on. Test packets are sent with a TTL of 0
+    /// so they never leave this host, and to a port other than bark's
+    /// usual one so receivers running here don't play the tone
+    #[structopt(long, default_value = "224.100.100.100:1539")]
+    pub group: SocketAddrV4,
+
+    /// Codec to send the tone with
+    #[structopt(long, default_value = "f32le")]
+    pub format: config::Codec,
+
+    /// Seconds of tone to send
+    #[structopt(long, default_value = "5")]
+    pub seconds: u64,
+
+    /// How far ahead of playing it the tone is sent
+    #[structopt(long, default_value = "50")]
+    pub delay_ms: u64,
+
+    /// Output device to play the tone on
+    #[structopt(long, env = "BARK_RECEIVE_OUTPUT_DEVICE")]
+    pub output_device: Option<String>,
+
+    #[structopt(long, env = "BARK_RECEIVE_OUTPUT_PERIOD")]
+    pub output_period: Option<usize>,
+
+    #[structopt(long, env = "BARK_RECEIVE_OUTPUT_BUFFER")]
+    pub output_buffer: Option<usize>,
+
+    #[structopt(long, env = "BARK_RECEIVE_OUTPUT_LATENCY_MS")]
+    pub output_latency_ms: Option<f64>,
+
+    /// Play into a sink which takes audio at the rate a device would,
+    /// rather than an output device, to test without hearing the tone
+    #[structopt(long)]
+    pub null: bool,
+}
+
+pub fn run(opt: TestOpt) -> Result<(), RunError> {
+    match opt {
+        TestOpt::Loopback(opt) => loopback(opt),
+    }
+}
+
+fn loopback(opt: LoopbackOpt) -> Result<(), RunError> {
+    let socket = Socket::open(&SocketOpt {
+        multicast: vec![opt.group],
+        control_secret: None,
+        transport: Transport::Udp,
+        connect: None,
+        tcp_listen: None,
+        multicast_if: None,
+        multicast_ttl: 0,
+        dscp: 0,
+    })?;
+
+    let protocol = ProtocolSocket::new(socket);
+    let mut sink = open_sink(&opt)?;
+
+    let encoder = registry::new_encoder(opt.format.packet_format())?;
+
+    let header = AudioPacketHeader {
+        sid: SessionId(time::now().0 as i64),
+        seq: 1,
+        pts: TimestampMicros(0),
+        dts: TimestampMicros(0),
+        format: encoder.header_format(),
+        priority: 0,
+        packet_frames: PACKET_FRAMES as u16,
+        delay_ms: u16::try_from(opt.delay_ms).unwrap_or(u16::MAX),
+        min_buffer_ms: 0,
+        start_pts: TimestampMicros(0),
+    };
+
+    let packets = opt.seconds * u64::from(SAMPLE_RATE.0) / PACKET_FRAMES as u64;
+
+    // give up on packets that never come some time after the last is due
+    let deadline = Instant::now()
+        + Duration::from_secs(opt.seconds)
+        + Duration::from_millis(opt.delay_ms)
+        + RECEIVE_TIMEOUT;
+
+    println!("sending {}s of {TONE_HZ} Hz tone as {} to {}", opt.seconds, opt.format, opt.group);
+
+    let (sent, report) = thread::scope(|scope| {
+        let protocol = &protocol;
+        let sender = scope.spawn(move || send_tone(protocol, encoder, header, packets));
+        let report = receive(protocol, &mut sink, header.sid, packets, deadline);
+        (sender.join().expect("tone sender panicked"), report)
+    });
+
+    let sent = sent?;
+    let report = report?;
+
+    let checks = report.checks(sent, sink.underruns());
+
+    println!();
+
+    for check in &checks {
+        let result = if check.pass { "pass" } else { "FAIL" };
+        println!("  {result:<4}  {:<7}  {}", check.name, check.detail);
+    }
+
+    let failed = checks.iter().filter(|check| !check.pass).count();
+
+    if failed > 0 {
+        return Err(RunError::TestFailed(failed));
+    }
+
+    println!();
+    println!("all checks passed");
+    Ok(())
+}
+
+fn open_sink(opt: &LoopbackOpt) -> Result<Sink, RunError> {
+    if opt.null {
+        return Ok(Sink::Null(NullSink::new()));
+    }
+
+    let device = DeviceOpt {
+        device: opt.output_device.clone(),
+        period: opt.output_period.map(SampleDuration::from_frame_count),
+        buffer: opt.output_buffer.map(SampleDuration::from_frame_count),
+        latency: audio_config::latency(opt.output_latency_ms),
+        shared: false,
+    };
+
+    let metrics = Arc::new(ReceiverMetricsData::new());
+    let output = Output::new(&[device], metrics.clone())?;
+
+    Ok(Sink::Device(output, metrics))
+}
+
+/// Send `packets` packets of tone, paced by the audio they carry, as
+/// `bark stream` would. Returns the number sent
+fn send_tone(
+    protocol: &ProtocolSocket,
+    mut encoder: Box<dyn Encode>,
+    mut header: AudioPacketHeader,
+    packets: u64,
+) -> Result<u64, RunError> {
+    let delay = SampleDuration::from_frame_count_u64(u64::from(header.delay_ms) * u64::from(SAMPLE_RATE.0) / 1000);
+    let packet = SampleDuration::from_frame_count(PACKET_FRAMES);
+
+    let start = Timestamp::from_micros_lossy(time::now());
+    let started = Instant::now();
+
+    let step = TAU * TONE_HZ / SAMPLE_RATE.0 as f32;
+    let mut phase = 0.0f32;
+
+    let mut frames = [FrameF32::zeroed(); PACKET_FRAMES];
+    let mut encoded = [0u8; Audio::MAX_BUFFER_LENGTH];
+
+    for n in 0..packets {
+        let offset = SampleDuration::from_frame_count_u64(n * packet.to_frame_count());
+        thread::sleep((started + offset.to_std_duration_lossy()).saturating_duration_since(Instant::now()));
+
+        for frame in &mut frames {
+            let sample = phase.sin() * TONE_AMPLITUDE;
+            *frame = FrameF32(sample, sample);
+            phase = (phase + step) % TAU;
+        }
+
+        let len = encoder.encode_packet(Frames::F32(&frames), &mut encoded)
+            .expect("encode tone");
+
+        header.pts = start.add(offset).add(delay).to_micros_lossy();
+        header.dts = time::now();
+
+        let audio = Audio::new(&header, &encoded[0..len])
+            .expect("allocate Audio packet");
+
+        protocol.broadcast(audio.as_packet()).map_err(RunError::Send)?;
+        header.seq += 1;
+    }
+
+    Ok(packets)
+}
+
+/// Receive the tone as `bark receive` would, through the queue, decoder
+/// and resampler into the sink, keeping it in sync with the stream
+fn receive(
+    protocol: &ProtocolSocket,
+    sink: &mut Sink,
+    sid: SessionId,
+    packets: u64,
+    deadline: Instant,
+) -> Result<Report, RunError> {
+    let mut report = Report::default();
+    let mut stream: Option<(PacketQueue, Pipeline<F32>)> = None;
+
+    // resampler may output more frames than it takes in, leave room:
+    let mut buffer = vec![FrameF32::zeroed(); PACKET_FRAMES * 2];
+
+    // when the first packet of tone was played
+    let mut playing: Option<Instant> = None;
+
+    loop {
+        // take everything that has arrived, waiting only for the first packet
+        let mut timeout = match stream {
+            Some(_) => Duration::ZERO,
+            None => RECEIVE_TIMEOUT,
+        };
+
+        while let Some((packet, _)) = protocol.recv_from_timeout(Some(timeout)).map_err(RunError::Receive)? {
+            timeout = Duration::ZERO;
+
+            let Some(PacketKind::Audio(audio)) = packet.parse() else {
+                continue;
+            };
+
+            if audio.header().sid != sid {
+                continue;
+            }
+
+            report.received += 1;
+            report.network_latency(time::now().saturating_duration_since(audio.header().dts));
+
+            let (queue, _) = stream.get_or_insert_with(|| {
+                let queue = PacketQueue::new(audio.header(), SampleDuration::zero(), None);
+                (queue, Pipeline::new(audio.header()))
+            });
+
+            let pts = Timestamp::from_micros_lossy(audio.header().pts);
+            queue.insert_packet(AudioPts { pts, audio });
+        }
+
+        let Some((queue, pipeline)) = stream.as_mut() else {
+            // nothing arrived at all
+            return Ok(report);
+        };
+
+        // take len before popping
+        let queue_len = queue.len();
+        let item = queue.pop_front();
+
+        if item.is_none() && playing.is_some() {
+            report.missing += 1;
+        }
+
+        // if packet was lost, grab the next packet for forward error correction
+        let next = match item {
+            None if queue_len > 0 => queue.front()
+                .and_then(|next| Audio::new(next.header(), next.audio.buffer_bytes()).ok()),
+            _ => None,
+        };
+
+        let frames = pipeline.process(item.as_ref().map(|item| &item.audio), next.as_ref(), &mut buffer);
+        let output = &buffer[0..frames];
+
+        let delay = sink.delay().map_err(RunError::AudioOutput)?;
+        let real = Timestamp::from_micros_lossy(time::now()).add(delay);
+
+        let mut done = false;
+
+        if let Some(item) = &item {
+            pipeline.set_timing(Timing { real, play: item.pts });
+
+            let since = *playing.get_or_insert_with(Instant::now);
+
+            if since.elapsed() >= SETTLE {
+                let dts = Timestamp::from_micros_lossy(item.header().dts);
+                report.played(output, real.delta(item.pts), real.delta(dts), sink.underruns());
+            }
+
+            done = item.header().seq >= packets;
+        }
+
+        sink.write(output).map_err(RunError::AudioOutput)?;
+
+        if done || Instant::now() >= deadline {
+            return Ok(report);
+        }
+    }
+}
+
+struct Check {
+    name: &'static str,
+    pass: bool,
+    detail: String,
+}
+
+/// What the receiver saw and played. Everything but packet counts and
+/// network latency is taken once playback has settled
+#[derive(Default)]
+struct Report {
+    received: u64,
+    /// Packets missing when due to be played
+    missing: u64,
+    latency_total: Duration,
+    latency_max: Duration,
+    /// Output underruns when playback settled
+    settled_underruns: Option<u64>,
+    /// Sums for averaging over packets played
+    packets_played: u64,
+    offset_total: f64,
+    end_to_end_total: f64,
+    /// Sums for measuring the level and pitch of what was played
+    frames: u64,
+    energy: f64,
+    crossings: u64,
+    last_sample: f32,
+}
+
+impl Report {
+    fn network_latency(&mut self, latency: Duration) {
+        self.latency_total += latency;
+        self.latency_max = self.latency_max.max(latency);
+    }
+
+    fn played(&mut self, output: &[FrameF32], offset: TimestampDelta, end_to_end: TimestampDelta, underruns: u64) {
+        self.settled_underruns.get_or_insert(underruns);
+
+        self.packets_played += 1;
+        self.offset_total += offset.to_seconds().abs();
+        self.end_to_end_total += end_to_end.to_seconds();
+
+        for frame in output {
+            self.frames += 1;
+            self.energy += f64::from(frame.0) * f64::from(frame.0);
+
+            if self.last_sample <= 0.0 && frame.0 > 0.0 {
+                self.crossings += 1;
+            }
+
+            self.last_sample = frame.0;
+        }
+    }
+
+    fn checks(&self, sent: u64, underruns: u64) -> Vec<Check> {
+        let mut checks = Vec::new();
+
+        let latency_avg = self.latency_total / self.received.max(1) as u32;
+
+        checks.push(Check {
+            name: "network",
+            // loopback multicast shouldn't lose anything, but leave room
+            // for a busy host
+            pass: self.received * 100 >= sent * 99 && self.missing == 0,
+            detail: format!("{} of {sent} packets received, {} missing when due, latency {} average, {} max",
+                self.received,
+                self.missing,
+                ms(latency_avg.as_secs_f64()),
+                ms(self.latency_max.as_secs_f64())),
+        });
+
+        if self.frames == 0 {
+            checks.push(Check {
+                name: "decode",
+                pass: false,
+                detail: "nothing played".to_owned(),
+            });
+        } else {
+            let pitch = self.crossings as f64 * f64::from(SAMPLE_RATE.0) / self.frames as f64;
+            let level = dbfs((self.energy / self.frames as f64).sqrt());
+            let expected_level = dbfs(f64::from(TONE_AMPLITUDE) / 2f64.sqrt());
+
+            checks.push(Check {
+                name: "decode",
+                pass: (pitch - f64::from(TONE_HZ)).abs() <= PITCH_TOLERANCE_HZ
+                    && (level - expected_level).abs() <= LEVEL_TOLERANCE_DB,
+                detail: format!("heard {pitch:.1} Hz at {level:.1} dBFS, sent {TONE_HZ:.1} Hz at {expected_level:.1} dBFS"),
+            });
+        }
+
+        let underruns = underruns - self.settled_underruns.unwrap_or(underruns);
+
+        checks.push(Check {
+            name: "output",
+            pass: underruns == 0,
+            detail: format!("{underruns} underruns"),
+        });
+
+        let played = self.packets_played.max(1) as f64;
+        let offset = self.offset_total / played;
+
+        checks.push(Check {
+            name: "sync",
+            pass: self.packets_played > 0 && offset <= SYNC_TOLERANCE.as_secs_f64(),
+            detail: format!("{} from the stream on average, {} end to end latency",
+                ms(offset),
+                ms(self.end_to_end_total / played)),
+        });
+
+        checks
+    }
+}
+
+fn ms(secs: f64) -> String {
+    format!("{:.2} ms", secs * 1000.0)
+}
+
+fn dbfs(rms: f64) -> f64 {
+    20.0 * rms.max(1e-9).log10()
+}
+
+/// Where the receiver plays to
+enum Sink {
+    Device(Output<F32>, ReceiverMetrics),
+    Null(NullSink),
+}
+
+impl Sink {
+    fn write(&mut self, frames: &[FrameF32]) -> Result<(), audio::Error> {
+        match self {
+            Sink::Device(output, _) => output.write(frames),
+            Sink::Null(null) => { null.write(frames.len()); Ok(()) }
+        }
+    }
+
+    fn delay(&mut self) -> Result<SampleDuration, audio::Error> {
+        match self {
+            Sink::Device(output, _) => output.delay(),
+            Sink::Null(null) => Ok(null.delay()),
+        }
+    }
+
+    fn underruns(&self) -> u64 {
+        match self {
+            Sink::Device(_, metrics) => metrics.buffer_underruns.get(),
+            Sink::Null(null) => null.underruns,
+        }
+    }
+}
+
+/// Stands in for an output device, taking audio at the rate one would
+/// play it and keeping as much buffered as one would by default
+struct NullSink {
+    started: Instant,
+    written: u64,
+    underruns: u64,
+}
+
+impl NullSink {
+    fn new() -> Self {
+        NullSink {
+            started: Instant::now(),
+            written: 0,
+            underruns: 0,
+        }
+    }
+
+    fn played(&self) -> u64 {
+        SampleDuration::from_std_duration_lossy(self.started.elapsed()).to_frame_count()
+    }
+
+    fn delay(&self) -> SampleDuration {
+        SampleDuration::from_frame_count_u64(self.written.saturating_sub(self.played()))
+    }
+
+    fn write(&mut self, frames: usize) {
+        // ran dry, start again from here as a device recovering from an
+        // underrun does. nothing written yet is just starting
+        if self.played() > self.written {
+            if self.written > 0 {
+                self.underruns += 1;
+            }
+
+            self.started = Instant::now();
+            self.written = 0;
+        }
+
+        self.written += frames as u64;
+
+        // block while more than a buffer's worth is waiting to play
+        let buffered = self.delay();
+        let excess = buffered.to_frame_count().saturating_sub(audio_config::DEFAULT_LATENCY.to_frame_count());
+        thread::sleep(SampleDuration::from_frame_count_u64(excess).to_std_duration_lossy());
+    }
+}
@@ -0,0 +1,171 @@
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus};
+
+use structopt::StructOpt;
+use thiserror::Error;
+
+use crate::RunError;
+
+#[derive(StructOpt)]
+pub struct InstallServiceOpt {
+    /// Name of the systemd unit, defaults to bark-<command>
+    #[structopt(long)]
+    pub name: Option<String>,
+
+    /// Install as a user service rather than a system service
+    #[structopt(long)]
+    pub user: bool,
+
+    /// Print the unit file instead of installing it
+    #[structopt(long)]
+    pub print: bool,
+
+    /// Bark command to run as a service, eg. `-- receive --zone kitchen`
+    #[structopt(last = true, required = true)]
+    pub command: Vec<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum ServiceError {
+    #[error("invalid service command: {0}")]
+    InvalidCommand(String),
+    #[error("finding bark executable: {0}")]
+    CurrentExe(std::io::Error),
+    #[error("finding user config directory")]
+    NoConfigDir,
+    #[error("writing {0}: {1}")]
+    Write(PathBuf, std::io::Error),
+    #[error("running systemctl: {0}")]
+    Systemctl(std::io::Error),
+    #[error("systemctl failed: {0}")]
+    SystemctlFailed(ExitStatus),
+}
+
+pub fn run(opt: InstallServiceOpt) -> Result<(), RunError> {
+    Ok(install(opt)?)
+}
+
+fn install(opt: InstallServiceOpt) -> Result<(), ServiceError> {
+    // check the command parses now rather than when the service starts
+    let args = std::iter::once("bark".to_owned()).chain(opt.command.iter().cloned());
+    crate::Opt::from_iter_safe(args)
+        .map_err(|e| ServiceError::InvalidCommand(e.message))?;
+
+    let name = opt.name.clone()
+        .unwrap_or_else(|| format!("bark-{}", opt.command[0]));
+
+    let unit = unit(&opt)?;
+
+    if opt.print {
+        print!("{unit}");
+        return Ok(());
+    }
+
+    let dir = if opt.user {
+        xdg::BaseDirectories::new()
+            .map_err(|_| ServiceError::NoConfigDir)?
+            .get_config_home()
+            .join("systemd/user")
+    } else {
+        PathBuf::from("/etc/systemd/system")
+    };
+
+    let path = dir.join(format!("{name}.service"));
+
+    std::fs::create_dir_all(&dir)
+        .and_then(|()| std::fs::write(&path, unit))
+        .map_err(|e| ServiceError::Write(path.clone(), e))?;
+
+    log::info!("wrote {}", path.display());
+
+    systemctl(opt.user, &["daemon-reload"])?;
+    systemctl(opt.user, &["enable", "--now", &name])?;
+
+    log::info!("enabled and started {name}");
+    Ok(())
+}
+
+fn unit(opt: &InstallServiceOpt) -> Result<String, ServiceError> {
+    let exe = std::env::current_exe()
+        .map_err(ServiceError::CurrentExe)?;
+
+    let exec = std::iter::once(exe.display().to_string())
+        .chain(opt.command.iter().cloned())
+        .map(|arg| quote(&arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    // options from the environment and config file, which has already been
+    // loaded into the environment. the service may not see the same config
+    // file, so carry them over explicitly
+    let mut env = std::env::vars()
+        .filter(|(key, _)| key.starts_with("BARK_") || key == "RUST_LOG")
+        .collect::<Vec<_>>();
+    env.sort();
+
+    let mut unit = String::new();
+
+    let _ = writeln!(unit, "[Unit]");
+    let _ = writeln!(unit, "Description=bark {}", opt.command.join(" "));
+    let _ = writeln!(unit, "After=network-online.target sound.target");
+    let _ = writeln!(unit, "Wants=network-online.target");
+    let _ = writeln!(unit);
+    let _ = writeln!(unit, "[Service]");
+    let _ = writeln!(unit, "ExecStart={exec}");
+
+    for (key, value) in env {
+        let _ = writeln!(unit, "Environment={}", quote(&format!("{key}={value}")));
+    }
+
+    let _ = writeln!(unit, "Restart=always");
+    let _ = writeln!(unit, "RestartSec=1");
+
+    // audio threads run with SCHED_FIFO by default, see bark_app::sched
+    let _ = writeln!(unit, "LimitRTPRIO=99");
+    let _ = writeln!(unit, "LimitMEMLOCK=infinity");
+
+    if !opt.user {
+        let _ = writeln!(unit, "DynamicUser=yes");
+        let _ = writeln!(unit, "SupplementaryGroups=audio");
+        let _ = writeln!(unit, "AmbientCapabilities=CAP_SYS_NICE");
+    }
+
+    let _ = writeln!(unit);
+    let _ = writeln!(unit, "[Install]");
+    let _ = writeln!(unit, "WantedBy={}", if opt.user { "default.target" } else { "multi-user.target" });
+
+    Ok(unit)
+}
+
+// quote a value for systemd, which also expands % specifiers
+fn quote(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%");
+
+    if escaped.is_empty() || escaped.contains(char::is_whitespace) || escaped != value {
+        format!("\"{escaped}\"")
+    } else {
+        escaped
+    }
+}
+
+fn systemctl(user: bool, args: &[&str]) -> Result<(), ServiceError> {
+    let mut command = Command::new("systemctl");
+
+    if user {
+        command.arg("--user");
+    }
+
+    let status = command.args(args)
+        .status()
+        .map_err(ServiceError::Systemctl)?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(ServiceError::SystemctlFailed(status))
+    }
+}
@@ -1,6 +1,8 @@
 use std::io;
-use std::net::{Ipv4Addr, UdpSocket, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, UdpSocket, SocketAddr, SocketAddrV4};
 use std::os::fd::AsFd;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use derive_more::Display;
 use nix::poll::{PollFd, PollFlags, PollTimeout};
@@ -8,12 +10,26 @@ use socket2::{Domain, Type};
 use structopt::StructOpt;
 
 use bark_protocol::buffer::PacketBuffer;
-use bark_protocol::packet::Packet;
+use bark_protocol::packet::{Packet, Signed};
+use bark_protocol::types::TimestampMicros;
 use thiserror::Error;
 
-// expedited forwarding - IP header field indicating that switches should
-// prioritise our packets for minimal delay
-const IPTOS_DSCP_EF: u32 = 0xb8;
+use crate::config::Transport;
+use crate::discover;
+use crate::time;
+
+use self::batch::RecvBatch;
+use self::monitor::Membership;
+use self::tcp::Streams;
+
+pub mod batch;
+pub mod monitor;
+pub mod tcp;
+pub mod txtime;
+
+// expedited forwarding - DSCP indicating that switches should prioritise
+// our packets for minimal delay
+const DSCP_EF: u8 = 46;
 
 #[derive(Debug, Error)]
 pub enum ListenError {
@@ -27,85 +43,343 @@ pub enum ListenError {
     Bind(SocketAddrV4, io::Error),
     #[error("joining multicast group {0}: {1}")]
     JoinMulticastGroup(Ipv4Addr, io::Error),
+    #[error("no multicast group configured, and none discovered on the network")]
+    NoMulticastGroup,
+    #[error("tcp transport needs a node to connect to, set --connect")]
+    NoConnectAddress,
+    #[error("listening for tcp connections on {0}: {1}")]
+    TcpListen(SocketAddr, io::Error),
+    #[error("dscp must be between 0 and 63, got {0}")]
+    InvalidDscp(u8),
 }
 
 #[derive(StructOpt, Debug, Clone)]
 pub struct SocketOpt {
-    #[structopt(long, name="addr", env = "BARK_MULTICAST")]
-    /// Multicast group address including port, eg. 224.100.100.100:1530
-    pub multicast: SocketAddrV4,
+    #[structopt(long, name="addr", env = "BARK_MULTICAST", use_delimiter = true)]
+    /// Multicast group address including port, eg. 224.100.100.100:1530.
+    /// May be given more than once to send to several groups, the first is
+    /// used for discovery. Discovered from other nodes over mDNS if not given
+    pub multicast: Vec<SocketAddrV4>,
+
+    #[structopt(long, env = "BARK_CONTROL_SECRET", hide_env_values = true)]
+    /// Secret shared by every node, used to sign control packets such as
+    /// volume and zone changes, and to verify them on receivers
+    pub control_secret: Option<String>,
+
+    #[structopt(long, env = "BARK_TRANSPORT", default_value = "udp")]
+    /// How to reach other nodes: udp multicast, or tcp to the node given
+    /// by --connect, for networks that drop multicast
+    pub transport: Transport,
+
+    #[structopt(long, env = "BARK_CONNECT")]
+    /// Node to connect to with tcp transport, eg. source.local:1530. It
+    /// must be accepting connections with --tcp-listen
+    pub connect: Option<String>,
+
+    #[structopt(long, env = "BARK_TCP_LISTEN")]
+    /// Accept tcp transport connections on this address, eg. 0.0.0.0:1530,
+    /// sending everything we multicast to each node connected
+    pub tcp_listen: Option<SocketAddr>,
+
+    #[structopt(long, env = "BARK_MULTICAST_IF")]
+    /// Address of the interface to join multicast groups and send on, for
+    /// hosts on more than one network. Follows the routing table if not
+    /// given
+    pub multicast_if: Option<Ipv4Addr>,
+
+    #[structopt(long, env = "BARK_MULTICAST_TTL", default_value = "1")]
+    /// How many routers multicast packets may cross. The default of 1
+    /// keeps them on the local network
+    pub multicast_ttl: u32,
+
+    #[structopt(long, env = "BARK_DSCP", default_value = "46")]
+    /// DSCP to mark packets with, for switches and routers to prioritise
+    /// them by. Defaults to expedited forwarding
+    pub dscp: u8,
+}
+
+/// Options applied to each socket as it's opened
+#[derive(Clone, Copy)]
+struct Tuning {
+    interface: Ipv4Addr,
+    ttl: u32,
+    dscp: u8,
+}
+
+impl Tuning {
+    fn new(opt: &SocketOpt) -> Result<Self, ListenError> {
+        if opt.dscp > 63 {
+            return Err(ListenError::InvalidDscp(opt.dscp));
+        }
+
+        Ok(Tuning {
+            interface: opt.multicast_if.unwrap_or(Ipv4Addr::UNSPECIFIED),
+            ttl: opt.multicast_ttl,
+            dscp: opt.dscp,
+        })
+    }
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Tuning {
+            interface: Ipv4Addr::UNSPECIFIED,
+            ttl: 1,
+            dscp: DSCP_EF,
+        }
+    }
 }
 
 pub struct Socket {
-    multicast: SocketAddrV4,
+    // groups we send multicast packets to. the first is our primary group
+    multicast: Vec<SocketAddrV4>,
 
     // used to send unicast + multicast packets, as well as receive unicast replies
     // bound to 0.0.0.0:0, aka. OS picks a port
     tx: UdpSocket,
 
-    // uses to receive multicast packets
-    rx: UdpSocket,
+    // uses to receive multicast packets, one per group
+    rx: Vec<UdpSocket>,
+
+    // connections with nodes over tcp transport, in either direction
+    tcp: Option<Arc<Streams>>,
+
+    // packets read in the last batch, not yet handed out
+    batch: Mutex<RecvBatch>,
+
+    // interface multicast groups are joined on, for rejoining them
+    interface: Ipv4Addr,
+
+    // whether tx honours transmit times, see the txtime module
+    txtime: bool,
+
+    control_secret: Option<String>,
 }
 
 #[derive(Clone, Copy, Debug, Display, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PeerId(SocketAddr);
 
+impl From<SocketAddr> for PeerId {
+    fn from(addr: SocketAddr) -> Self {
+        PeerId(addr)
+    }
+}
+
+impl PeerId {
+    pub fn ip(&self) -> IpAddr {
+        self.0.ip()
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.0
+    }
+}
+
 impl Socket {
     pub fn open(opt: &SocketOpt) -> Result<Socket, ListenError> {
-        let group = *opt.multicast.ip();
-        let port = opt.multicast.port();
+        let tuning = Tuning::new(opt)?;
+
+        let tcp = match (opt.transport, &opt.tcp_listen) {
+            (Transport::Udp, None) => None,
+            _ => Some(Streams::new().map_err(ListenError::Socket)?),
+        };
+
+        if let (Some(streams), Some(addr)) = (&tcp, opt.tcp_listen) {
+            streams.listen(addr).map_err(|e| ListenError::TcpListen(addr, e))?;
+        }
+
+        if opt.transport == Transport::Tcp {
+            let addr = opt.connect.clone().ok_or(ListenError::NoConnectAddress)?;
+            let streams = tcp.as_ref().expect("tcp transport has streams");
+            streams.connect(addr);
+
+            // everything goes through the connection, the socket is only
+            // for unicast to nodes reachable directly
+            let any = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+            let tx = open_multicast(Ipv4Addr::UNSPECIFIED, any, tuning)?;
+
+            return Ok(Socket {
+                multicast: Vec::new(),
+                tx: tx.into(),
+                rx: Vec::new(),
+                tcp,
+                batch: Mutex::new(RecvBatch::new()),
+                interface: tuning.interface,
+                txtime: false,
+                control_secret: opt.control_secret.clone(),
+            });
+        }
+
+        let multicast = if opt.multicast.is_empty() {
+            let group = discover::find_group(discover::DISCOVER_TIMEOUT)
+                .ok_or(ListenError::NoMulticastGroup)?;
+
+            vec![group]
+        } else {
+            opt.multicast.clone()
+        };
 
-        let tx = open_multicast(group, SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
-        let rx = open_multicast(group, SocketAddrV4::new(group, port))?;
+        let primary = *multicast[0].ip();
+        let tx = open_multicast(primary, SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0), tuning)?;
+
+        let rx = multicast.iter()
+            .map(|group| open_multicast(*group.ip(), *group, tuning))
+            .map(|socket| socket.map(UdpSocket::from))
+            .collect::<Result<Vec<_>, _>>()?;
 
         Ok(Socket {
-            multicast: SocketAddrV4::new(group, port),
+            multicast,
             tx: tx.into(),
-            rx: rx.into(),
+            rx,
+            tcp,
+            batch: Mutex::new(RecvBatch::new()),
+            interface: tuning.interface,
+            txtime: false,
+            control_secret: opt.control_secret.clone(),
         })
     }
 
+    /// Send audio at transmit times given to `broadcast_batch`, rather than
+    /// as soon as it's sent. Linux only
+    pub fn enable_txtime(&mut self) -> Result<(), io::Error> {
+        txtime::enable(&self.tx)?;
+        self.txtime = true;
+        Ok(())
+    }
+
+    /// Handle for rejoining our multicast groups after network changes
+    pub fn membership(&self) -> Result<Membership, io::Error> {
+        let groups = self.multicast.iter()
+            .zip(&self.rx)
+            .map(|(group, socket)| Ok((*group, socket.try_clone()?)))
+            .collect::<Result<Vec<_>, io::Error>>()?;
+
+        Ok(Membership::new(groups, self.interface))
+    }
+
+    /// Primary multicast group, None with tcp transport
+    pub fn multicast(&self) -> Option<SocketAddrV4> {
+        self.multicast.first().copied()
+    }
+
+    /// Shared secret for signing and verifying control packets
+    pub fn control_secret(&self) -> Option<&[u8]> {
+        self.control_secret.as_deref().map(str::as_bytes)
+    }
+
+    /// All multicast groups we are sending to
+    pub fn groups(&self) -> &[SocketAddrV4] {
+        &self.multicast
+    }
+
     pub fn broadcast(&self, msg: &[u8]) -> Result<(), io::Error> {
-        self.tx.send_to(msg, self.multicast)?;
+        if let Some(tcp) = &self.tcp {
+            tcp.broadcast(msg);
+        }
+
+        for group in &self.multicast {
+            self.tx.send_to(msg, group)?;
+        }
+
         Ok(())
     }
 
+    /// Broadcast several packets at once, in as few syscalls as we can.
+    /// With transmit times enabled, they leave the NIC at `at`
+    pub fn broadcast_batch(&self, msgs: &[&[u8]], at: Option<TimestampMicros>) -> Result<(), io::Error> {
+        if let Some(tcp) = &self.tcp {
+            for msg in msgs {
+                tcp.broadcast(msg);
+            }
+        }
+
+        let txtime = at.filter(|_| self.txtime).map(txtime::monotonic_nanos);
+        batch::send(&self.tx, msgs, &self.multicast, txtime)
+    }
+
     pub fn send_to(&self, msg: &[u8], dest: PeerId) -> Result<(), io::Error> {
+        if self.tcp.as_ref().is_some_and(|tcp| tcp.send_to(msg, dest)) {
+            return Ok(());
+        }
+
         self.tx.send_to(msg, dest.0)?;
         Ok(())
     }
 
-    pub fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, PeerId), io::Error> {
-        let mut poll = [
-            PollFd::new(self.tx.as_fd(), PollFlags::POLLIN),
-            PollFd::new(self.rx.as_fd(), PollFlags::POLLIN),
-        ];
+    /// Receive from any of our sockets, giving up with `None` if nothing
+    /// arrives within `timeout`. Everything waiting on a socket is read at
+    /// once, and handed out from there by later calls
+    pub fn recv_from_timeout(&self, timeout: Option<Duration>)
+        -> Result<Option<(PacketBuffer, PeerId)>, io::Error>
+    {
+        if let Some(received) = self.batch.lock().unwrap().pop() {
+            return Ok(Some(received));
+        }
 
-        nix::poll::poll(&mut poll, PollTimeout::NONE)?;
+        let mut poll = std::iter::once(&self.tx)
+            .chain(&self.rx)
+            .map(|socket| PollFd::new(socket.as_fd(), PollFlags::POLLIN))
+            .collect::<Vec<_>>();
 
-        let (nbytes, addr) =
-            if poll[0].any() == Some(true) {
-                self.tx.recv_from(buf)?
-            } else if poll[1].any() == Some(true) {
-                self.rx.recv_from(buf)?
-            } else {
-                unreachable!("poll returned with no readable sockets");
-            };
+        // packets from tcp connections come last
+        if let Some(tcp) = &self.tcp {
+            poll.push(PollFd::new(tcp.wake_fd(), PollFlags::POLLIN));
+        }
+
+        let timeout = match timeout {
+            Some(timeout) => PollTimeout::try_from(timeout).unwrap_or(PollTimeout::MAX),
+            None => PollTimeout::NONE,
+        };
+
+        if nix::poll::poll(&mut poll, timeout)? == 0 {
+            return Ok(None);
+        }
+
+        let ready = poll.iter()
+            .position(|fd| fd.any() == Some(true))
+            .expect("poll returned with no readable sockets");
+
+        let mut batch = self.batch.lock().unwrap();
+
+        if let (Some(tcp), true) = (&self.tcp, ready == self.rx.len() + 1) {
+            if let Some((packet, peer)) = tcp.recv()? {
+                batch.push(PacketBuffer::from_raw(packet), peer);
+            }
+        } else {
+            let socket = if ready == 0 { &self.tx } else { &self.rx[ready - 1] };
+            batch.recv(socket)?;
+        }
 
-        Ok((nbytes, PeerId(addr)))
+        Ok(batch.pop())
     }
 }
 
-fn open_multicast(group: Ipv4Addr, bind: SocketAddrV4) -> Result<socket2::Socket, ListenError> {
-    let socket = bind_socket(bind)?;
+/// Open a plain UDP socket for another protocol such as RTP, bound to
+/// `bind` and joined to `group` if it is a multicast group
+pub fn open_udp(group: Ipv4Addr, bind: SocketAddrV4) -> Result<UdpSocket, ListenError> {
+    Ok(open_multicast(group, bind, Tuning::default())?.into())
+}
+
+fn open_multicast(group: Ipv4Addr, bind: SocketAddrV4, tuning: Tuning) -> Result<socket2::Socket, ListenError> {
+    let socket = bind_socket(bind, tuning.dscp)?;
 
     // join multicast group
     if group.is_multicast() {
-        socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)
+        socket.join_multicast_v4(&group, &tuning.interface)
             .map_err(|e| ListenError::JoinMulticastGroup(group, e))?;
 
         let _ = socket.set_multicast_loop_v4(true);
 
+        if !tuning.interface.is_unspecified() {
+            if let Err(e) = socket.set_multicast_if_v4(&tuning.interface) {
+                log::warn!("failed to set IP_MULTICAST_IF to {}: {e:?}", tuning.interface);
+            }
+        }
+
+        if let Err(e) = socket.set_multicast_ttl_v4(tuning.ttl) {
+            log::warn!("failed to set IP_MULTICAST_TTL to {}: {e:?}", tuning.ttl);
+        }
+
         socket.set_broadcast(true).map_err(ListenError::SetBroadcast)?;
     }
 
@@ -115,14 +389,15 @@ fn open_multicast(group: Ipv4Addr, bind: SocketAddrV4) -> Result<socket2::Socket
     Ok(socket.into())
 }
 
-fn bind_socket(bind: SocketAddrV4) -> Result<socket2::Socket, ListenError> {
+fn bind_socket(bind: SocketAddrV4, dscp: u8) -> Result<socket2::Socket, ListenError> {
     let socket = socket2::Socket::new(Domain::IPV4, Type::DGRAM, None)
         .map_err(ListenError::Socket)?;
 
     socket.set_reuse_address(true).map_err(ListenError::SetReuseAddr)?;
 
-    if let Err(e) = socket.set_tos(IPTOS_DSCP_EF) {
-        log::warn!("failed to set IPTOS_DSCP_EF: {e:?}");
+    // dscp is the top six bits of the tos byte
+    if let Err(e) = socket.set_tos(u32::from(dscp) << 2) {
+        log::warn!("failed to set dscp {dscp}: {e:?}");
     }
 
     socket.bind(&bind.into()).map_err(|e| ListenError::Bind(bind, e))?;
@@ -140,33 +415,68 @@ impl ProtocolSocket {
     }
 
     pub fn broadcast(&self, packet: &Packet) -> Result<(), io::Error> {
-        self.socket.broadcast(packet.as_buffer().as_bytes())
+        match self.sign(packet) {
+            Some(signed) => self.socket.broadcast(signed.as_packet().as_buffer().as_bytes()),
+            None => self.socket.broadcast(packet.as_buffer().as_bytes()),
+        }
     }
 
-    pub fn send_to(&self, packet: &Packet, peer: PeerId) -> Result<(), io::Error> {
-        self.socket.send_to(packet.as_buffer().as_bytes(), peer)
+    pub fn broadcast_batch(&self, packets: &[&Packet], at: Option<TimestampMicros>) -> Result<(), io::Error> {
+        let signed = packets.iter()
+            .map(|packet| self.sign(packet))
+            .collect::<Vec<_>>();
+
+        let msgs = packets.iter()
+            .zip(&signed)
+            .map(|(packet, signed)| match signed {
+                Some(signed) => signed.as_packet().as_buffer().as_bytes(),
+                None => packet.as_buffer().as_bytes(),
+            })
+            .collect::<Vec<_>>();
+
+        self.socket.broadcast_batch(&msgs, at)
     }
 
-    fn recv_buffer_from(&self) -> Result<(PacketBuffer, PeerId), io::Error> {
-        let mut buffer = vec![0u8; bark_protocol::packet::MAX_PACKET_SIZE];
+    pub fn send_to(&self, packet: &Packet, peer: PeerId) -> Result<(), io::Error> {
+        match self.sign(packet) {
+            Some(signed) => self.socket.send_to(signed.as_packet().as_buffer().as_bytes(), peer),
+            None => self.socket.send_to(packet.as_buffer().as_bytes(), peer),
+        }
+    }
 
-        let (nbytes, peer) = self.socket.recv_from(&mut buffer)?;
+    // control packets are signed whenever we have a secret to sign with
+    fn sign(&self, packet: &Packet) -> Option<Signed> {
+        if !packet.is_control() {
+            return None;
+        }
 
-        // shrink vec to what we just read:
-        assert!(nbytes <= buffer.len());
-        buffer.resize(nbytes, 0);
+        let key = self.socket.control_secret()?;
 
-        let buffer = PacketBuffer::from_raw(buffer);
+        Some(Signed::new(packet, key, time::now())
+            .expect("allocate Signed packet"))
+    }
 
-        Ok((buffer, peer))
+    fn recv_buffer_from(&self, timeout: Option<Duration>) -> Result<Option<(PacketBuffer, PeerId)>, io::Error> {
+        self.socket.recv_from_timeout(timeout)
     }
 
     pub fn recv_from(&self) -> Result<(Packet, PeerId), io::Error> {
         loop {
-            let (buffer, peer) = self.recv_buffer_from()?;
+            if let Some(received) = self.recv_from_timeout(None)? {
+                return Ok(received);
+            }
+        }
+    }
+
+    /// Receive the next packet, or `None` if nothing arrives within `timeout`
+    pub fn recv_from_timeout(&self, timeout: Option<Duration>) -> Result<Option<(Packet, PeerId)>, io::Error> {
+        loop {
+            let Some((buffer, peer)) = self.recv_buffer_from(timeout)? else {
+                return Ok(None);
+            };
 
             if let Some(packet) = Packet::from_buffer(buffer) {
-                return Ok((packet, peer));
+                return Ok(Some((packet, peer)));
             }
         }
     }
@@ -0,0 +1,246 @@
+//! Batched receive and send. Every datagram waiting on a socket is read
+//! with one recvmmsg call, into scratch buffers kept from one call to the
+//! next, and each is then copied out into a buffer of exactly its size. A
+//! stream at 1000 packets per second costs a syscall per poll wakeup rather
+//! than per packet, and no longer a zeroed allocation of the largest packet
+//! size for every packet. Likewise packets sent together, such as to
+//! several groups or with redundant copies, go out in one sendmmsg call.
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::{SocketAddrV4, UdpSocket};
+
+use bark_protocol::buffer::PacketBuffer;
+use bark_protocol::packet::MAX_PACKET_SIZE;
+
+use super::PeerId;
+
+/// Most datagrams read in one call
+const BATCH_SIZE: usize = 16;
+
+pub struct RecvBatch {
+    buffers: Vec<Box<[u8]>>,
+    // received and waiting to be handed out
+    pending: VecDeque<(PacketBuffer, PeerId)>,
+}
+
+impl RecvBatch {
+    pub fn new() -> Self {
+        RecvBatch {
+            buffers: (0..BATCH_SIZE).map(|_| vec![0; MAX_PACKET_SIZE].into_boxed_slice()).collect(),
+            pending: VecDeque::with_capacity(BATCH_SIZE),
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<(PacketBuffer, PeerId)> {
+        self.pending.pop_front()
+    }
+
+    pub fn push(&mut self, buffer: PacketBuffer, peer: PeerId) {
+        self.pending.push_back((buffer, peer));
+    }
+
+    /// Read the datagrams waiting on `socket`, without blocking
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn recv(&mut self, socket: &UdpSocket) -> Result<(), io::Error> {
+        use std::mem;
+        use std::os::fd::AsRawFd;
+
+        use socket2::SockAddr;
+
+        // SAFETY: sockaddr_storage and mmsghdr are plain old data, for
+        // which all zeroes is valid
+        let mut addrs: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+
+        let mut iovecs = self.buffers.iter_mut()
+            .map(|buffer| libc::iovec {
+                iov_base: buffer.as_mut_ptr().cast(),
+                iov_len: buffer.len(),
+            })
+            .collect::<Vec<_>>();
+
+        let mut headers = iovecs.iter_mut()
+            .zip(&mut addrs)
+            .map(|(iovec, addr)| {
+                let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+                header.msg_hdr.msg_name = (addr as *mut libc::sockaddr_storage).cast();
+                header.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+                header.msg_hdr.msg_iov = iovec;
+                header.msg_hdr.msg_iovlen = 1;
+                header
+            })
+            .collect::<Vec<_>>();
+
+        // SAFETY: each header points at an iovec over one of our buffers
+        // and at an address to fill in, all of which outlive the call
+        let count = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                headers.as_mut_ptr(),
+                BATCH_SIZE as _,
+                libc::MSG_DONTWAIT as _,
+                std::ptr::null_mut(),
+            )
+        };
+
+        if count < 0 {
+            let err = io::Error::last_os_error();
+
+            // another thread got there first
+            if err.kind() == io::ErrorKind::WouldBlock {
+                return Ok(());
+            }
+
+            return Err(err);
+        }
+
+        for (index, header) in headers.iter().enumerate().take(count as usize) {
+            // SAFETY: the kernel filled in the address, of the length given
+            let addr = unsafe { SockAddr::new(addrs[index], header.msg_hdr.msg_namelen) };
+
+            let Some(addr) = addr.as_socket() else {
+                continue;
+            };
+
+            let len = header.msg_len as usize;
+            let buffer = PacketBuffer::from_raw(self.buffers[index][0..len].to_vec());
+            self.pending.push_back((buffer, PeerId::from(addr)));
+        }
+
+        Ok(())
+    }
+
+    /// Read the next datagram waiting on `socket`, without blocking
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn recv(&mut self, socket: &UdpSocket) -> Result<(), io::Error> {
+        socket.set_nonblocking(true)?;
+        let result = socket.recv_from(&mut self.buffers[0]);
+        socket.set_nonblocking(false)?;
+
+        match result {
+            Ok((len, addr)) => {
+                let buffer = PacketBuffer::from_raw(self.buffers[0][0..len].to_vec());
+                self.pending.push_back((buffer, PeerId::from(addr)));
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Send each of `msgs` to each of `dests`, to leave at `txtime` on
+/// CLOCK_MONOTONIC if given, see the `txtime` module
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn send(socket: &UdpSocket, msgs: &[&[u8]], dests: &[SocketAddrV4], txtime: Option<u64>) -> Result<(), io::Error> {
+    use std::mem;
+    use std::os::fd::AsRawFd;
+
+    use socket2::SockAddr;
+
+    let dests = dests.iter()
+        .map(|dest| SockAddr::from(*dest))
+        .collect::<Vec<_>>();
+
+    let mut iovecs = msgs.iter()
+        .map(|msg| libc::iovec {
+            // sendmmsg only reads from the buffer
+            iov_base: msg.as_ptr() as *mut libc::c_void,
+            iov_len: msg.len(),
+        })
+        .collect::<Vec<_>>();
+
+    // one SCM_TXTIME control message, shared by every header. u64s to
+    // keep it aligned for cmsghdr
+    let mut control = [0u64; 4];
+    let control_len = txtime_control(&mut control, txtime);
+
+    let mut headers = Vec::with_capacity(msgs.len() * dests.len());
+
+    for iovec in &mut iovecs {
+        for dest in &dests {
+            // SAFETY: mmsghdr is plain old data, for which all zeroes is valid
+            let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+            header.msg_hdr.msg_name = dest.as_ptr() as *mut libc::c_void;
+            header.msg_hdr.msg_namelen = dest.len();
+            header.msg_hdr.msg_iov = iovec;
+            header.msg_hdr.msg_iovlen = 1;
+
+            if control_len > 0 {
+                header.msg_hdr.msg_control = control.as_mut_ptr().cast();
+                header.msg_hdr.msg_controllen = control_len as _;
+            }
+
+            headers.push(header);
+        }
+    }
+
+    // the kernel may send fewer than asked, carry on from there
+    let mut sent = 0;
+
+    while sent < headers.len() {
+        let remaining = &mut headers[sent..];
+
+        // SAFETY: each header points at a message and destination address
+        // which outlive the call
+        let count = unsafe {
+            libc::sendmmsg(socket.as_raw_fd(), remaining.as_mut_ptr(), remaining.len() as _, 0)
+        };
+
+        if count < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        sent += count as usize;
+    }
+
+    Ok(())
+}
+
+/// Send each of `msgs` to each of `dests`. Transmit times aren't
+/// supported here, and `txtime::enable` fails so none are ever given
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn send(socket: &UdpSocket, msgs: &[&[u8]], dests: &[SocketAddrV4], _: Option<u64>) -> Result<(), io::Error> {
+    for msg in msgs {
+        for dest in dests {
+            socket.send_to(msg, dest)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Write a control message carrying `txtime` into `control`, returning its
+/// length, or 0 if there is none to send
+#[cfg(target_os = "linux")]
+fn txtime_control(control: &mut [u64; 4], txtime: Option<u64>) -> usize {
+    use std::mem;
+
+    let Some(txtime) = txtime else {
+        return 0;
+    };
+
+    // SAFETY: control is zeroed, aligned, and large enough for one cmsghdr
+    // carrying a u64, which is all CMSG_FIRSTHDR is asked to find room for
+    unsafe {
+        let len = libc::CMSG_SPACE(mem::size_of::<u64>() as u32) as usize;
+        assert!(len <= mem::size_of_val(control));
+
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = len as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_TXTIME;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u64>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<u64>(), txtime);
+
+        len
+    }
+}
+
+#[cfg(target_os = "android")]
+fn txtime_control(_: &mut [u64; 4], _: Option<u64>) -> usize {
+    0
+}
@@ -0,0 +1,167 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::time::Duration;
+
+use nix::sys::socket::{self, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockProtocol, SockType};
+use socket2::SockRef;
+
+// wait for link changes to settle before rejoining, bringing up an interface
+// generates a burst of link, address and route events
+const SETTLE: Duration = Duration::from_millis(500);
+
+/// Multicast group memberships, which can be rejoined after network changes
+pub struct Membership {
+    groups: Vec<(SocketAddrV4, UdpSocket)>,
+    // interface to join on, unspecified to follow the routing table
+    interface: Ipv4Addr,
+}
+
+impl Membership {
+    pub(super) fn new(groups: Vec<(SocketAddrV4, UdpSocket)>, interface: Ipv4Addr) -> Self {
+        Membership { groups, interface }
+    }
+
+    /// Leave and rejoin each multicast group, so that the kernel picks the
+    /// interface according to the current routing table, or picks up the
+    /// configured interface again once it's back
+    pub fn rejoin(&self) {
+        for (group, socket) in &self.groups {
+            if !group.ip().is_multicast() {
+                continue;
+            }
+
+            let socket = SockRef::from(socket);
+            let _ = socket.leave_multicast_v4(group.ip(), &self.interface);
+
+            match socket.join_multicast_v4(group.ip(), &self.interface) {
+                Ok(()) => log::info!("rejoined multicast group {group}"),
+                Err(e) => log::warn!("rejoining multicast group {group}: {e}"),
+            }
+        }
+    }
+}
+
+/// Watch for network link, address, and route changes, rejoining multicast
+/// groups whenever they happen. This lets a receiver fail over between
+/// interfaces (eg. Ethernet and WiFi), or carry on through its DHCP lease
+/// changing address, without restarting. Nothing else is tied to our
+/// address: replies go out from an unbound socket, sources learn it afresh
+/// from each packet, and stream timing comes from the packets themselves,
+/// so playback carries on undisturbed.
+pub fn start(membership: Membership) {
+    let netlink = match open_netlink() {
+        Ok(netlink) => netlink,
+        Err(e) => {
+            log::warn!("failed to monitor network changes: {e}");
+            return;
+        }
+    };
+
+    std::thread::spawn(move || {
+        bark_app::thread::set_name("bark/netlink");
+
+        let mut buffer = vec![0u8; 8192];
+
+        loop {
+            let len = match socket::recv(netlink.as_raw_fd(), &mut buffer, MsgFlags::empty()) {
+                Ok(len) => len,
+                Err(e) => {
+                    log::warn!("monitoring network changes: {e}");
+                    return;
+                }
+            };
+
+            log_address_changes(&buffer[..len]);
+
+            std::thread::sleep(SETTLE);
+
+            // drain any further events that arrived while settling
+            while let Ok(len) = socket::recv(netlink.as_raw_fd(), &mut buffer, MsgFlags::MSG_DONTWAIT) {
+                log_address_changes(&buffer[..len]);
+            }
+
+            log::info!("network changed, rejoining multicast groups");
+            membership.rejoin();
+        }
+    });
+}
+
+fn open_netlink() -> Result<OwnedFd, io::Error> {
+    let fd = socket::socket(
+        AddressFamily::Netlink,
+        SockType::Raw,
+        SockFlag::SOCK_CLOEXEC,
+        SockProtocol::NetlinkRoute,
+    ).map_err(io::Error::from)?;
+
+    let groups = libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV4_ROUTE;
+
+    socket::bind(fd.as_raw_fd(), &NetlinkAddr::new(0, groups as u32))
+        .map_err(io::Error::from)?;
+
+    Ok(fd)
+}
+
+/// Log IPv4 addresses added to or removed from our interfaces, such as when
+/// a DHCP lease changes address
+fn log_address_changes(mut messages: &[u8]) {
+    const NLMSG_HDRLEN: usize = 16;
+    const IFADDRMSG_LEN: usize = 8;
+
+    // each message is an nlmsghdr, padded to 4 bytes
+    while messages.len() >= NLMSG_HDRLEN {
+        let len = u32::from_ne_bytes([messages[0], messages[1], messages[2], messages[3]]) as usize;
+        let kind = u16::from_ne_bytes([messages[4], messages[5]]);
+
+        if len < NLMSG_HDRLEN || len > messages.len() {
+            return;
+        }
+
+        let added = match kind {
+            libc::RTM_NEWADDR => Some(true),
+            libc::RTM_DELADDR => Some(false),
+            _ => None,
+        };
+
+        // an ifaddrmsg, followed by attributes carrying the address
+        if let Some(added) = added {
+            let body = &messages[NLMSG_HDRLEN..len];
+
+            if body.len() >= IFADDRMSG_LEN && body[0] == libc::AF_INET as u8 {
+                if let Some(addr) = local_address(&body[IFADDRMSG_LEN..]) {
+                    match added {
+                        true => log::info!("local address {addr} added"),
+                        false => log::info!("local address {addr} removed"),
+                    }
+                }
+            }
+        }
+
+        messages = &messages[len.next_multiple_of(4).min(messages.len())..];
+    }
+}
+
+/// The IFA_LOCAL attribute of an address message
+fn local_address(mut attrs: &[u8]) -> Option<Ipv4Addr> {
+    const RTA_HDRLEN: usize = 4;
+
+    while attrs.len() >= RTA_HDRLEN {
+        let len = usize::from(u16::from_ne_bytes([attrs[0], attrs[1]]));
+        let kind = u16::from_ne_bytes([attrs[2], attrs[3]]);
+
+        if len < RTA_HDRLEN || len > attrs.len() {
+            return None;
+        }
+
+        let value = &attrs[RTA_HDRLEN..len];
+
+        if kind == libc::IFA_LOCAL && value.len() == 4 {
+            return Some(Ipv4Addr::new(value[0], value[1], value[2], value[3]));
+        }
+
+        attrs = &attrs[len.next_multiple_of(4).min(attrs.len())..];
+    }
+
+    None
+}
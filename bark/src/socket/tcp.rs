@@ -0,0 +1,258 @@
+//! TCP transport, for networks that drop UDP multicast such as guest WiFi.
+//! A node run with `--transport tcp --connect host:port` reaches another
+//! node listening with `--tcp-listen`, usually the source, over a single
+//! TCP connection. Packets are framed with a big endian u16 length and
+//! otherwise sent exactly as they would be over UDP.
+//!
+//! A listening node sends everything it broadcasts down each connection as
+//! well as to its multicast groups, and packets arriving on a connection
+//! are received as if they came from the connecting node, so replies to
+//! them find their way back.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsFd, BorrowedFd};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bark_app::thread;
+use bark_protocol::packet::MAX_PACKET_SIZE;
+
+use super::PeerId;
+
+/// Packets queued for sending on each connection, beyond which packets
+/// are dropped rather than holding up the audio thread
+const SEND_QUEUE: usize = 256;
+
+/// Packets read from connections awaiting receipt, beyond which they are
+/// dropped
+const RECV_QUEUE: usize = 256;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connections to and from other nodes, shared between the socket and the
+/// threads servicing each connection
+pub struct Streams {
+    connections: Mutex<Vec<Connection>>,
+    inbox: Mutex<VecDeque<(Vec<u8>, PeerId)>>,
+    // readable once for each packet in the inbox, so that receiving can
+    // poll for it alongside the UDP sockets
+    wake_rx: UnixStream,
+    wake_tx: UnixStream,
+}
+
+struct Connection {
+    peer: PeerId,
+    tx: SyncSender<Vec<u8>>,
+}
+
+impl Streams {
+    pub fn new() -> Result<Arc<Self>, io::Error> {
+        let (wake_rx, wake_tx) = UnixStream::pair()?;
+
+        Ok(Arc::new(Streams {
+            connections: Mutex::new(Vec::new()),
+            inbox: Mutex::new(VecDeque::new()),
+            wake_rx,
+            wake_tx,
+        }))
+    }
+
+    /// Accept connections from nodes on `addr`
+    pub fn listen(self: &Arc<Self>, addr: SocketAddr) -> Result<(), io::Error> {
+        let listener = TcpListener::bind(addr)?;
+        log::info!("accepting tcp transport connections on {addr}");
+
+        let streams = self.clone();
+
+        std::thread::spawn(move || {
+            thread::set_name("bark/tcp-listen");
+
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let streams = streams.clone();
+                        std::thread::spawn(move || {
+                            thread::set_name("bark/tcp-read");
+                            streams.serve(stream);
+                        });
+                    }
+                    Err(e) => log::warn!("error accepting tcp connection: {e}"),
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Stay connected to the node at `addr`, reconnecting whenever the
+    /// connection drops
+    pub fn connect(self: &Arc<Self>, addr: String) {
+        let streams = self.clone();
+
+        std::thread::spawn(move || {
+            thread::set_name("bark/tcp-conn");
+
+            // log each outage once rather than every attempt
+            let mut connected = true;
+
+            loop {
+                match open(&addr) {
+                    Ok(stream) => {
+                        connected = true;
+                        streams.serve(stream);
+                        log::warn!("tcp connection to {addr} lost, reconnecting");
+                    }
+                    Err(e) if connected => {
+                        connected = false;
+                        log::warn!("error connecting to {addr}, retrying: {e}");
+                    }
+                    Err(_) => {}
+                }
+
+                std::thread::sleep(RECONNECT_DELAY);
+            }
+        });
+    }
+
+    /// Send a packet down every connection
+    pub fn broadcast(&self, msg: &[u8]) {
+        for connection in self.connections.lock().unwrap().iter() {
+            connection.send(msg);
+        }
+    }
+
+    /// Send a packet to `peer` if it is connected, returning whether it was
+    pub fn send_to(&self, msg: &[u8], peer: PeerId) -> bool {
+        let connections = self.connections.lock().unwrap();
+
+        match connections.iter().find(|connection| connection.peer == peer) {
+            Some(connection) => {
+                connection.send(msg);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Readable while packets are waiting to be received
+    pub fn wake_fd(&self) -> BorrowedFd<'_> {
+        self.wake_rx.as_fd()
+    }
+
+    /// Take the next packet received, once `wake_fd` is readable
+    pub fn recv(&self) -> Result<Option<(Vec<u8>, PeerId)>, io::Error> {
+        let mut byte = [0];
+        (&self.wake_rx).read_exact(&mut byte)?;
+        Ok(self.inbox.lock().unwrap().pop_front())
+    }
+
+    /// Service a connection until it drops: packets read from it go to the
+    /// inbox, and a writer thread sends it what is queued for it
+    fn serve(&self, stream: TcpStream) {
+        let peer = match stream.peer_addr() {
+            Ok(addr) => PeerId::from(addr),
+            Err(e) => {
+                log::warn!("tcp connection has no peer address: {e}");
+                return;
+            }
+        };
+
+        let writer = match stream.try_clone() {
+            Ok(writer) => writer,
+            Err(e) => {
+                log::warn!("error setting up tcp connection with {peer}: {e}");
+                return;
+            }
+        };
+
+        // audio packets are small and latency matters more than throughput
+        let _ = stream.set_nodelay(true);
+
+        log::info!("tcp connection established with {peer}");
+
+        let (tx, rx) = mpsc::sync_channel::<Vec<u8>>(SEND_QUEUE);
+        self.connections.lock().unwrap().push(Connection { peer, tx });
+
+        std::thread::spawn(move || {
+            thread::set_name("bark/tcp-write");
+            write_frames(writer, rx);
+        });
+
+        if let Err(e) = self.read_frames(&stream, peer) {
+            log::warn!("tcp connection with {peer} closed: {e}");
+        }
+
+        // dropping the sender ends the writer thread
+        self.connections.lock().unwrap().retain(|connection| connection.peer != peer);
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+
+    fn read_frames(&self, mut stream: &TcpStream, peer: PeerId) -> Result<(), io::Error> {
+        loop {
+            let mut len = [0; 2];
+            stream.read_exact(&mut len)?;
+
+            let len = usize::from(u16::from_be_bytes(len));
+
+            if len > MAX_PACKET_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("frame of {len} bytes is larger than any packet")));
+            }
+
+            let mut packet = vec![0; len];
+            stream.read_exact(&mut packet)?;
+
+            let mut inbox = self.inbox.lock().unwrap();
+
+            if inbox.len() >= RECV_QUEUE {
+                continue;
+            }
+
+            inbox.push_back((packet, peer));
+            drop(inbox);
+
+            (&self.wake_tx).write_all(&[0])?;
+        }
+    }
+}
+
+impl Connection {
+    fn send(&self, msg: &[u8]) {
+        // if the connection is backed up or going away, drop the packet
+        // rather than wait for it
+        let _ = self.tx.try_send(msg.to_vec());
+    }
+}
+
+fn open(addr: &str) -> Result<TcpStream, io::Error> {
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "address resolved to nothing");
+
+    for addr in std::net::ToSocketAddrs::to_socket_addrs(addr)? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = e,
+        }
+    }
+
+    Err(last_err)
+}
+
+fn write_frames(mut stream: TcpStream, rx: mpsc::Receiver<Vec<u8>>) {
+    for packet in rx {
+        // length and packet in one write, so they go out in one segment
+        let mut frame = Vec::with_capacity(2 + packet.len());
+        frame.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&packet);
+
+        if stream.write_all(&frame).is_err() {
+            // wakes the reader, which cleans up the connection
+            let _ = stream.shutdown(Shutdown::Both);
+            return;
+        }
+    }
+}
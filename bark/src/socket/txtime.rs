@@ -0,0 +1,73 @@
+//! Transmit times for outgoing packets with SO_TXTIME, so that the kernel
+//! hands each packet to the NIC at the moment it is due rather than as
+//! soon as it is sent. Packets are sent ahead of time and released at
+//! precise intervals, taking scheduling jitter on the sending thread out
+//! of network timing.
+//!
+//! Times are given against CLOCK_MONOTONIC, which the fq qdisc honours:
+//!
+//!     tc qdisc replace dev eth0 root fq
+//!
+//! Packets sent without a transmit time, such as control packets, go out
+//! straight away as usual.
+
+use std::io;
+use std::net::UdpSocket;
+
+use bark_protocol::types::TimestampMicros;
+
+use crate::time;
+
+/// Ask the kernel to honour transmit times on packets sent from `socket`
+#[cfg(target_os = "linux")]
+pub fn enable(socket: &UdpSocket) -> Result<(), io::Error> {
+    use std::mem;
+    use std::os::fd::AsRawFd;
+
+    // struct sock_txtime from linux/net_tstamp.h
+    #[repr(C)]
+    struct SockTxtime {
+        clockid: libc::clockid_t,
+        flags: u32,
+    }
+
+    let config = SockTxtime {
+        clockid: libc::CLOCK_MONOTONIC,
+        flags: 0,
+    };
+
+    // SAFETY: config is a valid sock_txtime of the length given
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_TXTIME,
+            (&config as *const SockTxtime).cast(),
+            mem::size_of::<SockTxtime>() as libc::socklen_t,
+        )
+    };
+
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enable(_: &UdpSocket) -> Result<(), io::Error> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "SO_TXTIME is only supported on linux"))
+}
+
+/// Convert a time on the audio clock into a transmit time in nanoseconds
+/// on CLOCK_MONOTONIC. Times already past are sent straight away
+pub fn monotonic_nanos(at: TimestampMicros) -> u64 {
+    let now = time::now();
+
+    let monotonic = nix::time::clock_gettime(nix::time::ClockId::CLOCK_MONOTONIC)
+        .expect("clock_gettime");
+
+    let monotonic = monotonic.tv_sec() as u64 * 1_000_000_000 + monotonic.tv_nsec() as u64;
+
+    monotonic + at.0.saturating_sub(now.0) * 1000
+}
@@ -0,0 +1,182 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use bark_protocol::packet::StatsReply;
+use bark_protocol::types::StatsReplyFlags;
+
+use crate::socket::PeerId;
+use super::{json, node};
+
+const HEADER: &[&str] = &[
+    "time",
+    "peer",
+    "kind",
+    "session",
+    "username",
+    "hostname",
+    "stream",
+    "audio_latency",
+    "output_latency",
+    "network_latency",
+    "output_device",
+    "volume",
+    "muted",
+    "max_volume",
+    "volume_locked",
+    "zone",
+    "zone_muted",
+    "clock_drift_ppm",
+    "stream_position",
+    "name",
+    "id",
+];
+
+/// When to start a new file, moving older ones aside as `<path>.1`,
+/// `<path>.2` and so on
+#[derive(Clone, Copy)]
+pub struct Rotation {
+    pub max_bytes: u64,
+    /// Older files kept, beyond which the oldest is deleted
+    pub keep: usize,
+}
+
+/// Appends stats to a CSV file, one row per peer each time it's written
+pub struct CsvLog {
+    path: PathBuf,
+    file: BufWriter<File>,
+    rotation: Option<Rotation>,
+}
+
+impl CsvLog {
+    /// Open a log for appending, writing a header if the file is new
+    pub fn open(path: &Path, rotation: Option<Rotation>) -> Result<Self, io::Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+
+        let is_new = file.metadata()?.len() == 0;
+
+        let mut log = CsvLog {
+            path: path.to_owned(),
+            file: BufWriter::new(file),
+            rotation,
+        };
+
+        if is_new {
+            log.row(HEADER.iter().map(|field| field.to_string()))?;
+            log.file.flush()?;
+        }
+
+        Ok(log)
+    }
+
+    pub fn write(&mut self, time: SystemTime, entries: &[(PeerId, &StatsReply)]) -> Result<(), io::Error> {
+        let time = time.duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+
+        for (peer, reply) in entries {
+            let data = reply.data();
+            let is_receiver = reply.flags().contains(StatsReplyFlags::IS_RECEIVER);
+
+            let mut row = vec![
+                format!("{time:.3}"),
+                peer.to_string(),
+                if is_receiver { "receiver" } else { "source" }.to_owned(),
+                data.sid.0.to_string(),
+                node::username(&data.node).to_owned(),
+                node::hostname(&data.node).to_owned(),
+            ];
+
+            if is_receiver {
+                let stats = &data.receiver;
+
+                row.extend([
+                    stats.stream().map(json::stream_status).map(str::to_owned).unwrap_or_default(),
+                    optional(stats.audio_latency()),
+                    optional(stats.output_latency()),
+                    optional(stats.network_latency()),
+                    stats.output_device().unwrap_or_default().to_owned(),
+                    optional(stats.volume()),
+                    stats.muted().to_string(),
+                    optional(stats.max_volume()),
+                    stats.volume_locked().to_string(),
+                    stats.zone().unwrap_or_default().to_owned(),
+                    stats.zone_muted().to_string(),
+                    optional(stats.clock_drift()),
+                    optional(stats.stream_position()),
+                ]);
+            }
+
+            // node identity comes last, after receiver fields, which are
+            // left empty for sources
+            row.resize(HEADER.len() - 2, String::new());
+            row.extend([
+                node::name(&data.node).unwrap_or_default().to_owned(),
+                node::id(&data.node).unwrap_or_default(),
+            ]);
+
+            self.row(row)?;
+        }
+
+        self.file.flush()?;
+
+        if let Some(rotation) = self.rotation {
+            if self.file.get_ref().metadata()?.len() >= rotation.max_bytes {
+                self.rotate(rotation)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Move the current file aside and start a new one
+    fn rotate(&mut self, rotation: Rotation) -> Result<(), io::Error> {
+        // shuffle older files along, the oldest falling off the end
+        for n in (1..rotation.keep).rev() {
+            let from = numbered(&self.path, n);
+
+            if from.exists() {
+                fs::rename(&from, numbered(&self.path, n + 1))?;
+            }
+        }
+
+        if rotation.keep > 0 {
+            fs::rename(&self.path, numbered(&self.path, 1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+
+        *self = CsvLog::open(&self.path.clone(), self.rotation)?;
+        Ok(())
+    }
+
+    fn row(&mut self, fields: impl IntoIterator<Item = String>) -> Result<(), io::Error> {
+        let fields = fields.into_iter()
+            .map(|field| escape(&field))
+            .collect::<Vec<_>>();
+
+        writeln!(self.file, "{}", fields.join(","))
+    }
+}
+
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{n}"));
+    PathBuf::from(path)
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+fn escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
@@ -0,0 +1,98 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+#[cfg(feature = "http")]
+use std::time::Duration;
+
+use crate::time;
+
+/// How long the main loop may go without going round before the node is
+/// considered stuck
+#[cfg(feature = "http")]
+const RUNNING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long since audio was last sent or received that a stream still
+/// counts as active
+#[cfg(feature = "http")]
+const STREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+// stored in place of a time for events that have never happened
+const NEVER: u64 = 0;
+
+/// State of a node's subsystems, as reported to supervisors through the
+/// health endpoints. Updated from the audio and network threads as they go
+pub struct Health {
+    // times on the bark clock, in microseconds
+    heartbeat: AtomicU64,
+    audio: AtomicU64,
+    // audio devices currently disconnected and waiting to be reopened
+    devices_lost: AtomicUsize,
+    // whether the device failed to open when it was last needed
+    open_failed: AtomicBool,
+}
+
+/// Snapshot of each check
+#[cfg(feature = "http")]
+pub struct Checks {
+    /// The main loop is going round, rather than stuck or stopped
+    pub running: bool,
+    /// Every audio device in use is open
+    pub device: bool,
+    /// Audio has been sent or received recently
+    pub stream: bool,
+    /// Time since audio was last sent or received
+    pub last_audio: Option<Duration>,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Health {
+            heartbeat: AtomicU64::new(NEVER),
+            audio: AtomicU64::new(NEVER),
+            devices_lost: AtomicUsize::new(0),
+            open_failed: AtomicBool::new(false),
+        }
+    }
+
+    /// Mark the main loop as having gone round
+    pub fn heartbeat(&self) {
+        self.heartbeat.store(time::now().0, Ordering::Relaxed);
+    }
+
+    /// Mark audio as having been sent or received
+    pub fn audio(&self) {
+        self.audio.store(time::now().0, Ordering::Relaxed);
+    }
+
+    pub fn device_lost(&self) {
+        self.devices_lost.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn device_restored(&self) {
+        self.devices_lost.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn set_open_failed(&self, failed: bool) {
+        self.open_failed.store(failed, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "http")]
+    pub fn check(&self) -> Checks {
+        let now = time::now().0;
+
+        let since = |event: &AtomicU64| {
+            match event.load(Ordering::Relaxed) {
+                NEVER => None,
+                at => Some(Duration::from_micros(now.saturating_sub(at))),
+            }
+        };
+
+        let last_audio = since(&self.audio);
+
+        Checks {
+            running: since(&self.heartbeat).is_some_and(|since| since < RUNNING_TIMEOUT),
+            device: self.devices_lost.load(Ordering::Relaxed) == 0 &&
+                !self.open_failed.load(Ordering::Relaxed),
+            stream: last_audio.is_some_and(|since| since < STREAM_TIMEOUT),
+            last_audio,
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use serde::Serialize;
+
+use bark_protocol::packet::StatsReply;
+use bark_protocol::types::StatsReplyFlags;
+use bark_protocol::types::stats::receiver::{ReceiverStats, StreamStatus};
+use bark_protocol::types::stats::node::NodeStats;
+use bark_protocol::types::stats::source::SourceStats;
+
+use crate::socket::PeerId;
+use super::node;
+
+#[derive(Serialize)]
+pub struct Snapshot<'a> {
+    peers: Vec<Peer<'a>>,
+}
+
+#[derive(Serialize)]
+struct Peer<'a> {
+    peer: String,
+    kind: &'static str,
+    session: i64,
+    node: Node<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    receiver: Option<Receiver<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<Source>,
+}
+
+#[derive(Serialize)]
+struct Node<'a> {
+    username: &'a str,
+    hostname: &'a str,
+    name: Option<&'a str>,
+    id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Receiver<'a> {
+    stream: Option<&'static str>,
+    audio_latency: Option<f64>,
+    output_latency: Option<f64>,
+    network_latency: Option<f64>,
+    clock_drift_ppm: Option<f64>,
+    stream_position: Option<f64>,
+    decoders: Option<Vec<&'static str>>,
+    max_packet_frames: Option<usize>,
+    output_device: Option<&'a str>,
+    volume: Option<f32>,
+    muted: bool,
+    max_volume: Option<f32>,
+    volume_locked: bool,
+    zone: Option<&'a str>,
+    zone_muted: bool,
+}
+
+#[derive(Serialize)]
+struct Source {
+    codec: Option<&'static str>,
+    packet_frames: Option<u16>,
+    bitrate: Option<f64>,
+    packet_rate: f64,
+    uptime: f64,
+    receivers: u32,
+    loss: Option<f64>,
+    jitter: Option<f64>,
+}
+
+pub fn snapshot<'a>(entries: &[(PeerId, &'a StatsReply)]) -> Snapshot<'a> {
+    let peers = entries.iter()
+        .map(|(peer, reply)| self::peer(*peer, reply))
+        .collect();
+
+    Snapshot { peers }
+}
+
+fn peer(peer: PeerId, reply: &StatsReply) -> Peer<'_> {
+    let data = reply.data();
+    let is_receiver = reply.flags().contains(StatsReplyFlags::IS_RECEIVER);
+
+    Peer {
+        peer: peer.to_string(),
+        kind: if is_receiver { "receiver" } else { "source" },
+        session: data.sid.0,
+        node: self::node(&data.node),
+        receiver: is_receiver.then(|| receiver(&data.receiver)),
+        source: (!is_receiver).then(|| source(&data.source)),
+    }
+}
+
+fn node(stats: &NodeStats) -> Node<'_> {
+    Node {
+        username: node::username(stats),
+        hostname: node::hostname(stats),
+        name: node::name(stats),
+        id: node::id(stats),
+    }
+}
+
+fn receiver(stats: &ReceiverStats) -> Receiver<'_> {
+    Receiver {
+        stream: stats.stream().map(stream_status),
+        audio_latency: stats.audio_latency(),
+        output_latency: stats.output_latency(),
+        network_latency: stats.network_latency(),
+        clock_drift_ppm: stats.clock_drift(),
+        stream_position: stats.stream_position(),
+        decoders: stats.decoders().map(|decoders| decoders.iter().filter_map(|format| format.name()).collect()),
+        max_packet_frames: stats.max_packet_frames(),
+        output_device: stats.output_device(),
+        volume: stats.volume(),
+        muted: stats.muted(),
+        max_volume: stats.max_volume(),
+        volume_locked: stats.volume_locked(),
+        zone: stats.zone(),
+        zone_muted: stats.zone_muted(),
+    }
+}
+
+fn source(stats: &SourceStats) -> Source {
+    Source {
+        codec: stats.format().and_then(|format| format.name()),
+        packet_frames: stats.packet_frames(),
+        bitrate: stats.bitrate(),
+        packet_rate: stats.packet_rate(),
+        uptime: stats.uptime(),
+        receivers: stats.receivers(),
+        loss: stats.reception().map(|(loss, _)| loss),
+        jitter: stats.reception().map(|(_, jitter)| jitter),
+    }
+}
+
+pub fn stream_status(status: StreamStatus) -> &'static str {
+    match status {
+        StreamStatus::Seek => "seek",
+        StreamStatus::Sync => "sync",
+        StreamStatus::Slew => "slew",
+        StreamStatus::Miss => "miss",
+    }
+}
@@ -2,7 +2,9 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use bark_protocol::time::{SampleDuration, TimestampDelta};
+use bark_protocol::SampleRate;
 
+use super::health::Health;
 use super::value::{Counter, Gauge};
 
 pub type ReceiverMetrics = Arc<ReceiverMetricsData>;
@@ -12,13 +14,27 @@ pub struct ReceiverMetricsData {
     pub audio_offset: Gauge<Option<TimestampDelta>>,
     pub buffer_delay: Gauge<SampleDuration>,
     pub buffer_underruns: Counter,
+    pub buffer_target: Gauge<SampleDuration>,
+    pub resample_rate: Gauge<SampleRate>,
+    /// Output device clock drift relative to the stream, in parts per billion
+    pub clock_drift: Gauge<i64>,
     pub queued_packets: Gauge<usize>,
     pub network_latency: Gauge<Duration>,
+    pub latency_outliers: Counter,
     pub packets_received: Counter,
     pub packets_lost: Counter,
     pub packets_missed: Counter,
+    pub packets_duplicate: Counter,
     pub frames_decoded: Counter,
     pub frames_played: Counter,
+    pub frames_concealed: Counter,
+    pub frames_silenced: Counter,
+    pub frames_slewed: Counter,
+    pub control_rejected: Counter,
+    pub output_reconnects: Counter,
+    pub stream_stops_slow: Counter,
+    pub stream_stops_leaked: Counter,
+    pub health: Health,
 }
 
 impl ReceiverMetricsData {
@@ -27,21 +43,62 @@ impl ReceiverMetricsData {
             audio_offset: Gauge::new("bark_receiver_audio_offset_usec"),
             buffer_delay: Gauge::new("bark_receiver_buffer_delay_usec"),
             buffer_underruns: Counter::new("bark_receiver_buffer_underruns"),
+            buffer_target: Gauge::new("bark_receiver_buffer_target_usec"),
+            resample_rate: Gauge::new("bark_receiver_resample_rate_hz"),
+            clock_drift: Gauge::new("bark_receiver_clock_drift_ppb"),
             network_latency: Gauge::new("bark_receiver_network_latency_usec"),
+            latency_outliers: Counter::new("bark_receiver_latency_outliers"),
             queued_packets: Gauge::new("bark_receiver_queued_packet_count"),
             packets_received: Counter::new("bark_receiver_packets_received"),
             packets_lost: Counter::new("bark_receiver_packets_lost"),
             packets_missed: Counter::new("bark_receiver_packets_missed"),
+            packets_duplicate: Counter::new("bark_receiver_packets_duplicate"),
             frames_decoded: Counter::new("bark_receiver_frames_decoded"),
             frames_played: Counter::new("bark_receiver_frames_played"),
+            frames_concealed: Counter::new("bark_receiver_frames_concealed"),
+            frames_silenced: Counter::new("bark_receiver_frames_silenced"),
+            frames_slewed: Counter::new("bark_receiver_frames_slewed"),
+            control_rejected: Counter::new("bark_receiver_control_rejected"),
+            output_reconnects: Counter::new("bark_receiver_output_reconnects"),
+            stream_stops_slow: Counter::new("bark_receiver_stream_stops_slow"),
+            stream_stops_leaked: Counter::new("bark_receiver_stream_stops_leaked"),
+            health: Health::new(),
         }
     }
 }
 
-pub struct SourceMetricsData {}
+pub struct SourceMetricsData {
+    pub packets_sent: Counter,
+    pub packets_redundant: Counter,
+    /// Packets dropped because they couldn't be sent, eg. while the network
+    /// interface is down
+    pub packets_dropped: Counter,
+    pub packets_corrected: Counter,
+    /// How long audio input has been exact digital silence, zero if not
+    pub input_silence: Gauge<Duration>,
+    pub input_reconnects: Counter,
+    /// Packets receivers report never having received, across receivers
+    /// currently reporting
+    pub receiver_packets_lost: Gauge<u64>,
+    /// Worst interarrival jitter receivers report
+    pub receiver_jitter: Gauge<Duration>,
+    pub control_rejected: Counter,
+    pub health: Health,
+}
 
 impl SourceMetricsData {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            packets_sent: Counter::new("bark_source_packets_sent"),
+            packets_redundant: Counter::new("bark_source_packets_redundant"),
+            packets_dropped: Counter::new("bark_source_packets_dropped"),
+            packets_corrected: Counter::new("bark_source_packets_corrected"),
+            input_silence: Gauge::new("bark_source_input_digital_silence_usec"),
+            input_reconnects: Counter::new("bark_source_input_reconnects"),
+            receiver_packets_lost: Gauge::new("bark_source_receiver_packets_lost"),
+            receiver_jitter: Gauge::new("bark_source_receiver_jitter_usec"),
+            control_rejected: Counter::new("bark_source_control_rejected"),
+            health: Health::new(),
+        }
     }
 }
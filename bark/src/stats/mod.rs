@@ -1,31 +1,124 @@
+pub mod csv;
+pub mod health;
+pub mod json;
 pub mod metrics;
 pub mod node;
 pub mod render;
+#[cfg(feature = "http")]
 pub mod server;
 pub mod value;
+#[cfg(feature = "http")]
+pub mod web;
 
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant, SystemTime};
 use std::io::Write;
 
+use bark_app::metrics::MetricsOpt;
 use structopt::StructOpt;
 use termcolor::BufferedStandardStream;
 
-use bark_protocol::packet::{StatsRequest, StatsReply, PacketKind};
+use bark_protocol::packet::{Metadata, StatsRequest, StatsReply, PacketKind};
 use bark_protocol::types::StatsReplyFlags;
 
 use crate::socket::{Socket, SocketOpt, PeerId, ProtocolSocket};
 use crate::RunError;
 
+use self::csv::{CsvLog, Rotation};
 use self::render::Padding;
 
 pub use metrics::{ReceiverMetrics, SourceMetrics};
 
+/// Start collecting receiver metrics, serving them over HTTP along with an
+/// API that can request a resync by setting `resync`
+#[cfg(feature = "http")]
+pub async fn start_receiver(opt: &MetricsOpt, resync: Arc<AtomicBool>) -> Result<ReceiverMetrics, RunError> {
+    Ok(server::start_receiver(opt, resync).await?)
+}
+
+/// Start collecting source metrics, serving them over HTTP along with the
+/// web control UI, joined to the groups in `web_ui`, if given
+#[cfg(feature = "http")]
+pub async fn start_source(opt: &MetricsOpt, web_ui: Option<&SocketOpt>) -> Result<SourceMetrics, RunError> {
+    let web = web_ui.map(web::router).transpose()?;
+    Ok(server::start_source(opt, web).await?)
+}
+
+#[cfg(not(feature = "http"))]
+pub async fn start_receiver(opt: &MetricsOpt, _resync: Arc<AtomicBool>) -> Result<ReceiverMetrics, RunError> {
+    log::debug!("built without http, not serving metrics on {}", opt.listen());
+    Ok(Arc::new(metrics::ReceiverMetricsData::new()))
+}
+
+#[cfg(not(feature = "http"))]
+pub async fn start_source(opt: &MetricsOpt, web_ui: Option<&SocketOpt>) -> Result<SourceMetrics, RunError> {
+    if web_ui.is_some() {
+        log::warn!("built without http, the web UI is not available");
+    }
+
+    log::debug!("built without http, not serving metrics on {}", opt.listen());
+    Ok(Arc::new(metrics::SourceMetricsData::new()))
+}
+
 #[derive(StructOpt)]
 pub struct StatsOpt {
     #[structopt(flatten)]
     pub socket: SocketOpt,
+
+    /// Print stats as JSON rather than a live terminal view
+    #[structopt(long)]
+    pub json: bool,
+
+    /// Print a single snapshot of stats and exit
+    #[structopt(long)]
+    pub once: bool,
+
+    /// Append stats to a CSV file, one row per peer each interval
+    #[structopt(long, alias = "record", env = "BARK_STATS_LOG_CSV")]
+    pub log_csv: Option<PathBuf>,
+
+    /// Start a new CSV file once it grows past this many megabytes, moving
+    /// older ones aside as <file>.1, <file>.2 and so on
+    #[structopt(long, env = "BARK_STATS_LOG_CSV_MAX_MB")]
+    pub log_csv_max_mb: Option<u64>,
+
+    /// How many older CSV files to keep when rotating
+    #[structopt(long, env = "BARK_STATS_LOG_CSV_KEEP", default_value = "5")]
+    pub log_csv_keep: usize,
+
+    /// How often to print JSON snapshots or log to CSV, eg. 1s or 500ms
+    #[structopt(long, env = "BARK_STATS_INTERVAL", default_value = "1s", parse(try_from_str = parse_interval))]
+    pub interval: Duration,
+}
+
+// how long to collect replies for before printing with --once
+const ONCE_COLLECT: Duration = Duration::from_millis(500);
+
+// how long a source's now playing metadata is shown for after it was last
+// sent, a few times longer than the interval sources repeat it at
+const METADATA_EXPIRY: Duration = Duration::from_secs(10);
+
+fn parse_interval(interval: &str) -> Result<Duration, String> {
+    let (number, scale) = if let Some(ms) = interval.strip_suffix("ms") {
+        (ms, 0.001)
+    } else if let Some(secs) = interval.strip_suffix('s') {
+        (secs, 1.0)
+    } else if let Some(mins) = interval.strip_suffix('m') {
+        (mins, 60.0)
+    } else {
+        (interval, 1.0)
+    };
+
+    let value = number.trim().parse::<f64>()
+        .map_err(|_| format!("invalid interval: {interval}"))?;
+
+    Duration::try_from_secs_f64(value * scale)
+        .ok()
+        .filter(|duration| !duration.is_zero())
+        .ok_or_else(|| format!("invalid interval: {interval}"))
 }
 
 pub fn run(opt: StatsOpt) -> Result<(), RunError> {
@@ -48,56 +141,153 @@ pub fn run(opt: StatsOpt) -> Result<(), RunError> {
         }
     });
 
+    let rotation = opt.log_csv_max_mb.map(|max_mb| Rotation {
+        max_bytes: max_mb * 1024 * 1024,
+        keep: opt.log_csv_keep,
+    });
+
+    let mut csv = opt.log_csv.as_deref()
+        .map(|path| CsvLog::open(path, rotation))
+        .transpose()
+        .map_err(RunError::StatsLog)?;
+
     let mut stats = HashMap::<PeerId, Entry>::new();
+    let mut now_playing = HashMap::<PeerId, (Instant, Metadata)>::new();
+    let started = Instant::now();
+    let mut last_json = started;
+    let mut last_csv = started;
 
     loop {
-        let (reply, peer) = protocol.recv_from().map_err(RunError::Receive)?;
+        // with --once, wait no longer than the rest of the collection window,
+        // so nodes not replying doesn't keep us waiting forever
+        let timeout = opt.once.then(|| ONCE_COLLECT.saturating_sub(started.elapsed()));
 
-        let Some(PacketKind::StatsReply(reply)) = reply.parse() else {
-            continue;
+        let received = protocol.recv_from_timeout(timeout)
+            .map_err(RunError::Receive)?;
+
+        let Some((reply, peer)) = received else {
+            print_once(opt.json, &stats, &now_playing);
+            return Ok(());
+        };
+
+        let reply = match reply.parse() {
+            Some(PacketKind::StatsReply(reply)) => reply,
+            Some(PacketKind::Metadata(metadata)) => {
+                now_playing.insert(peer, (Instant::now(), metadata));
+                continue;
+            }
+            _ => continue,
         };
 
         let prev_entries = stats.len();
 
         let now = Instant::now();
+        now_playing.retain(|_, (time, _)| now.duration_since(*time) < METADATA_EXPIRY);
         stats.insert(peer, Entry { time: now, reply });
         stats.retain(|_, ent| ent.valid_at(now));
 
-        let current_entries = stats.len();
+        if let Some(csv) = csv.as_mut() {
+            if now.duration_since(last_csv) >= opt.interval {
+                let entries = sorted_entries(&stats).into_iter()
+                    .map(|(peer, entry)| (*peer, &entry.reply))
+                    .collect::<Vec<_>>();
+
+                csv.write(SystemTime::now(), &entries)
+                    .map_err(RunError::StatsLog)?;
 
-        let mut out = BufferedStandardStream::stdout(termcolor::ColorChoice::Auto);
+                last_csv = now;
+            }
+        }
 
-        // move cursor up:
-        move_cursor_up(&mut out, prev_entries);
+        if opt.once {
+            if now.duration_since(started) >= ONCE_COLLECT {
+                print_once(opt.json, &stats, &now_playing);
+                return Ok(());
+            }
+        } else if opt.json {
+            if now.duration_since(last_json) >= opt.interval {
+                print_json(&stats);
+                last_json = now;
+            }
+        } else {
+            render_terminal(&stats, &now_playing, prev_entries);
+        }
+    }
+}
 
-        // write stats for stream sources first
-        let mut stats = stats.iter().collect::<Vec<_>>();
-        stats.sort_by_key(|(peer, entry)| (entry.is_receiver(), *peer));
+/// Print everything collected for --once
+fn print_once(
+    json: bool,
+    stats: &HashMap<PeerId, Entry>,
+    now_playing: &HashMap<PeerId, (Instant, Metadata)>,
+) {
+    if json {
+        print_json(stats);
+    } else {
+        render_terminal(stats, now_playing, 0);
+    }
+}
 
-        let mut padding = Padding::default();
+fn sorted_entries(stats: &HashMap<PeerId, Entry>) -> Vec<(&PeerId, &Entry)> {
+    // stream sources first
+    let mut stats = stats.iter().collect::<Vec<_>>();
+    stats.sort_by_key(|(peer, entry)| (entry.is_receiver(), *peer));
+    stats
+}
 
-        for (peer, entry) in &stats {
-            render::calculate(&mut padding, entry.reply.data(), **peer);
-        }
+fn print_json(stats: &HashMap<PeerId, Entry>) {
+    match json_snapshot(stats) {
+        Ok(json) => { println!("{json}"); }
+        Err(e) => { log::error!("error serializing stats: {e}"); }
+    }
+}
+
+fn json_snapshot(stats: &HashMap<PeerId, Entry>) -> Result<String, serde_json::Error> {
+    let entries = sorted_entries(stats).into_iter()
+        .map(|(peer, entry)| (*peer, &entry.reply))
+        .collect::<Vec<_>>();
+
+    serde_json::to_string(&json::snapshot(&entries))
+}
+
+fn render_terminal(
+    stats: &HashMap<PeerId, Entry>,
+    now_playing: &HashMap<PeerId, (Instant, Metadata)>,
+    prev_entries: usize,
+) {
+    let current_entries = stats.len();
+
+    let mut out = BufferedStandardStream::stdout(termcolor::ColorChoice::Auto);
+
+    // move cursor up:
+    move_cursor_up(&mut out, prev_entries);
+
+    let stats = sorted_entries(stats);
+
+    let mut padding = Padding::default();
+
+    for (peer, entry) in &stats {
+        render::calculate(&mut padding, entry.reply.data(), **peer);
+    }
 
-        for (peer, entry) in &stats {
-            // kill line
+    for (peer, entry) in &stats {
+        // kill line
+        kill_line(&mut out);
+        let metadata = now_playing.get(*peer).map(|(_, metadata)| metadata);
+        render::line(&mut out, &padding, &entry.reply, metadata, **peer);
+        new_line(&mut out);
+    }
+
+    if current_entries < prev_entries {
+        let remove_lines = prev_entries - current_entries;
+        for _ in 0..remove_lines {
             kill_line(&mut out);
-            render::line(&mut out, &padding, &entry.reply, **peer);
             new_line(&mut out);
         }
-
-        if current_entries < prev_entries {
-            let remove_lines = prev_entries - current_entries;
-            for _ in 0..remove_lines {
-                kill_line(&mut out);
-                new_line(&mut out);
-            }
-            move_cursor_up(&mut out, remove_lines);
-        }
-
-        let _ = out.flush();
+        move_cursor_up(&mut out, remove_lines);
     }
+
+    let _ = out.flush();
 }
 
 fn move_cursor_up(out: &mut BufferedStandardStream, lines: usize) {
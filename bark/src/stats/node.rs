@@ -1,21 +1,71 @@
+use std::sync::OnceLock;
+
 use bark_protocol::types::stats::node::NodeStats;
 
+static IDENTITY: OnceLock<Identity> = OnceLock::new();
+
+/// Persistent id and name of a receiver, reported in its stats
+pub struct Identity {
+    pub id: [u8; 16],
+    pub name: Option<String>,
+}
+
+/// Set the identity reported by `get`, before stats are first taken
+pub fn set_identity(identity: Identity) {
+    let _ = IDENTITY.set(identity);
+}
+
 pub fn get() -> NodeStats {
     let username = get_username();
     let hostname = get_hostname();
+    let identity = IDENTITY.get();
+
+    let name = identity
+        .and_then(|identity| identity.name.as_deref())
+        .unwrap_or_default();
 
     NodeStats {
         username: as_fixed(&username),
         hostname: as_fixed(&hostname),
+        id: identity.map(|identity| identity.id).unwrap_or_default(),
+        name: as_fixed(name),
     }
 }
 
+/// The node's name if it has one, or user@host
 pub fn display(stats: &NodeStats) -> String {
-    let username = from_fixed(&stats.username);
-    let hostname = from_fixed(&stats.hostname);
+    if let Some(name) = name(stats) {
+        return name.to_owned();
+    }
+
+    let username = username(stats);
+    let hostname = hostname(stats);
     format!("{username}@{hostname}")
 }
 
+pub fn username(stats: &NodeStats) -> &str {
+    from_fixed(&stats.username)
+}
+
+pub fn hostname(stats: &NodeStats) -> &str {
+    from_fixed(&stats.hostname)
+}
+
+pub fn name(stats: &NodeStats) -> Option<&str> {
+    Some(from_fixed(&stats.name)).filter(|name| !name.is_empty())
+}
+
+/// Persistent id as a uuid, for receivers that send one
+pub fn id(stats: &NodeStats) -> Option<String> {
+    (stats.id != [0; 16]).then(|| format_id(&stats.id))
+}
+
+/// Format an id as a hyphenated uuid
+pub fn format_id(id: &[u8; 16]) -> String {
+    let hex = id.iter().map(|byte| format!("{byte:02x}")).collect::<String>();
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
 fn from_fixed(bytes: &[u8]) -> &str {
     let len = bytes.iter()
         .position(|b| *b == 0)
@@ -26,7 +76,14 @@ fn from_fixed(bytes: &[u8]) -> &str {
 
 fn as_fixed(s: &str) -> [u8; 32] {
     let mut buff = [0u8; 32];
-    buff[0..s.as_bytes().len()].copy_from_slice(s.as_bytes());
+
+    // truncate anything too long on a char boundary
+    let mut len = s.len().min(buff.len());
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+
+    buff[0..len].copy_from_slice(&s.as_bytes()[0..len]);
     buff
 }
 
@@ -1,9 +1,10 @@
 use termcolor::{WriteColor, ColorSpec, Color};
 
-use bark_protocol::packet::StatsReply;
+use bark_protocol::packet::{Metadata, StatsReply};
 use bark_protocol::types::{StatsReplyPacket, StatsReplyFlags};
 use bark_protocol::types::stats::receiver::{ReceiverStats, StreamStatus};
 use bark_protocol::types::stats::node::NodeStats;
+use bark_protocol::types::stats::source::SourceStats;
 
 use crate::socket::PeerId;
 use super::node;
@@ -22,7 +23,7 @@ pub fn calculate(padding: &mut Padding, stats: &StatsReplyPacket, peer: PeerId)
     padding.peer_width = std::cmp::max(padding.peer_width, peer_width);
 }
 
-pub fn line(out: &mut dyn WriteColor, padding: &Padding, stats: &StatsReply, peer: PeerId) {
+pub fn line(out: &mut dyn WriteColor, padding: &Padding, stats: &StatsReply, metadata: Option<&Metadata>, peer: PeerId) {
     node(out, padding, &stats.data().node, peer);
 
     if stats.flags().contains(StatsReplyFlags::IS_RECEIVER) {
@@ -33,6 +34,57 @@ pub fn line(out: &mut dyn WriteColor, padding: &Padding, stats: &StatsReply, pee
             .set_bold(true));
         let _ = write!(out, "stream source");
         let _ = out.set_color(&ColorSpec::new());
+
+        source(out, &stats.data().source);
+
+        if let Some(playing) = metadata.and_then(now_playing) {
+            let _ = write!(out, "  Playing:[{playing}]");
+        }
+    }
+}
+
+fn source(out: &mut dyn WriteColor, stats: &SourceStats) {
+    match stats.format() {
+        Some(format) => {
+            let _ = write!(out, "  Codec:[{:<5}]", format.name().unwrap_or("?"));
+        }
+        None => {
+            let _ = write!(out, "  Codec:[     ]");
+        }
+    }
+
+    if let Some(bitrate) = stats.bitrate() {
+        let _ = write!(out, "  Bitrate:[{:>6.1} kbps]", bitrate / 1000.0);
+    } else {
+        let _ = write!(out, "  Bitrate:[       kbps]");
+    }
+
+    let _ = write!(out, "  Rate:[{:>6.1} pkt/s]", stats.packet_rate());
+    let _ = write!(out, "  Receivers:[{:>3}]", stats.receivers());
+
+    if let Some((loss, jitter)) = stats.reception() {
+        let _ = write!(out, "  Loss:[{:>6.2}%]", loss * 100.0);
+        time_field(out, "Jitter", Some(jitter));
+    } else {
+        let _ = write!(out, "  Loss:[       ]");
+        time_field(out, "Jitter", None);
+    }
+
+    let secs = stats.uptime() as u64;
+    let _ = write!(out, "  Up:[{:>3}:{:02}:{:02}]", secs / 3600, secs / 60 % 60, secs % 60);
+}
+
+fn now_playing(metadata: &Metadata) -> Option<String> {
+    let playing = match (metadata.artist(), metadata.title()) {
+        (Some(artist), Some(title)) => format!("{artist} - {title}"),
+        (None, Some(title)) => title.to_owned(),
+        (Some(artist), None) => artist.to_owned(),
+        (None, None) => return None,
+    };
+
+    match metadata.album() {
+        Some(album) => Some(format!("{playing} ({album})")),
+        None => Some(playing),
     }
 }
 
@@ -57,6 +109,42 @@ fn receiver(out: &mut dyn WriteColor, stats: &ReceiverStats) {
     time_field(out, "Audio", stats.audio_latency());
     time_field(out, "Output", stats.output_latency());
     time_field(out, "Network", stats.network_latency());
+
+    if let Some(ppm) = stats.clock_drift() {
+        let _ = write!(out, "  Drift:[{ppm:>+7.1} ppm]");
+    } else {
+        let _ = write!(out, "  Drift:[        ppm]");
+    }
+
+    // only sent by receivers playing a stream
+    if let Some(secs) = stats.stream_position() {
+        let secs = secs as u64;
+        let _ = write!(out, "  Pos:[{:>3}:{:02}]", secs / 60, secs % 60);
+    }
+
+    if stats.muted() || stats.zone_muted() {
+        let _ = write!(out, "  Vol:[MUTE]");
+    } else if let Some(volume) = stats.volume() {
+        let _ = write!(out, "  Vol:[{:>3.0}%]", volume * 100.0);
+    }
+
+    // only worth showing when it limits the volume
+    if let Some(max) = stats.max_volume().filter(|max| *max < 1.0) {
+        let locked = if stats.volume_locked() { " locked" } else { "" };
+        let _ = write!(out, "  Max:[{:>3.0}%{locked}]", max * 100.0);
+    } else if stats.volume_locked() {
+        let _ = write!(out, "  Max:[locked]");
+    }
+
+    if let Some(zone) = stats.zone() {
+        let _ = write!(out, "  Zone:[{zone}]");
+    }
+
+    if let Some(device) = stats.output_device() {
+        let _ = out.set_color(ColorSpec::new().set_dimmed(true));
+        let _ = write!(out, "  {device}");
+        let _ = out.set_color(&ColorSpec::new());
+    }
 }
 
 fn stream_status(out: &mut dyn WriteColor, stream: Option<StreamStatus>) {
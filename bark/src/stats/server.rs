@@ -1,24 +1,19 @@
 use std::fmt::Write;
-use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use axum::extract::State;
-use axum::Router;
-use axum::routing::get;
-use structopt::StructOpt;
-use thiserror::Error;
+use axum::http::StatusCode;
+use axum::{Json, Router};
+use axum::routing::{get, post};
+use bark_protocol::SAMPLE_RATE;
+use serde::Serialize;
 
-use super::metrics::{ReceiverMetrics, ReceiverMetricsData, SourceMetrics, SourceMetricsData};
+use bark_app::metrics::{MetricsOpt, StartError};
 
-#[derive(StructOpt)]
-pub struct MetricsOpt {
-    #[structopt(
-        long = "metrics-listen",
-        env = "BARK_METRICS_LISTEN",
-        default_value = "0.0.0.0:1530",
-    )]
-    listen: SocketAddr,
-}
+use super::health::{Checks, Health};
+use super::metrics::{ReceiverMetrics, ReceiverMetricsData, SourceMetrics, SourceMetricsData};
+use super::node;
 
 #[derive(Clone)]
 enum MetricsState {
@@ -26,34 +21,40 @@ enum MetricsState {
     Source(SourceMetrics),
 }
 
-#[derive(Debug, Error)]
-#[error("starting metrics server: {0}")]
-pub struct StartError(#[from] tokio::io::Error);
-
-pub async fn start_receiver(opt: &MetricsOpt) -> Result<ReceiverMetrics, StartError> {
+/// Start the receiver metrics server, along with an API to inspect timing
+/// and request a resync by setting `resync`
+pub async fn start_receiver(opt: &MetricsOpt, resync: Arc<AtomicBool>) -> Result<ReceiverMetrics, StartError> {
     let metrics = Arc::new(ReceiverMetricsData::new());
-    start(opt, MetricsState::Receiver(metrics.clone())).await?;
+
+    let api = Router::new()
+        .route("/api/timing", get(timing))
+        .route("/api/impact", get(impact))
+        .with_state(metrics.clone())
+        .route("/resync", post(move || request_resync(resync)));
+
+    start(opt, MetricsState::Receiver(metrics.clone()), Some(api)).await?;
     Ok(metrics)
 }
 
-pub async fn start_source(opt: &MetricsOpt) -> Result<SourceMetrics, StartError> {
+/// Start the source metrics server, additionally serving `web` if given
+pub async fn start_source(opt: &MetricsOpt, web: Option<Router>) -> Result<SourceMetrics, StartError> {
     let metrics = Arc::new(SourceMetricsData::new());
-    start(opt, MetricsState::Source(metrics.clone())).await?;
+    start(opt, MetricsState::Source(metrics.clone()), web).await?;
     Ok(metrics)
 }
 
-async fn start(opt: &MetricsOpt, state: MetricsState) -> Result<(), StartError> {
-    let app = Router::new()
+async fn start(opt: &MetricsOpt, state: MetricsState, web: Option<Router>) -> Result<(), StartError> {
+    let mut app = Router::new()
         .route("/metrics", get(metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
         .with_state(state);
 
-    let listener = tokio::net::TcpListener::bind(&opt.listen).await?;
-
-    tokio::spawn(async move {
-        axum::serve(listener, app).await.unwrap()
-    });
+    if let Some(web) = web {
+        app = app.merge(web);
+    }
 
-    Ok(())
+    bark_app::metrics::serve(opt, app).await
 }
 
 async fn metrics(metrics: State<MetricsState>) -> String {
@@ -63,22 +64,160 @@ async fn metrics(metrics: State<MetricsState>) -> String {
     }
 }
 
+impl MetricsState {
+    fn health(&self) -> &Health {
+        match self {
+            MetricsState::Receiver(metrics) => &metrics.health,
+            MetricsState::Source(metrics) => &metrics.health,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct HealthReport {
+    ok: bool,
+    /// The audio or network thread is going round, rather than stuck
+    running: bool,
+    /// Every audio device in use is open
+    device: bool,
+    /// Audio has been sent or received in the last few seconds
+    stream: bool,
+    last_audio_secs: Option<f64>,
+}
+
+/// Liveness: whether the node is running, rather than stuck
+async fn healthz(state: State<MetricsState>) -> (StatusCode, Json<HealthReport>) {
+    health_report(&state, |checks| checks.running)
+}
+
+/// Readiness: whether the node is doing its job. Receivers are ready to
+/// play whenever their output device is open, whether or not a stream is
+/// playing, but sources are only ready while they are sending audio
+async fn readyz(state: State<MetricsState>) -> (StatusCode, Json<HealthReport>) {
+    match &*state {
+        MetricsState::Receiver(_) => health_report(&state, |checks| checks.running && checks.device),
+        MetricsState::Source(_) => health_report(&state, |checks| checks.running && checks.device && checks.stream),
+    }
+}
+
+fn health_report(state: &MetricsState, ok: impl Fn(&Checks) -> bool) -> (StatusCode, Json<HealthReport>) {
+    let checks = state.health().check();
+    let ok = ok(&checks);
+
+    let status = if ok { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+
+    (status, Json(HealthReport {
+        ok,
+        running: checks.running,
+        device: checks.device,
+        stream: checks.stream,
+        last_audio_secs: checks.last_audio.map(|since| since.as_secs_f64()),
+    }))
+}
+
+#[derive(Serialize)]
+struct Timing {
+    /// How far ahead (positive) or behind the stream playback is
+    audio_offset_usec: Option<i64>,
+    /// Rate the resampler is consuming the stream at to correct the offset
+    resample_rate_hz: Option<i64>,
+    buffer_delay_usec: Option<i64>,
+    network_latency_usec: Option<i64>,
+}
+
+async fn timing(metrics: State<ReceiverMetrics>) -> Json<Timing> {
+    Json(Timing {
+        audio_offset_usec: metrics.audio_offset.get(),
+        resample_rate_hz: metrics.resample_rate.get(),
+        buffer_delay_usec: metrics.buffer_delay.get(),
+        network_latency_usec: metrics.network_latency.get(),
+    })
+}
+
+/// Seconds of audio played since startup that were affected by network or
+/// timing problems, which is what's audible to listeners
+#[derive(Serialize)]
+struct Impact {
+    /// Lost audio filled in by the codec's loss concealment
+    concealed_secs: f64,
+    /// Lost audio played as silence
+    silenced_secs: f64,
+    /// Audio played faster or slower than normal to correct timing
+    slewed_secs: f64,
+}
+
+async fn impact(metrics: State<ReceiverMetrics>) -> Json<Impact> {
+    let seconds = |frames: u64| frames as f64 / f64::from(SAMPLE_RATE.0);
+
+    Json(Impact {
+        concealed_secs: seconds(metrics.frames_concealed.get()),
+        silenced_secs: seconds(metrics.frames_silenced.get()),
+        slewed_secs: seconds(metrics.frames_slewed.get()),
+    })
+}
+
+async fn request_resync(resync: Arc<AtomicBool>) {
+    log::info!("resync requested over HTTP");
+    resync.store(true, Ordering::Relaxed);
+}
+
 fn render_receiver_metrics(metrics: &ReceiverMetrics) -> Result<String, std::fmt::Error> {
     let mut buffer = String::new();
+    render_receiver_info(&mut buffer)?;
     write!(&mut buffer, "{}", metrics.audio_offset)?;
     write!(&mut buffer, "{}", metrics.buffer_delay)?;
     write!(&mut buffer, "{}", metrics.buffer_underruns)?;
+    write!(&mut buffer, "{}", metrics.buffer_target)?;
+    write!(&mut buffer, "{}", metrics.resample_rate)?;
+    write!(&mut buffer, "{}", metrics.clock_drift)?;
     write!(&mut buffer, "{}", metrics.network_latency)?;
+    write!(&mut buffer, "{}", metrics.latency_outliers)?;
     write!(&mut buffer, "{}", metrics.queued_packets)?;
     write!(&mut buffer, "{}", metrics.packets_received)?;
     write!(&mut buffer, "{}", metrics.packets_lost)?;
     write!(&mut buffer, "{}", metrics.packets_missed)?;
+    write!(&mut buffer, "{}", metrics.packets_duplicate)?;
     write!(&mut buffer, "{}", metrics.frames_decoded)?;
     write!(&mut buffer, "{}", metrics.frames_played)?;
+    write!(&mut buffer, "{}", metrics.frames_concealed)?;
+    write!(&mut buffer, "{}", metrics.frames_silenced)?;
+    write!(&mut buffer, "{}", metrics.frames_slewed)?;
+    write!(&mut buffer, "{}", metrics.control_rejected)?;
+    write!(&mut buffer, "{}", metrics.output_reconnects)?;
+    write!(&mut buffer, "{}", metrics.stream_stops_slow)?;
+    write!(&mut buffer, "{}", metrics.stream_stops_leaked)?;
     Ok(buffer)
 }
 
-fn render_source_metrics(_metrics: &SourceMetrics) -> Result<String, std::fmt::Error> {
-    let buffer = String::new();
+/// The receiver's id and name as labels on a constant metric, to join its
+/// other metrics against
+fn render_receiver_info(buffer: &mut String) -> Result<(), std::fmt::Error> {
+    let node = node::get();
+    let id = node::id(&node).unwrap_or_default();
+    let name = node::name(&node).unwrap_or_default();
+
+    writeln!(buffer, "# TYPE bark_receiver_info gauge")?;
+    writeln!(buffer, "bark_receiver_info{{id=\"{}\",name=\"{}\"}} 1", label(&id), label(name))?;
+    writeln!(buffer)
+}
+
+/// Escape a label value for the Prometheus text format
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn render_source_metrics(metrics: &SourceMetrics) -> Result<String, std::fmt::Error> {
+    let mut buffer = String::new();
+    write!(&mut buffer, "{}", metrics.packets_sent)?;
+    write!(&mut buffer, "{}", metrics.packets_redundant)?;
+    write!(&mut buffer, "{}", metrics.packets_dropped)?;
+    write!(&mut buffer, "{}", metrics.packets_corrected)?;
+    write!(&mut buffer, "{}", metrics.input_silence)?;
+    write!(&mut buffer, "{}", metrics.input_reconnects)?;
+    write!(&mut buffer, "{}", metrics.receiver_packets_lost)?;
+    write!(&mut buffer, "{}", metrics.receiver_jitter)?;
+    write!(&mut buffer, "{}", metrics.control_rejected)?;
     Ok(buffer)
 }
@@ -5,6 +5,7 @@ use std::time::Duration;
 
 use bark_core::audio::FrameCount;
 use bark_protocol::time::{SampleDuration, TimestampDelta};
+use bark_protocol::SampleRate;
 
 pub struct Counter {
     name: &'static str,
@@ -89,6 +90,18 @@ impl<T> GaugeValue for Option<T> where T: GaugeValue {
     }
 }
 
+impl GaugeValue for i64 {
+    fn to_i64(&self) -> i64 {
+        *self
+    }
+}
+
+impl GaugeValue for u64 {
+    fn to_i64(&self) -> i64 {
+        i64::try_from(*self).unwrap_or(GAUGE_NO_VALUE)
+    }
+}
+
 impl GaugeValue for usize {
     fn to_i64(&self) -> i64 {
         i64::try_from(*self).unwrap_or(GAUGE_NO_VALUE)
@@ -118,3 +131,9 @@ impl GaugeValue for FrameCount {
         i64::try_from(self.0).unwrap_or(GAUGE_NO_VALUE)
     }
 }
+
+impl GaugeValue for SampleRate {
+    fn to_i64(&self) -> i64 {
+        i64::from(self.0)
+    }
+}
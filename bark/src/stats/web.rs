@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::{Path, State};
+use axum::http::{header, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Deserialize;
+
+use bark_protocol::packet::{PacketKind, StatsRequest, VolumeRequest};
+
+use crate::socket::{ListenError, PeerId, ProtocolSocket, Socket, SocketOpt};
+
+use super::Entry;
+
+const INDEX: &str = include_str!("web.html");
+
+#[derive(Clone)]
+struct WebState {
+    protocol: Arc<ProtocolSocket>,
+    peers: Arc<Mutex<HashMap<PeerId, Entry>>>,
+}
+
+#[derive(Deserialize)]
+struct SetVolume {
+    volume: Option<f32>,
+    muted: Option<bool>,
+}
+
+/// Routes for the web control UI, served alongside metrics
+pub fn router(opt: &SocketOpt) -> Result<Router, ListenError> {
+    let socket = Socket::open(opt)?;
+
+    let state = WebState {
+        protocol: Arc::new(ProtocolSocket::new(socket)),
+        peers: Arc::new(Mutex::new(HashMap::new())),
+    };
+
+    // spawn poller thread
+    std::thread::spawn({
+        let protocol = state.protocol.clone();
+        move || {
+            let request = StatsRequest::new()
+                .expect("allocate StatsRequest packet");
+
+            loop {
+                let _ = protocol.broadcast(request.as_packet());
+                std::thread::sleep(Duration::from_millis(500));
+            }
+        }
+    });
+
+    std::thread::spawn({
+        let state = state.clone();
+        move || collect_stats(&state)
+    });
+
+    Ok(Router::new()
+        .route("/", get(index))
+        .route("/api/peers", get(peers))
+        .route("/api/peers/{peer}/volume", post(set_volume))
+        .with_state(state))
+}
+
+fn collect_stats(state: &WebState) {
+    loop {
+        let (packet, peer) = match state.protocol.recv_from() {
+            Ok(result) => result,
+            Err(e) => {
+                log::error!("web ui receiving from network: {e}");
+                return;
+            }
+        };
+
+        let Some(PacketKind::StatsReply(reply)) = packet.parse() else {
+            continue;
+        };
+
+        let now = Instant::now();
+        let mut peers = state.peers.lock().unwrap();
+        peers.insert(peer, Entry { time: now, reply });
+        peers.retain(|_, ent| ent.valid_at(now));
+    }
+}
+
+async fn index() -> Html<&'static str> {
+    Html(INDEX)
+}
+
+async fn peers(State(state): State<WebState>) -> Response {
+    let json = super::json_snapshot(&state.peers.lock().unwrap());
+
+    match json {
+        Ok(json) => ([(header::CONTENT_TYPE, "application/json")], json).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+async fn set_volume(
+    State(state): State<WebState>,
+    Path(peer): Path<SocketAddr>,
+    Json(body): Json<SetVolume>,
+) -> StatusCode {
+    let peer = PeerId::from(peer);
+
+    // only send control packets to receivers we know about
+    let known = state.peers.lock().unwrap()
+        .get(&peer)
+        .is_some_and(|entry| entry.is_receiver());
+
+    if !known {
+        return StatusCode::NOT_FOUND;
+    }
+
+    let request = VolumeRequest::new(body.volume, body.muted)
+        .expect("allocate VolumeRequest packet");
+
+    match state.protocol.send_to(request.as_packet(), peer) {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(e) => {
+            log::warn!("sending volume request to {peer}: {e}");
+            StatusCode::BAD_GATEWAY
+        }
+    }
+}
@@ -1,31 +1,61 @@
 use std::future::Future;
+use std::net::SocketAddrV4;
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
 use std::time::Duration;
 
+use bark_app::thread;
 use bark_core::audio::{Format, F32, S16};
-use bark_core::encode::Encode;
-use bark_core::encode::pcm::{S16LEEncoder, F32LEEncoder};
-use bark_protocol::FRAMES_PER_PACKET;
+use bark_core::audio::loudness::Normalizer;
+use bark_core::encode::{Encode, NewEncoderError};
+use bark_core::registry;
+use bark_protocol::{CHANNELS, MAX_FRAMES_PER_PACKET, SAMPLE_RATE};
 use bytemuck::Zeroable;
 use futures::future;
 use structopt::StructOpt;
 
 #[cfg(feature = "opus")]
-use bark_core::encode::opus::OpusEncoder;
+use bark_core::encode::opus::{OpusEncoder, OpusEncoderOpt};
 
 use bark_protocol::time::SampleDuration;
-use bark_protocol::packet::{Audio, PacketKind, Pong, StatsReply};
-use bark_protocol::types::{TimestampMicros, AudioPacketHeader, SessionId};
+use bark_protocol::packet::{Audio, PacketKind, Pong, ReplayReply, StatsReply, StatsRequest, StreamEnd};
+use bark_protocol::types::{AudioPacketFormat, TimestampMicros, AudioPacketHeader, PacketHeader, SessionId, StatsReplyFlags};
 
-use crate::audio::config::{DeviceOpt, DEFAULT_PERIOD, DEFAULT_BUFFER};
+use crate::audio::config::{self as audio_config, DeviceOpt};
+use crate::audio::generator::Generator;
 use crate::audio::Input;
+use crate::auth::ControlAuth;
 use crate::socket::{Socket, SocketOpt, ProtocolSocket};
-use crate::stats::server::MetricsOpt;
+use bark_app::metrics::MetricsOpt;
 use crate::stats::SourceMetrics;
-use crate::{config, stats, thread, time};
+use crate::discover::{self, Role};
+use crate::{config, stats, time};
 use crate::RunError;
 
+use self::adapt::{Adaptation, Rung};
+use self::backoff::Backoff;
+use self::monotonic::MonotonicClock;
+use self::negotiate::Negotiation;
+use self::redundancy::RedundantSender;
+use self::replay::History;
+use self::standby::Standby;
+use self::status::SourceStatus;
+use self::watchdog::WatchedInput;
+
+pub mod adapt;
+pub mod backoff;
+pub mod monotonic;
+pub mod mpd;
+pub mod negotiate;
+pub mod pacer;
+pub mod redundancy;
+pub mod replay;
+pub mod standby;
+pub mod status;
+pub mod watchdog;
+
 #[derive(StructOpt)]
 pub struct StreamOpt {
     #[structopt(flatten)]
@@ -35,14 +65,21 @@ pub struct StreamOpt {
     #[structopt(long, env = "BARK_SOURCE_INPUT_DEVICE")]
     pub input_device: Option<String>,
 
-    /// Size of discrete audio transfer buffer in frames
+    /// Size of discrete audio transfer buffer in frames, chosen from the
+    /// target latency if not set
     #[structopt(long, env = "BARK_SOURCE_INPUT_PERIOD")]
     pub input_period: Option<usize>,
 
-    /// Size of decoded audio buffer in frames
+    /// Size of decoded audio buffer in frames, chosen from the target
+    /// latency if not set
     #[structopt(long, env = "BARK_SOURCE_INPUT_BUFFER")]
     pub input_buffer: Option<usize>,
 
+    /// Target latency of the audio device buffer in milliseconds, used to
+    /// size period and buffer within the limits the device supports
+    #[structopt(long, env = "BARK_SOURCE_INPUT_LATENCY_MS")]
+    pub input_latency_ms: Option<f64>,
+
     #[structopt(long, env = "BARK_SOURCE_INPUT_FORMAT", default_value = "f32")]
     pub input_format: config::Format,
 
@@ -60,94 +97,620 @@ pub struct StreamOpt {
     )]
     pub format: config::Codec,
 
+    /// Receivers play the highest priority stream, ties are won by the
+    /// most recently started stream
     #[structopt(
         long,
         env = "BARK_SOURCE_PRIORITY",
         default_value = "0",
     )]
     pub priority: i8,
+
+    /// Duration of audio carried in each packet in milliseconds. Larger
+    /// packets reduce packet rate at the cost of latency
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_PACKET_MS",
+        default_value = "1",
+    )]
+    pub packet_ms: f64,
+
+    /// Minimum audio in milliseconds receivers should buffer, for sources
+    /// that deliver audio in bursts. Receivers extend the stream delay to
+    /// this if it is shorter
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_BUFFER_HINT_MS",
+        default_value = "0",
+    )]
+    pub buffer_hint_ms: u16,
+
+    /// How far ahead in milliseconds to announce when a new stream starts
+    /// playing, so that every receiver can fill its buffers and start at
+    /// the same moment. 0 starts each receiver as soon as it has buffered
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_START_DELAY_MS",
+        default_value = "200",
+    )]
+    pub start_delay_ms: u64,
+
+    /// Number of times to send each audio packet, for lossy networks
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_REDUNDANCY",
+        default_value = "1",
+    )]
+    pub redundancy: u8,
+
+    /// Delay between redundant copies of a packet in milliseconds, so that
+    /// a burst of loss doesn't take out every copy. 0 sends them together
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_REDUNDANCY_SPACING_MS",
+        default_value = "0",
+    )]
+    pub redundancy_spacing_ms: u64,
+
+    /// Spread out packets read from the input together, as happens when its
+    /// period is longer than a packet, rather than sending them in a burst.
+    /// Smooths traffic on wifi at the cost of up to a period of latency
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_PACE",
+        default_value = "false",
+        parse(try_from_str),
+    )]
+    pub pace: bool,
+
+    /// Hand packets to the kernel with SO_TXTIME, to leave the NIC this
+    /// many milliseconds after their audio was captured, evenly spaced.
+    /// Must be longer than the input period. Linux only, and needs the fq
+    /// qdisc on the outgoing interface
+    #[structopt(long, env = "BARK_SOURCE_TXTIME_LEAD_MS")]
+    pub txtime_lead_ms: Option<u64>,
+
+    /// Seconds of recently sent audio to keep for `bark ctl replay`,
+    /// 0 to disable
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_REPLAY_HISTORY_SECS",
+        default_value = "30",
+    )]
+    pub replay_history_secs: u64,
+
+    /// Normalize loudness towards this target in LUFS, so that sources
+    /// mastered at different levels play at a similar volume
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_LOUDNESS_TARGET",
+        allow_hyphen_values = true,
+    )]
+    pub loudness_target: Option<f64>,
+
+    /// Run as a standby source: stay silent while another source at this
+    /// priority or higher is streaming, and take over once it has been
+    /// silent for this many milliseconds
+    #[structopt(long, env = "BARK_SOURCE_STANDBY_MS")]
+    pub standby_ms: Option<u64>,
+
+    /// Warn when audio input has been exact digital silence for this many
+    /// seconds, such as a loopback device whose player has restarted. 0
+    /// disables the warning, silence is still reported in metrics
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_INPUT_WATCHDOG_SECS",
+        default_value = "120",
+    )]
+    pub input_watchdog_secs: u64,
+
+    /// Shell command to run when the input watchdog warns, eg. to restart
+    /// the player service feeding the input
+    #[structopt(long, env = "BARK_SOURCE_INPUT_WATCHDOG_HOOK")]
+    pub input_watchdog_hook: Option<String>,
+
+    /// Take audio from an RTP stream sent to this address, such as an
+    /// existing multicast stream, instead of an audio device
+    #[structopt(long, env = "BARK_SOURCE_RTP_INPUT")]
+    pub rtp_input: Option<SocketAddrV4>,
+
+    /// Payload of the RTP input stream: l16 or opus, stereo at 48 kHz
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_RTP_INPUT_PAYLOAD",
+        default_value = "l16",
+    )]
+    pub rtp_input_payload: config::RtpPayload,
+
+    /// Generate a test signal instead of taking audio from a device:
+    /// tone[:HZ] (1000 Hz by default) or noise (pink), optionally followed
+    /// by :left, :right or :alternate to confine it to one channel or swap
+    /// channels each second, eg. tone:440:alternate
+    #[structopt(long = "input", env = "BARK_SOURCE_GENERATOR")]
+    pub generator: Option<Generator>,
+
+    /// Take audio from a named pipe carrying raw PCM in the input format,
+    /// such as MPD's fifo output, instead of an audio device
+    #[structopt(long, env = "BARK_SOURCE_FIFO_INPUT")]
+    pub fifo_input: Option<PathBuf>,
+
+    /// Broadcast the current song of the MPD server at this address, eg.
+    /// localhost:6600, for receivers and `bark stats` to show
+    #[structopt(long, env = "BARK_SOURCE_MPD_METADATA")]
+    pub mpd_metadata: Option<String>,
+
+    /// Step down to cheaper codecs while receivers report sustained packet
+    /// loss, and back up once reception is clean: from the configured
+    /// codec to s16le, then opus at lower and lower bitrates
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_ADAPTIVE_CODEC",
+        default_value = "false",
+        parse(try_from_str),
+    )]
+    pub adaptive_codec: bool,
+
+    /// Fall back to a format every receiver can decode while any receiver
+    /// playing the stream can't decode the configured one, such as one
+    /// built without opus, and shrink packets to the largest every
+    /// receiver accepts
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_NEGOTIATE_FORMAT",
+        default_value = "false",
+        parse(try_from_str),
+    )]
+    pub negotiate_format: bool,
+
+    /// Serve a web control UI alongside metrics
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_WEB_UI",
+        default_value = "false",
+        parse(try_from_str),
+    )]
+    pub web_ui: bool,
+
+    /// Act on replay requests that aren't signed with the control secret,
+    /// such as from older versions of bark. Anyone on the network can then
+    /// have this source replay audio to every receiver
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_ALLOW_UNSIGNED_CONTROL",
+        default_value = "false",
+        parse(try_from_str),
+    )]
+    pub allow_unsigned_control: bool,
+
+    #[cfg(feature = "opus")]
+    #[structopt(flatten)]
+    pub opus: OpusOpt,
+}
+
+#[cfg(feature = "opus")]
+#[derive(StructOpt)]
+pub struct OpusOpt {
+    /// Opus target bitrate in bits per second, defaults to maximum
+    #[structopt(long, env = "BARK_SOURCE_OPUS_BITRATE")]
+    pub opus_bitrate: Option<i32>,
+
+    /// Embed forward error correction data in opus packets
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_OPUS_INBAND_FEC",
+        default_value = "true",
+        parse(try_from_str),
+    )]
+    pub opus_inband_fec: bool,
+
+    /// Opus encoder complexity from 0 to 10, trading CPU time for quality.
+    /// Defaults to the libopus default, the maximum
+    #[structopt(long, env = "BARK_SOURCE_OPUS_COMPLEXITY", parse(try_from_str = parse_opus_complexity))]
+    pub opus_complexity: Option<u8>,
+
+    /// Send almost empty opus packets while the input is silent, to save
+    /// bandwidth
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_OPUS_DTX",
+        default_value = "false",
+        parse(try_from_str),
+    )]
+    pub opus_dtx: bool,
+}
+
+#[cfg(feature = "opus")]
+fn parse_opus_complexity(complexity: &str) -> Result<u8, String> {
+    match complexity.parse() {
+        Ok(complexity @ 0..=10) => Ok(complexity),
+        _ => Err(format!("invalid opus complexity, must be 0 to 10: {complexity}")),
+    }
+}
+
+#[cfg(feature = "opus")]
+impl OpusOpt {
+    fn encoder_opt(&self) -> OpusEncoderOpt {
+        OpusEncoderOpt {
+            bitrate: self.opus_bitrate,
+            inband_fec: self.opus_inband_fec,
+            complexity: self.opus_complexity,
+            dtx: self.opus_dtx,
+        }
+    }
 }
 
 pub async fn run(opt: StreamOpt, metrics: MetricsOpt) -> Result<(), RunError> {
-    let socket = Socket::open(&opt.socket)?;
+    let mut socket = Socket::open(&opt.socket)?;
+
+    if opt.txtime_lead_ms.is_some() {
+        if let Err(e) = socket.enable_txtime() {
+            log::warn!("failed to enable SO_TXTIME, sending packets as soon as they're ready: {e}");
+        }
+    }
+
+    // reuse the groups we joined, they may have been discovered
+    let socket_opt = SocketOpt {
+        multicast: socket.groups().to_vec(),
+        control_secret: opt.socket.control_secret.clone(),
+        transport: opt.socket.transport,
+        connect: opt.socket.connect.clone(),
+        // we are already listening
+        tcp_listen: None,
+        multicast_if: opt.socket.multicast_if,
+        multicast_ttl: opt.socket.multicast_ttl,
+        dscp: opt.socket.dscp,
+    };
+    let group = socket.multicast();
+    let _announce = group.and_then(|group| discover::announce(Role::Source, group));
+
     let protocol = Arc::new(ProtocolSocket::new(socket));
 
     let sid = generate_session_id();
 
-    let metrics = stats::server::start_source(&metrics).await?;
+    let web_ui = opt.web_ui.then_some(&socket_opt);
+    let metrics = stats::start_source(&metrics, web_ui).await?;
+
+    // replay requests have us play to every receiver, so are checked as
+    // receivers check control packets
+    let control_secret = opt.socket.control_secret.as_deref().map(str::as_bytes);
+    let auth = ControlAuth::new(control_secret, opt.allow_unsigned_control, {
+        let metrics = metrics.clone();
+        move || metrics.control_rejected.increment()
+    });
+
+    let delay = Duration::from_millis(opt.delay_ms);
+    let delay = SampleDuration::from_std_duration_lossy(delay);
+
+    let history = Arc::new(History::new(Duration::from_secs(opt.replay_history_secs)));
+
+    let standby = opt.standby_ms.map(|ms| {
+        log::info!("standing by for primary source to go silent for {ms}ms");
+        Arc::new(Standby::new(Duration::from_millis(ms), opt.priority, sid))
+    });
+
+    let start_delay = SampleDuration::from_std_duration_lossy(Duration::from_millis(opt.start_delay_ms));
+
+    let session = Session {
+        sid: Arc::new(AtomicI64::new(sid.0)),
+        delay,
+        start_delay,
+        history,
+        standby,
+        status: Arc::new(SourceStatus::new(opt.redundancy)),
+        stopping: Arc::new(AtomicBool::new(false)),
+    };
+
+    // tells receivers the stream has ended however we stop, including
+    // this future being dropped on shutdown
+    let _end = EndOfStream { protocol: protocol.clone(), session: session.clone() };
+
+    if let Some(addr) = opt.mpd_metadata.clone() {
+        mpd::start(addr, protocol.clone(), session.clone());
+    }
+
+    poll_receivers(protocol.clone());
 
     let audio_th = match opt.input_format {
-        config::Format::S16 => start_audio_thread::<S16>(opt, protocol.clone(), sid, metrics)?,
-        config::Format::F32 => start_audio_thread::<F32>(opt, protocol.clone(), sid, metrics)?,
+        config::Format::S16 => start_audio_thread::<S16>(opt, protocol.clone(), group, session.clone(), metrics.clone())?,
+        config::Format::F32 => start_audio_thread::<F32>(opt, protocol.clone(), group, session.clone(), metrics.clone())?,
     };
 
     let network_th = thread::start("bark/network", {
-        move || network_thread(sid, protocol)
+        move || network_thread(session, protocol, auth, metrics)
     });
 
     future::select(audio_th, network_th).await;
     Ok(())
 }
 
+/// State shared between the audio and network threads
+#[derive(Clone)]
+struct Session {
+    sid: Arc<AtomicI64>,
+    delay: SampleDuration,
+    // how far beyond the first packet's pts receivers start together
+    start_delay: SampleDuration,
+    history: Arc<History>,
+    standby: Option<Arc<Standby>>,
+    status: Arc<SourceStatus>,
+    // set once the stream has ended, the audio thread stops sending
+    stopping: Arc<AtomicBool>,
+}
+
+impl Session {
+    /// The session currently being sent, which changes each time a standby
+    /// source takes over or the codec adapts
+    fn sid(&self) -> SessionId {
+        match &self.standby {
+            Some(standby) => standby.sid(),
+            None => SessionId(self.sid.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Start a new session, for a change receivers should pick up cleanly
+    fn begin_session(&self) -> SessionId {
+        match &self.standby {
+            Some(standby) => standby.begin_session(),
+            None => {
+                let sid = generate_session_id();
+                self.sid.store(sid.0, Ordering::Relaxed);
+                sid
+            }
+        }
+    }
+}
+
+/// Ask receivers for stats every so often, so that their replies can be
+/// counted towards the receivers playing the stream
+fn poll_receivers(protocol: Arc<ProtocolSocket>) {
+    std::thread::spawn(move || {
+        thread::set_name("bark/poll");
+
+        let request = StatsRequest::new()
+            .expect("allocate StatsRequest packet");
+
+        loop {
+            let _ = protocol.broadcast(request.as_packet());
+            std::thread::sleep(status::RECEIVER_POLL);
+        }
+    });
+}
+
+/// Times each StreamEnd is sent, so that a lost packet doesn't leave
+/// receivers to time the session out instead
+const STREAM_END_REPEAT: usize = 3;
+
+struct EndOfStream {
+    protocol: Arc<ProtocolSocket>,
+    session: Session,
+}
+
+impl Drop for EndOfStream {
+    fn drop(&mut self) {
+        self.session.stopping.store(true, Ordering::Relaxed);
+
+        let sid = self.session.sid();
+        log::info!("ending stream: sid={}", sid.0);
+
+        let packet = StreamEnd::new(sid)
+            .expect("allocate StreamEnd packet");
+
+        for _ in 0..STREAM_END_REPEAT {
+            if let Err(e) = self.protocol.broadcast(packet.as_packet()) {
+                log::warn!("error sending stream end: {e}");
+                break;
+            }
+        }
+    }
+}
+
 fn start_audio_thread<F: Format>(
     opt: StreamOpt,
     protocol: Arc<ProtocolSocket>,
-    sid: SessionId,
-    _metrics: SourceMetrics,
+    group: Option<SocketAddrV4>,
+    session: Session,
+    metrics: SourceMetrics,
 ) -> Result<Pin<Box<dyn Future<Output = ()>>>, RunError> {
-    let input = Input::<F>::new(&DeviceOpt {
-        device: opt.input_device,
-        period: opt.input_period
-            .map(SampleDuration::from_frame_count)
-            .unwrap_or(DEFAULT_PERIOD),
-        buffer: opt.input_buffer
-            .map(SampleDuration::from_frame_count)
-            .unwrap_or(DEFAULT_BUFFER),
-    })?;
-
-    let encoder: Box<dyn Encode> = match opt.format {
-        config::Codec::S16LE => Box::new(S16LEEncoder),
-        config::Codec::F32LE => Box::new(F32LEEncoder),
-        #[cfg(feature = "opus")]
-        config::Codec::Opus => Box::new(OpusEncoder::new()?),
+    let input = match (opt.generator, opt.rtp_input, &opt.fifo_input) {
+        (Some(generator), _, _) => Input::<F>::generator(generator),
+        (None, Some(addr), _) => Input::<F>::rtp(addr, opt.rtp_input_payload)?,
+        (None, None, Some(path)) => Input::<F>::fifo(path)?,
+        (None, None, None) => Input::<F>::new(&DeviceOpt {
+            device: opt.input_device,
+            period: opt.input_period.map(SampleDuration::from_frame_count),
+            buffer: opt.input_buffer.map(SampleDuration::from_frame_count),
+            latency: audio_config::latency(opt.input_latency_ms),
+            shared: false,
+        })?,
     };
 
+    #[cfg(feature = "opus")]
+    {
+        let opus = opt.opus.encoder_opt();
+        registry::register_encoder(AudioPacketFormat::OPUS, move || {
+            Ok(Box::new(OpusEncoder::new(&opus)?) as Box<dyn Encode>)
+        });
+    }
+
+    let encoder = registry::new_encoder(opt.format.packet_format())?;
+
     log::info!("instantiated encoder: {}", encoder);
 
-    let delay = Duration::from_millis(opt.delay_ms);
-    let delay = SampleDuration::from_std_duration_lossy(delay);
+    let packet_frames = packet_frames(opt.packet_ms, opt.format)?;
+
+    let packet_frames = if opt.negotiate_format {
+        negotiate::fit_packet_frames(&protocol, group, packet_frames, opt.format)?
+    } else {
+        packet_frames
+    };
+
+    log::info!("sending {packet_frames} frames per packet");
+
+    let header = AudioPacketHeader {
+        sid: session.sid(),
+        seq: 1,
+        pts: TimestampMicros(0),
+        dts: TimestampMicros(0),
+        format: encoder.header_format(),
+        priority: opt.priority,
+        packet_frames,
+        delay_ms: u16::try_from(opt.delay_ms).unwrap_or(u16::MAX),
+        min_buffer_ms: opt.buffer_hint_ms,
+        start_pts: TimestampMicros(0),
+    };
+
+    session.status.set_format(header.format, packet_frames);
+
+    let clock = MonotonicClock::new(packet_frames, metrics.clone());
+
+    let watchdog = Some(opt.input_watchdog_secs)
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs);
+
+    let input = WatchedInput::new(input, watchdog, opt.input_watchdog_hook, metrics.clone());
+
+    let sender = RedundantSender::new(
+        protocol,
+        metrics,
+        opt.redundancy,
+        Duration::from_millis(opt.redundancy_spacing_ms),
+        opt.pace,
+    );
+
+    let sender = match opt.txtime_lead_ms {
+        Some(lead) => sender.with_txtime(Duration::from_millis(lead)),
+        None => sender,
+    };
+
+    let normalizer = opt.loudness_target.map(|target| {
+        log::info!("normalizing loudness to {target} LUFS");
+        Normalizer::new(target)
+    });
+
+    let adaptation = opt.adaptive_codec.then(|| {
+        log::info!("adapting codec to reception, starting from {}", opt.format);
+        Adaptation::new(
+            header.format,
+            packet_frames,
+            #[cfg(feature = "opus")]
+            opt.opus.encoder_opt(),
+        )
+    });
+
+    let negotiation = opt.negotiate_format.then(|| {
+        log::info!("falling back to a format every receiver can decode when needed");
+        Negotiation::new()
+    });
+
+    let encoder = Encoder {
+        encoder,
+        configured: header.format,
+        adaptation,
+        negotiation,
+    };
 
     let audio_th = thread::start("bark/audio", {
-        let protocol = protocol.clone();
-        move || audio_thread(input, encoder, delay, sid, opt.priority, protocol)
+        move || audio_thread(input, encoder, normalizer, header, clock, sender, session)
     });
 
     Ok(Box::pin(audio_th))
 }
 
+/// Largest UDP payload that fits in a single 1500 byte ethernet frame
+const MTU_PAYLOAD: usize = 1500 - 20 - 8;
+
+fn packet_frames(packet_ms: f64, codec: config::Codec) -> Result<u16, RunError> {
+    let frames = (packet_ms * f64::from(SAMPLE_RATE.0) / 1000.0).round();
+
+    if frames < 1.0 || frames > MAX_FRAMES_PER_PACKET as f64 {
+        return Err(RunError::InvalidPacketDuration(packet_ms));
+    }
+
+    let frames = frames as u16;
+
+    #[cfg(feature = "opus")]
+    if matches!(codec, config::Codec::Opus) && !adapt::OPUS_PACKET_FRAMES.contains(&frames) {
+        return Err(RunError::InvalidOpusPacketDuration(packet_ms));
+    }
+
+    // pcm packets over the MTU are fragmented by IP, and losing any one
+    // fragment loses the whole packet
+    if let Some(sample_bytes) = codec.sample_bytes() {
+        let size = size_of::<PacketHeader>()
+            + size_of::<AudioPacketHeader>()
+            + usize::from(frames) * usize::from(CHANNELS) * sample_bytes;
+
+        if size > MTU_PAYLOAD {
+            log::warn!("{packet_ms}ms packets of {codec} are {size} bytes, more than fit in one {MTU_PAYLOAD} byte datagram on ethernet. they will be fragmented, and are lost if any fragment is. use a smaller --packet-ms, or opus");
+        }
+    }
+
+    Ok(frames)
+}
+
+/// The stream's encoder, along with adaptation to switch it out as
+/// reception changes and negotiation to switch it out for receivers that
+/// can't decode it, if enabled
+struct Encoder {
+    encoder: Box<dyn Encode>,
+    configured: AudioPacketFormat,
+    adaptation: Option<Adaptation>,
+    negotiation: Option<Negotiation>,
+}
+
+impl Encoder {
+    /// Switch to `new`, as a new session so receivers start the new format
+    /// afresh
+    fn switch(&mut self, new: Box<dyn Encode>, header: &mut AudioPacketHeader, session: &Session) {
+        self.encoder = new;
+        header.format = self.encoder.header_format();
+        header.sid = session.begin_session();
+        header.seq = 1;
+        session.status.set_format(header.format, header.packet_frames);
+    }
+
+    /// The encoder negotiation should switch to, if any
+    fn negotiate(&mut self, status: &SourceStatus, current: AudioPacketFormat)
+        -> Option<Result<Box<dyn Encode>, NewEncoderError>>
+    {
+        let preferred = match &self.adaptation {
+            Some(adaptation) => adaptation.current(),
+            None => Rung { format: self.configured, bitrate: None },
+        };
+
+        let format = self.negotiation.as_mut()?.poll(status, preferred.format, current)?;
+
+        if format == preferred.format {
+            log::info!("every receiver can decode {preferred}, switching back");
+        } else {
+            log::warn!("not every receiver can decode {preferred}, falling back to {}",
+                format.name().unwrap_or("unknown"));
+        }
+
+        Some(match &self.adaptation {
+            Some(adaptation) if format == preferred.format => adaptation.new_encoder(preferred),
+            _ => registry::new_encoder(format),
+        })
+    }
+}
+
 fn audio_thread<F: Format>(
-    input: Input<F>,
-    mut encoder: Box<dyn Encode>,
-    delay: SampleDuration,
-    sid: SessionId,
-    priority: i8,
-    protocol: Arc<ProtocolSocket>,
+    mut input: WatchedInput<F>,
+    mut encoder: Encoder,
+    mut normalizer: Option<Normalizer>,
+    mut audio_header: AudioPacketHeader,
+    mut clock: MonotonicClock,
+    mut sender: RedundantSender,
+    session: Session,
 ) {
     thread::set_realtime_priority();
 
-    let mut audio_header = AudioPacketHeader {
-        sid,
-        seq: 1,
-        pts: TimestampMicros(0),
-        dts: TimestampMicros(0),
-        format: encoder.header_format(),
-        priority,
-        padding: Default::default(),
-    };
+    let mut audio_buffer = vec![F::Frame::zeroed(); usize::from(audio_header.packet_frames)];
 
-    loop {
-        let mut audio_buffer = [F::Frame::zeroed(); FRAMES_PER_PACKET];
+    // standby sources start out silent
+    let mut sending = session.standby.is_none();
 
+    loop {
         // read audio input
         let timestamp = match input.read(&mut audio_buffer) {
             Ok(ts) => ts,
@@ -157,9 +720,67 @@ fn audio_thread<F: Format>(
             }
         };
 
+        // keep reading input while on standby so it doesn't overrun, but
+        // only send once the primary has gone silent
+        if let Some(standby) = &session.standby {
+            let primary_silent = standby.primary_silent();
+
+            if primary_silent && !sending {
+                audio_header.sid = standby.begin_session();
+                audio_header.seq = 1;
+                log::info!("primary source went silent, taking over: sid={}", audio_header.sid.0);
+            } else if !primary_silent && sending {
+                log::info!("primary source resumed, standing by");
+            }
+
+            sending = primary_silent;
+        }
+
+        if !sending {
+            continue;
+        }
+
+        // stream has ended, stop before sending anything after StreamEnd
+        if session.stopping.load(Ordering::Relaxed) {
+            break;
+        }
+
+        // normalize loudness
+        if let Some(normalizer) = normalizer.as_mut() {
+            normalizer.process(F::frames_mut(&mut audio_buffer));
+        }
+
+        // switch codec if reception calls for it, as a new session so
+        // receivers start the new format afresh
+        if let Some(adaptation) = encoder.adaptation.as_mut() {
+            if let Some(rung) = adaptation.poll(&session.status) {
+                match adaptation.new_encoder(rung) {
+                    Ok(new) => {
+                        encoder.switch(new, &mut audio_header, &session);
+                        log::info!("switched to {rung}: sid={}", audio_header.sid.0);
+                    }
+                    Err(e) => {
+                        log::warn!("error instantiating encoder for {rung}, staying put: {e}");
+                    }
+                }
+            }
+        }
+
+        // and if a receiver can't decode it
+        match encoder.negotiate(&session.status, audio_header.format) {
+            Some(Ok(new)) => {
+                encoder.switch(new, &mut audio_header, &session);
+                log::info!("switched to {}: sid={}", encoder.encoder, audio_header.sid.0);
+            }
+            Some(Err(e)) => {
+                log::warn!("error instantiating encoder, staying put: {e}");
+            }
+            None => {}
+        }
+
         // encode audio
         let mut encode_buffer = [0; Audio::MAX_BUFFER_LENGTH];
-        let encoded_data = match encoder.encode_packet(F::frames(&audio_buffer), &mut encode_buffer) {
+        let encoded_data = match encoder.encoder.encode_packet(F::frames(&audio_buffer), &mut encode_buffer) {
             Ok(size) => &encode_buffer[0..size],
             Err(e) => {
                 log::error!("error encoding audio: {e}");
@@ -167,12 +788,25 @@ fn audio_thread<F: Format>(
             }
         };
 
+        // hold back packets read in a burst, before stamping dts so that
+        // it says when the packet really went out
+        sender.pace(audio_header.packet_duration().to_std_duration_lossy());
+
         // assemble new packet header
-        let pts = timestamp.add(delay);
+        let pts = clock.pts(timestamp).add(session.delay);
+
+        // announce when receivers start playing this session, on the
+        // boundary of a packet so it begins exactly on the start
+        if audio_header.seq == 1 && session.start_delay != SampleDuration::zero() {
+            let packet = audio_header.packet_duration().to_frame_count();
+            let packets = session.start_delay.to_frame_count().div_ceil(packet);
+            let start = pts.add(SampleDuration::from_frame_count_u64(packets * packet));
+            audio_header.start_pts = start.to_micros_lossy();
+        }
 
         let header = AudioPacketHeader {
             pts: pts.to_micros_lossy(),
-            dts: time::now(),
+            dts: clock.dts(time::now()),
             ..audio_header
         };
 
@@ -180,8 +814,12 @@ fn audio_thread<F: Format>(
         let audio = Audio::new(&header, encoded_data)
             .expect("allocate Audio packet");
 
-        // send it
-        protocol.broadcast(audio.as_packet()).expect("broadcast");
+        // send it, dropping it if the network is down
+        sender.broadcast(&audio);
+        session.status.sent(encoded_data.len());
+
+        // keep packet around for replay
+        session.history.record::<F>(audio, &audio_buffer);
 
         // reset header for next packet:
         audio_header.seq += 1;
@@ -189,35 +827,125 @@ fn audio_thread<F: Format>(
 }
 
 fn network_thread(
-    sid: SessionId,
+    session: Session,
     protocol: Arc<ProtocolSocket>,
+    mut auth: ControlAuth,
+    metrics: SourceMetrics,
 ) {
     thread::set_realtime_priority();
     let node = stats::node::get();
+    let mut backoff = Backoff::new("receiving");
 
     loop {
-        let (packet, peer) = protocol.recv_from().expect("protocol.recv_from");
+        let (packet, peer) = match protocol.recv_from() {
+            Ok(received) => {
+                backoff.succeeded();
+                received
+            }
+            Err(e) => {
+                // keep the session going through network outages, the
+                // audio thread is what ends it
+                backoff.failed(&e);
+                std::thread::sleep(backoff.delay());
+                continue;
+            }
+        };
+
+        let Some(packet) = auth.check(packet, peer) else {
+            continue;
+        };
 
         match packet.parse() {
-            Some(PacketKind::Audio(_)) => {
-                // ignore
+            Some(PacketKind::Audio(audio)) => {
+                if let Some(standby) = &session.standby {
+                    standby.observe(audio.header());
+                }
             }
             Some(PacketKind::StatsRequest(_)) => {
-                let reply = StatsReply::source(sid, node)
+                let reply = StatsReply::source(session.sid(), session.status.stats(), node)
                     .expect("allocate StatsReply packet");
 
                 let _ = protocol.send_to(reply.as_packet(), peer);
             }
+            Some(PacketKind::StatsReply(reply)) if reply.flags().contains(StatsReplyFlags::IS_RECEIVER) => {
+                session.status.receiver(peer, reply.data().sid, session.sid(), &reply.data().receiver);
+            }
             Some(PacketKind::StatsReply(_)) => {
                 // ignore
             }
-            Some(PacketKind::Ping(_)) => {
-                let pong = Pong::new().expect("allocate Pong packet");
+            Some(PacketKind::Ping(ping)) => {
+                let pong = Pong::reply(&ping).expect("allocate Pong packet");
                 let _ = protocol.send_to(pong.as_packet(), peer);
             }
             Some(PacketKind::Pong(_)) => {
                 // ignore
             }
+            Some(PacketKind::DumpRequest(_)) => {
+                // ignore
+            }
+            Some(PacketKind::DumpReply(_)) => {
+                // ignore
+            }
+            Some(PacketKind::OutputRequest(_)) => {
+                // ignore
+            }
+            Some(PacketKind::OutputReply(_)) => {
+                // ignore
+            }
+            Some(PacketKind::ReplayRequest(request)) => {
+                let reply = match replay::start(&session.history, &request, session.delay, protocol.clone()) {
+                    Ok(length) => {
+                        let message = format!("replaying {:.1}s of audio", length.as_secs_f64());
+                        log::info!("{message}");
+                        ReplayReply::started(&message)
+                    }
+                    Err(message) => ReplayReply::error(&message),
+                };
+
+                let reply = reply.expect("allocate ReplayReply packet");
+                let _ = protocol.send_to(reply.as_packet(), peer);
+            }
+            Some(PacketKind::ReplayReply(_)) => {
+                // ignore
+            }
+            Some(PacketKind::VolumeRequest(_)) => {
+                // ignore
+            }
+            Some(PacketKind::ZoneRequest(_)) => {
+                // ignore
+            }
+            Some(PacketKind::Takeover(_)) => {
+                // ignore
+            }
+            Some(PacketKind::Identify(_)) => {
+                // ignore
+            }
+            Some(PacketKind::Signed(_)) => {
+                // unwrapped by auth above, so only nested signing is left
+            }
+            Some(PacketKind::DspRequest(_)) => {
+                // ignore
+            }
+            Some(PacketKind::StreamEnd(_)) => {
+                // ignore
+            }
+            Some(PacketKind::Metadata(_)) => {
+                // ignore
+            }
+            Some(PacketKind::ReceiverReport(report)) => {
+                session.status.report(peer, &report, session.sid());
+
+                if let Some(reception) = session.status.reception() {
+                    metrics.receiver_packets_lost.observe(reception.lost);
+                    metrics.receiver_jitter.observe(reception.jitter);
+                }
+            }
+            Some(PacketKind::SyncRequest(_)) => {
+                // ignore
+            }
+            Some(PacketKind::SyncReply(_)) => {
+                // ignore
+            }
             None => {
                 // unknown packet, ignore
             }
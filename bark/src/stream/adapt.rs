@@ -0,0 +1,220 @@
+//! Steps the stream down to cheaper codecs while receivers report sustained
+//! packet loss, and back up once reception has been clean for a while:
+//! f32le to s16le to opus at progressively lower bitrates. Each switch
+//! begins a new session, so receivers pick up the new format cleanly.
+
+use std::fmt::{self, Display};
+use std::time::{Duration, Instant};
+
+use bark_core::encode::{Encode, NewEncoderError};
+use bark_core::registry;
+use bark_protocol::types::AudioPacketFormat;
+
+#[cfg(feature = "opus")]
+use bark_core::encode::opus::{OpusEncoder, OpusEncoderOpt};
+
+use super::status::SourceStatus;
+
+/// How often reception is checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Time after a switch for receivers to pick up the new session before
+/// their reports are trusted again
+const SETTLE_TIME: Duration = Duration::from_secs(6);
+
+/// Loss above which a check counts against the current rung, and how many
+/// checks in a row it takes to step down
+const STEP_DOWN_LOSS: f64 = 0.02;
+const STEP_DOWN_CHECKS: u32 = 3;
+
+/// Loss below which reception counts as clean, and how long it must stay
+/// clean to step back up
+const STEP_UP_LOSS: f64 = 0.001;
+const STEP_UP_AFTER: Duration = Duration::from_secs(60);
+
+/// Opus bitrates to step down through, in bits per second
+#[cfg(feature = "opus")]
+const OPUS_BITRATES: [i32; 3] = [128_000, 96_000, 64_000];
+
+/// Packet sizes opus can encode, in frames
+#[cfg(feature = "opus")]
+pub const OPUS_PACKET_FRAMES: [u16; 4] = [120, 240, 480, 960];
+
+/// A codec the stream can be sent in
+#[derive(Clone, Copy, PartialEq)]
+pub struct Rung {
+    pub format: AudioPacketFormat,
+    /// Opus bitrate, None for the configured bitrate
+    pub bitrate: Option<i32>,
+}
+
+impl Display for Rung {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.format.name().unwrap_or("unknown");
+
+        match self.bitrate {
+            Some(bits) => write!(f, "{name} at {}kbps", bits / 1000),
+            None => write!(f, "{name}"),
+        }
+    }
+}
+
+pub struct Adaptation {
+    ladder: Vec<Rung>,
+    current: usize,
+    #[cfg(feature = "opus")]
+    opus: OpusEncoderOpt,
+    next_check: Instant,
+    switched: Instant,
+    // reception totals at the last check, to measure loss in between
+    baseline: Option<(u64, u64)>,
+    // consecutive checks with loss over STEP_DOWN_LOSS
+    lossy: u32,
+    // since when loss has stayed under STEP_UP_LOSS
+    clean_since: Option<Instant>,
+}
+
+impl Adaptation {
+    /// Adapt downwards from `top`, the configured codec. Opus is only
+    /// stepped down to when packets are a size it can encode
+    pub fn new(
+        top: AudioPacketFormat,
+        packet_frames: u16,
+        #[cfg(feature = "opus")] opus: OpusEncoderOpt,
+    ) -> Self {
+        let mut ladder = vec![Rung { format: top, bitrate: None }];
+
+        if top != AudioPacketFormat::S16LE && top != AudioPacketFormat::OPUS {
+            ladder.push(Rung { format: AudioPacketFormat::S16LE, bitrate: None });
+        }
+
+        #[cfg(feature = "opus")]
+        if OPUS_PACKET_FRAMES.contains(&packet_frames) {
+            if top != AudioPacketFormat::OPUS {
+                ladder.push(Rung { format: AudioPacketFormat::OPUS, bitrate: None });
+            }
+
+            // only step down to bitrates below the configured one
+            let configured = opus.bitrate.unwrap_or(i32::MAX);
+
+            for bits in OPUS_BITRATES.into_iter().filter(|bits| *bits < configured) {
+                ladder.push(Rung { format: AudioPacketFormat::OPUS, bitrate: Some(bits) });
+            }
+        }
+
+        #[cfg(not(feature = "opus"))]
+        let _ = packet_frames;
+
+        let now = Instant::now();
+
+        Adaptation {
+            ladder,
+            current: 0,
+            #[cfg(feature = "opus")]
+            opus,
+            next_check: now + CHECK_INTERVAL,
+            switched: now,
+            baseline: None,
+            lossy: 0,
+            clean_since: None,
+        }
+    }
+
+    /// The rung the stream is on
+    pub fn current(&self) -> Rung {
+        self.ladder[self.current]
+    }
+
+    pub fn new_encoder(&self, rung: Rung) -> Result<Box<dyn Encode>, NewEncoderError> {
+        #[cfg(feature = "opus")]
+        if rung.format == AudioPacketFormat::OPUS {
+            let opt = OpusEncoderOpt {
+                bitrate: rung.bitrate.or(self.opus.bitrate),
+                inband_fec: self.opus.inband_fec,
+                complexity: self.opus.complexity,
+                dtx: self.opus.dtx,
+            };
+
+            return Ok(Box::new(OpusEncoder::new(&opt)?) as Box<dyn Encode>);
+        }
+
+        registry::new_encoder(rung.format)
+    }
+
+    /// Check reception as receivers report it, returning the rung to
+    /// switch to if it calls for a change
+    pub fn poll(&mut self, status: &SourceStatus) -> Option<Rung> {
+        let now = Instant::now();
+
+        if now < self.next_check {
+            return None;
+        }
+
+        self.next_check = now + CHECK_INTERVAL;
+
+        // reports still describe the old session for a little while
+        if now.duration_since(self.switched) < SETTLE_TIME {
+            return None;
+        }
+
+        // nobody reporting, nothing to adapt to
+        let Some(reception) = status.reception() else {
+            self.baseline = None;
+            return None;
+        };
+
+        let (received, lost) = self.baseline.replace((reception.received, reception.lost))?;
+
+        // totals go backwards when receivers come and go, start again
+        if reception.received < received || reception.lost < lost {
+            return None;
+        }
+
+        let received = reception.received - received;
+        let lost = reception.lost - lost;
+
+        if received + lost == 0 {
+            return None;
+        }
+
+        let loss = lost as f64 / (received + lost) as f64;
+
+        if loss > STEP_DOWN_LOSS {
+            self.lossy += 1;
+            self.clean_since = None;
+        } else {
+            self.lossy = 0;
+
+            if loss <= STEP_UP_LOSS {
+                self.clean_since.get_or_insert(now);
+            } else {
+                self.clean_since = None;
+            }
+        }
+
+        if self.lossy >= STEP_DOWN_CHECKS && self.current + 1 < self.ladder.len() {
+            let rung = self.switch(self.current + 1, now);
+            log::warn!("receivers losing {:.1}% of packets, stepping down to {rung}", loss * 100.0);
+            return Some(rung);
+        }
+
+        let clean = self.clean_since.is_some_and(|since| now.duration_since(since) >= STEP_UP_AFTER);
+
+        if clean && self.current > 0 {
+            let rung = self.switch(self.current - 1, now);
+            log::info!("reception has been clean for {}s, stepping up to {rung}", STEP_UP_AFTER.as_secs());
+            return Some(rung);
+        }
+
+        None
+    }
+
+    fn switch(&mut self, index: usize, now: Instant) -> Rung {
+        self.current = index;
+        self.switched = now;
+        self.baseline = None;
+        self.lossy = 0;
+        self.clean_since = None;
+        self.ladder[index]
+    }
+}
@@ -0,0 +1,70 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::reconnect::Reconnect;
+
+/// Shortest and longest waits between attempts while the network is down
+const MIN_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Rides out network outages, such as an interface going down while WiFi
+/// reassociates, by backing off between attempts rather than giving up.
+/// Each outage is logged once when it begins and once when it ends.
+pub struct Backoff {
+    what: &'static str,
+    // when the current outage began, None while the network works
+    since: Option<Instant>,
+    reconnect: Reconnect,
+    // attempts that failed or were skipped during the current outage
+    failures: u64,
+}
+
+impl Backoff {
+    /// `what` describes the operation in logs, eg. "sending audio"
+    pub fn new(what: &'static str) -> Self {
+        Backoff {
+            what,
+            since: None,
+            reconnect: Reconnect::new(MIN_BACKOFF, MAX_BACKOFF),
+            failures: 0,
+        }
+    }
+
+    /// Whether to make an attempt now, rather than waiting out the backoff.
+    /// Callers that skip an attempt should count it with `skipped`
+    pub fn ready(&self) -> bool {
+        self.since.is_none() || self.reconnect.due()
+    }
+
+    /// Time left until the next attempt is due
+    pub fn delay(&self) -> Duration {
+        match self.since {
+            Some(_) => self.reconnect.delay(),
+            None => Duration::ZERO,
+        }
+    }
+
+    pub fn skipped(&mut self) {
+        self.failures += 1;
+    }
+
+    pub fn succeeded(&mut self) {
+        if let Some(since) = self.since.take() {
+            log::info!("{} recovered after {:.1}s, {} attempts failed or skipped",
+                self.what, since.elapsed().as_secs_f64(), self.failures);
+        }
+
+        self.failures = 0;
+    }
+
+    pub fn failed(&mut self, err: &io::Error) {
+        if self.since.is_none() {
+            log::warn!("error {}, backing off until the network recovers: {err}", self.what);
+            self.since = Some(Instant::now());
+            self.reconnect = Reconnect::new(MIN_BACKOFF, MAX_BACKOFF);
+        }
+
+        self.reconnect.failed();
+        self.failures += 1;
+    }
+}
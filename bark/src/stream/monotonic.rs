@@ -0,0 +1,60 @@
+use bark_protocol::time::{SampleDuration, Timestamp};
+use bark_protocol::types::TimestampMicros;
+
+use crate::stats::SourceMetrics;
+
+/// Keeps packet timestamps moving forwards when the capture layer reports
+/// timestamps that regress, as some do after an xrun. When that happens,
+/// the pts is instead derived from the previous packet plus the number of
+/// frames it contained, until the capture clock catches up again.
+pub struct MonotonicClock {
+    packet_duration: SampleDuration,
+    // allow a little jitter in capture timestamps before correcting them
+    tolerance: SampleDuration,
+    last_pts: Option<Timestamp>,
+    last_dts: TimestampMicros,
+    metrics: SourceMetrics,
+}
+
+impl MonotonicClock {
+    pub fn new(packet_frames: u16, metrics: SourceMetrics) -> Self {
+        let packet_duration = SampleDuration::from_frame_count(usize::from(packet_frames));
+
+        MonotonicClock {
+            packet_duration,
+            tolerance: SampleDuration::from_frame_count(usize::from(packet_frames / 2)),
+            last_pts: None,
+            last_dts: TimestampMicros(0),
+            metrics,
+        }
+    }
+
+    /// Presentation timestamp for the next packet, given the timestamp
+    /// captured for it
+    pub fn pts(&mut self, captured: Timestamp) -> Timestamp {
+        let pts = match self.last_pts {
+            Some(last) => {
+                let derived = last.add(self.packet_duration);
+
+                if captured < derived.saturating_sub(self.tolerance) {
+                    self.metrics.packets_corrected.increment();
+                    log::debug!("capture timestamp went backwards by {} frames, deriving pts from frame count",
+                        derived.duration_since(captured).to_frame_count());
+                    derived
+                } else {
+                    captured
+                }
+            }
+            None => captured,
+        };
+
+        self.last_pts = Some(pts);
+        pts
+    }
+
+    /// Decode timestamp for the next packet, never earlier than the last
+    pub fn dts(&mut self, now: TimestampMicros) -> TimestampMicros {
+        self.last_dts = TimestampMicros(now.0.max(self.last_dts.0));
+        self.last_dts
+    }
+}
@@ -0,0 +1,178 @@
+//! Now playing metadata from MPD, for sources fed by MPD's fifo output.
+//! The current song is polled over the MPD protocol and broadcast as a
+//! Metadata packet for the session, repeated so that receivers and
+//! `bark stats` starting up later pick it up too.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bark_protocol::packet::Metadata;
+
+use crate::socket::ProtocolSocket;
+
+use super::Session;
+
+/// How often the current song is polled and broadcast
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long to wait before reconnecting to MPD after an error
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Default, PartialEq)]
+struct Song {
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+}
+
+/// Poll MPD at `addr`, eg. `localhost:6600`, in the background
+pub(super) fn start(addr: String, protocol: Arc<ProtocolSocket>, session: Session) {
+    std::thread::spawn(move || {
+        bark_app::thread::set_name("bark/mpd");
+
+        // only report the first error of each outage
+        let mut failing = false;
+
+        loop {
+            match poll(&addr, &protocol, &session, &mut failing) {
+                Ok(()) => return,
+                Err(e) => {
+                    if !failing {
+                        log::warn!("error reading metadata from MPD at {addr}, will keep retrying: {e}");
+                    }
+                    failing = true;
+                }
+            }
+
+            std::thread::sleep(RETRY_INTERVAL);
+        }
+    });
+}
+
+/// Broadcast the current song until the stream stops or MPD goes away
+fn poll(addr: &str, protocol: &ProtocolSocket, session: &Session, failing: &mut bool) -> Result<(), io::Error> {
+    let mut mpd = Mpd::connect(addr)?;
+
+    if *failing {
+        log::info!("reconnected to MPD at {addr}");
+        *failing = false;
+    }
+
+    let mut playing = None;
+
+    while !session.stopping.load(std::sync::atomic::Ordering::Relaxed) {
+        let song = mpd.current_song()?;
+
+        if playing.as_ref() != Some(&song) {
+            log::info!("now playing: {}", describe(&song));
+        }
+
+        let packet = Metadata::new(
+            session.sid(),
+            song.title.as_deref(),
+            song.artist.as_deref(),
+            song.album.as_deref(),
+        ).expect("allocate Metadata packet");
+
+        if let Err(e) = protocol.broadcast(packet.as_packet()) {
+            log::warn!("error sending metadata: {e}");
+        }
+
+        playing = Some(song);
+        std::thread::sleep(POLL_INTERVAL);
+    }
+
+    Ok(())
+}
+
+fn describe(song: &Song) -> String {
+    match (&song.artist, &song.title) {
+        (Some(artist), Some(title)) => format!("{artist} - {title}"),
+        (None, Some(title)) => title.clone(),
+        (Some(artist), None) => artist.clone(),
+        (None, None) => "nothing".to_owned(),
+    }
+}
+
+struct Mpd {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Mpd {
+    fn connect(addr: &str) -> Result<Self, io::Error> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(RETRY_INTERVAL))?;
+
+        let mut mpd = Mpd {
+            reader: BufReader::new(stream.try_clone()?),
+            stream,
+        };
+
+        let greeting = mpd.read_line()?;
+
+        if !greeting.starts_with("OK MPD") {
+            return Err(invalid(format!("not an MPD server: {greeting:?}")));
+        }
+
+        Ok(mpd)
+    }
+
+    fn current_song(&mut self) -> Result<Song, io::Error> {
+        self.stream.write_all(b"currentsong\n")?;
+
+        let mut song = Song::default();
+        let mut file = None;
+        let mut name = None;
+
+        loop {
+            let line = self.read_line()?;
+
+            if line == "OK" {
+                break;
+            }
+
+            if line.starts_with("ACK") {
+                return Err(invalid(line));
+            }
+
+            let Some((key, value)) = line.split_once(": ") else {
+                continue;
+            };
+
+            let value = Some(value.to_owned());
+
+            match key {
+                "Title" => song.title = value,
+                "Artist" => song.artist = value,
+                "Album" => song.album = value,
+                // radio streams name themselves, files have a path at least
+                "Name" => name = value,
+                "file" => file = value,
+                _ => {}
+            }
+        }
+
+        if song.title.is_none() {
+            song.title = name.or(file);
+        }
+
+        Ok(song)
+    }
+
+    fn read_line(&mut self) -> Result<String, io::Error> {
+        let mut line = String::new();
+
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        Ok(line.trim_end_matches('\n').to_owned())
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,116 @@
+//! Falls back to a format every receiver can decode while one playing the
+//! stream says it can't decode the format it would otherwise be sent in,
+//! such as a receiver built without opus, and switches back once that
+//! receiver has gone. Each switch begins a new session, as adaptation's do.
+//!
+//! Packet size can't change once the stream has started, so is fitted to
+//! the largest packets receivers accept before it starts.
+
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant};
+
+use bark_protocol::types::AudioPacketFormat;
+
+use crate::socket::ProtocolSocket;
+use crate::{config, discover, zones, RunError};
+
+use super::status::SourceStatus;
+
+/// How often receivers' formats are checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to look for receivers' mDNS announcements before streaming
+const ANNOUNCE_BROWSE: Duration = Duration::from_secs(1);
+
+/// Formats to fall back to, most preferred first. Every bark receiver
+/// decodes PCM
+const FALLBACKS: [AudioPacketFormat; 4] = [
+    AudioPacketFormat::S16LE,
+    AudioPacketFormat::F32LE,
+    AudioPacketFormat::S24LE,
+    AudioPacketFormat::S32LE,
+];
+
+pub struct Negotiation {
+    next_check: Instant,
+    // preferred format at the last check
+    preferred: Option<AudioPacketFormat>,
+}
+
+impl Negotiation {
+    pub fn new() -> Self {
+        Negotiation {
+            next_check: Instant::now(),
+            preferred: None,
+        }
+    }
+
+    /// Check what receivers can decode, returning the format to switch to
+    /// if the stream should not stay in `current`. `preferred` is the
+    /// format it would be sent in if every receiver could decode it
+    pub fn poll(&mut self, status: &SourceStatus, preferred: AudioPacketFormat, current: AudioPacketFormat)
+        -> Option<AudioPacketFormat>
+    {
+        let now = Instant::now();
+
+        // check straight away when adaptation changes the preferred
+        // format, before any audio goes out in it
+        if now < self.next_check && self.preferred == Some(preferred) {
+            return None;
+        }
+
+        self.next_check = now + CHECK_INTERVAL;
+        self.preferred = Some(preferred);
+
+        // nobody saying, nothing to negotiate
+        let common = status.common_decoders()?;
+
+        let format = if common.contains(preferred) {
+            preferred
+        } else {
+            FALLBACKS.into_iter().find(|format| common.contains(*format))?
+        };
+
+        (format != current).then_some(format)
+    }
+}
+
+/// Ask receivers the largest packet they accept, and shrink `packet_frames`
+/// to fit the smallest of those, if any receiver says. Receivers say in
+/// their stats replies, and in their mDNS announcement on `group`, which
+/// also covers receivers that don't answer stats requests
+pub fn fit_packet_frames(protocol: &ProtocolSocket, group: Option<SocketAddrV4>, packet_frames: u16, codec: config::Codec)
+    -> Result<u16, RunError>
+{
+    let replied = zones::receivers(protocol)?
+        .values()
+        .filter_map(|reply| reply.data().receiver.max_packet_frames())
+        .min();
+
+    let announced = group.and_then(|group| discover::max_packet_frames(group, ANNOUNCE_BROWSE));
+
+    let max = replied.into_iter().chain(announced).min();
+
+    let Some(max) = max.filter(|max| *max < usize::from(packet_frames)) else {
+        return Ok(packet_frames);
+    };
+
+    let fits = |frames: &u16| usize::from(*frames) <= max;
+
+    let fitted = match codec {
+        #[cfg(feature = "opus")]
+        config::Codec::Opus => super::adapt::OPUS_PACKET_FRAMES.into_iter().rev().find(fits),
+        _ => u16::try_from(max).ok().filter(fits),
+    };
+
+    match fitted {
+        Some(frames) => {
+            log::warn!("a receiver accepts packets of up to {max} frames, sending {frames} frames per packet");
+            Ok(frames)
+        }
+        None => {
+            log::warn!("a receiver accepts packets of up to {max} frames, smaller than {codec} can send");
+            Ok(packet_frames)
+        }
+    }
+}
@@ -0,0 +1,52 @@
+//! Pacing for sources that aren't captured live, such as a file being
+//! announced or audio being replayed from history, which would otherwise
+//! go out as fast as it can be read. Packets are scheduled against the
+//! protocol clock from a fixed start rather than by sleeping for each
+//! packet's duration in turn, so time spent encoding and sending doesn't
+//! build up into drift.
+
+use bark_protocol::time::{SampleDuration, Timestamp};
+
+use crate::time;
+
+/// How far behind schedule sending may fall, such as after the process was
+/// descheduled, before the schedule is moved back rather than caught up
+/// on with a burst of packets. 100ms at 48 kHz
+const MAX_BURST: SampleDuration = SampleDuration::from_frame_count(4800);
+
+pub struct Pacer {
+    start: Timestamp,
+}
+
+impl Pacer {
+    /// Begin pacing with the first packet due at `start`
+    pub fn new(start: Timestamp) -> Self {
+        Pacer { start }
+    }
+
+    /// When the packet `offset` into the stream is due
+    pub fn due(&self, offset: SampleDuration) -> Timestamp {
+        self.start.add(offset)
+    }
+
+    /// Wait until the packet `offset` into the stream is due, returning the
+    /// time it was due at. Packets already due are returned straight away,
+    /// up to `MAX_BURST` behind, beyond which the whole schedule slips so
+    /// that receivers get a short gap rather than a flood of late packets
+    pub fn wait(&mut self, offset: SampleDuration) -> Timestamp {
+        let now = Timestamp::from_micros_lossy(time::now());
+        let due = self.due(offset);
+
+        let behind = now.saturating_duration_since(due);
+
+        if behind > MAX_BURST {
+            let slip = behind.sub(MAX_BURST);
+            log::warn!("fell {}ms behind sending, skipping ahead", slip.to_micros_lossy() / 1000);
+            self.start = self.start.add(slip);
+            return due.add(slip);
+        }
+
+        std::thread::sleep(due.saturating_duration_since(now).to_std_duration_lossy());
+        due
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bark_protocol::packet::{Audio, Packet};
+use bark_protocol::types::TimestampMicros;
+
+use crate::socket::ProtocolSocket;
+use crate::stats::SourceMetrics;
+
+use super::backoff::Backoff;
+
+/// Spread packets over this fraction of the time they carry, so that a
+/// burst is sent out before the next one arrives even if the input's clock
+/// runs a little fast
+const SPREAD: f64 = 0.9;
+
+/// Broadcasts audio packets, sending each one a configurable number of times
+/// so that receivers on lossy links are more likely to receive at least one
+/// copy. Receivers discard copies by seq.
+///
+/// Packets that can't be sent, such as while the network interface is
+/// down, are dropped and counted rather than failing the stream, and
+/// sending backs off until the network recovers.
+///
+/// Packets sent at the same moment, a packet and its unspaced copies along
+/// with any spaced copies now due, go out together in one batch.
+///
+/// With a transmit lead, each batch is handed to the kernel to leave a
+/// fixed time after its audio was captured, see `socket::txtime`.
+pub struct RedundantSender {
+    protocol: Arc<ProtocolSocket>,
+    metrics: SourceMetrics,
+    copies: u8,
+    spacing: Duration,
+    // spaced copies waiting to be sent
+    pending: VecDeque<(Instant, Audio)>,
+    backoff: Backoff,
+    pace: bool,
+    // when the next packet is due, when pacing
+    next: Option<Instant>,
+    // how long after capture packets leave, when sending with SO_TXTIME
+    txtime_lead: Option<Duration>,
+}
+
+impl RedundantSender {
+    pub fn new(protocol: Arc<ProtocolSocket>, metrics: SourceMetrics, copies: u8, spacing: Duration, pace: bool) -> Self {
+        RedundantSender {
+            protocol,
+            metrics,
+            copies: copies.max(1),
+            spacing,
+            pending: VecDeque::new(),
+            backoff: Backoff::new("sending audio"),
+            pace,
+            next: None,
+            txtime_lead: None,
+        }
+    }
+
+    /// Send each packet at its capture time plus `lead`, which must be
+    /// longer than the input period for packets to leave evenly spaced.
+    /// The socket must have transmit times enabled
+    pub fn with_txtime(mut self, lead: Duration) -> Self {
+        self.txtime_lead = Some(lead);
+        self
+    }
+
+    /// When pacing, wait until the next packet is due. Input with a period
+    /// longer than a packet hands over several packets at once, which are
+    /// then spread out rather than sent in a burst, which wifi in
+    /// particular handles poorly. Packets are never held back once sending
+    /// has fallen behind.
+    pub fn pace(&mut self, packet: Duration) {
+        if !self.pace {
+            return;
+        }
+
+        let now = Instant::now();
+
+        let sent = match self.next {
+            Some(due) if due > now => {
+                std::thread::sleep(due - now);
+                due
+            }
+            _ => now,
+        };
+
+        self.next = Some(sent + packet.mul_f64(SPREAD));
+    }
+
+    /// Broadcast a packet and any redundant copies. Spaced copies are sent
+    /// on later calls once they become due, so spacing is only as precise
+    /// as the packet duration.
+    pub fn broadcast(&mut self, audio: &Audio) {
+        let now = Instant::now();
+
+        let mut due = Vec::new();
+
+        while let Some(idx) = self.pending.iter().position(|(due, _)| *due <= now) {
+            if let Some((_, copy)) = self.pending.remove(idx) {
+                due.push(copy);
+            }
+        }
+
+        let mut batch = vec![audio.as_packet()];
+
+        if self.spacing.is_zero() {
+            for _ in 1..self.copies {
+                batch.push(audio.as_packet());
+            }
+        }
+
+        batch.extend(due.iter().map(|copy| copy.as_packet()));
+
+        // pts is capture time plus the stream's delay
+        let txtime = self.txtime_lead.map(|lead| {
+            let header = audio.header();
+            let captured = header.pts.0.saturating_sub(u64::from(header.delay_ms) * 1000);
+            TimestampMicros(captured + lead.as_micros() as u64)
+        });
+
+        self.send(&batch, txtime);
+
+        if !self.spacing.is_zero() {
+            for n in 1..self.copies {
+                let copy = Audio::new(audio.header(), audio.buffer_bytes())
+                    .expect("allocate Audio packet");
+
+                self.pending.push_back((now + self.spacing * u32::from(n), copy));
+            }
+        }
+    }
+
+    /// Send a batch of packets, the first an original and the rest copies
+    fn send(&mut self, batch: &[&Packet], txtime: Option<TimestampMicros>) {
+        if !self.backoff.ready() {
+            self.backoff.skipped();
+            self.metrics.packets_dropped.add(batch.len());
+            return;
+        }
+
+        if let Err(e) = self.protocol.broadcast_batch(batch, txtime) {
+            self.backoff.failed(&e);
+            self.metrics.packets_dropped.add(batch.len());
+            return;
+        }
+
+        self.backoff.succeeded();
+        self.metrics.packets_sent.add(batch.len());
+        self.metrics.packets_redundant.add(batch.len() - 1);
+        self.metrics.health.audio();
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bark_app::thread;
+use bark_core::audio::Format;
+use bark_protocol::packet::{Audio, ReplayRequest, StreamEnd};
+use bark_protocol::time::{SampleDuration, Timestamp};
+use bark_protocol::types::{AudioPacketHeader, TimestampMicros};
+
+#[cfg(feature = "opus")]
+use bark_core::audio::{self, FrameF32, Frames};
+#[cfg(feature = "opus")]
+use bark_core::encode::Encode;
+#[cfg(feature = "opus")]
+use bark_core::encode::opus::{OpusEncoder, OpusEncoderOpt};
+#[cfg(feature = "opus")]
+use bark_protocol::types::AudioPacketFormat;
+#[cfg(feature = "opus")]
+use bark_protocol::MAX_FRAMES_PER_PACKET;
+
+use crate::socket::{PeerId, ProtocolSocket};
+use crate::stream::pacer::Pacer;
+use crate::time;
+
+/// History is compressed with opus in packets of 20ms, or as long as fit,
+/// at a bitrate that keeps half a minute of it to around half a megabyte
+#[cfg(feature = "opus")]
+const HISTORY_PACKET_FRAMES: usize = if MAX_FRAMES_PER_PACKET < 960 { MAX_FRAMES_PER_PACKET } else { 960 };
+/// Shortest packet opus encodes, 2.5ms. Builds with packets shorter than
+/// this keep history as sent
+#[cfg(feature = "opus")]
+const OPUS_MIN_FRAMES: usize = 120;
+#[cfg(feature = "opus")]
+const HISTORY_BITRATE: i32 = 128_000;
+
+/// Rolling history of recently sent audio packets, compressed with opus
+/// unless the stream already is
+pub struct History {
+    length: Duration,
+    packets: Mutex<VecDeque<Audio>>,
+    // None if the encoder couldn't be created, history is then kept as sent
+    #[cfg(feature = "opus")]
+    compress: Mutex<Option<Compress>>,
+}
+
+impl History {
+    pub fn new(length: Duration) -> Self {
+        History {
+            length,
+            packets: Mutex::new(VecDeque::new()),
+            #[cfg(feature = "opus")]
+            compress: Mutex::new((!length.is_zero() && HISTORY_PACKET_FRAMES >= OPUS_MIN_FRAMES).then(Compress::new).flatten()),
+        }
+    }
+
+    /// Record a sent packet along with the audio it carries, discarding
+    /// packets older than the history length
+    pub fn record<F: Format>(&self, audio: Audio, frames: &[F::Frame]) {
+        if self.length.is_zero() {
+            return;
+        }
+
+        #[cfg(feature = "opus")]
+        if audio.header().format != AudioPacketFormat::OPUS {
+            if let Some(compress) = self.compress.lock().unwrap().as_mut() {
+                compress.push(audio.header(), F::frames(frames), |packet| self.push(packet));
+                return;
+            }
+        }
+
+        #[cfg(not(feature = "opus"))]
+        let _ = frames;
+
+        self.push(audio);
+    }
+
+    fn push(&self, audio: Audio) {
+        let cutoff = audio.header().pts.saturating_sub(self.length);
+
+        let mut packets = self.packets.lock().unwrap();
+        packets.push_back(audio);
+
+        while packets.front().is_some_and(|packet| packet.header().pts < cutoff) {
+            packets.pop_front();
+        }
+    }
+
+    /// Copy the most recent packets covering the given duration
+    fn recent(&self, duration: Duration) -> Vec<Audio> {
+        let packets = self.packets.lock().unwrap();
+
+        let Some(latest) = packets.back() else {
+            return Vec::new();
+        };
+
+        let cutoff = latest.header().pts.saturating_sub(duration);
+
+        packets.iter()
+            .filter(|packet| packet.header().pts >= cutoff)
+            .map(|packet| Audio::new(packet.header(), packet.buffer_bytes())
+                .expect("allocate Audio packet"))
+            .collect()
+    }
+}
+
+/// Begin replaying recent audio as a new high priority session, returning
+/// the duration of audio that will be replayed
+pub fn start(
+    history: &History,
+    request: &ReplayRequest,
+    delay: SampleDuration,
+    protocol: Arc<ProtocolSocket>,
+) -> Result<Duration, String> {
+    let packets = history.recent(request.duration());
+
+    let (Some(first), Some(last)) = (packets.first(), packets.last()) else {
+        return Err("no audio in replay history".to_owned());
+    };
+
+    let length = pts(last).saturating_duration_since(pts(first))
+        .add(last.header().packet_duration())
+        .to_std_duration_lossy();
+
+    let targets = request.targets()
+        .map(|addr| PeerId::from(SocketAddr::V4(addr)))
+        .collect::<Vec<_>>();
+
+    std::thread::spawn(move || {
+        thread::set_name("bark/replay");
+        thread::set_realtime_priority();
+        send(packets, targets, delay, &protocol);
+    });
+
+    Ok(length)
+}
+
+/// Compresses audio for the history
+#[cfg(feature = "opus")]
+struct Compress {
+    encoder: OpusEncoder,
+    frames: Vec<FrameF32>,
+    // pts of the first frame in `frames`
+    pts: Timestamp,
+}
+
+#[cfg(feature = "opus")]
+impl Compress {
+    fn new() -> Option<Self> {
+        let opt = OpusEncoderOpt {
+            bitrate: Some(HISTORY_BITRATE),
+            // keep encoding light, it happens on the audio thread
+            complexity: Some(5),
+            ..OpusEncoderOpt::default()
+        };
+
+        match OpusEncoder::new(&opt) {
+            Ok(encoder) => Some(Compress {
+                encoder,
+                frames: Vec::with_capacity(HISTORY_PACKET_FRAMES),
+                pts: Timestamp::from_micros_lossy(TimestampMicros(0)),
+            }),
+            Err(e) => {
+                log::warn!("error creating opus encoder, keeping replay history uncompressed: {e}");
+                None
+            }
+        }
+    }
+
+    /// Add a sent packet's audio, passing each packet of history completed
+    /// to `packet`
+    fn push(&mut self, header: &AudioPacketHeader, frames: Frames, mut packet: impl FnMut(Audio)) {
+        let pts = Timestamp::from_micros_lossy(header.pts);
+
+        for i in 0..frames.len() {
+            if self.frames.is_empty() {
+                self.pts = pts.add(SampleDuration::from_frame_count(i));
+            }
+
+            self.frames.push(match &frames {
+                Frames::S16(frames) => FrameF32(audio::s16_to_f32(frames[i].0), audio::s16_to_f32(frames[i].1)),
+                Frames::F32(frames) => frames[i],
+            });
+
+            if self.frames.len() < HISTORY_PACKET_FRAMES {
+                continue;
+            }
+
+            let mut encoded = [0; Audio::MAX_BUFFER_LENGTH];
+
+            match self.encoder.encode_packet(Frames::F32(&self.frames), &mut encoded) {
+                Ok(size) => {
+                    let header = AudioPacketHeader {
+                        pts: self.pts.to_micros_lossy(),
+                        format: AudioPacketFormat::OPUS,
+                        packet_frames: HISTORY_PACKET_FRAMES as u16,
+                        ..*header
+                    };
+
+                    packet(Audio::new(&header, &encoded[0..size])
+                        .expect("allocate Audio packet"));
+                }
+                Err(e) => {
+                    log::warn!("error compressing replay history: {e}");
+                }
+            }
+
+            self.frames.clear();
+        }
+    }
+}
+
+fn send(packets: Vec<Audio>, targets: Vec<PeerId>, delay: SampleDuration, protocol: &ProtocolSocket) {
+    let Some(first) = packets.first() else { return };
+    let first_pts = pts(first);
+
+    let sid = super::generate_session_id();
+    let mut pacer = Pacer::new(Timestamp::from_micros_lossy(time::now()));
+
+    for (seq, packet) in (1..).zip(&packets) {
+        let offset = pts(packet).saturating_duration_since(first_pts);
+
+        // pace packets as they were originally sent
+        let send_at = pacer.wait(offset);
+
+        let header = AudioPacketHeader {
+            sid,
+            seq,
+            pts: send_at.add(delay).to_micros_lossy(),
+            dts: time::now(),
+            // take over from the live stream for the duration of the replay
+            priority: i8::MAX,
+            // the original session's start has long passed
+            start_pts: TimestampMicros(0),
+            ..*packet.header()
+        };
+
+        let audio = Audio::new(&header, packet.buffer_bytes())
+            .expect("allocate Audio packet");
+
+        if targets.is_empty() {
+            let _ = protocol.broadcast(audio.as_packet());
+        } else {
+            for target in &targets {
+                let _ = protocol.send_to(audio.as_packet(), *target);
+            }
+        }
+    }
+
+    // let the last packet play out before ending the session, as receivers
+    // fade out as soon as they hear of the end
+    if let Some(last) = packets.last() {
+        let end = pts(last).saturating_duration_since(first_pts)
+            .add(last.header().packet_duration());
+
+        pacer.wait(end);
+        std::thread::sleep(delay.to_std_duration_lossy());
+    }
+
+    let end = StreamEnd::new(sid)
+        .expect("allocate StreamEnd packet");
+
+    for _ in 0..super::STREAM_END_REPEAT {
+        let _ = protocol.broadcast(end.as_packet());
+    }
+
+    log::info!("finished replay: sid={}", sid.0);
+}
+
+fn pts(packet: &Audio) -> Timestamp {
+    Timestamp::from_micros_lossy(packet.header().pts)
+}
@@ -0,0 +1,60 @@
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use bark_protocol::types::{AudioPacketHeader, SessionId};
+
+/// Watches for audio from a primary source so that a standby source can
+/// stay silent while the primary is streaming, and take over once it stops
+pub struct Standby {
+    timeout: Duration,
+    priority: i8,
+    // our own current session, so we don't mistake it for the primary
+    sid: AtomicI64,
+    last_seen: Mutex<Instant>,
+}
+
+impl Standby {
+    pub fn new(timeout: Duration, priority: i8, sid: SessionId) -> Self {
+        Standby {
+            timeout,
+            priority,
+            sid: AtomicI64::new(sid.0),
+            // give the primary a chance to be heard before taking over
+            last_seen: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn sid(&self) -> SessionId {
+        SessionId(self.sid.load(Ordering::Relaxed))
+    }
+
+    /// Record an audio packet seen on the network. Only streams at our
+    /// priority or above count as a primary. Replays are sent at maximum
+    /// priority but are not a live primary, so they are ignored.
+    pub fn observe(&self, header: &AudioPacketHeader) {
+        if header.sid == self.sid() {
+            return;
+        }
+
+        if header.priority < self.priority || header.priority == i8::MAX {
+            return;
+        }
+
+        *self.last_seen.lock().unwrap() = Instant::now();
+    }
+
+    /// Whether the primary has been silent long enough for us to send
+    pub fn primary_silent(&self) -> bool {
+        self.last_seen.lock().unwrap().elapsed() >= self.timeout
+    }
+
+    /// Start a new session when taking over from the primary. A fresh
+    /// session id makes receivers switch to us straight away at equal
+    /// priority, rather than waiting for the primary stream to time out.
+    pub fn begin_session(&self) -> SessionId {
+        let sid = super::generate_session_id();
+        self.sid.store(sid.0, Ordering::Relaxed);
+        sid
+    }
+}
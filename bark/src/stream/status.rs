@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU16, AtomicU64, AtomicU8, Ordering};
+use std::time::{Duration, Instant};
+
+use bark_protocol::SAMPLE_RATE;
+use bark_protocol::packet::ReceiverReport;
+use bark_protocol::types::{AudioFormatSet, AudioPacketFormat, SessionId};
+use bark_protocol::types::stats::receiver::ReceiverStats;
+use bark_protocol::types::stats::source::SourceStats;
+
+use crate::socket::PeerId;
+
+/// How long a receiver counts as playing the stream after its last stats
+/// reply, a couple of polls
+const RECEIVER_EXPIRY: Duration = Duration::from_secs(5);
+
+/// How often the source asks receivers for stats, to count them
+pub const RECEIVER_POLL: Duration = Duration::from_secs(2);
+
+/// What the source reports about its stream in stats replies. The audio
+/// thread records what it sends, and the network thread counts receivers
+/// from their stats replies
+pub struct SourceStatus {
+    started: Instant,
+    redundancy: u8,
+    // zero until the audio thread has started encoding
+    format: AtomicU8,
+    packet_frames: AtomicU16,
+    // audio sent, not counting redundant copies
+    packets: AtomicU64,
+    bytes: AtomicU64,
+    // receivers playing the stream, by when they last replied, with the
+    // formats they can decode if they say
+    receivers: Mutex<HashMap<PeerId, (Instant, Option<AudioFormatSet>)>>,
+    // receivers warned about not being able to decode the stream, and the
+    // format they were warned about
+    unsupported: Mutex<HashMap<PeerId, AudioPacketFormat>>,
+    // receivers warned about not accepting packets the size of the
+    // stream's, and the largest they said they accept
+    oversized: Mutex<HashMap<PeerId, usize>>,
+    // latest report from each receiver, and when it arrived
+    reports: Mutex<HashMap<PeerId, (Instant, Reception)>>,
+}
+
+/// How the stream is arriving at receivers, from their reports
+#[derive(Clone, Copy, Default)]
+pub struct Reception {
+    pub received: u64,
+    pub lost: u64,
+    /// Worst interarrival jitter among receivers
+    pub jitter: Duration,
+}
+
+impl Reception {
+    /// Fraction of packets lost
+    pub fn loss(&self) -> f64 {
+        match self.received + self.lost {
+            0 => 0.0,
+            expected => self.lost as f64 / expected as f64,
+        }
+    }
+}
+
+impl SourceStatus {
+    pub fn new(redundancy: u8) -> Self {
+        SourceStatus {
+            started: Instant::now(),
+            redundancy,
+            format: AtomicU8::new(0),
+            packet_frames: AtomicU16::new(0),
+            packets: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            receivers: Mutex::new(HashMap::new()),
+            unsupported: Mutex::new(HashMap::new()),
+            oversized: Mutex::new(HashMap::new()),
+            reports: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn set_format(&self, format: AudioPacketFormat, packet_frames: u16) {
+        self.format.store(bytemuck::cast(format), Ordering::Relaxed);
+        self.packet_frames.store(packet_frames, Ordering::Relaxed);
+    }
+
+    /// Record a packet of encoded audio sent
+    pub fn sent(&self, encoded_len: usize) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(encoded_len as u64, Ordering::Relaxed);
+    }
+
+    /// Format the stream is being sent in, once the audio thread has
+    /// started encoding
+    pub fn format(&self) -> Option<AudioPacketFormat> {
+        if self.packet_frames.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+
+        Some(bytemuck::cast(self.format.load(Ordering::Relaxed)))
+    }
+
+    /// Record a stats reply from a receiver playing `sid`
+    pub fn receiver(&self, peer: PeerId, sid: SessionId, current: SessionId, stats: &ReceiverStats) {
+        let decoders = stats.decoders();
+        let mut receivers = self.receivers.lock().unwrap();
+
+        if sid == current {
+            receivers.insert(peer, (Instant::now(), decoders));
+        } else {
+            // playing something else now
+            receivers.remove(&peer);
+        }
+
+        drop(receivers);
+
+        let mut unsupported = self.unsupported.lock().unwrap();
+
+        let format = self.format()
+            .filter(|_| sid == current)
+            .filter(|format| decoders.is_some_and(|decoders| !decoders.contains(*format)));
+
+        match format {
+            Some(format) => {
+                if unsupported.insert(peer, format) != Some(format) {
+                    log::warn!("receiver {peer} can't decode {} and is playing silence, \
+                        stream a format every receiver can decode or run with --negotiate-format",
+                        format.name().unwrap_or("the stream"));
+                }
+            }
+            None => {
+                unsupported.remove(&peer);
+            }
+        }
+
+        drop(unsupported);
+
+        let mut oversized = self.oversized.lock().unwrap();
+
+        let packet_frames = usize::from(self.packet_frames.load(Ordering::Relaxed));
+
+        let max = stats.max_packet_frames()
+            .filter(|max| sid == current && *max < packet_frames);
+
+        match max {
+            Some(max) => {
+                if oversized.insert(peer, max) != Some(max) {
+                    log::warn!("receiver {peer} accepts packets of up to {max} frames, not the \
+                        stream's {packet_frames}, and is playing silence. use a smaller --packet-ms \
+                        or run with --negotiate-format");
+                }
+            }
+            None => {
+                oversized.remove(&peer);
+            }
+        }
+    }
+
+    /// Formats every receiver playing the stream can decode, of those that
+    /// say. None if none do
+    pub fn common_decoders(&self) -> Option<AudioFormatSet> {
+        let mut receivers = self.receivers.lock().unwrap();
+        receivers.retain(|_, (last, _)| last.elapsed() < RECEIVER_EXPIRY);
+
+        receivers.values()
+            .filter_map(|(_, decoders)| *decoders)
+            .reduce(|common, decoders| common.intersection(decoders))
+    }
+
+    /// Record a receiver's report on the stream
+    pub fn report(&self, peer: PeerId, report: &ReceiverReport, current: SessionId) {
+        let mut reports = self.reports.lock().unwrap();
+
+        if report.sid() != current {
+            reports.remove(&peer);
+            return;
+        }
+
+        reports.insert(peer, (Instant::now(), Reception {
+            received: report.received(),
+            lost: report.lost(),
+            jitter: report.jitter(),
+        }));
+    }
+
+    /// Reception across receivers which have reported recently
+    pub fn reception(&self) -> Option<Reception> {
+        let mut reports = self.reports.lock().unwrap();
+        reports.retain(|_, (last, _)| last.elapsed() < RECEIVER_EXPIRY);
+
+        reports.values()
+            .map(|(_, reception)| *reception)
+            .reduce(|total, reception| Reception {
+                received: total.received + reception.received,
+                lost: total.lost + reception.lost,
+                jitter: total.jitter.max(reception.jitter),
+            })
+    }
+
+    pub fn stats(&self) -> SourceStats {
+        let mut stats = SourceStats::new();
+
+        stats.set_uptime(self.started.elapsed());
+
+        let receivers = {
+            let mut receivers = self.receivers.lock().unwrap();
+            receivers.retain(|_, (last, _)| last.elapsed() < RECEIVER_EXPIRY);
+            receivers.len()
+        };
+
+        stats.set_receivers(receivers);
+
+        if let Some(reception) = self.reception() {
+            stats.set_reception(reception.loss(), reception.jitter);
+        }
+
+        let Some(format) = self.format() else {
+            return stats;
+        };
+
+        let packet_frames = self.packet_frames.load(Ordering::Relaxed);
+
+        stats.set_format(format, packet_frames);
+
+        let packet_rate = f64::from(SAMPLE_RATE.0) / f64::from(packet_frames);
+        stats.set_packet_rate(packet_rate * f64::from(self.redundancy));
+
+        let packets = self.packets.load(Ordering::Relaxed);
+        let bytes = self.bytes.load(Ordering::Relaxed);
+
+        if packets > 0 {
+            let bytes_per_packet = bytes as f64 / packets as f64;
+            stats.set_bitrate(bytes_per_packet * 8.0 * packet_rate);
+        }
+
+        stats
+    }
+}
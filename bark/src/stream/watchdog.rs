@@ -0,0 +1,117 @@
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use bark_core::audio::Format;
+use bark_protocol::time::Timestamp;
+
+use crate::audio::{Error, Input};
+use crate::stats::SourceMetrics;
+
+/// Audio input that watches for a dead capture device: one delivering
+/// nothing but exact digital silence for a long time, as an ALSA loopback
+/// does once the player feeding it restarts without reopening it. Quiet
+/// passages, fades, and most players' idle output still carry some
+/// nonzero samples, so only exact zeros count.
+pub struct WatchedInput<F: Format> {
+    input: Input<F>,
+    timeout: Option<Duration>,
+    hook: Option<String>,
+    // when the current run of digital silence began
+    silent_since: Option<Instant>,
+    // whether we have already warned about the current run
+    tripped: bool,
+    // input reconnects already counted in metrics
+    reconnects: u64,
+    // whether the input was connected as of the last read
+    connected: bool,
+    metrics: SourceMetrics,
+}
+
+impl<F: Format> WatchedInput<F> {
+    /// Warn, and run `hook` with `sh -c` if given, once input has been
+    /// silent for `timeout`. `None` only reports silence in metrics
+    pub fn new(input: Input<F>, timeout: Option<Duration>, hook: Option<String>, metrics: SourceMetrics) -> Self {
+        WatchedInput {
+            input,
+            timeout,
+            hook,
+            silent_since: None,
+            tripped: false,
+            reconnects: 0,
+            connected: true,
+            metrics,
+        }
+    }
+
+    pub fn read(&mut self, audio: &mut [F::Frame]) -> Result<Timestamp, Error> {
+        let timestamp = self.input.read(audio)?;
+
+        let reconnects = self.input.reconnects();
+        if reconnects != self.reconnects {
+            self.metrics.input_reconnects.add((reconnects - self.reconnects) as usize);
+            self.reconnects = reconnects;
+        }
+
+        let connected = self.input.is_connected();
+        if connected != self.connected {
+            if connected {
+                self.metrics.health.device_restored();
+            } else {
+                self.metrics.health.device_lost();
+            }
+            self.connected = connected;
+        }
+
+        self.metrics.health.heartbeat();
+
+        self.observe(audio);
+        Ok(timestamp)
+    }
+
+    fn observe(&mut self, audio: &[F::Frame]) {
+        let silent = bytemuck::cast_slice::<F::Frame, u8>(audio)
+            .iter()
+            .all(|byte| *byte == 0);
+
+        if !silent {
+            if self.tripped {
+                log::info!("audio input has recovered from digital silence");
+            }
+
+            self.silent_since = None;
+            self.tripped = false;
+            self.metrics.input_silence.observe(Duration::ZERO);
+            return;
+        }
+
+        let silent_for = self.silent_since.get_or_insert_with(Instant::now).elapsed();
+        self.metrics.input_silence.observe(silent_for);
+
+        let Some(timeout) = self.timeout else {
+            return;
+        };
+
+        if self.tripped || silent_for < timeout {
+            return;
+        }
+
+        self.tripped = true;
+
+        log::warn!("audio input has been digital silence for {}s, capture device may be dead \
+            (eg. a loopback whose player has restarted)", silent_for.as_secs());
+
+        if let Some(hook) = self.hook.clone() {
+            // run off the audio thread so input keeps being read
+            std::thread::spawn(move || {
+                bark_app::thread::set_name("bark/watchdog");
+                log::info!("running input watchdog hook: {hook}");
+
+                match Command::new("sh").arg("-c").arg(&hook).status() {
+                    Ok(status) if status.success() => {}
+                    Ok(status) => log::warn!("input watchdog hook failed: {status}"),
+                    Err(e) => log::error!("error running input watchdog hook: {e}"),
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! How closely receivers play together. Each round asks every receiver
+//! when it heard the same upcoming packet of a stream, and compares their
+//! answers. Times are from each receiver's own clock, as bark plays by, so
+//! error in synchronising the clocks themselves doesn't show up in the
+//! comparison. It is estimated separately from the round trip of each
+//! request, as NTP does.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use structopt::StructOpt;
+
+use bark_protocol::packet::{PacketKind, SyncRequest};
+use bark_protocol::types::{AudioPacketHeader, SessionId, TimestampMicros};
+
+use crate::socket::{PeerId, ProtocolSocket, Socket, SocketOpt};
+use crate::{time, RunError};
+
+/// How far ahead the packet asked about must be, so that every receiver
+/// has the request before it plays
+const MARGIN: Duration = Duration::from_millis(50);
+
+/// How long after the packet plays replies are waited for
+const REPLY_GRACE: Duration = Duration::from_millis(200);
+
+#[derive(StructOpt)]
+pub struct SyncCheckOpt {
+    #[structopt(flatten)]
+    pub socket: SocketOpt,
+
+    /// Addresses of the receivers to compare, as shown in `bark stats`.
+    /// Every receiver playing the stream if not given
+    #[structopt(long)]
+    pub peer: Vec<SocketAddr>,
+
+    /// Session to check, as shown in `bark stats`. The first stream heard
+    /// if not given
+    #[structopt(long, allow_hyphen_values = true)]
+    pub sid: Option<i64>,
+
+    /// Rounds of measurements
+    #[structopt(long, short = "c", default_value = "10")]
+    pub count: u64,
+
+    /// Milliseconds between rounds, which is also how long replies are
+    /// waited for
+    #[structopt(long, default_value = "1000")]
+    pub interval_ms: u64,
+}
+
+struct Reply {
+    /// When the packet was heard, by the receiver's clock. None if missed
+    played: Option<i64>,
+    /// Estimated offset of the receiver's clock from ours, in microseconds
+    clock: i64,
+}
+
+type Round = BTreeMap<PeerId, Reply>;
+
+pub fn run(opt: SyncCheckOpt) -> Result<(), RunError> {
+    let socket = Socket::open(&opt.socket)
+        .map_err(RunError::Listen)?;
+
+    let protocol = ProtocolSocket::new(socket);
+    let interval = Duration::from_millis(opt.interval_ms);
+    let peers = opt.peer.iter().copied().map(PeerId::from).collect::<Vec<_>>();
+
+    let mut sid = opt.sid.map(SessionId);
+    let mut rounds = Vec::new();
+
+    for _ in 0..opt.count {
+        let start = Instant::now();
+
+        let Some(header) = next_packet(&protocol, sid, start + interval)? else {
+            println!("no stream heard");
+            continue;
+        };
+
+        if sid.is_none() {
+            println!("checking session {}", header.sid.0);
+            sid = Some(header.sid);
+        }
+
+        let (seq, wait) = upcoming(&header);
+        let sent = time::now();
+        let request = SyncRequest::new(header.sid, seq, sent).expect("allocate SyncRequest packet");
+
+        if peers.is_empty() {
+            protocol.broadcast(request.as_packet()).map_err(RunError::Send)?;
+        } else {
+            for peer in &peers {
+                protocol.send_to(request.as_packet(), *peer).map_err(RunError::Send)?;
+            }
+        }
+
+        let deadline = std::cmp::max(start + interval, Instant::now() + wait + REPLY_GRACE);
+        let round = collect(&protocol, &request, &peers, deadline)?;
+
+        print_round(seq, &round);
+        rounds.push(round);
+    }
+
+    summary(&rounds);
+    Ok(())
+}
+
+/// Wait for a packet of the session to check, any session if `None`
+fn next_packet(protocol: &ProtocolSocket, sid: Option<SessionId>, deadline: Instant)
+    -> Result<Option<AudioPacketHeader>, RunError>
+{
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+
+        if remaining.is_zero() {
+            return Ok(None);
+        }
+
+        let Some((packet, _)) = protocol.recv_from_timeout(Some(remaining))
+            .map_err(RunError::Receive)? else {
+            return Ok(None);
+        };
+
+        let Some(PacketKind::Audio(audio)) = packet.parse() else {
+            continue;
+        };
+
+        if sid.is_some_and(|sid| sid != audio.header().sid) {
+            continue;
+        }
+
+        return Ok(Some(*audio.header()));
+    }
+}
+
+/// A packet of the stream far enough ahead of `header` that no receiver
+/// will have played it before being asked, and how long until it plays
+fn upcoming(header: &AudioPacketHeader) -> (u64, Duration) {
+    let now = time::now().0 as i64;
+    let packet = header.packet_duration().to_micros_lossy().max(1) as i64;
+    let ahead = header.pts.0 as i64 - now;
+
+    let short = (MARGIN.as_micros() as i64 - ahead).max(0);
+    let skip = (short + packet - 1) / packet;
+
+    let plays_in = (ahead + skip * packet).max(0) as u64;
+    (header.seq + skip as u64, Duration::from_micros(plays_in))
+}
+
+/// Collect replies to `request` until `deadline`
+fn collect(protocol: &ProtocolSocket, request: &SyncRequest, peers: &[PeerId], deadline: Instant)
+    -> Result<Round, RunError>
+{
+    let mut round = Round::new();
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+
+        if remaining.is_zero() {
+            return Ok(round);
+        }
+
+        let Some((packet, peer)) = protocol.recv_from_timeout(Some(remaining))
+            .map_err(RunError::Receive)? else {
+            return Ok(round);
+        };
+
+        let now = time::now();
+
+        let Some(PacketKind::SyncReply(reply)) = packet.parse() else {
+            continue;
+        };
+
+        // a late reply to an earlier round
+        if reply.seq() != request.seq() || reply.sent() != request.sent() {
+            continue;
+        }
+
+        if !peers.is_empty() && !peers.contains(&peer) {
+            continue;
+        }
+
+        round.insert(peer, Reply {
+            played: reply.played().map(|played| played.0 as i64),
+            clock: clock_offset(request.sent(), reply.received(), reply.replied(), now),
+        });
+    }
+}
+
+/// Offset of a receiver's clock from ours, given the times a request was
+/// sent, received, replied to, and the reply received
+fn clock_offset(sent: TimestampMicros, received: TimestampMicros, replied: TimestampMicros, now: TimestampMicros) -> i64 {
+    let there = received.0 as i64 - sent.0 as i64;
+    let back = replied.0 as i64 - now.0 as i64;
+    (there + back) / 2
+}
+
+fn print_round(seq: u64, round: &Round) {
+    let earliest = round.values().filter_map(|reply| reply.played).min();
+
+    let Some(earliest) = earliest else {
+        println!("seq={seq}: no receiver played it");
+        return;
+    };
+
+    let latest = round.values().filter_map(|reply| reply.played).max().unwrap_or(earliest);
+
+    let peers = round.iter()
+        .map(|(peer, reply)| match reply.played {
+            Some(played) => format!("{peer} +{}us", played - earliest),
+            None => format!("{peer} missed"),
+        })
+        .collect::<Vec<_>>();
+
+    println!("seq={seq}: spread {}us: {}", latest - earliest, peers.join(", "));
+}
+
+fn summary(rounds: &[Round]) {
+    println!();
+
+    let mut peers = BTreeMap::<PeerId, (u64, u64, Vec<i64>)>::new();
+
+    for round in rounds {
+        for (peer, reply) in round {
+            let (played, missed, clocks) = peers.entry(*peer).or_default();
+
+            match reply.played {
+                Some(_) => *played += 1,
+                None => *missed += 1,
+            }
+
+            clocks.push(reply.clock);
+        }
+    }
+
+    if peers.is_empty() {
+        println!("no replies");
+        return;
+    }
+
+    println!("{:<21}  {:>6}  {:>6}  {:>10}", "receiver", "played", "missed", "clock");
+
+    for (peer, (played, missed, clocks)) in &peers {
+        println!("{:<21}  {:>6}  {:>6}  {:>10}", peer.to_string(), played, missed, micros(mean(clocks)));
+    }
+
+    if peers.len() < 2 {
+        return;
+    }
+
+    println!();
+    println!("{:<21}  {:<21}  {:>6}  {:>10}  {:>10}  {:>10}", "receiver", "other", "rounds", "mean", "max", "clock");
+
+    let ids = peers.keys().copied().collect::<Vec<_>>();
+
+    for (i, a) in ids.iter().enumerate() {
+        for b in &ids[i + 1..] {
+            let mut deviations = Vec::new();
+            let mut clocks = Vec::new();
+
+            for round in rounds {
+                let (Some(reply_a), Some(reply_b)) = (round.get(a), round.get(b)) else {
+                    continue;
+                };
+
+                clocks.push(reply_b.clock - reply_a.clock);
+
+                if let (Some(played_a), Some(played_b)) = (reply_a.played, reply_b.played) {
+                    deviations.push(played_b - played_a);
+                }
+            }
+
+            let max = deviations.iter().copied().max_by_key(|deviation| deviation.abs());
+
+            println!("{:<21}  {:<21}  {:>6}  {:>10}  {:>10}  {:>10}",
+                a.to_string(),
+                b.to_string(),
+                deviations.len(),
+                micros(mean(&deviations)),
+                micros(max),
+                micros(mean(&clocks)));
+        }
+    }
+}
+
+fn mean(values: &[i64]) -> Option<i64> {
+    let len = i64::try_from(values.len()).ok().filter(|len| *len > 0)?;
+    Some(values.iter().sum::<i64>() / len)
+}
+
+fn micros(value: Option<i64>) -> String {
+    match value {
+        Some(value) => format!("{value:+}us"),
+        None => "-".to_owned(),
+    }
+}
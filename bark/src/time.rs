@@ -1,11 +1,75 @@
+use std::fs::File;
+use std::os::fd::IntoRawFd;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
 use nix::sys::time::TimeValLike;
 use nix::time::ClockId;
 
 use bark_protocol::types::TimestampMicros;
 
+/// Clock that all audio timestamps are taken from. Every node must use
+/// clocks that are synchronised with each other, eg. by NTP or PTP
+#[derive(Debug, Clone)]
+pub enum Clock {
+    /// System wall clock
+    Realtime,
+    /// PTP hardware clock of a network interface, eg. /dev/ptp0
+    Ptp(PathBuf),
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("clock must be realtime, ptp, or ptp:<device>")]
+pub struct ParseClockError;
+
+impl FromStr for Clock {
+    type Err = ParseClockError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "realtime" => Ok(Clock::Realtime),
+            "ptp" => Ok(Clock::Ptp(PathBuf::from("/dev/ptp0"))),
+            _ => match s.strip_prefix("ptp:") {
+                Some(device) => Ok(Clock::Ptp(PathBuf::from(device))),
+                None => Err(ParseClockError),
+            },
+        }
+    }
+}
+
+static CLOCK: OnceLock<ClockId> = OnceLock::new();
+
+/// Select the clock used by `now`. Must be called before any timestamps
+/// are taken, defaults to the realtime clock otherwise
+pub fn set_clock(clock: &Clock) -> Result<(), std::io::Error> {
+    let id = match clock {
+        Clock::Realtime => ClockId::CLOCK_REALTIME,
+        Clock::Ptp(device) => {
+            // the device must stay open for as long as we read the clock,
+            // which is the life of the process
+            let fd = File::open(device)?.into_raw_fd();
+
+            // see FD_TO_CLOCKID in linux/posix-timers.h
+            let id = ClockId::from_raw((!fd << 3) | 3);
+
+            // make sure it is actually a clock
+            nix::time::clock_gettime(id)?;
+
+            log::info!("using PTP hardware clock {}", device.display());
+            id
+        }
+    };
+
+    let _ = CLOCK.set(id);
+    Ok(())
+}
+
 pub fn now() -> TimestampMicros {
-    let timespec = nix::time::clock_gettime(ClockId::CLOCK_REALTIME)
-        .expect("clock_gettime(CLOCK_REALTIME)");
+    let clock = CLOCK.get().copied().unwrap_or(ClockId::CLOCK_REALTIME);
+
+    let timespec = nix::time::clock_gettime(clock)
+        .expect("clock_gettime");
 
     let micros = u64::try_from(timespec.num_microseconds())
         .expect("cannot convert i64 time value to u64");
@@ -0,0 +1,172 @@
+//! Reading WAV files that bark plays itself, such as announcements. Only
+//! formats that need no conversion beyond stereo f32 are accepted: 16 bit
+//! or float, mono or stereo, at 48 kHz.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use bark_core::audio::FrameF32;
+use bark_protocol::SAMPLE_RATE;
+
+#[derive(Debug, Error)]
+pub enum WavError {
+    #[error("reading {0}: {1}")]
+    Read(PathBuf, io::Error),
+    #[error("{0} is not a WAV file")]
+    NotWav(PathBuf),
+    #[error("{0}: {1}")]
+    Unsupported(PathBuf, String),
+}
+
+/// Reads a WAV file's audio a little at a time, so that long files such as
+/// music need not be held in memory
+pub struct Reader {
+    path: PathBuf,
+    file: BufReader<File>,
+    // bytes of audio left in the data chunk
+    remaining: u64,
+    sample: fn(&[u8]) -> f32,
+    width: usize,
+    channels: usize,
+}
+
+impl Reader {
+    pub fn open(path: &Path) -> Result<Reader, WavError> {
+        let read_err = |e| WavError::Read(path.to_owned(), e);
+        let not_wav = || WavError::NotWav(path.to_owned());
+        let unsupported = |msg: String| WavError::Unsupported(path.to_owned(), msg);
+
+        let mut file = BufReader::new(File::open(path).map_err(read_err)?);
+
+        let mut riff = [0; 12];
+        file.read_exact(&mut riff).map_err(|_| not_wav())?;
+
+        if &riff[0..4] != b"RIFF" || &riff[8..12] != b"WAVE" {
+            return Err(not_wav());
+        }
+
+        let mut fmt = None;
+
+        // the data chunk follows the fmt chunk, anything else is skipped
+        let data_len = loop {
+            let mut header = [0; 8];
+            file.read_exact(&mut header).map_err(|_| not_wav())?;
+
+            let id = &header[0..4];
+            let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+
+            match id {
+                b"data" => break len,
+                b"fmt " => {
+                    let mut body = vec![0; len as usize];
+                    file.read_exact(&mut body).map_err(|_| not_wav())?;
+                    fmt = Some(body);
+                }
+                _ => {
+                    file.seek(SeekFrom::Current(i64::from(len))).map_err(read_err)?;
+                }
+            }
+
+            // chunks are padded to an even length
+            if len % 2 == 1 {
+                file.seek(SeekFrom::Current(1)).map_err(read_err)?;
+            }
+        };
+
+        let fmt = fmt.filter(|fmt| fmt.len() >= 16).ok_or_else(not_wav)?;
+
+        let u16_at = |i: usize| u16::from_le_bytes([fmt[i], fmt[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([fmt[i], fmt[i + 1], fmt[i + 2], fmt[i + 3]]);
+
+        let mut tag = u16_at(0);
+        let channels = u16_at(2);
+        let rate = u32_at(4);
+        let bits = u16_at(14);
+
+        // WAVE_FORMAT_EXTENSIBLE keeps the real format tag in its sub format
+        if tag == 0xfffe && fmt.len() >= 26 {
+            tag = u16_at(24);
+        }
+
+        if rate != SAMPLE_RATE.0 {
+            return Err(unsupported(format!("sample rate is {rate} Hz, resample to {} Hz first", SAMPLE_RATE.0)));
+        }
+
+        let sample: fn(&[u8]) -> f32 = match (tag, bits) {
+            (1, 16) => |b| f32::from(i16::from_le_bytes([b[0], b[1]])) / 32768.0,
+            (3, 32) => |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            _ => return Err(unsupported(format!("{bits} bit samples of format {tag} are not supported, use 16 bit or float"))),
+        };
+
+        if channels != 1 && channels != 2 {
+            return Err(unsupported(format!("{channels} channel audio is not supported, use mono or stereo")));
+        }
+
+        Ok(Reader {
+            path: path.to_owned(),
+            file,
+            remaining: u64::from(data_len),
+            sample,
+            width: usize::from(bits / 8),
+            channels: usize::from(channels),
+        })
+    }
+
+    /// Read audio into `frames`, returning how many frames were read. Fewer
+    /// than asked for are only returned at the end of the file
+    pub fn read(&mut self, frames: &mut [FrameF32]) -> Result<usize, WavError> {
+        let frame_bytes = self.width * self.channels;
+        let want = (frames.len() * frame_bytes).min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+
+        let mut bytes = vec![0; want];
+        let mut read = 0;
+
+        // files still being written may have a data length running past
+        // their end, so stop early at end of file
+        while read < want {
+            match self.file.read(&mut bytes[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(WavError::Read(self.path.clone(), e)),
+            }
+        }
+
+        self.remaining -= read as u64;
+
+        let mut count = 0;
+
+        for (frame, bytes) in frames.iter_mut().zip(bytes[0..read].chunks_exact(frame_bytes)) {
+            let left = (self.sample)(&bytes[0..self.width]);
+            let right = match self.channels {
+                1 => left,
+                _ => (self.sample)(&bytes[self.width..]),
+            };
+
+            *frame = FrameF32(left, right);
+            count += 1;
+        }
+
+        Ok(count)
+    }
+}
+
+/// Read a whole WAV file in
+pub fn read(path: &Path) -> Result<Vec<FrameF32>, WavError> {
+    let mut reader = Reader::open(path)?;
+    let mut frames = Vec::new();
+    let mut buffer = [FrameF32(0.0, 0.0); 4096];
+
+    loop {
+        let n = reader.read(&mut buffer)?;
+
+        if n == 0 {
+            return Ok(frames);
+        }
+
+        frames.extend_from_slice(&buffer[0..n]);
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use structopt::StructOpt;
+
+use bark_protocol::packet::{PacketKind, StatsReply, StatsRequest, ZoneRequest};
+use bark_protocol::types::{StatsReplyFlags, ZoneFlags, ZONE_NAME_LENGTH};
+
+use crate::socket::{PeerId, ProtocolSocket, Socket, SocketOpt};
+use crate::stats;
+use crate::RunError;
+
+// how long to collect stats replies for when listing zones, asking again
+// every POLL_INTERVAL in case a request or reply goes missing
+const LIST_COLLECT: Duration = Duration::from_millis(500);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(StructOpt)]
+pub enum ZonesOpt {
+    /// List receivers grouped by zone
+    List(ListOpt),
+    /// Move a receiver into a zone
+    Assign(AssignOpt),
+    /// Mute every receiver in a zone, or all receivers if no zone given
+    Mute(ZoneOpt),
+    /// Unmute every receiver in a zone, or all receivers if no zone given
+    Unmute(ZoneOpt),
+    /// Unmute a zone and mute every other receiver
+    Solo(ZoneOpt),
+}
+
+#[derive(StructOpt)]
+pub struct ListOpt {
+    #[structopt(flatten)]
+    pub socket: SocketOpt,
+}
+
+#[derive(StructOpt)]
+pub struct AssignOpt {
+    #[structopt(flatten)]
+    pub socket: SocketOpt,
+
+    /// Address of the receiver, as shown in `bark stats`
+    #[structopt(long)]
+    pub peer: SocketAddr,
+
+    /// Zone name, or remove the receiver from its zone if omitted
+    #[structopt(parse(try_from_str = parse_zone))]
+    pub zone: Option<String>,
+}
+
+#[derive(StructOpt)]
+pub struct ZoneOpt {
+    #[structopt(flatten)]
+    pub socket: SocketOpt,
+
+    #[structopt(parse(try_from_str = parse_zone))]
+    pub zone: Option<String>,
+}
+
+pub fn parse_zone(zone: &str) -> Result<String, String> {
+    if zone.len() > ZONE_NAME_LENGTH {
+        return Err(format!("zone name longer than {ZONE_NAME_LENGTH} bytes"));
+    }
+
+    Ok(zone.to_owned())
+}
+
+pub fn run(opt: ZonesOpt) -> Result<(), RunError> {
+    match opt {
+        ZonesOpt::List(opt) => list(opt),
+        ZonesOpt::Assign(opt) => {
+            let peer = PeerId::from(opt.peer);
+            send(&opt.socket, ZoneFlags::ASSIGN, opt.zone.as_deref(), Some(peer))
+        }
+        ZonesOpt::Mute(opt) => send(&opt.socket, ZoneFlags::MUTE, opt.zone.as_deref(), None),
+        ZonesOpt::Unmute(opt) => send(&opt.socket, ZoneFlags::UNMUTE, opt.zone.as_deref(), None),
+        ZonesOpt::Solo(opt) => send(&opt.socket, ZoneFlags::SOLO, opt.zone.as_deref(), None),
+    }
+}
+
+fn send(socket: &SocketOpt, flags: ZoneFlags, zone: Option<&str>, peer: Option<PeerId>) -> Result<(), RunError> {
+    let socket = Socket::open(socket)
+        .map_err(RunError::Listen)?;
+
+    let protocol = ProtocolSocket::new(socket);
+
+    let request = ZoneRequest::new(flags, zone)
+        .expect("allocate ZoneRequest packet");
+
+    let result = match peer {
+        Some(peer) => protocol.send_to(request.as_packet(), peer),
+        None => protocol.broadcast(request.as_packet()),
+    };
+
+    result.map_err(RunError::Send)
+}
+
+fn list(opt: ListOpt) -> Result<(), RunError> {
+    let socket = Socket::open(&opt.socket)
+        .map_err(RunError::Listen)?;
+
+    let protocol = ProtocolSocket::new(socket);
+    let receivers = receivers(&protocol)?;
+
+    let mut zones = BTreeMap::<Option<&str>, Vec<(PeerId, &StatsReply)>>::new();
+
+    for (peer, reply) in &receivers {
+        zones.entry(reply.data().receiver.zone())
+            .or_default()
+            .push((*peer, reply));
+    }
+
+    for (zone, mut members) in zones {
+        println!("{}", zone.unwrap_or("(no zone)"));
+
+        members.sort_by_key(|(peer, _)| *peer);
+
+        for (peer, reply) in members {
+            let receiver = &reply.data().receiver;
+            let node = stats::node::display(&reply.data().node);
+            let muted = if receiver.zone_muted() { "  (muted)" } else { "" };
+            println!("  {node}  {peer}{muted}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Every receiver that replies to stats requests, with its reply, which
+/// says what zone it's in
+pub fn receivers(protocol: &ProtocolSocket) -> Result<HashMap<PeerId, StatsReply>, RunError> {
+    let request = StatsRequest::new()
+        .expect("allocate StatsRequest packet");
+
+    let mut receivers = HashMap::<PeerId, StatsReply>::new();
+    let started = Instant::now();
+    let mut next_poll = started;
+
+    // stop at the end of the collection window, even if nothing replies
+    while let Some(remaining) = LIST_COLLECT.checked_sub(started.elapsed()) {
+        if Instant::now() >= next_poll {
+            let _ = protocol.broadcast(request.as_packet());
+            next_poll += POLL_INTERVAL;
+        }
+
+        let wait = remaining.min(next_poll.saturating_duration_since(Instant::now()));
+
+        let received = protocol.recv_from_timeout(Some(wait))
+            .map_err(RunError::Receive)?;
+
+        let Some((reply, peer)) = received else {
+            continue;
+        };
+
+        let Some(PacketKind::StatsReply(reply)) = reply.parse() else {
+            continue;
+        };
+
+        if reply.flags().contains(StatsReplyFlags::IS_RECEIVER) {
+            receivers.insert(peer, reply);
+        }
+    }
+
+    Ok(receivers)
+}